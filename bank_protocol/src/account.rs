@@ -0,0 +1,170 @@
+//! IBAN-like account numbers: `BB` (check digits: 2) (bank code: 4) (account id: 10 digits)
+//!
+//! The check digits are ISO 7064 MOD 97-10 like IBAN, so one wrong digit or two swapped digits
+//! are always found.
+//!
+//! Legacy accounts are still numbered by the bare `u32` id, a string of digits only is taken as
+//! the legacy id if in [`LEGACY_ID_RANGE`]. The others have no check digits to find the typos, so
+//! the full account number is needed.
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+pub const COUNTRY_CODE: &'static str = "BB";
+pub const BANK_CODE: &'static str = "6226";
+pub const ACCOUNT_NUMBER_LEN: usize = 18;
+/// The bare ids accepted as the legacy accounts, below the new ones
+pub const LEGACY_ID_RANGE: std::ops::Range<u32> = 0..10_000_000;
+/// The range for allocating new account ids
+pub const NEW_ID_RANGE: std::ops::RangeInclusive<u32> = 10_000_000..=999_999_999;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountNumberError {
+    Empty,
+    BadLength,
+    BadCountry,
+    BadBankCode,
+    BadCharacter,
+    BadCheckDigits,
+    /// Digits only but not a legacy id
+    NotLegacyId,
+}
+
+impl AccountNumberError {
+    pub fn msg(&self) -> &'static str {
+        match self {
+            AccountNumberError::Empty => "账号不能为空",
+            AccountNumberError::BadLength => "账号长度错误",
+            AccountNumberError::BadCountry => "账号前缀错误",
+            AccountNumberError::BadBankCode => "不是本行账号",
+            AccountNumberError::BadCharacter => "账号含有非法字符",
+            AccountNumberError::BadCheckDigits => "账号校验位错误，请检查是否输错",
+            AccountNumberError::NotLegacyId => "请输入以 BB 开头的完整账号",
+        }
+    }
+}
+
+impl Display for AccountNumberError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.msg())
+    }
+}
+
+impl Error for AccountNumberError {}
+
+/// Remainder of the number (letters as 10..=35) by 97
+fn mod97(s: &str) -> Option<u32> {
+    let mut rem = 0u32;
+    for c in s.chars() {
+        rem = match c {
+            '0'..='9' => (rem * 10 + (c as u32 - '0' as u32)) % 97,
+            'A'..='Z' => (rem * 100 + (c as u32 - 'A' as u32 + 10)) % 97,
+            _ => return None,
+        };
+    }
+    Some(rem)
+}
+
+pub fn format_account_number(id: u32) -> String {
+    let bban = format!("{}{:010}", BANK_CODE, id);
+    let check = 98 - mod97(&format!("{}{}00", bban, COUNTRY_CODE)).expect("Only digits and letters");
+    format!("{}{:02}{}", COUNTRY_CODE, check, bban)
+}
+
+/// Split into groups of 4 for showing
+pub fn group_account_number(number: &str) -> String {
+    number.as_bytes().chunks(4)
+        .map(|x| std::str::from_utf8(x).unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Parse the account number (or the legacy numeric id) to the account id.
+///
+/// Spaces are ignored and letters are case insensitive.
+pub fn parse_account_number(input: &str) -> Result<u32, AccountNumberError> {
+    let number = input.chars()
+        .filter(|x| !x.is_whitespace())
+        .map(|x| x.to_ascii_uppercase())
+        .collect::<String>();
+    if number.is_empty() {
+        return Err(AccountNumberError::Empty);
+    }
+    if number.bytes().all(|x| x.is_ascii_digit()) {
+        return match number.parse() {
+            Ok(id) if LEGACY_ID_RANGE.contains(&id) => Ok(id),
+            _ => Err(AccountNumberError::NotLegacyId),
+        };
+    }
+    if !number.bytes().all(|x| x.is_ascii_alphanumeric()) {
+        return Err(AccountNumberError::BadCharacter);
+    }
+    if number.len() != ACCOUNT_NUMBER_LEN {
+        return Err(AccountNumberError::BadLength);
+    }
+    if !number.starts_with(COUNTRY_CODE) {
+        return Err(AccountNumberError::BadCountry);
+    }
+    if &number[4..8] != BANK_CODE {
+        return Err(AccountNumberError::BadBankCode);
+    }
+    if !number[8..].bytes().all(|x| x.is_ascii_digit()) {
+        return Err(AccountNumberError::BadCharacter);
+    }
+    let rearranged = format!("{}{}", &number[4..], &number[..4]);
+    if mod97(&rearranged) != Some(1) {
+        return Err(AccountNumberError::BadCheckDigits);
+    }
+    number[8..].parse().map_err(|_| AccountNumberError::BadLength)
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::account::*;
+
+    #[test]
+    fn round_trip() {
+        for id in [0, 1, 1234, 10_000_000, 999_999_999, u32::MAX] {
+            let number = format_account_number(id);
+            assert_eq!(number.len(), ACCOUNT_NUMBER_LEN);
+            assert_eq!(parse_account_number(&number), Ok(id));
+            assert_eq!(parse_account_number(&group_account_number(&number).to_lowercase()), Ok(id));
+        }
+    }
+
+    #[test]
+    fn legacy_id() {
+        assert_eq!(parse_account_number("1234"), Ok(1234));
+        assert_eq!(parse_account_number(" 42 "), Ok(42));
+        assert_eq!(parse_account_number("9999999"), Ok(9_999_999));
+        assert_eq!(parse_account_number("99999999999"), Err(AccountNumberError::NotLegacyId));
+        assert_eq!(parse_account_number(""), Err(AccountNumberError::Empty));
+        // the new ids have no check digits bare, a typo would be another account
+        assert_eq!(parse_account_number("12345678"), Err(AccountNumberError::NotLegacyId));
+        assert_eq!(parse_account_number("12345679"), Err(AccountNumberError::NotLegacyId));
+        let number = format_account_number(12345678);
+        assert_eq!(parse_account_number(&number[2..]), Err(AccountNumberError::NotLegacyId));
+    }
+
+    #[test]
+    fn typo_detected() {
+        let number = format_account_number(12345678);
+        let bytes = number.as_bytes();
+        for i in 8..ACCOUNT_NUMBER_LEN {
+            let mut wrong = bytes.to_vec();
+            wrong[i] = if wrong[i] == b'9' { b'0' } else { wrong[i] + 1 };
+            assert_eq!(parse_account_number(std::str::from_utf8(&wrong).unwrap()), Err(AccountNumberError::BadCheckDigits));
+        }
+        for i in 8..ACCOUNT_NUMBER_LEN - 1 {
+            if bytes[i] == bytes[i + 1] {
+                continue;
+            }
+            let mut wrong = bytes.to_vec();
+            wrong.swap(i, i + 1);
+            assert_eq!(parse_account_number(std::str::from_utf8(&wrong).unwrap()), Err(AccountNumberError::BadCheckDigits));
+        }
+        assert_eq!(parse_account_number(&number.replacen(BANK_CODE, "1111", 1)), Err(AccountNumberError::BadBankCode));
+        assert_eq!(parse_account_number(&number.replacen(COUNTRY_CODE, "CN", 1)), Err(AccountNumberError::BadCountry));
+    }
+}
@@ -0,0 +1,217 @@
+//! Split the large messages into chunks for the kcp message mode.
+//!
+//! The peers receive into a fixed buffer, so a packet longer than [`CHUNK_SIZE`] is sent as
+//! sequenced chunks and reassembled by the receiver. Short packets are sent as they are.
+//!
+//! Chunk format: `<header: rPtc> <message id: u32> <index: u16> <count: u16> <total len: u32> <payload>`
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::{PacketReader, PacketWriter, ProtocolError};
+
+pub const CHUNK_HEADER: &[u8] = b"rPtc";
+/// The max payload of a chunk, far less than the receive buffer
+pub const CHUNK_SIZE: usize = 16 * 1024;
+const CHUNK_HEAD_LEN: usize = 16;
+
+/// Whether the packet is a chunk of a large message
+pub fn is_chunk(data: &[u8]) -> bool {
+    data.starts_with(CHUNK_HEADER)
+}
+
+/// Split the outgoing packets, a chunker for each peer
+#[derive(Debug, Default)]
+pub struct Chunker {
+    last_id: u32,
+}
+
+impl Chunker {
+    /// The packets to send in order, the packet itself if short enough
+    pub fn split(&mut self, packet: Vec<u8>) -> Result<Vec<Vec<u8>>, ProtocolError> {
+        if packet.len() <= CHUNK_SIZE {
+            return Ok(vec![packet]);
+        }
+        let count = packet.len().div_ceil(CHUNK_SIZE);
+        let count = u16::try_from(count).map_err(|_| ProtocolError::MessageTooLarge(packet.len()))?;
+        let total = u32::try_from(packet.len()).map_err(|_| ProtocolError::MessageTooLarge(packet.len()))?;
+        self.last_id = self.last_id.wrapping_add(1);
+        let ret = packet.chunks(CHUNK_SIZE).enumerate().map(|(index, payload)| {
+            let mut w = PacketWriter::default();
+            w.put_bytes(CHUNK_HEADER);
+            w.put_u32(self.last_id);
+            w.put_u16(index as u16);
+            w.put_u16(count);
+            w.put_u32(total);
+            w.put_bytes(payload);
+            w.into_inner()
+        }).collect();
+        Ok(ret)
+    }
+}
+
+/// The limits of the incomplete messages from a peer
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkLimits {
+    /// The max len of a reassembled message
+    pub max_message_size: usize,
+    /// The max count of messages receiving at the same time
+    pub max_pending: usize,
+    /// Drop the message if not completed in time
+    pub timeout: Duration,
+}
+
+impl Default for ChunkLimits {
+    fn default() -> Self {
+        Self {
+            max_message_size: 4 * 1024 * 1024,
+            max_pending: 8,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// How much of a large message received
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkProgress {
+    pub message_id: u32,
+    pub received: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reassembled {
+    /// More chunks needed
+    Progress(ChunkProgress),
+    /// The whole message
+    Complete(Vec<u8>),
+}
+
+struct Pending {
+    total: usize,
+    received: usize,
+    chunks: Vec<Option<Vec<u8>>>,
+    started: Instant,
+}
+
+/// Collect the chunks from a peer
+#[derive(Default)]
+pub struct Reassembler {
+    limits: ChunkLimits,
+    pending: HashMap<u32, Pending>,
+}
+
+impl Reassembler {
+    pub fn new(limits: ChunkLimits) -> Self {
+        Self { limits, pending: HashMap::new() }
+    }
+
+    /// Take a packet checked by [`is_chunk`]
+    pub fn push(&mut self, data: &[u8], now: Instant) -> Result<Reassembled, ProtocolError> {
+        if data.len() < CHUNK_HEAD_LEN || !is_chunk(data) {
+            return Err(ProtocolError::BadChunk("header"));
+        }
+        let mut r = PacketReader::new(&data[CHUNK_HEADER.len()..]);
+        let message_id = r.u32()?;
+        let index = r.u16()? as usize;
+        let count = r.u16()? as usize;
+        let total = r.u32()? as usize;
+        let payload = r.rest();
+        if total > self.limits.max_message_size {
+            self.pending.remove(&message_id);
+            return Err(ProtocolError::MessageTooLarge(total));
+        }
+        if index >= count || count != total.div_ceil(CHUNK_SIZE) {
+            return Err(ProtocolError::BadChunk("index"));
+        }
+        let expected = if index + 1 == count { total - index * CHUNK_SIZE } else { CHUNK_SIZE };
+        if payload.len() != expected {
+            return Err(ProtocolError::BadChunk("len"));
+        }
+
+        if !self.pending.contains_key(&message_id) && self.pending.len() >= self.limits.max_pending {
+            return Err(ProtocolError::BadChunk("too many messages"));
+        }
+        let pending = self.pending.entry(message_id).or_insert_with(|| Pending {
+            total,
+            received: 0,
+            chunks: vec![None; count],
+            started: now,
+        });
+        if pending.total != total {
+            self.pending.remove(&message_id);
+            return Err(ProtocolError::BadChunk("total"));
+        }
+        let slot = &mut pending.chunks[index];
+        if slot.is_none() {
+            pending.received += payload.len();
+            *slot = Some(payload.to_vec());
+        }
+        if pending.received < total {
+            return Ok(Reassembled::Progress(ChunkProgress { message_id, received: pending.received, total }));
+        }
+        let pending = self.pending.remove(&message_id).unwrap();
+        Ok(Reassembled::Complete(pending.chunks.into_iter().flatten().flatten().collect()))
+    }
+
+    /// Drop the messages timed out, return the count dropped
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let before = self.pending.len();
+        let timeout = self.limits.timeout;
+        self.pending.retain(|_, x| now.saturating_duration_since(x.started) < timeout);
+        before - self.pending.len()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::chunk::{CHUNK_SIZE, ChunkLimits, Chunker, is_chunk, Reassembled, Reassembler};
+    use crate::ProtocolError;
+
+    #[test]
+    fn round_trip() {
+        let mut chunker = Chunker::default();
+        assert_eq!(chunker.split(vec![1, 2, 3]).unwrap(), vec![vec![1, 2, 3]]);
+
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|x| x as u8).collect();
+        let mut chunks = chunker.split(data.clone()).unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|x| is_chunk(x)));
+
+        // out of order and duplicated
+        chunks.swap(0, 2);
+        chunks.insert(1, chunks[0].clone());
+        let now = Instant::now();
+        let mut reassembler = Reassembler::default();
+        let mut result = vec![];
+        for chunk in &chunks {
+            result.push(reassembler.push(chunk, now).unwrap());
+        }
+        assert!(matches!(result[1], Reassembled::Progress(x) if x.received == 100 && x.total == data.len()));
+        assert_eq!(result.pop(), Some(Reassembled::Complete(data)));
+    }
+
+    #[test]
+    fn limits() {
+        let mut chunker = Chunker::default();
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new(ChunkLimits { max_message_size: CHUNK_SIZE * 2, max_pending: 1, timeout: Duration::from_secs(1) });
+        let large = chunker.split(vec![0; CHUNK_SIZE * 3]).unwrap();
+        assert_eq!(reassembler.push(&large[0], now), Err(ProtocolError::MessageTooLarge(CHUNK_SIZE * 3)));
+
+        let first = chunker.split(vec![0; CHUNK_SIZE * 2]).unwrap();
+        let second = chunker.split(vec![0; CHUNK_SIZE * 2]).unwrap();
+        assert!(reassembler.push(&first[0], now).is_ok());
+        assert!(reassembler.push(&second[0], now).is_err());
+        assert_eq!(reassembler.expire(now + Duration::from_secs(2)), 1);
+        assert!(reassembler.push(&second[0], now).is_ok());
+
+        let mut bad = second[1].clone();
+        bad.pop();
+        assert_eq!(reassembler.push(&bad, now), Err(ProtocolError::BadChunk("len")));
+        assert_eq!(reassembler.push(b"rPtc", now), Err(ProtocolError::BadChunk("header")));
+    }
+}
@@ -0,0 +1,262 @@
+use chrono::{DateTime, Utc};
+
+use crate::{CURRENT_VERSION, MIN_VERSION, PACKET_HEADER, ProtocolError};
+
+/// Write the fields in big endian, strings are u16 len and utf8 bytes
+#[derive(Default)]
+pub struct PacketWriter {
+    data: Vec<u8>,
+}
+
+impl PacketWriter {
+    /// Writer with the header and current version added
+    pub fn with_header() -> Self {
+        Self::with_version(CURRENT_VERSION)
+    }
+
+    pub fn with_version(version: u32) -> Self {
+        let mut data = Vec::with_capacity(64);
+        data.extend_from_slice(PACKET_HEADER);
+        data.extend_from_slice(&version.to_be_bytes());
+        Self { data }
+    }
+
+    pub fn put_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn put_u8(&mut self, x: u8) {
+        self.data.push(x);
+    }
+
+    pub fn put_bool(&mut self, x: bool) {
+        self.data.push(x as u8);
+    }
+
+    pub fn put_u16(&mut self, x: u16) {
+        self.data.extend_from_slice(&x.to_be_bytes());
+    }
+
+    pub fn put_u32(&mut self, x: u32) {
+        self.data.extend_from_slice(&x.to_be_bytes());
+    }
+
+    pub fn put_i32(&mut self, x: i32) {
+        self.data.extend_from_slice(&x.to_be_bytes());
+    }
+
+    pub fn put_i64(&mut self, x: i64) {
+        self.data.extend_from_slice(&x.to_be_bytes());
+    }
+
+    /// The count of the following items
+    pub fn put_len(&mut self, len: usize) -> Result<(), ProtocolError> {
+        let len = u32::try_from(len).map_err(|_| ProtocolError::BadValue("len"))?;
+        self.put_u32(len);
+        Ok(())
+    }
+
+    pub fn put_string(&mut self, s: &str) -> Result<(), ProtocolError> {
+        let len = u16::try_from(s.len()).map_err(|_| ProtocolError::StringTooLong(s.len()))?;
+        self.data.extend_from_slice(&len.to_be_bytes());
+        self.data.extend_from_slice(s.as_bytes());
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Read the fields written by [`PacketWriter`], return error instead of panic if not enough
+pub struct PacketReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> PacketReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn bytes(&mut self, n: usize) -> Result<&'a [u8], ProtocolError> {
+        if self.data.len() < n {
+            return Err(ProtocolError::Truncated { need: n, remaining: self.data.len() });
+        }
+        let (ret, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(ret)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], ProtocolError> {
+        let mut ret = [0; N];
+        ret.copy_from_slice(self.bytes(N)?);
+        Ok(ret)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, ProtocolError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, ProtocolError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(ProtocolError::BadValue("bool")),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, ProtocolError> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, ProtocolError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    pub fn i32(&mut self) -> Result<i32, ProtocolError> {
+        Ok(i32::from_be_bytes(self.array()?))
+    }
+
+    pub fn i64(&mut self) -> Result<i64, ProtocolError> {
+        Ok(i64::from_be_bytes(self.array()?))
+    }
+
+    pub fn string(&mut self) -> Result<String, ProtocolError> {
+        let len = u16::from_be_bytes(self.array()?) as usize;
+        let bytes = self.bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| ProtocolError::BadUtf8)
+    }
+
+    /// Unix seconds
+    pub fn time(&mut self) -> Result<DateTime<Utc>, ProtocolError> {
+        DateTime::from_timestamp(self.i64()?, 0).ok_or(ProtocolError::BadValue("time"))
+    }
+
+    /// All the bytes left
+    pub fn rest(self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Make sure all bytes are read
+    pub fn finish(self) -> Result<(), ProtocolError> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(ProtocolError::TrailingBytes(self.data.len()))
+        }
+    }
+}
+
+/// Check the header, return the version and the content
+pub fn read_frame(data: &[u8]) -> Result<(u32, &[u8]), ProtocolError> {
+    let mut reader = PacketReader::new(data);
+    if reader.bytes(PACKET_HEADER.len()).map_err(|_| ProtocolError::BadHeader)? != PACKET_HEADER {
+        return Err(ProtocolError::BadHeader);
+    }
+    let version = reader.u32().map_err(|_| ProtocolError::BadHeader)?;
+    Ok((version, reader.data))
+}
+
+/// Check the header and the version is supported, return the content
+pub fn read_header(data: &[u8]) -> Result<&[u8], ProtocolError> {
+    let (version, content) = read_frame(data)?;
+    if !(MIN_VERSION..=CURRENT_VERSION).contains(&version) {
+        return Err(ProtocolError::UnsupportedVersion(version));
+    }
+    Ok(content)
+}
+
+/// The message with the id of the request: (id: u32) (message)
+///
+/// The response has the same id as the request, and 0 for the packets pushed by server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tagged<T> {
+    pub id: u32,
+    pub body: T,
+}
+
+/// The id of the packets not for any request
+pub const UNSOLICITED: u32 = 0;
+
+impl<T: Message> Message for Tagged<T> {
+    fn encode(&self, w: &mut PacketWriter) -> Result<(), ProtocolError> {
+        w.put_u32(self.id);
+        self.body.encode(w)
+    }
+
+    fn decode(r: &mut PacketReader<'_>) -> Result<Self, ProtocolError> {
+        Ok(Self { id: r.u32()?, body: T::decode(r)? })
+    }
+}
+
+pub trait Message: Sized {
+    /// Write the content without header
+    fn encode(&self, w: &mut PacketWriter) -> Result<(), ProtocolError>;
+
+    /// Read the content without header, the bytes left are not checked
+    fn decode(r: &mut PacketReader<'_>) -> Result<Self, ProtocolError>;
+
+    /// The whole packet with header to send
+    fn to_packet(&self) -> Result<Vec<u8>, ProtocolError> {
+        self.to_packet_version(CURRENT_VERSION)
+    }
+
+    /// The packet with the version field set, like the version negotiated
+    fn to_packet_version(&self, version: u32) -> Result<Vec<u8>, ProtocolError> {
+        let mut w = PacketWriter::with_version(version);
+        self.encode(&mut w)?;
+        Ok(w.into_inner())
+    }
+
+    /// Decode the content and make sure nothing left
+    fn from_content(content: &[u8]) -> Result<Self, ProtocolError> {
+        let mut r = PacketReader::new(content);
+        let ret = Self::decode(&mut r)?;
+        r.finish()?;
+        Ok(ret)
+    }
+
+    fn from_packet(data: &[u8]) -> Result<Self, ProtocolError> {
+        Self::from_content(read_header(data)?)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{CURRENT_VERSION, PacketReader, PacketWriter, ProtocolError, read_frame, read_header};
+
+    #[test]
+    fn header() {
+        let w = PacketWriter::with_header();
+        assert_eq!(read_header(&w.into_inner()), Ok(&[][..]));
+        assert_eq!(read_header(b"rPt"), Err(ProtocolError::BadHeader));
+        assert_eq!(read_header(b"xPtm\0\0\0\0"), Err(ProtocolError::BadHeader));
+        let mut data = b"rPtm".to_vec();
+        data.extend_from_slice(&(CURRENT_VERSION + 1).to_be_bytes());
+        assert_eq!(read_header(&data), Err(ProtocolError::UnsupportedVersion(CURRENT_VERSION + 1)));
+        assert_eq!(read_header(b"rPtm\0\0\0\0"), Err(ProtocolError::UnsupportedVersion(0)));
+        assert_eq!(read_frame(b"rPtm\0\0\0\0x"), Ok((0, &b"x"[..])));
+    }
+
+    #[test]
+    fn truncated() {
+        let mut w = PacketWriter::default();
+        w.put_string("你好").unwrap();
+        let data = w.into_inner();
+        for len in 0..data.len() {
+            assert!(matches!(PacketReader::new(&data[..len]).string(), Err(ProtocolError::Truncated { .. })));
+        }
+        assert_eq!(PacketReader::new(&data).string().as_deref(), Ok("你好"));
+        assert_eq!(PacketReader::new(&[0, 1, 0xff]).string(), Err(ProtocolError::BadUtf8));
+        assert!(PacketWriter::default().put_string(&"a".repeat(70000)).is_err());
+    }
+}
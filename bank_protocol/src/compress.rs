@@ -0,0 +1,165 @@
+//! Compress the large packets with the algorithm negotiated by [`Capabilities`].
+//!
+//! Only the packets longer than [`COMPRESS_THRESHOLD`] and smaller after compressed are sent
+//! compressed, the others and the handshake packets are sent as they are. The receiver
+//! decompresses whatever algorithm it knows, the negotiation only decides what to send.
+//!
+//! Compressed format: `<header: rPtz> <algorithm: u8> <raw len: u32> <compressed bytes>`
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{Capabilities, is_handshake, PacketReader, PacketWriter, ProtocolError};
+
+pub const COMPRESS_HEADER: &[u8] = b"rPtz";
+/// Shorter packets are not worth compressing
+pub const COMPRESS_THRESHOLD: usize = 512;
+/// The max len after decompressed, refuse the packets claiming more
+pub const MAX_DECOMPRESSED_SIZE: usize = 4 * 1024 * 1024;
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Compression {
+    Zstd = 1,
+    Lz4 = 2,
+}
+
+impl Compression {
+    /// The algorithm to use for the negotiated capabilities, zstd preferred
+    pub fn negotiate(capabilities: Capabilities) -> Option<Compression> {
+        if capabilities.contains(Capabilities::ZSTD) {
+            Some(Compression::Zstd)
+        } else if capabilities.contains(Capabilities::LZ4) {
+            Some(Compression::Lz4)
+        } else {
+            None
+        }
+    }
+
+    fn from_u8(x: u8) -> Result<Compression, ProtocolError> {
+        match x {
+            1 => Ok(Compression::Zstd),
+            2 => Ok(Compression::Lz4),
+            _ => Err(ProtocolError::BadValue("compression")),
+        }
+    }
+}
+
+/// Whether the packet is compressed
+pub fn is_compressed(data: &[u8]) -> bool {
+    data.starts_with(COMPRESS_HEADER)
+}
+
+/// Bytes before and after compressed of a peer
+#[derive(Debug, Default)]
+pub struct CompressionStats {
+    /// The len of the packets compressed
+    pub raw_bytes: AtomicU64,
+    /// The len sent for them
+    pub compressed_bytes: AtomicU64,
+    /// The len of the packets decompressed
+    pub inflated_bytes: AtomicU64,
+    /// The len received for them
+    pub received_bytes: AtomicU64,
+}
+
+impl CompressionStats {
+    /// The bytes not sent and not received thanks to the compression
+    pub fn saved(&self) -> u64 {
+        let sent = self.raw_bytes.load(Ordering::Relaxed).saturating_sub(self.compressed_bytes.load(Ordering::Relaxed));
+        let received = self.inflated_bytes.load(Ordering::Relaxed).saturating_sub(self.received_bytes.load(Ordering::Relaxed));
+        sent + received
+    }
+}
+
+/// Compress the packet to send if worth it, otherwise return it as is
+pub fn compress(compression: Compression, packet: Vec<u8>, stats: &CompressionStats) -> Vec<u8> {
+    if packet.len() < COMPRESS_THRESHOLD || packet.len() > MAX_DECOMPRESSED_SIZE || is_handshake(&packet) {
+        return packet;
+    }
+    let compressed = match compression {
+        Compression::Zstd => match zstd::bulk::compress(&packet, ZSTD_LEVEL) {
+            Ok(x) => x,
+            Err(_) => return packet,
+        },
+        Compression::Lz4 => lz4_flex::compress(&packet),
+    };
+    let head_len = COMPRESS_HEADER.len() + 5;
+    if compressed.len() + head_len >= packet.len() {
+        return packet;
+    }
+    let mut w = PacketWriter::default();
+    w.put_bytes(COMPRESS_HEADER);
+    w.put_u8(compression as u8);
+    w.put_u32(packet.len() as u32);
+    w.put_bytes(&compressed);
+    let ret = w.into_inner();
+    stats.raw_bytes.fetch_add(packet.len() as u64, Ordering::Relaxed);
+    stats.compressed_bytes.fetch_add(ret.len() as u64, Ordering::Relaxed);
+    ret
+}
+
+/// Decompress the packet checked by [`is_compressed`], never more than [`MAX_DECOMPRESSED_SIZE`]
+pub fn decompress(data: &[u8], stats: &CompressionStats) -> Result<Vec<u8>, ProtocolError> {
+    let mut r = PacketReader::new(data);
+    if r.bytes(COMPRESS_HEADER.len())? != COMPRESS_HEADER {
+        return Err(ProtocolError::BadHeader);
+    }
+    let compression = Compression::from_u8(r.u8()?)?;
+    let raw_len = r.u32()? as usize;
+    if raw_len > MAX_DECOMPRESSED_SIZE {
+        return Err(ProtocolError::MessageTooLarge(raw_len));
+    }
+    let content = r.rest();
+    // the output is bounded by the raw len claimed, so a bomb fails instead of filling the memory
+    let raw = match compression {
+        Compression::Zstd => zstd::bulk::decompress(content, raw_len).map_err(|_| ProtocolError::BadValue("zstd"))?,
+        Compression::Lz4 => lz4_flex::decompress(content, raw_len).map_err(|_| ProtocolError::BadValue("lz4"))?,
+    };
+    if raw.len() != raw_len {
+        return Err(ProtocolError::BadValue("raw len"));
+    }
+    stats.inflated_bytes.fetch_add(raw.len() as u64, Ordering::Relaxed);
+    stats.received_bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+    Ok(raw)
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{Capabilities, PacketWriter, ProtocolError};
+    use crate::compress::{compress, Compression, CompressionStats, decompress, is_compressed, MAX_DECOMPRESSED_SIZE};
+
+    #[test]
+    fn round_trip() {
+        let data = "2023-12-01 转账 100.00 元\n".repeat(100).into_bytes();
+        for compression in [Compression::Zstd, Compression::Lz4] {
+            let stats = CompressionStats::default();
+            let packet = compress(compression, data.clone(), &stats);
+            assert!(is_compressed(&packet));
+            assert!(packet.len() < data.len());
+            assert_eq!(decompress(&packet, &stats), Ok(data.clone()));
+            assert_eq!(stats.saved(), 2 * (data.len() - packet.len()) as u64);
+        }
+        let stats = CompressionStats::default();
+        assert_eq!(compress(Compression::Zstd, b"short".to_vec(), &stats), b"short");
+        assert_eq!(stats.saved(), 0);
+        assert_eq!(Compression::negotiate(Capabilities::LZ4.union(Capabilities::ZSTD)), Some(Compression::Zstd));
+        assert_eq!(Compression::negotiate(Capabilities::NOTIFICATIONS), None);
+    }
+
+    #[test]
+    fn bomb() {
+        let stats = CompressionStats::default();
+        let zeros = zstd::bulk::compress(&vec![0; MAX_DECOMPRESSED_SIZE * 2], 3).unwrap();
+        for raw_len in [MAX_DECOMPRESSED_SIZE as u32 * 2, 1024] {
+            let mut w = PacketWriter::default();
+            w.put_bytes(b"rPtz");
+            w.put_u8(Compression::Zstd as u8);
+            w.put_u32(raw_len);
+            w.put_bytes(&zeros);
+            assert!(decompress(&w.into_inner(), &stats).is_err());
+        }
+        assert_eq!(decompress(b"rPtz\x09\0\0\0\0", &stats), Err(ProtocolError::BadValue("compression")));
+    }
+}
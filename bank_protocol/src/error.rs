@@ -0,0 +1,44 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// Not starting with [`crate::PACKET_HEADER`]
+    BadHeader,
+    UnsupportedVersion(u32),
+    /// Need more bytes than remaining
+    Truncated { need: usize, remaining: usize },
+    /// Bytes left after the whole message read
+    TrailingBytes(usize),
+    BadUtf8,
+    /// The string is longer than u16 len could describe
+    StringTooLong(usize),
+    UnknownRequest(u8),
+    UnknownResponse([u8; 4]),
+    /// The field is read but not valid
+    BadValue(&'static str),
+    /// The chunk is not valid for the field
+    BadChunk(&'static str),
+    /// The message is longer than allowed
+    MessageTooLarge(usize),
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::BadHeader => f.write_str("Bad packet header"),
+            ProtocolError::UnsupportedVersion(v) => write!(f, "Unsupported protocol version {}", v),
+            ProtocolError::Truncated { need, remaining } => write!(f, "Need {} bytes but only {} remaining", need, remaining),
+            ProtocolError::TrailingBytes(n) => write!(f, "{} bytes left after the packet", n),
+            ProtocolError::BadUtf8 => f.write_str("String is not utf8"),
+            ProtocolError::StringTooLong(n) => write!(f, "String with {} bytes is too long", n),
+            ProtocolError::UnknownRequest(t) => write!(f, "Unknown request type {}", t),
+            ProtocolError::UnknownResponse(t) => write!(f, "Unknown response type {:?}", String::from_utf8_lossy(t)),
+            ProtocolError::BadValue(field) => write!(f, "Bad value for {}", field),
+            ProtocolError::BadChunk(field) => write!(f, "Bad chunk for {}", field),
+            ProtocolError::MessageTooLarge(n) => write!(f, "Message with {} bytes is too large", n),
+        }
+    }
+}
+
+impl Error for ProtocolError {}
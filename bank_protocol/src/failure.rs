@@ -0,0 +1,249 @@
+//! The stable error codes for the requests failed.
+//!
+//! The code never changes its number once released, the client shows the text by the code and
+//! params, so the human message from server is optional.
+
+use crate::{PacketReader, PacketWriter, ProtocolError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u16)]
+pub enum ErrorCode {
+    /// The code not known by this side, see the message
+    Unknown = 0,
+    Internal = 1,
+    BadPacket = 2,
+    HandshakeRequired = 3,
+    /// The server is shutting down
+    Maintenance = 4,
+    /// params: the seconds to wait
+    RateLimited = 5,
+    /// params: the seconds to wait
+    TemporarilyBanned = 6,
+
+    WrongPassword = 100,
+    BadInputLength = 101,
+    WrongTotpCode = 102,
+    TooManyTotpTries = 103,
+    /// The password is right but the totp code is missing
+    TotpCodeRequired = 104,
+    /// The token of the http gateway is missing or expired
+    SessionExpired = 105,
+
+    AccountNotFound = 200,
+    /// params: the reason
+    BadAccountNumber = 201,
+    /// params: the limit in cents
+    BalanceLimit = 202,
+    /// params: the limit in cents
+    RecipientBalanceLimit = 203,
+    InsufficientBalance = 204,
+    BadAmount = 205,
+    TotpRequiredForTransfer = 206,
+    RecipientNotConfirmed = 207,
+    ConfirmExpired = 208,
+    ConfirmMismatch = 209,
+    LegacyTransfer = 210,
+
+    TotpAlreadyEnabled = 300,
+    TotpNotEnabled = 301,
+    TotpNotPending = 302,
+
+    BadPayeeNickname = 400,
+    /// params: the limit
+    TooManyPayees = 401,
+    PayeeExists = 402,
+    PayeeNotFound = 403,
+    /// Edit failed, the payee not found or the new account saved already
+    PayeeNotFoundOrExists = 404,
+
+    /// The phone is not linked to any account
+    PhoneNotLinked = 500,
+    BadReservedPhone = 501,
+    PhoneLinkedElsewhere = 502,
+    /// This account has not linked the phone
+    PhoneAliasDisabled = 503,
+
+    SamePassword = 600,
+    BadPhone = 601,
+    ProfileUnchanged = 602,
+    /// Changing the phone needs the password
+    PasswordRequired = 603,
+
+    /// Only reading and changing the password are allowed
+    AccountFrozen = 700,
+    /// Only reading is allowed
+    AccountClosed = 701,
+    RecipientClosed = 702,
+    /// The balance of the closed account could not be paid to itself
+    PayoutToSelf = 703,
+
+    /// No fee for the operation
+    BadFeeOperation = 800,
+
+    /// Challenged by the risk rules, the totp code is needed
+    RiskChallenge = 900,
+    /// Blocked by the risk rules
+    RiskBlocked = 901,
+    /// Challenged by the risk rules but totp is not enabled
+    RiskTotpNotEnabled = 902,
+}
+
+const ALL_CODES: &[ErrorCode] = &[
+    ErrorCode::Unknown, ErrorCode::Internal, ErrorCode::BadPacket, ErrorCode::HandshakeRequired, ErrorCode::Maintenance,
+    ErrorCode::RateLimited, ErrorCode::TemporarilyBanned,
+    ErrorCode::WrongPassword, ErrorCode::BadInputLength, ErrorCode::WrongTotpCode, ErrorCode::TooManyTotpTries,
+    ErrorCode::TotpCodeRequired, ErrorCode::SessionExpired,
+    ErrorCode::AccountNotFound, ErrorCode::BadAccountNumber, ErrorCode::BalanceLimit, ErrorCode::RecipientBalanceLimit,
+    ErrorCode::InsufficientBalance, ErrorCode::BadAmount, ErrorCode::TotpRequiredForTransfer,
+    ErrorCode::RecipientNotConfirmed, ErrorCode::ConfirmExpired, ErrorCode::ConfirmMismatch, ErrorCode::LegacyTransfer,
+    ErrorCode::TotpAlreadyEnabled, ErrorCode::TotpNotEnabled, ErrorCode::TotpNotPending,
+    ErrorCode::BadPayeeNickname, ErrorCode::TooManyPayees, ErrorCode::PayeeExists, ErrorCode::PayeeNotFound, ErrorCode::PayeeNotFoundOrExists,
+    ErrorCode::PhoneNotLinked, ErrorCode::BadReservedPhone, ErrorCode::PhoneLinkedElsewhere, ErrorCode::PhoneAliasDisabled,
+    ErrorCode::SamePassword, ErrorCode::BadPhone, ErrorCode::ProfileUnchanged, ErrorCode::PasswordRequired,
+    ErrorCode::AccountFrozen, ErrorCode::AccountClosed, ErrorCode::RecipientClosed, ErrorCode::PayoutToSelf,
+    ErrorCode::BadFeeOperation,
+    ErrorCode::RiskChallenge, ErrorCode::RiskBlocked, ErrorCode::RiskTotpNotEnabled,
+];
+
+impl ErrorCode {
+    /// Unknown for the codes from newer version
+    pub fn from_u16(x: u16) -> Self {
+        ALL_CODES.iter().copied().find(|c| *c as u16 == x).unwrap_or(ErrorCode::Unknown)
+    }
+
+    /// The text to show, `{}` in it are replaced by the params in order
+    pub fn template(self) -> &'static str {
+        match self {
+            ErrorCode::Unknown => "未知错误",
+            ErrorCode::Internal => "服务器内部错误",
+            ErrorCode::BadPacket => "数据包错误",
+            ErrorCode::HandshakeRequired => "请先握手",
+            ErrorCode::Maintenance => "服务器维护中，请稍后重新连接",
+            ErrorCode::RateLimited => "操作过于频繁，请 {} 秒后再试",
+            ErrorCode::TemporarilyBanned => "请求过多，已被暂时禁止访问，请 {} 秒后再连接",
+            ErrorCode::WrongPassword => "账号或密码错误",
+            ErrorCode::BadInputLength => "输入长度错误",
+            ErrorCode::WrongTotpCode => "动态验证码错误",
+            ErrorCode::TooManyTotpTries => "动态验证码错误次数过多",
+            ErrorCode::TotpCodeRequired => "请输入动态验证码",
+            ErrorCode::SessionExpired => "登录已过期，请重新登录",
+            ErrorCode::AccountNotFound => "找不到账号",
+            ErrorCode::BadAccountNumber => "{}",
+            ErrorCode::BalanceLimit => "超出存款上限 {}",
+            ErrorCode::RecipientBalanceLimit => "对方存款到达上限 {}",
+            ErrorCode::InsufficientBalance => "我方存款不足",
+            ErrorCode::BadAmount => "转账金额错误",
+            ErrorCode::TotpRequiredForTransfer => "大额转账需要新的动态验证码",
+            ErrorCode::RecipientNotConfirmed => "请先确认收款人后再转账",
+            ErrorCode::ConfirmExpired => "确认已过期，请重新确认收款人",
+            ErrorCode::ConfirmMismatch => "转账信息与确认的不一致",
+            ErrorCode::LegacyTransfer => "请先确认收款人后再转账",
+            ErrorCode::TotpAlreadyEnabled => "已开启两步验证",
+            ErrorCode::TotpNotEnabled => "未开启两步验证",
+            ErrorCode::TotpNotPending => "请先开始绑定两步验证",
+            ErrorCode::BadPayeeNickname => "备注名长度错误",
+            ErrorCode::TooManyPayees => "收款人数量已达上限 {}",
+            ErrorCode::PayeeExists => "该收款人已保存",
+            ErrorCode::PayeeNotFound => "找不到收款人",
+            ErrorCode::PayeeNotFoundOrExists => "找不到收款人或该账号已保存",
+            ErrorCode::PhoneNotLinked => "该手机号未开通手机号收款",
+            ErrorCode::BadReservedPhone => "预留手机号格式错误，无法开通手机号收款",
+            ErrorCode::PhoneLinkedElsewhere => "该手机号已绑定其他账户，请先在原账户解除",
+            ErrorCode::PhoneAliasDisabled => "未开通手机号收款",
+            ErrorCode::SamePassword => "新密码不能与原密码相同",
+            ErrorCode::BadPhone => "手机号格式错误",
+            ErrorCode::ProfileUnchanged => "资料没有变化",
+            ErrorCode::PasswordRequired => "修改手机号需要验证密码",
+            ErrorCode::AccountFrozen => "账户已冻结，只能查询，如有疑问请联系银行",
+            ErrorCode::AccountClosed => "账户已销户，只能查询记录",
+            ErrorCode::RecipientClosed => "对方账户已销户",
+            ErrorCode::PayoutToSelf => "销户余额不能转入本账户",
+            ErrorCode::BadFeeOperation => "该操作没有手续费",
+            ErrorCode::RiskChallenge => "此操作需要验证，请输入动态验证码",
+            ErrorCode::RiskBlocked => "此操作存在风险，已被拒绝，如有疑问请联系银行",
+            ErrorCode::RiskTotpNotEnabled => "此操作需要验证，请先开启两步验证",
+        }
+    }
+}
+
+/// The request failed: (code: u16) (param_cnt: u32) <param: String> (message: String, empty for none)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    pub code: ErrorCode,
+    pub params: Vec<String>,
+    pub message: Option<String>,
+}
+
+impl Failure {
+    pub fn new(code: ErrorCode) -> Self {
+        Self { code, params: vec![], message: None }
+    }
+
+    pub fn with_params(code: ErrorCode, params: Vec<String>) -> Self {
+        Self { code, params, message: None }
+    }
+
+    pub fn with_message(mut self, message: impl Into<String>) -> Self {
+        self.message = Some(message.into());
+        self
+    }
+
+    /// The text by the code and params, or the message if the code is unknown
+    pub fn describe(&self) -> String {
+        if self.code == ErrorCode::Unknown {
+            if let Some(message) = &self.message {
+                return message.clone();
+            }
+        }
+        let mut params = self.params.iter();
+        let mut ret = String::new();
+        let mut parts = self.code.template().split("{}");
+        if let Some(first) = parts.next() {
+            ret.push_str(first);
+        }
+        for part in parts {
+            ret.push_str(params.next().map(|x| x.as_str()).unwrap_or(""));
+            ret.push_str(part);
+        }
+        ret.trim_end().to_string()
+    }
+
+    pub(crate) fn encode(&self, w: &mut PacketWriter) -> Result<(), ProtocolError> {
+        w.put_u16(self.code as u16);
+        w.put_len(self.params.len())?;
+        for x in &self.params {
+            w.put_string(x)?;
+        }
+        w.put_string(self.message.as_deref().unwrap_or(""))
+    }
+
+    pub(crate) fn decode(r: &mut PacketReader<'_>) -> Result<Self, ProtocolError> {
+        let code = ErrorCode::from_u16(r.u16()?);
+        let count = r.u32()?;
+        let params = (0..count).map(|_| r.string()).collect::<Result<_, _>>()?;
+        let message = Some(r.string()?).filter(|x| !x.is_empty());
+        Ok(Self { code, params, message })
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::failure::{ALL_CODES, ErrorCode, Failure};
+
+    #[test]
+    fn codes() {
+        for code in ALL_CODES {
+            assert_eq!(ErrorCode::from_u16(*code as u16), *code);
+        }
+        assert_eq!(ErrorCode::from_u16(9999), ErrorCode::Unknown);
+    }
+
+    #[test]
+    fn describe() {
+        assert_eq!(Failure::new(ErrorCode::WrongPassword).describe(), "账号或密码错误");
+        assert_eq!(Failure::with_params(ErrorCode::TooManyPayees, vec!["50".into()]).describe(), "收款人数量已达上限 50");
+        assert_eq!(Failure::new(ErrorCode::TooManyPayees).describe(), "收款人数量已达上限");
+        assert_eq!(Failure::new(ErrorCode::Unknown).with_message("新错误").describe(), "新错误");
+    }
+}
@@ -0,0 +1,196 @@
+//! The hello exchange before any other packet.
+//!
+//! Handshake packets use [`HANDSHAKE_VERSION`] in the version field, so every version of client
+//! and server could read them. The client sends [`Handshake::Hello`] first after connected, and
+//! the server replies [`Handshake::Welcome`] with the version to use, or [`Handshake::Reject`]
+//! and disconnects.
+//!
+//! * Hello (b"helo") (min_version: u32) (max_version: u32) (capabilities: u32)
+//! * Welcome (b"welc") (version: u32) (capabilities: u32)
+//! * Reject (b"rjct") (min_version: u32) (max_version: u32) (reason: String)
+
+use std::fmt::{Debug, Formatter};
+
+use crate::{Message, PACKET_HEADER, PacketReader, PacketWriter, ProtocolError};
+
+/// The version field of handshake packets
+pub const HANDSHAKE_VERSION: u32 = u32::MAX;
+
+/// Optional features, both sides use the intersection
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    /// Large packets compressed by zstd, see [`crate::compress`]
+    pub const ZSTD: Capabilities = Capabilities(1);
+    pub const PAGINATION: Capabilities = Capabilities(1 << 1);
+    /// Server pushes b"ntfy" when the balance changed
+    pub const NOTIFICATIONS: Capabilities = Capabilities(1 << 2);
+    /// Large packets compressed by lz4, zstd preferred if both
+    pub const LZ4: Capabilities = Capabilities(1 << 3);
+    /// Ping and pong, see [`crate::heartbeat`]
+    pub const HEARTBEAT: Capabilities = Capabilities(1 << 4);
+
+    pub const fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersection(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+
+    pub const fn union(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+impl Debug for Capabilities {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let names = [
+            (Capabilities::ZSTD, "zstd"),
+            (Capabilities::PAGINATION, "pagination"),
+            (Capabilities::NOTIFICATIONS, "notifications"),
+            (Capabilities::LZ4, "lz4"),
+            (Capabilities::HEARTBEAT, "heartbeat"),
+        ];
+        f.debug_list()
+            .entries(names.iter().filter(|(x, _)| self.contains(*x)).map(|(_, name)| name))
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Handshake {
+    Hello { min_version: u32, max_version: u32, capabilities: Capabilities },
+    Welcome { version: u32, capabilities: Capabilities },
+    Reject { min_version: u32, max_version: u32, reason: String },
+}
+
+/// Is the packet a handshake packet
+pub fn is_handshake(data: &[u8]) -> bool {
+    data.len() >= 8 && &data[..4] == PACKET_HEADER && data[4..8] == HANDSHAKE_VERSION.to_be_bytes()
+}
+
+impl Handshake {
+    /// The hello of this side
+    pub fn hello(capabilities: Capabilities) -> Self {
+        Handshake::Hello { min_version: crate::MIN_VERSION, max_version: crate::CURRENT_VERSION, capabilities }
+    }
+
+    /// Choose the highest common version for the hello received.
+    ///
+    /// Return [`Handshake::Welcome`] or [`Handshake::Reject`] to reply.
+    pub fn negotiate(min_version: u32, max_version: u32, capabilities: Capabilities, hello: &Handshake) -> Handshake {
+        let (client_min, client_max, client_capabilities) = match hello {
+            Handshake::Hello { min_version, max_version, capabilities } => (*min_version, *max_version, *capabilities),
+            _ => return Handshake::Reject { min_version, max_version, reason: "握手数据错误".into() },
+        };
+        if client_max < min_version {
+            return Handshake::Reject { min_version, max_version, reason: "客户端版本过旧，请升级后再连接".into() };
+        }
+        if client_min > max_version || client_min > client_max {
+            return Handshake::Reject { min_version, max_version, reason: "服务器版本过旧，请使用旧版客户端或联系银行".into() };
+        }
+        Handshake::Welcome {
+            version: client_max.min(max_version),
+            capabilities: capabilities.intersection(client_capabilities),
+        }
+    }
+}
+
+impl Message for Handshake {
+    fn encode(&self, w: &mut PacketWriter) -> Result<(), ProtocolError> {
+        match self {
+            Handshake::Hello { min_version, max_version, capabilities } => {
+                w.put_bytes(b"helo");
+                w.put_u32(*min_version);
+                w.put_u32(*max_version);
+                w.put_u32(capabilities.0);
+            }
+            Handshake::Welcome { version, capabilities } => {
+                w.put_bytes(b"welc");
+                w.put_u32(*version);
+                w.put_u32(capabilities.0);
+            }
+            Handshake::Reject { min_version, max_version, reason } => {
+                w.put_bytes(b"rjct");
+                w.put_u32(*min_version);
+                w.put_u32(*max_version);
+                w.put_string(reason)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn decode(r: &mut PacketReader<'_>) -> Result<Self, ProtocolError> {
+        let mut type_id = [0; 4];
+        type_id.copy_from_slice(r.bytes(4)?);
+        Ok(match &type_id {
+            b"helo" => Handshake::Hello {
+                min_version: r.u32()?,
+                max_version: r.u32()?,
+                capabilities: Capabilities(r.u32()?),
+            },
+            b"welc" => Handshake::Welcome { version: r.u32()?, capabilities: Capabilities(r.u32()?) },
+            b"rjct" => Handshake::Reject { min_version: r.u32()?, max_version: r.u32()?, reason: r.string()? },
+            _ => Err(ProtocolError::UnknownResponse(type_id))?,
+        })
+    }
+
+    fn to_packet(&self) -> Result<Vec<u8>, ProtocolError> {
+        self.to_packet_version(HANDSHAKE_VERSION)
+    }
+
+    fn from_packet(data: &[u8]) -> Result<Self, ProtocolError> {
+        if !is_handshake(data) {
+            return Err(ProtocolError::BadHeader);
+        }
+        Self::from_content(&data[8..])
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{CURRENT_VERSION, Message, MIN_VERSION};
+    use crate::handshake::{Capabilities, Handshake, is_handshake};
+
+    #[test]
+    fn round_trip() {
+        let all = [
+            Handshake::hello(Capabilities::NOTIFICATIONS.union(Capabilities::ZSTD)),
+            Handshake::Welcome { version: 1, capabilities: Capabilities::NONE },
+            Handshake::Reject { min_version: 1, max_version: 2, reason: "请升级".into() },
+        ];
+        for x in all {
+            let packet = x.to_packet().unwrap();
+            assert!(is_handshake(&packet));
+            assert_eq!(Handshake::from_packet(&packet), Ok(x));
+        }
+        assert!(!is_handshake(&crate::Response::TotpRequired.to_packet().unwrap()));
+    }
+
+    #[test]
+    fn negotiate() {
+        let server = Capabilities::NOTIFICATIONS.union(Capabilities::ZSTD);
+        let hello = |min, max| Handshake::Hello { min_version: min, max_version: max, capabilities: Capabilities::NOTIFICATIONS };
+        assert_eq!(Handshake::negotiate(1, 3, server, &hello(1, 2)),
+                   Handshake::Welcome { version: 2, capabilities: Capabilities::NOTIFICATIONS });
+        assert_eq!(Handshake::negotiate(1, 3, server, &hello(2, 5)),
+                   Handshake::Welcome { version: 3, capabilities: Capabilities::NOTIFICATIONS });
+        assert!(matches!(Handshake::negotiate(2, 3, server, &hello(0, 1)), Handshake::Reject { .. }));
+        assert!(matches!(Handshake::negotiate(1, 3, server, &hello(4, 5)), Handshake::Reject { .. }));
+        assert!(matches!(Handshake::negotiate(1, 3, server, &hello(3, 2)), Handshake::Reject { .. }));
+        assert_eq!(Handshake::negotiate(MIN_VERSION, CURRENT_VERSION, server, &Handshake::hello(server)),
+                   Handshake::Welcome { version: CURRENT_VERSION, capabilities: server });
+    }
+
+    #[test]
+    fn capabilities() {
+        let x = Capabilities::NOTIFICATIONS.union(Capabilities::PAGINATION);
+        assert!(x.contains(Capabilities::NOTIFICATIONS));
+        assert!(!x.contains(Capabilities::ZSTD));
+        assert_eq!(format!("{:?}", x), r#"["pagination", "notifications"]"#);
+    }
+}
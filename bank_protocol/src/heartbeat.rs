@@ -0,0 +1,245 @@
+//! Ping and pong between the peers, to measure the round trip time and to find the dead connections.
+//!
+//! The pings are sent unreliably after [`Capabilities::HEARTBEAT`](crate::Capabilities::HEARTBEAT)
+//! negotiated, a lost one only counts in the loss. They are never compressed or chunked.
+//!
+//! Heartbeat format: `<header: rPth> <kind: u8, 1 ping 2 pong> <sequence: u32>`
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::{PacketReader, PacketWriter, ProtocolError};
+
+pub const HEARTBEAT_HEADER: &[u8] = b"rPth";
+const PING: u8 = 1;
+const PONG: u8 = 2;
+/// The latest pings to count the loss in
+const WINDOW: usize = 20;
+/// A ping not replied in time is lost, unless replied later
+const LOST_AFTER: Duration = Duration::from_secs(3);
+
+/// Whether the packet is a heartbeat
+pub fn is_heartbeat(data: &[u8]) -> bool {
+    data.starts_with(HEARTBEAT_HEADER)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Heartbeat {
+    Ping(u32),
+    /// The reply with the sequence of the ping
+    Pong(u32),
+}
+
+impl Heartbeat {
+    pub fn to_packet(self) -> Vec<u8> {
+        let mut w = PacketWriter::default();
+        w.put_bytes(HEARTBEAT_HEADER);
+        match self {
+            Heartbeat::Ping(seq) => {
+                w.put_u8(PING);
+                w.put_u32(seq);
+            }
+            Heartbeat::Pong(seq) => {
+                w.put_u8(PONG);
+                w.put_u32(seq);
+            }
+        }
+        w.into_inner()
+    }
+
+    /// Read a packet checked by [`is_heartbeat`]
+    pub fn from_packet(data: &[u8]) -> Result<Self, ProtocolError> {
+        let mut r = PacketReader::new(data);
+        if r.bytes(HEARTBEAT_HEADER.len())? != HEARTBEAT_HEADER {
+            return Err(ProtocolError::BadHeader);
+        }
+        let kind = r.u8()?;
+        let seq = r.u32()?;
+        r.finish()?;
+        match kind {
+            PING => Ok(Heartbeat::Ping(seq)),
+            PONG => Ok(Heartbeat::Pong(seq)),
+            _ => Err(ProtocolError::BadValue("heartbeat")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeartbeatConfig {
+    /// Ping every interval
+    pub interval: Duration,
+    /// Disconnect if nothing received for it, only after the heartbeats negotiated
+    pub dead_timeout: Duration,
+    /// Disconnect if no message but the heartbeats received for it
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            dead_timeout: Duration::from_secs(20),
+            idle_timeout: None,
+        }
+    }
+}
+
+struct Sent {
+    seq: u32,
+    at: Instant,
+    rtt: Option<Duration>,
+}
+
+/// The pings sent to a peer and the round trip time measured by the pongs
+#[derive(Default)]
+pub struct RttEstimator {
+    last_seq: u32,
+    window: VecDeque<Sent>,
+    /// Smoothed like tcp, 7/8 of the last value and 1/8 of the new sample
+    srtt: Option<Duration>,
+    pings: u64,
+    pongs: u64,
+}
+
+impl RttEstimator {
+    /// The next ping to send
+    pub fn ping(&mut self, now: Instant) -> Heartbeat {
+        self.last_seq = self.last_seq.wrapping_add(1);
+        self.window.push_back(Sent { seq: self.last_seq, at: now, rtt: None });
+        if self.window.len() > WINDOW {
+            self.window.pop_front();
+        }
+        self.pings += 1;
+        Heartbeat::Ping(self.last_seq)
+    }
+
+    /// Return the round trip time of the ping, None if unknown or replied already
+    pub fn pong(&mut self, seq: u32, now: Instant) -> Option<Duration> {
+        let sent = self.window.iter_mut().find(|x| x.seq == seq && x.rtt.is_none())?;
+        let rtt = now.saturating_duration_since(sent.at);
+        sent.rtt = Some(rtt);
+        self.srtt = Some(match self.srtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+        self.pongs += 1;
+        Some(rtt)
+    }
+
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// The ratio of the pings in the window not replied, the ones still in time are not counted
+    pub fn loss(&self, now: Instant) -> f32 {
+        let settled = self.window.iter()
+            .filter(|x| x.rtt.is_some() || now.saturating_duration_since(x.at) >= LOST_AFTER);
+        let (count, lost) = settled.fold((0, 0), |(count, lost), x| (count + 1, lost + x.rtt.is_none() as u32));
+        if count == 0 {
+            0.0
+        } else {
+            lost as f32 / count as f32
+        }
+    }
+
+    /// Update the stats shared with the others
+    pub fn publish(&self, stats: &LinkStats, now: Instant) {
+        stats.srtt_micros.store(self.srtt.map(|x| x.as_micros() as u64).unwrap_or(0), Ordering::Relaxed);
+        stats.loss_permille.store((self.loss(now) * 1000.0) as u32, Ordering::Relaxed);
+        stats.pings.store(self.pings, Ordering::Relaxed);
+        stats.pongs.store(self.pongs, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quality {
+    /// No pong yet
+    Unknown,
+    Good,
+    Fair,
+    Poor,
+}
+
+/// The heartbeat stats of a peer, updated by [`RttEstimator::publish`]
+#[derive(Debug, Default)]
+pub struct LinkStats {
+    /// 0 if unknown
+    pub srtt_micros: AtomicU64,
+    pub loss_permille: AtomicU32,
+    pub pings: AtomicU64,
+    pub pongs: AtomicU64,
+}
+
+impl LinkStats {
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.srtt_micros.load(Ordering::Relaxed) {
+            0 => None,
+            x => Some(Duration::from_micros(x)),
+        }
+    }
+
+    /// From 0 to 1
+    pub fn loss(&self) -> f32 {
+        self.loss_permille.load(Ordering::Relaxed) as f32 / 1000.0
+    }
+
+    pub fn quality(&self) -> Quality {
+        let Some(rtt) = self.rtt() else {
+            return Quality::Unknown;
+        };
+        let loss = self.loss();
+        if loss >= 0.2 || rtt >= Duration::from_millis(500) {
+            Quality::Poor
+        } else if loss >= 0.05 || rtt >= Duration::from_millis(150) {
+            Quality::Fair
+        } else {
+            Quality::Good
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::heartbeat::{Heartbeat, is_heartbeat, LinkStats, Quality, RttEstimator};
+    use crate::ProtocolError;
+
+    #[test]
+    fn packet() {
+        for x in [Heartbeat::Ping(1), Heartbeat::Pong(u32::MAX)] {
+            let packet = x.to_packet();
+            assert!(is_heartbeat(&packet));
+            assert_eq!(Heartbeat::from_packet(&packet), Ok(x));
+        }
+        assert_eq!(Heartbeat::from_packet(b"rPth\x03\0\0\0\x01"), Err(ProtocolError::BadValue("heartbeat")));
+        assert!(Heartbeat::from_packet(b"rPth\x01\0\0").is_err());
+    }
+
+    #[test]
+    fn rtt_and_loss() {
+        let start = Instant::now();
+        let mut rtt = RttEstimator::default();
+        let stats = LinkStats::default();
+        rtt.publish(&stats, start);
+        assert_eq!(stats.quality(), Quality::Unknown);
+
+        let mut seqs = vec![];
+        for i in 0..4 {
+            let Heartbeat::Ping(seq) = rtt.ping(start + Duration::from_secs(i)) else { unreachable!() };
+            seqs.push(seq);
+        }
+        assert_eq!(rtt.pong(seqs[0], start + Duration::from_millis(80)), Some(Duration::from_millis(80)));
+        assert_eq!(rtt.pong(seqs[0], start + Duration::from_millis(90)), None);
+        assert_eq!(rtt.pong(seqs[2], start + Duration::from_millis(2160)), Some(Duration::from_millis(160)));
+        assert_eq!(rtt.srtt(), Some(Duration::from_millis(90)));
+        // the second is lost, the last is still in time
+        let now = start + Duration::from_millis(4500);
+        assert_eq!(rtt.loss(now), 1.0 / 3.0);
+        rtt.publish(&stats, now);
+        assert_eq!(stats.rtt(), Some(Duration::from_millis(90)));
+        assert_eq!(stats.quality(), Quality::Poor);
+    }
+}
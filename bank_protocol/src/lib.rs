@@ -0,0 +1,59 @@
+//! The packets between the bank client and the bank server.
+//!
+//! for all string: u16 len and utf coded str
+//!
+//! Packet format: `<header> <version: u32> <content>`
+//!
+//! Packet header: rPtm
+//!
+//! version: [`MIN_VERSION`] to [`CURRENT_VERSION`] negotiated by [`Handshake`] first.
+//! Version 0 is the legacy protocol without handshake, the server only tells it to upgrade.
+//!
+//! Since version 2 every content is [`Tagged`] with the request id.
+//!
+//! Client to server contents are [`AuthRequest`] before logged in, [`TotpCode`] if the password
+//! accepted but totp needed, and [`Request`] after logged in.
+//!
+//! Server to client contents are [`Response`], starting with 4 bytes type like b"menu".
+//!
+//! Packets longer than [`chunk::CHUNK_SIZE`] are sent as chunks, see [`chunk`].
+//!
+//! Large packets may be compressed before split, see [`compress`].
+//!
+//! The accounts are shown and typed as the numbers with check digits, see [`account`], or as the
+//! linked phones, see [`phone`].
+//!
+//! The messages to send are queued in [`queue`] with bounds, for both the client and the server.
+//!
+//! The peers ping each other to measure the link and find the dead ones, see [`heartbeat`].
+//!
+//! The packets are framed the same over KCP, TCP and WebSocket, see [`transport`].
+//!
+//! Decoding never panics, a bad packet is a [`ProtocolError`].
+
+pub use codec::{Message, PacketReader, PacketWriter, read_frame, read_header, Tagged, UNSOLICITED};
+pub use error::ProtocolError;
+pub use failure::{ErrorCode, Failure};
+pub use handshake::{Capabilities, Handshake, HANDSHAKE_VERSION, is_handshake};
+pub use request::{AuthRequest, Request, TotpCode};
+pub use response::{NotifyKind, Notification, PayeeEntry, RecentEntry, Response, TradeRecord, UserInfo};
+
+pub mod account;
+pub mod chunk;
+mod codec;
+pub mod compress;
+mod error;
+mod failure;
+pub mod handshake;
+pub mod heartbeat;
+pub mod phone;
+pub mod queue;
+pub mod request;
+pub mod response;
+pub mod transport;
+
+pub const PACKET_HEADER: &[u8] = b"rPtm";
+/// The legacy version before handshake
+pub const LEGACY_VERSION: u32 = 0;
+pub const MIN_VERSION: u32 = 2;
+pub const CURRENT_VERSION: u32 = 2;
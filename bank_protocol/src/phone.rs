@@ -0,0 +1,52 @@
+//! Phone numbers as the alias of the default account for transfers.
+//!
+//! Only mainland mobile numbers are accepted: 11 digits starting with `1`, with optional
+//! `+86`/`0086` prefix and spaces or `-` between.
+
+/// Get the 11 digits form or None if it is not a phone number
+pub fn normalize_phone(input: &str) -> Option<String> {
+    let digits = input.chars()
+        .filter(|x| !x.is_whitespace() && *x != '-')
+        .collect::<String>();
+    let digits = digits.strip_prefix("+86")
+        .or_else(|| digits.strip_prefix("0086"))
+        .unwrap_or(digits.as_str());
+    if digits.len() == 11 && digits.starts_with('1') && digits.bytes().all(|x| x.is_ascii_digit()) {
+        Some(digits.to_string())
+    } else {
+        None
+    }
+}
+
+/// Hide the middle digits like "138****5678"
+pub fn mask_phone(phone: &str) -> String {
+    let chars = phone.chars().collect::<Vec<_>>();
+    if chars.len() < 7 {
+        return "*".repeat(chars.len());
+    }
+    format!("{}{}{}", chars[..3].iter().collect::<String>(),
+            "*".repeat(chars.len() - 7),
+            chars[chars.len() - 4..].iter().collect::<String>())
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::phone::{mask_phone, normalize_phone};
+
+    #[test]
+    fn normalize() {
+        assert_eq!(normalize_phone("13812345678").as_deref(), Some("13812345678"));
+        assert_eq!(normalize_phone("+86 138-1234-5678").as_deref(), Some("13812345678"));
+        assert_eq!(normalize_phone("008613812345678").as_deref(), Some("13812345678"));
+        assert_eq!(normalize_phone("1234"), None);
+        assert_eq!(normalize_phone("23812345678"), None);
+        assert_eq!(normalize_phone("BB12622600123456"), None);
+    }
+
+    #[test]
+    fn mask() {
+        assert_eq!(mask_phone("13812345678"), "138****5678");
+        assert_eq!(mask_phone("123"), "***");
+    }
+}
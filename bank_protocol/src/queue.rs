@@ -0,0 +1,374 @@
+//! The bounded queues of the messages a peer sends, the same for the client and the server.
+//!
+//! Each peer has a control queue sent before the bulk one, both bounded by the count of the
+//! messages and sharing a budget of bytes. What happens when full is decided by [`FullPolicy`],
+//! so one client not reading could never make the server buffer without limit.
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use log::warn;
+use tokio::select;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::mpsc::error::TrySendError;
+
+use crate::compress::Compression;
+
+/// Free slots and bytes of the bulk queue needed to read the next request with [`FullPolicy::Block`]
+const HEADROOM_MESSAGES: usize = 4;
+const HEADROOM_BYTES: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum NetworkMessage {
+    Rely(Vec<u8>),
+    Once(Vec<u8>),
+    /// Compress the following reliable packets, None to stop
+    Compress(Option<Compression>),
+    /// Ping the other side and disconnect if dead, after negotiated
+    Heartbeat(bool),
+}
+
+/// What to do when the queue is full, named like `drop_once` in the configs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FullPolicy {
+    /// Wait in [`PeerSender::send_wait`], and stop reading the requests of the peer until it reads the replies
+    Block,
+    /// Drop the unreliable [`NetworkMessage::Once`], the others are like [`FullPolicy::Block`]
+    DropOnce,
+    /// Disconnect the peer
+    Disconnect,
+}
+
+impl FullPolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            FullPolicy::Block => "block",
+            FullPolicy::DropOnce => "drop_once",
+            FullPolicy::Disconnect => "disconnect",
+        }
+    }
+}
+
+impl FromStr for FullPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(FullPolicy::Block),
+            "drop_once" => Ok(FullPolicy::DropOnce),
+            "disconnect" => Ok(FullPolicy::Disconnect),
+            _ => Err(format!("Unknown queue policy {}, should be block, drop_once or disconnect", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueConfig {
+    pub control_capacity: usize,
+    pub bulk_capacity: usize,
+    /// The bytes of all the queued messages, a larger message takes the whole budget
+    pub max_bytes: usize,
+    pub policy: FullPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            control_capacity: 64,
+            bulk_capacity: 256,
+            max_bytes: 8 * 1024 * 1024,
+            policy: FullPolicy::Block,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Handshakes and the settings of the connection, sent first
+    Control,
+    Bulk,
+}
+
+impl NetworkMessage {
+    pub fn priority(&self) -> Priority {
+        match self {
+            NetworkMessage::Compress(_) | NetworkMessage::Heartbeat(_) => Priority::Control,
+            NetworkMessage::Rely(_) | NetworkMessage::Once(_) => Priority::Bulk,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            NetworkMessage::Rely(x) | NetworkMessage::Once(x) => x.len(),
+            NetworkMessage::Compress(_) | NetworkMessage::Heartbeat(_) => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    Full,
+    Closed,
+}
+
+impl Display for SendError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SendError::Full => "send queue is full",
+            SendError::Closed => "peer is closed",
+        })
+    }
+}
+
+impl std::error::Error for SendError {}
+
+/// The messages and bytes waiting to be sent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueDepth {
+    pub control: usize,
+    pub bulk: usize,
+    pub bytes: usize,
+}
+
+impl std::ops::Add for QueueDepth {
+    type Output = QueueDepth;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        QueueDepth {
+            control: self.control + rhs.control,
+            bulk: self.bulk + rhs.bulk,
+            bytes: self.bytes + rhs.bytes,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    /// Messages not sent because the queue was full
+    pub dropped: AtomicU64,
+    /// Times a producer waited for the room
+    pub blocked: AtomicU64,
+    /// The most messages queued at the same time
+    pub peak_depth: AtomicUsize,
+}
+
+/// A queued message, the bytes are given back when the permit is dropped
+pub struct Queued {
+    pub msg: NetworkMessage,
+    pub permit: OwnedSemaphorePermit,
+}
+
+/// The sending side of the queues, cloned by everyone sending to the peer
+#[derive(Debug, Clone)]
+pub struct PeerSender {
+    control: Sender<Queued>,
+    bulk: Sender<Queued>,
+    bytes: Arc<Semaphore>,
+    config: QueueConfig,
+    stats: Arc<QueueStats>,
+    listening: Arc<AtomicBool>,
+    kick: Arc<Notify>,
+}
+
+pub struct PeerReceiver {
+    control: Receiver<Queued>,
+    bulk: Receiver<Queued>,
+    kick: Arc<Notify>,
+}
+
+/// `listening` is cleared when disconnected by [`FullPolicy::Disconnect`]
+pub fn queue(config: QueueConfig, listening: Arc<AtomicBool>) -> (PeerSender, PeerReceiver) {
+    let (control, control_receiver) = channel(config.control_capacity);
+    let (bulk, bulk_receiver) = channel(config.bulk_capacity);
+    let kick = Arc::new(Notify::new());
+    let sender = PeerSender {
+        control,
+        bulk,
+        bytes: Arc::new(Semaphore::new(config.max_bytes)),
+        config,
+        stats: Default::default(),
+        listening,
+        kick: kick.clone(),
+    };
+    (sender, PeerReceiver { control: control_receiver, bulk: bulk_receiver, kick })
+}
+
+impl PeerSender {
+    /// Queue the message by its [`Priority`] and never wait, the full queue is handled by the policy
+    pub fn send(&self, msg: NetworkMessage) -> Result<(), SendError> {
+        self.send_with(msg.priority(), msg)
+    }
+
+    /// Queue the message before all the bulk ones
+    pub fn send_control(&self, msg: NetworkMessage) -> Result<(), SendError> {
+        self.send_with(Priority::Control, msg)
+    }
+
+    /// Like [`PeerSender::send`] but wait for the room with [`FullPolicy::Block`].
+    ///
+    /// Never call it in the handler of the same peer, the queue is not sent while handling.
+    pub async fn send_wait(&self, msg: NetworkMessage) -> Result<(), SendError> {
+        if self.config.policy != FullPolicy::Block {
+            return self.send(msg);
+        }
+        let permits = self.permits(&msg);
+        let queue = self.queue(msg.priority());
+        if queue.capacity() == 0 || self.bytes.available_permits() < permits as usize {
+            self.stats.blocked.fetch_add(1, Ordering::Relaxed);
+        }
+        let slot = queue.reserve().await.map_err(|_| SendError::Closed)?;
+        let permit = self.bytes.clone().acquire_many_owned(permits).await.map_err(|_| SendError::Closed)?;
+        slot.send(Queued { msg, permit });
+        self.update_peak();
+        Ok(())
+    }
+
+    fn send_with(&self, priority: Priority, msg: NetworkMessage) -> Result<(), SendError> {
+        let queue = self.queue(priority);
+        let slot = match queue.try_reserve() {
+            Ok(slot) => Some(slot),
+            Err(TrySendError::Closed(_)) => return Err(SendError::Closed),
+            Err(TrySendError::Full(_)) => None,
+        };
+        let permit = self.bytes.clone().try_acquire_many_owned(self.permits(&msg)).ok();
+        match (slot, permit) {
+            (Some(slot), Some(permit)) => {
+                slot.send(Queued { msg, permit });
+                self.update_peak();
+                Ok(())
+            }
+            _ => self.full(&msg),
+        }
+    }
+
+    fn full(&self, msg: &NetworkMessage) -> Result<(), SendError> {
+        self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        match self.config.policy {
+            FullPolicy::DropOnce if matches!(msg, NetworkMessage::Once(_)) => Ok(()),
+            FullPolicy::Disconnect => {
+                warn!("Disconnect the peer not reading, {:?} queued", self.depth());
+                self.listening.store(false, Ordering::Release);
+                self.kick.notify_one();
+                Err(SendError::Closed)
+            }
+            _ => Err(SendError::Full),
+        }
+    }
+
+    fn queue(&self, priority: Priority) -> &Sender<Queued> {
+        match priority {
+            Priority::Control => &self.control,
+            Priority::Bulk => &self.bulk,
+        }
+    }
+
+    fn permits(&self, msg: &NetworkMessage) -> u32 {
+        msg.len().min(self.config.max_bytes) as u32
+    }
+
+    fn update_peak(&self) {
+        let depth = self.depth();
+        self.stats.peak_depth.fetch_max(depth.control + depth.bulk, Ordering::Relaxed);
+    }
+
+    pub fn depth(&self) -> QueueDepth {
+        QueueDepth {
+            control: self.control.max_capacity() - self.control.capacity(),
+            bulk: self.bulk.max_capacity() - self.bulk.capacity(),
+            bytes: self.config.max_bytes - self.bytes.available_permits(),
+        }
+    }
+
+    /// Whether all the queued are taken by the peer
+    pub fn is_empty(&self) -> bool {
+        let depth = self.depth();
+        depth.control + depth.bulk == 0
+    }
+
+    pub fn stats(&self) -> &QueueStats {
+        &self.stats
+    }
+
+    /// Whether to read the next request of the peer, false if the replies may not fit with [`FullPolicy::Block`]
+    pub fn accepting(&self) -> bool {
+        self.config.policy != FullPolicy::Block
+            || (self.bulk.capacity() >= HEADROOM_MESSAGES.min(self.config.bulk_capacity)
+            && self.bytes.available_permits() >= HEADROOM_BYTES.min(self.config.max_bytes))
+    }
+}
+
+impl PeerReceiver {
+    /// The next message, the control ones first.
+    ///
+    /// None if all the senders are dropped or the peer is disconnected for the full queue.
+    pub async fn recv(&mut self) -> Option<Queued> {
+        select! {
+            biased;
+            _ = self.kick.notified() => None,
+            Some(x) = self.control.recv() => Some(x),
+            Some(x) = self.bulk.recv() => Some(x),
+            else => None,
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<Queued> {
+        self.control.try_recv().or_else(|_| self.bulk.try_recv()).ok()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use crate::queue::{FullPolicy, NetworkMessage, queue, QueueConfig, QueueDepth, SendError};
+
+    fn config(policy: FullPolicy) -> QueueConfig {
+        QueueConfig { control_capacity: 2, bulk_capacity: 2, max_bytes: 10, policy }
+    }
+
+    #[test]
+    fn priority_and_bounds() {
+        let (sender, mut receiver) = queue(config(FullPolicy::Block), Default::default());
+        sender.send(NetworkMessage::Rely(vec![1; 4])).unwrap();
+        sender.send(NetworkMessage::Compress(None)).unwrap();
+        assert_eq!(sender.depth(), QueueDepth { control: 1, bulk: 1, bytes: 4 });
+        // the bytes are full before the count
+        assert_eq!(sender.send(NetworkMessage::Rely(vec![2; 7])), Err(SendError::Full));
+        assert!(!sender.accepting());
+        // a message larger than the budget takes the whole one
+        assert!(matches!(receiver.try_recv().unwrap().msg, NetworkMessage::Compress(None)));
+        let first = receiver.try_recv().unwrap();
+        assert!(matches!(first.msg, NetworkMessage::Rely(ref x) if x.len() == 4));
+        drop(first);
+        sender.send(NetworkMessage::Rely(vec![3; 100])).unwrap();
+        assert_eq!(sender.depth().bytes, 10);
+        assert_eq!(sender.stats().dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn policies() {
+        let (sender, _receiver) = queue(config(FullPolicy::DropOnce), Default::default());
+        sender.send(NetworkMessage::Once(vec![0; 2])).unwrap();
+        sender.send(NetworkMessage::Once(vec![0; 2])).unwrap();
+        assert_eq!(sender.send(NetworkMessage::Once(vec![0; 2])), Ok(()));
+        assert_eq!(sender.send(NetworkMessage::Rely(vec![0; 2])), Err(SendError::Full));
+        assert_eq!(sender.depth().bulk, 2);
+
+        let listening = Arc::new(AtomicBool::new(true));
+        let (sender, _receiver) = queue(config(FullPolicy::Disconnect), listening.clone());
+        sender.send(NetworkMessage::Once(vec![0; 2])).unwrap();
+        sender.send(NetworkMessage::Once(vec![0; 2])).unwrap();
+        assert_eq!(sender.send(NetworkMessage::Once(vec![0; 2])), Err(SendError::Closed));
+        assert!(!listening.load(Ordering::Relaxed));
+
+        for policy in [FullPolicy::Block, FullPolicy::DropOnce, FullPolicy::Disconnect] {
+            assert_eq!(policy.name().parse(), Ok(policy));
+        }
+        assert!("drop".parse::<FullPolicy>().is_err());
+    }
+}
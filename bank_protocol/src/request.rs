@@ -0,0 +1,344 @@
+//! Client to server packets
+
+use crate::{Message, PacketReader, PacketWriter, ProtocolError};
+
+/// Before logged in, told by the content len
+///
+/// * Login packet: (id: u32) (password: u32)
+/// * Register packet: (password: u32) (name: String) (phone_number: String)
+///
+/// The account id is allocated by server and returned in the menu packet.
+///
+/// Register with empty name is refused to encode, it would be the same len as login.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthRequest {
+    Login { id: u32, password: u32 },
+    Register { password: u32, name: String, phone: String },
+}
+
+/// The totp code (or one recovery code) after the password accepted: (code: String)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TotpCode {
+    pub code: String,
+}
+
+/// After logged in: (type: u8) (fields)
+///
+/// The transfer target could be an account number or a linked phone number.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// \0 amount: u32
+    Deposit { amount: u32 },
+    /// \1 amount: u32, (code: String)
+    ///
+    /// The code is needed if challenged by the risk rules and totp enabled
+    Withdraw { amount: u32, code: Option<String> },
+    /// \2 target: u32, amount: u32 (rejected, the recipient must be confirmed first)
+    LegacyTransfer { target: u32, amount: u32 },
+    /// \3
+    Info,
+    /// \4
+    BeginTotp,
+    /// \5 code: String
+    ConfirmTotp { code: String },
+    /// \6 code: String (recovery code accepted)
+    DisableTotp { code: String },
+    /// \7 target: String, amount: u32, token: String, (code: String)
+    ///
+    /// The code is needed if amount >= the threshold and totp enabled
+    Transfer { target: String, amount: u32, token: String, code: Option<String> },
+    /// \8 target: String, amount: u32
+    ResolveRecipient { target: String, amount: u32 },
+    /// \9 for_edit: u8
+    ListPayees { for_edit: bool },
+    /// \10 nickname: String, account: String
+    AddPayee { nickname: String, account: String },
+    /// \11 pid: i32, nickname: String, account: String
+    EditPayee { pid: i32, nickname: String, account: String },
+    /// \12 pid: i32
+    DeletePayee { pid: i32 },
+    /// \13 link: u8 (0 to unlink)
+    LinkPhone { link: bool },
+    /// \14 back to the login
+    Logout,
+    /// \15 old: u32, new: u32, (code: String)
+    ///
+    /// The code is needed if totp enabled, the other sessions are logged out after changed
+    ChangePassword { old: u32, new: u32, code: Option<String> },
+    /// \16 name: String, phone: String, (password: u32)
+    ///
+    /// The password is needed if the phone is changed
+    UpdateProfile { name: String, phone: String, password: Option<u32> },
+    /// \17 payout: String, password: u32, (code: String)
+    ///
+    /// The balance is paid to the payout account, like the target of the transfer.
+    /// The code is needed if totp enabled, the closed account could only read the history
+    CloseAccount { payout: String, password: u32, code: Option<String> },
+    /// \18 operation: String, amount: u32
+    ///
+    /// The fee of "withdraw" or "transfer" is replied before the customer confirms
+    QuoteFee { operation: String, amount: u32 },
+    /// \19 device: String
+    ///
+    /// The id of the client installation, sent after logged in and not replied unless failed.
+    /// The session without it is a new device for the risk rules
+    IdentifyDevice { device: String },
+}
+
+impl Message for AuthRequest {
+    fn encode(&self, w: &mut PacketWriter) -> Result<(), ProtocolError> {
+        match self {
+            AuthRequest::Login { id, password } => {
+                w.put_u32(*id);
+                w.put_u32(*password);
+            }
+            AuthRequest::Register { password, name, phone } => {
+                if name.is_empty() {
+                    return Err(ProtocolError::BadValue("name"));
+                }
+                w.put_u32(*password);
+                w.put_string(name)?;
+                w.put_string(phone)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn decode(r: &mut PacketReader<'_>) -> Result<Self, ProtocolError> {
+        if r.remaining() == 8 {
+            Ok(AuthRequest::Login { id: r.u32()?, password: r.u32()? })
+        } else {
+            Ok(AuthRequest::Register { password: r.u32()?, name: r.string()?, phone: r.string()? })
+        }
+    }
+}
+
+impl Message for TotpCode {
+    fn encode(&self, w: &mut PacketWriter) -> Result<(), ProtocolError> {
+        w.put_string(&self.code)
+    }
+
+    fn decode(r: &mut PacketReader<'_>) -> Result<Self, ProtocolError> {
+        Ok(Self { code: r.string()? })
+    }
+}
+
+impl Request {
+    pub fn type_id(&self) -> u8 {
+        match self {
+            Request::Deposit { .. } => 0,
+            Request::Withdraw { .. } => 1,
+            Request::LegacyTransfer { .. } => 2,
+            Request::Info => 3,
+            Request::BeginTotp => 4,
+            Request::ConfirmTotp { .. } => 5,
+            Request::DisableTotp { .. } => 6,
+            Request::Transfer { .. } => 7,
+            Request::ResolveRecipient { .. } => 8,
+            Request::ListPayees { .. } => 9,
+            Request::AddPayee { .. } => 10,
+            Request::EditPayee { .. } => 11,
+            Request::DeletePayee { .. } => 12,
+            Request::LinkPhone { .. } => 13,
+            Request::Logout => 14,
+            Request::ChangePassword { .. } => 15,
+            Request::UpdateProfile { .. } => 16,
+            Request::CloseAccount { .. } => 17,
+            Request::QuoteFee { .. } => 18,
+            Request::IdentifyDevice { .. } => 19,
+        }
+    }
+}
+
+impl Message for Request {
+    fn encode(&self, w: &mut PacketWriter) -> Result<(), ProtocolError> {
+        w.put_u8(self.type_id());
+        match self {
+            Request::Deposit { amount } => w.put_u32(*amount),
+            Request::Withdraw { amount, code } => {
+                w.put_u32(*amount);
+                if let Some(code) = code {
+                    w.put_string(code)?;
+                }
+            }
+            Request::LegacyTransfer { target, amount } => {
+                w.put_u32(*target);
+                w.put_u32(*amount);
+            }
+            Request::Info | Request::BeginTotp | Request::Logout => {}
+            Request::ConfirmTotp { code } | Request::DisableTotp { code } => w.put_string(code)?,
+            Request::Transfer { target, amount, token, code } => {
+                w.put_string(target)?;
+                w.put_u32(*amount);
+                w.put_string(token)?;
+                if let Some(code) = code {
+                    w.put_string(code)?;
+                }
+            }
+            Request::ResolveRecipient { target, amount } => {
+                w.put_string(target)?;
+                w.put_u32(*amount);
+            }
+            Request::ListPayees { for_edit } => w.put_bool(*for_edit),
+            Request::AddPayee { nickname, account } => {
+                w.put_string(nickname)?;
+                w.put_string(account)?;
+            }
+            Request::EditPayee { pid, nickname, account } => {
+                w.put_i32(*pid);
+                w.put_string(nickname)?;
+                w.put_string(account)?;
+            }
+            Request::DeletePayee { pid } => w.put_i32(*pid),
+            Request::LinkPhone { link } => w.put_bool(*link),
+            Request::ChangePassword { old, new, code } => {
+                w.put_u32(*old);
+                w.put_u32(*new);
+                if let Some(code) = code {
+                    w.put_string(code)?;
+                }
+            }
+            Request::UpdateProfile { name, phone, password } => {
+                w.put_string(name)?;
+                w.put_string(phone)?;
+                if let Some(password) = password {
+                    w.put_u32(*password);
+                }
+            }
+            Request::CloseAccount { payout, password, code } => {
+                w.put_string(payout)?;
+                w.put_u32(*password);
+                if let Some(code) = code {
+                    w.put_string(code)?;
+                }
+            }
+            Request::QuoteFee { operation, amount } => {
+                w.put_string(operation)?;
+                w.put_u32(*amount);
+            }
+            Request::IdentifyDevice { device } => w.put_string(device)?,
+        }
+        Ok(())
+    }
+
+    fn decode(r: &mut PacketReader<'_>) -> Result<Self, ProtocolError> {
+        Ok(match r.u8()? {
+            0 => Request::Deposit { amount: r.u32()? },
+            1 => {
+                let amount = r.u32()?;
+                let code = if r.is_empty() { None } else { Some(r.string()?) };
+                Request::Withdraw { amount, code }
+            }
+            2 => Request::LegacyTransfer { target: r.u32()?, amount: r.u32()? },
+            3 => Request::Info,
+            4 => Request::BeginTotp,
+            5 => Request::ConfirmTotp { code: r.string()? },
+            6 => Request::DisableTotp { code: r.string()? },
+            7 => {
+                let target = r.string()?;
+                let amount = r.u32()?;
+                let token = r.string()?;
+                let code = if r.is_empty() { None } else { Some(r.string()?) };
+                Request::Transfer { target, amount, token, code }
+            }
+            8 => Request::ResolveRecipient { target: r.string()?, amount: r.u32()? },
+            9 => Request::ListPayees { for_edit: r.bool()? },
+            10 => Request::AddPayee { nickname: r.string()?, account: r.string()? },
+            11 => Request::EditPayee { pid: r.i32()?, nickname: r.string()?, account: r.string()? },
+            12 => Request::DeletePayee { pid: r.i32()? },
+            13 => Request::LinkPhone { link: r.bool()? },
+            14 => Request::Logout,
+            15 => {
+                let old = r.u32()?;
+                let new = r.u32()?;
+                let code = if r.is_empty() { None } else { Some(r.string()?) };
+                Request::ChangePassword { old, new, code }
+            }
+            16 => {
+                let name = r.string()?;
+                let phone = r.string()?;
+                let password = if r.is_empty() { None } else { Some(r.u32()?) };
+                Request::UpdateProfile { name, phone, password }
+            }
+            17 => {
+                let payout = r.string()?;
+                let password = r.u32()?;
+                let code = if r.is_empty() { None } else { Some(r.string()?) };
+                Request::CloseAccount { payout, password, code }
+            }
+            18 => Request::QuoteFee { operation: r.string()?, amount: r.u32()? },
+            19 => Request::IdentifyDevice { device: r.string()? },
+            x => Err(ProtocolError::UnknownRequest(x))?,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::{AuthRequest, Message, ProtocolError, Request, Tagged, TotpCode};
+
+    fn round_trip<T: Message + PartialEq + std::fmt::Debug>(msg: T) {
+        let packet = msg.to_packet().unwrap();
+        assert_eq!(T::from_packet(&packet), Ok(msg));
+        // any truncated packet is an error but not panic
+        for len in 0..packet.len() {
+            let _ = T::from_packet(&packet[..len]);
+        }
+    }
+
+    #[test]
+    fn auth() {
+        round_trip(AuthRequest::Login { id: 12345678, password: 123456 });
+        round_trip(AuthRequest::Register { password: 1, name: "张三".into(), phone: "13812345678".into() });
+        round_trip(AuthRequest::Register { password: 1, name: "a".into(), phone: "".into() });
+        assert!(AuthRequest::Register { password: 1, name: "".into(), phone: "".into() }.to_packet().is_err());
+        round_trip(TotpCode { code: "123456".into() });
+        // the len of login is counted after the id
+        round_trip(Tagged { id: 7, body: AuthRequest::Login { id: 1, password: 2 } });
+        round_trip(Tagged { id: 8, body: AuthRequest::Register { password: 1, name: "a".into(), phone: "1".into() } });
+    }
+
+    #[test]
+    fn logged() {
+        let all = [
+            Request::Deposit { amount: 100 },
+            Request::Withdraw { amount: u32::MAX, code: None },
+            Request::Withdraw { amount: 1, code: Some("123456".into()) },
+            Request::LegacyTransfer { target: 1, amount: 2 },
+            Request::Info,
+            Request::BeginTotp,
+            Request::ConfirmTotp { code: "123456".into() },
+            Request::DisableTotp { code: "ABCD-EFGH".into() },
+            Request::Transfer { target: "BB1262260012345678".into(), amount: 100, token: "t".into(), code: None },
+            Request::Transfer { target: "13812345678".into(), amount: 9000, token: "t".into(), code: Some("654321".into()) },
+            Request::ResolveRecipient { target: "13812345678".into(), amount: 1 },
+            Request::ListPayees { for_edit: true },
+            Request::AddPayee { nickname: "房东".into(), account: "1234".into() },
+            Request::EditPayee { pid: -1, nickname: "n".into(), account: "a".into() },
+            Request::DeletePayee { pid: 3 },
+            Request::LinkPhone { link: false },
+            Request::Logout,
+            Request::ChangePassword { old: 1, new: 2, code: None },
+            Request::ChangePassword { old: 1, new: 2, code: Some("123456".into()) },
+            Request::UpdateProfile { name: "张三".into(), phone: "".into(), password: None },
+            Request::UpdateProfile { name: "张三".into(), phone: "13812345678".into(), password: Some(7) },
+            Request::CloseAccount { payout: "BB1262260012345678".into(), password: 1, code: None },
+            Request::CloseAccount { payout: "13812345678".into(), password: 1, code: Some("ABCD-EFGH".into()) },
+            Request::QuoteFee { operation: "withdraw".into(), amount: 100 },
+            Request::IdentifyDevice { device: "0123456789abcdef".into() },
+        ];
+        for x in all {
+            round_trip(x);
+        }
+    }
+
+    #[test]
+    fn bad_packet() {
+        assert_eq!(Request::from_content(&[20]), Err(ProtocolError::UnknownRequest(20)));
+        assert_eq!(Request::from_content(&[3, 0]), Err(ProtocolError::TrailingBytes(1)));
+        assert_eq!(Request::from_content(&[9, 2]), Err(ProtocolError::BadValue("bool")));
+        assert!(matches!(Request::from_content(&[0, 0]), Err(ProtocolError::Truncated { .. })));
+        assert!(matches!(Request::from_content(&[]), Err(ProtocolError::Truncated { .. })));
+    }
+}
@@ -0,0 +1,364 @@
+//! Server to client packets: (type: 4 bytes) (fields)
+
+use chrono::{DateTime, Utc};
+
+use crate::{Failure, Message, PacketReader, PacketWriter, ProtocolError};
+
+/// (id: u32) (name: String) (balance: u32) (phone_number: String)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserInfo {
+    pub id: u32,
+    pub name: String,
+    pub balance: u32,
+    pub phone: String,
+}
+
+/// tid: i32, receiver: u32, sender: String, time: (i64 u32), amount: i32
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TradeRecord {
+    pub tid: i32,
+    pub receiver: u32,
+    pub sender: String,
+    pub time: DateTime<Utc>,
+    pub amount: i32,
+}
+
+/// pid: i32, nickname: String, account: String, last_used: i64 (-1 for never)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayeeEntry {
+    pub pid: i32,
+    pub nickname: String,
+    pub account: String,
+    pub last_used: Option<DateTime<Utc>>,
+}
+
+/// The account transferred to recently but not saved: account: String, time: i64
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecentEntry {
+    pub account: String,
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum NotifyKind {
+    /// Money in
+    Credit = 0,
+    /// Money out
+    Debit = 1,
+    /// Changed by the bank
+    Admin = 2,
+}
+
+/// (kind: u8) (amount: u32) (balance: u32) (counterpart: String) (time: i64) (msg: String)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    pub kind: NotifyKind,
+    pub amount: u32,
+    pub balance: u32,
+    pub counterpart: String,
+    pub time: DateTime<Utc>,
+    pub msg: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// Return main menu with info (b"menu")
+    Menu(UserInfo),
+    /// Normal tip and do nothing (b"msgb") (msg: String)
+    Tip(String),
+    /// The request failed (b"fail") <Failure>
+    Failed(Failure),
+    /// Error and disconnect (b"errr") <Failure>
+    Error(Failure),
+    /// b"info" current_page: u32, total_page: u32, info_cnt: u32, <User>, <info>
+    Info { current_page: u32, total_page: u32, user: UserInfo, trades: Vec<TradeRecord> },
+    /// Password accepted but need totp code to login (b"totp")
+    TotpRequired,
+    /// b"totb" secret: String, provisioning_uri: String
+    TotpBegin { secret: String, uri: String },
+    /// b"totr" code_cnt: u32 <recovery_code: String>
+    RecoveryCodes(Vec<String>),
+    /// The recipient to confirm (b"cfrm") token: String, target: String, masked_name: String, amount: u32
+    Confirm { token: String, target: String, masked_name: String, amount: u32 },
+    /// b"payl" for_edit: u8, payee_cnt: u32 <payee>, recent_cnt: u32 <recent>
+    Payees { for_edit: bool, payees: Vec<PayeeEntry>, recent: Vec<RecentEntry> },
+    /// Account changed by others or other sessions (b"ntfy")
+    Notify(Notification),
+    /// Logged out, back to the login (b"lout")
+    LoggedOut,
+    /// The fee to confirm before the operation (b"feeq") operation: String, amount: u32, fee: u32
+    FeeQuote { operation: String, amount: u32, fee: u32 },
+}
+
+impl UserInfo {
+    fn encode(&self, w: &mut PacketWriter) -> Result<(), ProtocolError> {
+        w.put_u32(self.id);
+        w.put_string(&self.name)?;
+        w.put_u32(self.balance);
+        w.put_string(&self.phone)
+    }
+
+    fn decode(r: &mut PacketReader<'_>) -> Result<Self, ProtocolError> {
+        Ok(Self { id: r.u32()?, name: r.string()?, balance: r.u32()?, phone: r.string()? })
+    }
+}
+
+impl NotifyKind {
+    pub fn from_u8(x: u8) -> Result<Self, ProtocolError> {
+        match x {
+            0 => Ok(NotifyKind::Credit),
+            1 => Ok(NotifyKind::Debit),
+            2 => Ok(NotifyKind::Admin),
+            _ => Err(ProtocolError::BadValue("notify kind")),
+        }
+    }
+}
+
+impl Response {
+    pub fn type_id(&self) -> &'static [u8; 4] {
+        match self {
+            Response::Menu(_) => b"menu",
+            Response::Tip(_) => b"msgb",
+            Response::Failed(_) => b"fail",
+            Response::Error(_) => b"errr",
+            Response::Info { .. } => b"info",
+            Response::TotpRequired => b"totp",
+            Response::TotpBegin { .. } => b"totb",
+            Response::RecoveryCodes(_) => b"totr",
+            Response::Confirm { .. } => b"cfrm",
+            Response::Payees { .. } => b"payl",
+            Response::Notify(_) => b"ntfy",
+            Response::LoggedOut => b"lout",
+            Response::FeeQuote { .. } => b"feeq",
+        }
+    }
+}
+
+impl Message for Response {
+    fn encode(&self, w: &mut PacketWriter) -> Result<(), ProtocolError> {
+        w.put_bytes(self.type_id());
+        match self {
+            Response::Menu(user) => user.encode(w)?,
+            Response::Tip(msg) => w.put_string(msg)?,
+            Response::Failed(failure) | Response::Error(failure) => failure.encode(w)?,
+            Response::Info { current_page, total_page, user, trades } => {
+                w.put_u32(*current_page);
+                w.put_u32(*total_page);
+                w.put_len(trades.len())?;
+                user.encode(w)?;
+                for trade in trades {
+                    w.put_i32(trade.tid);
+                    w.put_u32(trade.receiver);
+                    w.put_string(&trade.sender)?;
+                    w.put_i64(trade.time.timestamp());
+                    w.put_u32(trade.time.timestamp_subsec_nanos());
+                    w.put_i32(trade.amount);
+                }
+            }
+            Response::TotpRequired | Response::LoggedOut => {}
+            Response::TotpBegin { secret, uri } => {
+                w.put_string(secret)?;
+                w.put_string(uri)?;
+            }
+            Response::RecoveryCodes(codes) => {
+                w.put_len(codes.len())?;
+                for code in codes {
+                    w.put_string(code)?;
+                }
+            }
+            Response::Confirm { token, target, masked_name, amount } => {
+                w.put_string(token)?;
+                w.put_string(target)?;
+                w.put_string(masked_name)?;
+                w.put_u32(*amount);
+            }
+            Response::Payees { for_edit, payees, recent } => {
+                w.put_bool(*for_edit);
+                w.put_len(payees.len())?;
+                for payee in payees {
+                    w.put_i32(payee.pid);
+                    w.put_string(&payee.nickname)?;
+                    w.put_string(&payee.account)?;
+                    w.put_i64(payee.last_used.map(|x| x.timestamp()).unwrap_or(-1));
+                }
+                w.put_len(recent.len())?;
+                for x in recent {
+                    w.put_string(&x.account)?;
+                    w.put_i64(x.time.timestamp());
+                }
+            }
+            Response::Notify(notice) => {
+                w.put_u8(notice.kind as u8);
+                w.put_u32(notice.amount);
+                w.put_u32(notice.balance);
+                w.put_string(&notice.counterpart)?;
+                w.put_i64(notice.time.timestamp());
+                w.put_string(&notice.msg)?;
+            }
+            Response::FeeQuote { operation, amount, fee } => {
+                w.put_string(operation)?;
+                w.put_u32(*amount);
+                w.put_u32(*fee);
+            }
+        }
+        Ok(())
+    }
+
+    fn decode(r: &mut PacketReader<'_>) -> Result<Self, ProtocolError> {
+        let mut type_id = [0; 4];
+        type_id.copy_from_slice(r.bytes(4)?);
+        Ok(match &type_id {
+            b"menu" => Response::Menu(UserInfo::decode(r)?),
+            b"msgb" => Response::Tip(r.string()?),
+            b"fail" => Response::Failed(Failure::decode(r)?),
+            b"errr" => Response::Error(Failure::decode(r)?),
+            b"info" => {
+                let current_page = r.u32()?;
+                let total_page = r.u32()?;
+                let count = r.u32()?;
+                let user = UserInfo::decode(r)?;
+                let mut trades = vec![];
+                for _ in 0..count {
+                    let tid = r.i32()?;
+                    let receiver = r.u32()?;
+                    let sender = r.string()?;
+                    let secs = r.i64()?;
+                    let nanos = r.u32()?;
+                    let time = DateTime::from_timestamp(secs, nanos).ok_or(ProtocolError::BadValue("time"))?;
+                    let amount = r.i32()?;
+                    trades.push(TradeRecord { tid, receiver, sender, time, amount });
+                }
+                Response::Info { current_page, total_page, user, trades }
+            }
+            b"totp" => Response::TotpRequired,
+            b"totb" => Response::TotpBegin { secret: r.string()?, uri: r.string()? },
+            b"totr" => {
+                let count = r.u32()?;
+                let codes = (0..count).map(|_| r.string()).collect::<Result<_, _>>()?;
+                Response::RecoveryCodes(codes)
+            }
+            b"cfrm" => Response::Confirm {
+                token: r.string()?,
+                target: r.string()?,
+                masked_name: r.string()?,
+                amount: r.u32()?,
+            },
+            b"payl" => {
+                let for_edit = r.bool()?;
+                let count = r.u32()?;
+                let mut payees = vec![];
+                for _ in 0..count {
+                    let pid = r.i32()?;
+                    let nickname = r.string()?;
+                    let account = r.string()?;
+                    let last_used = match r.i64()? {
+                        x if x < 0 => None,
+                        x => Some(DateTime::from_timestamp(x, 0).ok_or(ProtocolError::BadValue("time"))?),
+                    };
+                    payees.push(PayeeEntry { pid, nickname, account, last_used });
+                }
+                let count = r.u32()?;
+                let mut recent = vec![];
+                for _ in 0..count {
+                    recent.push(RecentEntry { account: r.string()?, time: r.time()? });
+                }
+                Response::Payees { for_edit, payees, recent }
+            }
+            b"ntfy" => Response::Notify(Notification {
+                kind: NotifyKind::from_u8(r.u8()?)?,
+                amount: r.u32()?,
+                balance: r.u32()?,
+                counterpart: r.string()?,
+                time: r.time()?,
+                msg: r.string()?,
+            }),
+            b"lout" => Response::LoggedOut,
+            b"feeq" => Response::FeeQuote { operation: r.string()?, amount: r.u32()?, fee: r.u32()? },
+            _ => Err(ProtocolError::UnknownResponse(type_id))?,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use chrono::{DateTime, Utc};
+
+    use crate::*;
+
+    fn round_trip(msg: Response) {
+        let packet = msg.to_packet().unwrap();
+        assert_eq!(Response::from_packet(&packet), Ok(msg));
+        for len in 0..packet.len() {
+            assert!(Response::from_packet(&packet[..len]).is_err());
+        }
+    }
+
+    fn user() -> UserInfo {
+        UserInfo { id: 12345678, name: "张三".into(), balance: 9999, phone: "13812345678".into() }
+    }
+
+    fn time(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(secs, 0).unwrap()
+    }
+
+    #[test]
+    fn all_responses() {
+        let all = [
+            Response::Menu(user()),
+            Response::Tip("提示".into()),
+            Response::Failed(Failure::with_params(ErrorCode::TooManyPayees, vec!["50".into()])),
+            Response::Error(Failure::new(ErrorCode::Internal).with_message("reason")),
+            Response::Info { current_page: 1, total_page: 1, user: user(), trades: vec![] },
+            Response::Info {
+                current_page: 1,
+                total_page: 2,
+                user: user(),
+                trades: vec![TradeRecord {
+                    tid: 1,
+                    receiver: 2,
+                    sender: "存款".into(),
+                    time: DateTime::from_timestamp(1700000000, 123456789).unwrap(),
+                    amount: -100,
+                }],
+            },
+            Response::TotpRequired,
+            Response::TotpBegin { secret: "JBSWY3DPEHPK3PXP".into(), uri: "otpauth://totp/x".into() },
+            Response::RecoveryCodes(vec!["ABCD-EFGH".into(), "IJKL-MNOP".into()]),
+            Response::Confirm { token: "t".into(), target: "BB1262260012345678".into(), masked_name: "张*".into(), amount: 1 },
+            Response::Payees {
+                for_edit: false,
+                payees: vec![
+                    PayeeEntry { pid: 1, nickname: "房东".into(), account: "a".into(), last_used: None },
+                    PayeeEntry { pid: 2, nickname: "n".into(), account: "b".into(), last_used: Some(time(1700000000)) },
+                ],
+                recent: vec![RecentEntry { account: "c".into(), time: time(1) }],
+            },
+            Response::Notify(Notification {
+                kind: NotifyKind::Debit,
+                amount: 100,
+                balance: 0,
+                counterpart: "存款".into(),
+                time: time(1700000000),
+                msg: "".into(),
+            }),
+            Response::LoggedOut,
+            Response::FeeQuote { operation: "transfer".into(), amount: 5000, fee: 25 },
+        ];
+        for x in all {
+            round_trip(x);
+        }
+    }
+
+    #[test]
+    fn bad_packet() {
+        let mut packet = Response::TotpRequired.to_packet().unwrap();
+        packet[8..12].copy_from_slice(b"what");
+        assert_eq!(Response::from_packet(&packet), Err(ProtocolError::UnknownResponse(*b"what")));
+        let mut packet = Response::Payees { for_edit: true, payees: vec![], recent: vec![] }.to_packet().unwrap();
+        // claims more payees than sent
+        packet[13..17].copy_from_slice(&100u32.to_be_bytes());
+        assert!(matches!(Response::from_packet(&packet), Err(ProtocolError::Truncated { .. })));
+    }
+}
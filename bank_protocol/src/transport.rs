@@ -0,0 +1,172 @@
+//! The streams between the bank client and the bank server, all of them send and receive whole packets.
+//!
+//! * KCP over UDP in message mode, the default
+//! * TCP with the u32 big endian len before each packet, for the networks blocking UDP
+//! * WebSocket binary messages, for the networks only allowing http
+//!
+//! Only the framing is here, the server listens and the client connects in their own crates.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures_util::{Sink, SinkExt, StreamExt};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio_kcp::KcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+/// The max len of a packet, the same as the receive buffer
+pub const MAX_FRAME_LEN: usize = 65536;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transport {
+    Kcp,
+    Tcp,
+    WebSocket,
+}
+
+impl Transport {
+    /// The order for the client to try if not chosen
+    pub const FALLBACK: [Transport; 3] = [Transport::Kcp, Transport::Tcp, Transport::WebSocket];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Transport::Kcp => "kcp",
+            Transport::Tcp => "tcp",
+            Transport::WebSocket => "websocket",
+        }
+    }
+}
+
+impl Display for Transport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kcp" => Ok(Transport::Kcp),
+            "tcp" => Ok(Transport::Tcp),
+            "websocket" => Ok(Transport::WebSocket),
+            _ => Err(format!("Unknown transport {}, should be kcp, tcp or websocket", s)),
+        }
+    }
+}
+
+/// The limits of both sides, a message larger than a packet is refused
+pub fn websocket_config() -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(MAX_FRAME_LEN),
+        max_frame_size: Some(MAX_FRAME_LEN),
+        ..Default::default()
+    }
+}
+
+pub fn websocket_error(e: tokio_tungstenite::tungstenite::Error) -> io::Error {
+    match e {
+        tokio_tungstenite::tungstenite::Error::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+/// The connection of a peer, the accepted websocket is [`MaybeTlsStream::Plain`]
+pub enum FrameStream {
+    Kcp(KcpStream),
+    Tcp(Framed<TcpStream, LengthDelimitedCodec>),
+    WebSocket(WebSocketStream<MaybeTlsStream<TcpStream>>),
+}
+
+impl FrameStream {
+    pub fn tcp(stream: TcpStream) -> Self {
+        let _ = stream.set_nodelay(true);
+        let codec = LengthDelimitedCodec::builder()
+            .length_field_type::<u32>()
+            .max_frame_length(MAX_FRAME_LEN)
+            .new_codec();
+        FrameStream::Tcp(Framed::new(stream, codec))
+    }
+
+    pub fn transport(&self) -> Transport {
+        match self {
+            FrameStream::Kcp(_) => Transport::Kcp,
+            FrameStream::Tcp(_) => Transport::Tcp,
+            FrameStream::WebSocket(_) => Transport::WebSocket,
+        }
+    }
+
+    /// Send the packet, it may be buffered until [`FrameStream::flush`]
+    pub async fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        match self {
+            FrameStream::Kcp(s) => s.send(packet).await.map(|_| ()).map_err(io::Error::from),
+            FrameStream::Tcp(s) => s.feed(Bytes::copy_from_slice(packet)).await,
+            FrameStream::WebSocket(s) => s.feed(Message::Binary(packet.to_vec())).await.map_err(websocket_error),
+        }
+    }
+
+    /// Send the packet only if could be sent without waiting, return the len sent
+    pub fn poll_send(&mut self, cx: &mut Context<'_>, packet: &[u8]) -> Poll<io::Result<usize>> {
+        match self {
+            FrameStream::Kcp(s) => s.poll_send(cx, packet).map_err(io::Error::from),
+            FrameStream::Tcp(s) => {
+                let mut s = Pin::new(s);
+                match s.as_mut().poll_ready(cx) {
+                    Poll::Ready(Ok(())) => Poll::Ready(s.start_send(Bytes::copy_from_slice(packet)).map(|_| packet.len())),
+                    x => x.map(|x| x.map(|_| 0)),
+                }
+            }
+            FrameStream::WebSocket(s) => {
+                let mut s = Pin::new(s);
+                match s.as_mut().poll_ready(cx) {
+                    Poll::Ready(Ok(())) => Poll::Ready(s.start_send(Message::Binary(packet.to_vec())).map(|_| packet.len()).map_err(websocket_error)),
+                    x => x.map(|x| x.map(|_| 0).map_err(websocket_error)),
+                }
+            }
+        }
+    }
+
+    pub async fn flush(&mut self) -> io::Result<()> {
+        match self {
+            FrameStream::Kcp(s) => s.flush().await,
+            FrameStream::Tcp(s) => SinkExt::flush(s).await,
+            FrameStream::WebSocket(s) => SinkExt::flush(s).await.map_err(websocket_error),
+        }
+    }
+
+    /// Receive a packet into the buffer, return the len.
+    ///
+    /// [`io::ErrorKind::UnexpectedEof`] if the other side closed the tcp or websocket.
+    pub async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = match self {
+            FrameStream::Kcp(s) => return s.recv(buf).await.map_err(io::Error::from),
+            FrameStream::Tcp(s) => match s.next().await {
+                Some(x) => x?.to_vec(),
+                None => return Err(io::ErrorKind::UnexpectedEof.into()),
+            },
+            FrameStream::WebSocket(s) => loop {
+                match s.next().await {
+                    Some(Ok(Message::Binary(data))) => break data,
+                    Some(Ok(Message::Close(_))) | None => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    // ping is replied by the next flush
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(websocket_error(e)),
+                }
+            },
+        };
+        if data.len() > buf.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Packet of {} bytes is too large", data.len())));
+        }
+        buf[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+}
@@ -0,0 +1,105 @@
+//! The commands of the bank staff, run instead of the server like `bank_server account freeze <account> <reason>`.
+//!
+//! The account is the number shown to the customers, the changes are recorded with the reason
+//! and the actor `staff@<user>` by the env `USER`.
+//!
+//! The customers logged in are notified by the running server, see [`BankServer::queue_notice`].
+
+use anyhow::{anyhow, bail};
+use bank_protocol::{account, NotifyKind};
+
+use crate::bank::UserInputError;
+use crate::bank::lifecycle::AccountStatus;
+use crate::bank::server::{BankServer, FeeClass};
+use crate::bank::service;
+use crate::config::Config;
+
+pub const USAGE: &str = "Usage: bank_server [--config <path>] account <status|freeze|unfreeze> <account> [<reason>]
+       bank_server [--config <path>] account close <account> <payout account> <reason>
+       bank_server [--config <path>] account <product|segment> <account> <value>";
+
+/// Status changes shown by `account status`
+const HISTORY_LIMIT: u32 = 10;
+
+pub async fn run(config: &Config, args: &[String]) -> anyhow::Result<()> {
+    let (command, id, rest) = match args {
+        [command, id, rest @ ..] => (command.as_str(), parse(id)?, rest),
+        _ => bail!(USAGE),
+    };
+    let server = BankServer::new(&config.database).await?;
+    let result = execute(&server, command, id, rest).await;
+    server.close().await;
+    // the errors of the customers are in chinese
+    result.map_err(|e| match e.downcast_ref::<UserInputError>() {
+        Some(x) => anyhow!("{}", x),
+        None => e,
+    })
+}
+
+async fn execute(server: &BankServer, command: &str, id: u32, rest: &[String]) -> anyhow::Result<()> {
+    let actor = format!("staff@{}", std::env::var("USER").unwrap_or_default());
+    match (command, rest) {
+        ("status", []) => {
+            let mut sql = server.0.sql_pool.acquire().await?;
+            let user = service::get_user(&mut sql, id).await?;
+            println!("{} {}, product {}, segment {}", account::format_account_number(id), server.account_status(id).await?.name(), user.product, user.segment);
+            for x in server.status_changes(id, HISTORY_LIMIT).await? {
+                println!("{} {} -> {} by {}: {}", x.time.format("%Y-%m-%d %H:%M:%S"), x.from.name(), x.to.name(), x.actor, x.reason);
+            }
+        }
+        ("freeze", [reason]) => {
+            check_reason(reason)?;
+            server.set_status(id, AccountStatus::Frozen, reason, &actor).await?;
+            queue_notice(server, id, NotifyKind::Admin, 0, "", &format!("账户已冻结：{}", reason)).await;
+            println!("Frozen");
+        }
+        ("unfreeze", [reason]) => {
+            check_reason(reason)?;
+            if server.account_status(id).await? != AccountStatus::Frozen {
+                bail!("The account is not frozen");
+            }
+            server.set_status(id, AccountStatus::Active, reason, &actor).await?;
+            queue_notice(server, id, NotifyKind::Admin, 0, "", &format!("账户已解冻：{}", reason)).await;
+            println!("Unfrozen");
+        }
+        ("close", [payout, reason]) => {
+            check_reason(reason)?;
+            let payout = parse(payout)?;
+            let paid = server.close_account(id, payout, reason, &actor).await?;
+            queue_notice(server, id, NotifyKind::Admin, paid, &account::format_account_number(payout), &format!("账户已销户：{}", reason)).await;
+            if paid > 0 {
+                queue_notice(server, payout, NotifyKind::Credit, paid, &account::format_account_number(id), "销户转入").await;
+            }
+            println!("Closed and paid {}", service::format_cents(paid));
+        }
+        ("product" | "segment", [value]) => {
+            if value.is_empty() || value.len() > 20 {
+                bail!("The {} should be 1 to 20 bytes", command);
+            }
+            let field = if command == "product" { FeeClass::Product } else { FeeClass::Segment };
+            server.set_fee_class(id, field, value).await?;
+            println!("Changed the {}", command);
+        }
+        _ => bail!(USAGE),
+    }
+    Ok(())
+}
+
+/// The change is committed already, only tell the staff if the customer is not notified
+async fn queue_notice(server: &BankServer, id: u32, kind: NotifyKind, amount: u32, counterpart: &str, msg: &str) {
+    if let Err(e) = server.queue_notice(id, kind, amount, counterpart, msg).await {
+        eprintln!("Notify {} failed for {:?}", account::format_account_number(id), e);
+    }
+}
+
+/// Saved in `bank_status_changes.reason`
+fn check_reason(reason: &str) -> anyhow::Result<()> {
+    if reason.trim().is_empty() || reason.len() > 200 {
+        bail!("The reason should be 1 to 200 bytes");
+    }
+    Ok(())
+}
+
+fn parse(input: &str) -> anyhow::Result<u32> {
+    account::parse_account_number(input).map_err(|e| anyhow!("Bad account {}: {}", input, e.msg()))
+}
@@ -0,0 +1,198 @@
+//! The fees of the operations by the rules of the `[fees]` config, changed live by the reload.
+//!
+//! The first rule matching the operation, the channel, the product of the account and the amount
+//! decides the fee: `fixed + amount * basis_points / 10000`, then clamped by `min_fee` and `max_fee`.
+//! No fee if no rule matched. The customer segments in `waivers` pay nothing for the operations listed.
+//!
+//! ```toml
+//! [[fees.rules]]
+//! id = "transfer-large"
+//! operation = "transfer"
+//! min_amount = 5000
+//! basis_points = 10
+//! min_fee = 1
+//!
+//! [fees.waivers]
+//! vip = ["*"]
+//! ```
+//!
+//! The fee is posted as its own trade log, with the `parent` of the operation charged.
+
+use std::collections::BTreeMap;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+/// The operations could have fees
+pub const FEE_OPERATIONS: [&str; 2] = ["withdraw", "transfer"];
+
+/// The sender of the trade logs of the fees
+pub const FEE_SENDER: &str = "手续费";
+
+/// Waive all the operations for the segment
+const ALL_OPERATIONS: &str = "*";
+
+/// Where the operation is requested
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    /// The connections of the client
+    Client,
+    /// The http gateway
+    Gateway,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeeRule {
+    /// Logged with the fee charged
+    pub id: String,
+    pub operation: String,
+    /// Any if not given
+    pub channel: Option<Channel>,
+    /// The product of the account, any if not given
+    pub product: Option<String>,
+    /// The amount band in cents, both inclusive
+    pub min_amount: u32,
+    pub max_amount: Option<u32>,
+    pub fixed: u32,
+    /// Of the amount, in 1/10000
+    pub basis_points: u32,
+    pub min_fee: u32,
+    pub max_fee: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeeSchedule {
+    /// Checked in order
+    pub rules: Vec<FeeRule>,
+    /// The operations free for the customer segments, like `vip = ["transfer"]`, `"*"` for all
+    pub waivers: BTreeMap<String, Vec<String>>,
+}
+
+/// The fee of one operation
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Quote {
+    pub fee: u32,
+    /// The id of the rule matched
+    pub rule: Option<String>,
+    /// Matched but waived for the segment
+    pub waived: bool,
+}
+
+impl FeeRule {
+    fn matches(&self, operation: &str, amount: u32, product: &str, channel: Channel) -> bool {
+        self.operation == operation
+            && self.channel.is_none_or(|x| x == channel)
+            && self.product.as_deref().is_none_or(|x| x == product)
+            && amount >= self.min_amount
+            && self.max_amount.is_none_or(|x| amount <= x)
+    }
+
+    fn fee(&self, amount: u32) -> u32 {
+        let fee = self.fixed as u64 + amount as u64 * self.basis_points as u64 / 10000;
+        fee.max(self.min_fee as u64).min(self.max_fee.unwrap_or(u32::MAX) as u64) as u32
+    }
+}
+
+impl FeeSchedule {
+    pub fn quote(&self, operation: &str, amount: u32, product: &str, segment: &str, channel: Channel) -> Quote {
+        let Some(rule) = self.rules.iter().find(|x| x.matches(operation, amount, product, channel)) else {
+            return Quote::default();
+        };
+        let waived = self.waivers.get(segment)
+            .is_some_and(|x| x.iter().any(|x| x == operation || x == ALL_OPERATIONS));
+        Quote {
+            fee: if waived { 0 } else { rule.fee(amount) },
+            rule: Some(rule.id.clone()),
+            waived,
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (i, rule) in self.rules.iter().enumerate() {
+            if rule.id.is_empty() || self.rules[..i].iter().any(|x| x.id == rule.id) {
+                bail!("fees.rules should have the unique ids, the rule {} is not", i + 1);
+            }
+            if !FEE_OPERATIONS.contains(&rule.operation.as_str()) {
+                bail!("fees.rules of {} should be for {}", rule.id, FEE_OPERATIONS.join(" or "));
+            }
+            if rule.max_amount.is_some_and(|x| x < rule.min_amount) || rule.max_fee.is_some_and(|x| x < rule.min_fee) {
+                bail!("fees.rules of {} should have the max not less than the min", rule.id);
+            }
+        }
+        for (segment, operations) in &self.waivers {
+            if let Some(x) = operations.iter().find(|x| *x != ALL_OPERATIONS && !FEE_OPERATIONS.contains(&x.as_str())) {
+                bail!("fees.waivers of {} has unknown operation {}", segment, x);
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::bank::fee::{Channel, FeeRule, FeeSchedule, Quote};
+
+    fn schedule() -> FeeSchedule {
+        toml::from_str(r#"
+[[rules]]
+id = "gateway-withdraw"
+operation = "withdraw"
+channel = "gateway"
+fixed = 50
+
+[[rules]]
+id = "transfer-business"
+operation = "transfer"
+product = "business"
+fixed = 10
+
+[[rules]]
+id = "transfer-large"
+operation = "transfer"
+min_amount = 5000
+basis_points = 10
+min_fee = 1
+max_fee = 20
+
+[waivers]
+vip = ["*"]
+staff = ["withdraw"]
+"#).unwrap()
+    }
+
+    #[test]
+    fn quote() {
+        let fees = schedule();
+        fees.validate().unwrap();
+        assert_eq!(fees.quote("withdraw", 100, "standard", "retail", Channel::Client), Quote::default());
+        assert_eq!(fees.quote("withdraw", 100, "standard", "retail", Channel::Gateway).fee, 50);
+        // the first rule matched wins
+        assert_eq!(fees.quote("transfer", 9000, "business", "retail", Channel::Client).fee, 10);
+        assert_eq!(fees.quote("transfer", 4999, "standard", "retail", Channel::Client).fee, 0);
+        assert_eq!(fees.quote("transfer", 5000, "standard", "retail", Channel::Client).fee, 5);
+        assert_eq!(fees.quote("transfer", 500, "business", "retail", Channel::Client).rule.as_deref(), Some("transfer-business"));
+        assert_eq!(fees.quote("transfer", 1000000, "standard", "retail", Channel::Client).fee, 20);
+        let waived = fees.quote("transfer", 9000, "standard", "vip", Channel::Client);
+        assert_eq!((waived.fee, waived.waived), (0, true));
+        assert_eq!(fees.quote("transfer", 9000, "standard", "staff", Channel::Client).fee, 9);
+        assert_eq!(fees.quote("withdraw", 1, "standard", "staff", Channel::Gateway).fee, 0);
+    }
+
+    #[test]
+    fn invalid() {
+        let rule = FeeRule { id: "a".into(), operation: "transfer".into(), ..Default::default() };
+        let mut fees = FeeSchedule { rules: vec![rule.clone(), rule.clone()], ..Default::default() };
+        assert!(fees.validate().is_err());
+        fees.rules = vec![FeeRule { operation: "deposit".into(), ..rule.clone() }];
+        assert!(fees.validate().is_err());
+        fees.rules = vec![FeeRule { min_amount: 2, max_amount: Some(1), ..rule.clone() }];
+        assert!(fees.validate().is_err());
+        fees.rules = vec![rule];
+        fees.waivers.insert("vip".into(), vec!["deposit".into()]);
+        assert!(fees.validate().is_err());
+    }
+}
@@ -1,319 +1,581 @@
 use std::future::Future;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
 use anyhow::anyhow;
-use bytes::{Buf, BufMut};
-use chrono::{DateTime, Utc};
+use bank_protocol::{account, AuthRequest, Capabilities, ErrorCode, Message, PayeeEntry, phone, RecentEntry, Request, Response, TotpCode};
+use futures_util::future::BoxFuture;
+use rand::Rng;
 use log::info;
-use sqlx::{MySql, query, Row};
-use sqlx::pool::PoolConnection;
 
-use crate::bank::{BankServer, UserInputError};
-use crate::bank::ext::{PacketReadExt, PacketWriteExt};
+use crate::bank::{BankServer, Caller, send_response, service, UserInputError};
+use crate::bank::fee::Channel;
+use crate::bank::lifecycle::AccountStatus;
+use crate::bank::risk::Signals;
+use crate::bank::router::{Context, Router};
+use crate::bank::server::TotpRecord;
+use crate::bank::service::LoginResult;
+use crate::bank::totp;
 use crate::bank::user::User;
-use crate::network::NetworkMessage;
 use crate::network::peer::Peer;
+use crate::telemetry::Redacted;
 
 pub trait BankDataHandler: Send + 'static {
-    fn handle<'a, 'b: 'a>(&'b mut self, server: &'a BankServer, src: &'a Peer, data: &'a [u8])
+    fn handle<'a, 'b: 'a>(&'b mut self, server: &'a BankServer, src: &'a Caller<'a>, data: &'a [u8])
                           -> Box<dyn Future<Output=anyhow::Result<Option<Box<dyn BankDataHandler>>>> + Send + Unpin + 'a>;
+
+    /// The name of the request for the metrics, the packet is not checked yet
+    fn operation(&self, data: &[u8]) -> &'static str;
+
+    /// The account logged in this state
+    fn account(&self) -> Option<u32> {
+        None
+    }
 }
 
-/// Login packet (from client to server): (id: u32 be) (password: u32 be)
+pub type HandlerResult = anyhow::Result<Option<Box<dyn BankDataHandler>>>;
+
+/// Handle [`AuthRequest`], login or register.
 ///
-/// Register packet: (id: u32) (password: u32) (name: String) (phone_number: String)
+/// If the account enabled totp, server replies b"totp" and waits for the code by [`HandleTotpLogin`]
 #[derive(Default)]
-pub struct HandleLogin {}
+pub struct HandleLogin {
+    /// Negotiated in the hello
+    capabilities: Capabilities,
+}
 
+impl HandleLogin {
+    pub fn new(capabilities: Capabilities) -> Self {
+        Self { capabilities }
+    }
+}
 
-async fn get_user_login(mut sql: PoolConnection<MySql>, id: u32, pswd: i32) -> anyhow::Result<User> {
-    let result = query("SELECT * FROM bank_user WHERE id=? AND password=?").bind(id).bind(pswd)
-        .fetch_optional(sql.as_mut()).await?;
-    let result = if let Some(result) = result {
-        result
-    } else {
-        Err(UserInputError::new("账号或密码错误"))?
-    };
+/// Saved payees allowed for one customer
+const MAX_PAYEES: i64 = 50;
+/// Recent recipients suggested in transfer
+const RECENT_RECIPIENTS: u32 = 5;
 
-    let user = User {
-        id,
-        balance: result.get("balance"),
-        name: result.get::<Option<&str>, _>("name").unwrap_or("").to_string(),
-        phone: result.get::<Option<&str>, _>("phone_number").unwrap_or("").to_string(),
-    };
+/// How long the confirmed recipient is valid
+const TRANSFER_CONFIRM_TIMEOUT: Duration = Duration::from_secs(120);
 
-    Ok(user)
+/// Wrong codes allowed before disconnecting
+const MAX_TOTP_TRIES: u32 = 5;
+
+/// Handle [`TotpCode`], the code could be the totp code or one recovery code.
+pub struct HandleTotpLogin {
+    user: User,
+    capabilities: Capabilities,
+    tries: u32,
 }
 
-async fn get_user(mut sql: &mut PoolConnection<MySql>, id: u32) -> anyhow::Result<User> {
-    let result = query("SELECT * FROM bank_user WHERE id=?").bind(id)
-        .fetch_optional(sql.as_mut()).await?;
-    let result = if let Some(result) = result {
-        result
-    } else {
-        Err(UserInputError::new("找不到账号"))?
-    };
+impl HandleTotpLogin {
+    pub fn new(user: User, capabilities: Capabilities) -> Self {
+        Self { user, capabilities, tries: 0 }
+    }
+}
 
-    let user = User {
-        id,
-        balance: result.get("balance"),
-        name: result.get::<Option<&str>, _>("name").unwrap_or("").to_string(),
-        phone: result.get::<Option<&str>, _>("phone_number").unwrap_or("").to_string(),
-    };
+/// Only the clients with notifications are pushed
+async fn register_session(server: &BankServer, src: &Peer, id: u32, capabilities: Capabilities) {
+    if capabilities.contains(Capabilities::NOTIFICATIONS) {
+        server.0.sessions.register(id, src).await;
+    }
+}
 
-    Ok(user)
+fn send_menu(src: &Caller<'_>, user: &User) -> anyhow::Result<()> {
+    send_response(src, &Response::Menu(user.info()))
 }
 
+/// The dormant account is active again, the frozen or closed one is told what is still allowed
+async fn check_status(server: &BankServer, src: &Caller<'_>, id: u32) -> anyhow::Result<()> {
+    match service::logged_in(server, id, Some(src.addr.ip())).await? {
+        AccountStatus::Frozen => send_tip(src, "账户已冻结，只能查询和修改密码，如有疑问请联系银行"),
+        AccountStatus::Closed => send_tip(src, "账户已销户，只能查询记录"),
+        _ => Ok(()),
+    }
+}
+
+
 impl BankDataHandler for HandleLogin {
-    fn handle<'a, 'b: 'a>(&'b mut self, server: &'a BankServer, src: &'a Peer, mut data: &'a [u8])
+    fn operation(&self, data: &[u8]) -> &'static str {
+        // the same as AuthRequest::decode
+        if data.len() == 8 { "login" } else { "register" }
+    }
+
+    fn handle<'a, 'b: 'a>(&'b mut self, server: &'a BankServer, src: &'a Caller<'a>, data: &'a [u8])
                           -> Box<dyn Future<Output=anyhow::Result<Option<Box<dyn BankDataHandler>>>> + Send + Unpin + 'a>
     {
-        match data.len() {
-            8 => {
-                // login
-                let id = data.get_u32();
-                let pswd = data.get_i32();
-                let task = async move {
-                    let sql = server.0.sql_pool.acquire().await?;
-                    let user = get_user_login(sql, id, pswd).await?;
-
-                    let mut data = vec![];
-                    data.add_header();
-                    // Return main menu with info (b"menu") (id: u32) (name: String) (balance: u32) (phone_number: String)
-                    data.extend_from_slice(b"menu");
-                    data.extend_from_slice(&id.to_be_bytes());
-                    data.write_string(&user.name);
-                    data.extend_from_slice(&user.balance.to_be_bytes());
-                    data.write_string(&user.phone);
-                    src.sender.send(NetworkMessage::Rely(data))?;
-
-                    info!("Logged user: {}", &user.name);
-                    Ok(Some(Box::new(LoggedHandler::new(user)) as _))
-                };
-                Box::new(Box::pin(task))
-            }
-            n if n > 8 => {
-                // register
-                let id = data.get_u32();
-                let pswd = data.get_i32();
-
-
-                let task = async move {
-                    let name = data.read_packet_string()?;
-                    let phone = data.read_packet_string()?;
-                    if name.len() > 60 || phone.len() > 20 {
-                        Err(UserInputError::new("输入长度错误"))?
-                    }
-
-                    let mut sql_connection = server.0.sql_pool.acquire().await?;
-                    let result = query("SELECT * FROM bank_user WHERE id=?").bind(id)
-                        .fetch_optional(sql_connection.as_mut()).await?;
-
-                    if result.is_some() {
-                        Err(UserInputError::new("该银行账号存在"))?;
-                    }
-                    info!("Now insert id {} into sql", id);
-
-                    query("INSERT INTO bank_user VALUES(?, ?, ?, ?, ?);")
-                        .bind(id)
-                        .bind(pswd)
-                        .bind(0)
-                        .bind(&name)
-                        .bind(&phone)
-                        .execute(sql_connection.as_mut()).await?;
-
-
-                    let mut data = vec![];
-                    data.add_header();
-                    // Return main menu with info (b"menu") (id: u32) (name: String) (balance: u32) (phone_number: String)
-                    data.extend_from_slice(b"menu");
-                    data.extend_from_slice(&id.to_be_bytes());
-                    data.write_string(&name);
-                    data.extend_from_slice(bytemuck::cast_slice(&[0u32]));
-                    data.write_string(&phone);
-                    src.sender.send(NetworkMessage::Rely(data))?;
-
-
-                    info!("Register user: {}", name);
-                    Ok(Some(Box::new(LoggedHandler::new(User {
-                        id,
-                        balance: 0,
-                        name,
-                        phone,
-                    })) as _))
-                };
-                Box::new(Box::pin(task))
+        let task = async move {
+            match AuthRequest::from_content(data)? {
+                AuthRequest::Login { id, password } => {
+                    let user = match service::login(server, id, password).await? {
+                        LoginResult::Logged(user) => user,
+                        LoginResult::TotpRequired(user) => {
+                            send_response(src, &Response::TotpRequired)?;
+                            info!("User {} need totp code", id);
+                            return Ok(Some(Box::new(HandleTotpLogin::new(user, self.capabilities)) as _));
+                        }
+                    };
+
+                    send_menu(src, &user)?;
+                    check_status(server, src, user.id).await?;
+                    register_session(server, src, user.id, self.capabilities).await;
+                    info!("Logged user: {}", Redacted::Name(&user.name));
+                    Ok(Some(Box::new(LoggedHandler::new(user, self.capabilities)) as _))
+                }
+                AuthRequest::Register { password, name, phone } => {
+                    let user = service::register(server, password, name, phone).await?;
+                    send_menu(src, &user)?;
+
+                    register_session(server, src, user.id, self.capabilities).await;
+                    info!("Register user: {}", Redacted::Name(&user.name));
+                    Ok(Some(Box::new(LoggedHandler::new(user, self.capabilities)) as _))
+                }
             }
-            _ => {
-                Box::new(Box::pin(async { Err(anyhow!("Not correct len")) }))
+        };
+        Box::new(Box::pin(task))
+    }
+}
+
+impl BankDataHandler for HandleTotpLogin {
+    fn operation(&self, _data: &[u8]) -> &'static str {
+        "totp_login"
+    }
+
+    fn handle<'a, 'b: 'a>(&'b mut self, server: &'a BankServer, src: &'a Caller<'a>, data: &'a [u8])
+                          -> Box<dyn Future<Output=anyhow::Result<Option<Box<dyn BankDataHandler>>>> + Send + Unpin + 'a>
+    {
+        let task = async move {
+            let TotpCode { code } = TotpCode::from_content(data)?;
+            if !server.check_totp(self.user.id, &code, true).await? {
+                self.tries += 1;
+                if self.tries >= MAX_TOTP_TRIES {
+                    info!("Too many wrong totp codes for {}", self.user.id);
+                    Err(UserInputError::fatal(ErrorCode::TooManyTotpTries))?
+                }
+                Err(UserInputError::new(ErrorCode::WrongTotpCode))?
             }
-        }
+            send_menu(src, &self.user)?;
+            check_status(server, src, self.user.id).await?;
+            register_session(server, src, self.user.id, self.capabilities).await;
+            info!("Logged user with totp: {}", Redacted::Name(&self.user.name));
+            Ok(Some(Box::new(LoggedHandler::new(self.user.clone(), self.capabilities)) as _))
+        };
+        Box::new(Box::pin(task))
     }
 }
 
 
-/// Client to server:
-/// * Deposit packet: \0 amount:u32
-/// * Withdraw packet: \1 amount: u32
-/// * transfer packet: \2 target: u32, amount: u32
-/// * info packet: \3
-///
-/// Server to client
-/// * b"info" current_page:u32, total_page:u32, info_cnt: u32 <User>
-/// * * info: tid: i32, receiver: u32 sender: String, time: (i64 u32), amount: i32
-///
+/// Handle [`Request`] after logged in.
 pub struct LoggedHandler {
     user: User,
+    /// Negotiated in the hello, kept for the login after logged out
+    capabilities: Capabilities,
+    /// The recipient resolved and shown to the user, waiting for the transfer
+    pending_transfer: Option<PendingTransfer>,
+    /// Identified by the client after logged in
+    device: Option<String>,
+}
+
+struct PendingTransfer {
+    token: String,
+    target: u32,
+    amount: u32,
+    expire: Instant,
 }
 
 impl LoggedHandler {
-    pub fn new(user: User) -> Self {
-        Self { user }
+    pub fn new(user: User, capabilities: Capabilities) -> Self {
+        Self { user, capabilities, pending_transfer: None, device: None }
+    }
+
+    fn signals(&self, src: &Caller<'_>) -> Signals {
+        Signals { channel: Channel::Client, ip: Some(src.addr.ip()), device: self.device.clone() }
     }
+
+    async fn send_payees(&self, server: &BankServer, src: &Caller<'_>, for_edit: bool) -> anyhow::Result<()> {
+        let payees = server.get_payees(self.user.id).await?;
+        let recent = server.recent_recipients(self.user.id, RECENT_RECIPIENTS).await?;
+
+        // saved payees are not suggested again
+        let recent = recent.into_iter()
+            .filter(|(id, _)| payees.iter().all(|x| x.account != *id))
+            .map(|(id, time)| RecentEntry { account: account::format_account_number(id), time })
+            .collect();
+        let payees = payees.into_iter()
+            .map(|x| PayeeEntry {
+                pid: x.pid,
+                nickname: x.nickname,
+                account: account::format_account_number(x.account),
+                last_used: x.last_used,
+            })
+            .collect();
+        send_response(src, &Response::Payees { for_edit, payees, recent })
+    }
+}
+
+fn send_tip(src: &Caller<'_>, msg: &str) -> anyhow::Result<()> {
+    send_response(src, &Response::Tip(msg.to_string()))
+}
+
+/// Check the payee nickname and account
+async fn check_payee(server: &BankServer, nickname: &str, target: &str) -> anyhow::Result<(String, u32)> {
+    let nickname = nickname.trim().to_string();
+    if nickname.is_empty() || nickname.len() > 60 {
+        Err(UserInputError::new(ErrorCode::BadPayeeNickname))?
+    }
+    let target = match account::parse_account_number(target) {
+        Ok(target) => target,
+        Err(e) => Err(UserInputError::with_params(ErrorCode::BadAccountNumber, vec![e.msg().to_string()]))?
+    };
+    let mut sql_connection = server.0.sql_pool.acquire().await?;
+    // make sure the account exists
+    service::get_user(&mut sql_connection, target).await?;
+    Ok((nickname, target))
 }
 
 impl BankDataHandler for LoggedHandler {
-    fn handle<'a, 'b: 'a>(&'b mut self, server: &'a BankServer, src: &'a Peer, mut data: &'a [u8])
+    fn operation(&self, data: &[u8]) -> &'static str {
+        logged_router().operation(data)
+    }
+
+    fn account(&self) -> Option<u32> {
+        Some(self.user.id)
+    }
+
+    fn handle<'a, 'b: 'a>(&'b mut self, server: &'a BankServer, src: &'a Caller<'a>, data: &'a [u8])
                           -> Box<dyn Future<Output=anyhow::Result<Option<Box<dyn BankDataHandler>>>> + Send + Unpin + 'a>
     {
-        if data.len() < 1 {
-            return Box::new(Box::pin(async {
-                Err(anyhow!("Wrong packet length"))
-            }));
-        }
-        let packet_type = data.get_u8();
+        Box::new(logged_router().dispatch(self, Context { server, src }, data))
+    }
+}
 
-        let task = async move {
-            match packet_type {
-                0 if data.len() == 4 => {
-                    // deposit
-                    let amount = data.get_u32();
-                    info!("Deposit {}", amount);
-                    if self.user.balance + amount > 10000 {
-                        Err(UserInputError::new("超出存款上限"))?
-                    }
-                    let mut sql_connection = server.0.sql_pool.acquire().await?;
-                    let result = query("UPDATE bank_user SET balance=balance+? WHERE id=?")
-                        .bind(amount)
-                        .bind(self.user.id)
-                        .execute(sql_connection.as_mut()).await?;
-                    info!("Deposit result: {:?}", result);
-                    server.insert_trade_log(self.user.id, "存款", amount as i32).await?;
-
-                    self.user = get_user(&mut sql_connection, self.user.id).await?;
-                    let mut data = vec![];
-                    data.add_header();
-                    // Return main menu with info (b"menu") (id: u32) (name: String) (balance: u32) (phone_number: String)
-                    data.extend_from_slice(b"menu");
-                    data.extend_from_slice(&self.user.id.to_be_bytes());
-                    data.write_string(&self.user.name);
-                    data.put_u32(self.user.balance);
-                    data.write_string(&self.user.phone);
-                    src.sender.send(NetworkMessage::Rely(data))?;
-                    Ok(None)
-                }
-                1 if data.len() == 4 => {
-                    // Withdraw
-                    let amount = data.get_u32();
-                    info!("Withdraw {}", amount);
-                    if self.user.balance < amount {
-                        Err(UserInputError::new("超出存款上限"))?
-                    }
-                    let mut sql_connection = server.0.sql_pool.acquire().await?;
-                    let result = query("UPDATE bank_user SET balance=balance-? WHERE id=?")
-                        .bind(amount)
-                        .bind(self.user.id)
-                        .execute(sql_connection.as_mut()).await?;
-                    info!("withdraw result: {:?}", result);
-                    server.insert_trade_log(self.user.id, "取款", -(amount as i32)).await?;
-
-
-                    self.user = get_user(&mut sql_connection, self.user.id).await?;
-                    let mut data = vec![];
-                    data.add_header();
-                    // Return main menu with info (b"menu") (id: u32) (name: String) (balance: u32) (phone_number: String)
-                    data.extend_from_slice(b"menu");
-                    data.extend_from_slice(&self.user.id.to_be_bytes());
-                    data.write_string(&self.user.name);
-                    data.put_u32(self.user.balance);
-                    data.write_string(&self.user.phone);
-                    src.sender.send(NetworkMessage::Rely(data))?;
-                    Ok(None)
-                }
-                2 if data.len() == 8 => {
-                    let target = data.get_u32();
-                    let amount = data.get_u32();
-                    let mut sql_connection = server.0.sql_pool.acquire().await?;
-                    let target_user = get_user(&mut sql_connection, target).await?;
-                    if target_user.balance + amount > 10000 {
-                        Err(UserInputError::new("对方存款到达上限"))?
-                    }
-                    if self.user.balance < amount {
-                        Err(UserInputError::new("我方存款不足"))?
-                    }
-                    let _ = server.add_balance(target, &self.user.id.to_string(), amount).await?;
-
-                    let result = query("UPDATE bank_user SET balance=balance-? WHERE id=?")
-                        .bind(amount)
-                        .bind(self.user.id)
-                        .execute(sql_connection.as_mut()).await?;
-
-                    // Copied code from packet type 1
-                    {
-                        self.user = get_user(&mut sql_connection, self.user.id).await?;
-                        let mut data = vec![];
-                        data.add_header();
-                        // Return main menu with info (b"menu") (id: u32) (name: String) (balance: u32) (phone_number: String)
-                        data.extend_from_slice(b"menu");
-                        data.extend_from_slice(&self.user.id.to_be_bytes());
-                        data.write_string(&self.user.name);
-                        data.put_u32(self.user.balance);
-                        data.write_string(&self.user.phone);
-                        src.sender.send(NetworkMessage::Rely(data))?;
-                    }
-                    Ok(None)
-                }
-                3 if data.len() == 0 => {
-                    let mut sql_connection = server.0.sql_pool.acquire().await?;
-                    let result = query("SELECT * FROM trade_logs WHERE receiver = ? OR sender = ?")
-                        .bind(self.user.id)
-                        .bind(&format!("{}", self.user.id))
-                        .fetch_all(sql_connection.as_mut()).await?;
-
-                    let mut packet_data: Vec<u8> = vec![];
-                    packet_data.add_header();
-                    packet_data.extend_from_slice(b"info");
-                    packet_data.put_u32(1);
-                    packet_data.put_u32(1);
-                    packet_data.put_u32(result.len() as u32);
-
-                    // user data
-                    packet_data.extend_from_slice(&self.user.id.to_be_bytes());
-                    packet_data.write_string(&self.user.name);
-                    packet_data.put_u32(self.user.balance);
-                    packet_data.write_string(&self.user.phone);
-
-                    for row in result {
-                        let tid: i32 = row.get("tid");
-                        let receiver = row.get::<i32, _>("receiver") as u32;
-                        let sender: String = row.get("sender");
-                        let time: DateTime<Utc> = row.get("time");
-                        let amount: i32 = row.get("amount");
-
-                        packet_data.put_i32(tid);
-                        packet_data.put_u32(receiver);
-                        packet_data.write_string(&sender);
-                        packet_data.put_i64(time.timestamp());
-                        packet_data.put_u32(time.timestamp_subsec_nanos());
-                        packet_data.put_i32(amount);
-                    }
-                    src.sender.send(NetworkMessage::Rely(packet_data))?;
-                    Ok(None)
+/// The commands after logged in by the type id of [`Request`], the new requests are routed here
+fn logged_router() -> &'static Router<LoggedHandler> {
+    static ROUTER: OnceLock<Router<LoggedHandler>> = OnceLock::new();
+    ROUTER.get_or_init(|| Router::default()
+        .route(0, "deposit", LoggedHandler::deposit)
+        .route(1, "withdraw", LoggedHandler::withdraw)
+        .route(2, "legacy_transfer", LoggedHandler::legacy_transfer)
+        .route(3, "info", LoggedHandler::info)
+        .route(4, "begin_totp", LoggedHandler::begin_totp)
+        .route(5, "confirm_totp", LoggedHandler::confirm_totp)
+        .route(6, "disable_totp", LoggedHandler::disable_totp)
+        .route(7, "transfer", LoggedHandler::transfer)
+        .route(8, "resolve_recipient", LoggedHandler::resolve_recipient)
+        .route(9, "list_payees", LoggedHandler::list_payees)
+        .route(10, "add_payee", LoggedHandler::add_payee)
+        .route(11, "edit_payee", LoggedHandler::edit_payee)
+        .route(12, "delete_payee", LoggedHandler::delete_payee)
+        .route(13, "link_phone", LoggedHandler::link_phone)
+        .route(14, "logout", LoggedHandler::logout)
+        .route(15, "change_password", LoggedHandler::change_password)
+        .route(16, "update_profile", LoggedHandler::update_profile)
+        .route(17, "close_account", LoggedHandler::close_account)
+        .route(18, "quote_fee", LoggedHandler::quote_fee)
+        .route(19, "identify_device", LoggedHandler::identify_device))
+}
+
+// the router only passes the request of the type routed
+impl LoggedHandler {
+    fn deposit<'a>(&'a mut self, cx: Context<'a>, request: Request) -> BoxFuture<'a, HandlerResult> {
+        let Request::Deposit { amount } = request else { unreachable!() };
+        Box::pin(async move {
+            service::deposit(cx.server, &mut self.user, amount, Some(cx.src.addr)).await?;
+            send_menu(cx.src, &self.user)?;
+            Ok(None)
+        })
+    }
+
+    fn withdraw<'a>(&'a mut self, cx: Context<'a>, request: Request) -> BoxFuture<'a, HandlerResult> {
+        let Request::Withdraw { amount, code } = request else { unreachable!() };
+        Box::pin(async move {
+            let signals = self.signals(cx.src);
+            service::withdraw(cx.server, &mut self.user, amount, code.as_deref().unwrap_or(""), &signals, Some(cx.src.addr)).await?;
+            send_menu(cx.src, &self.user)?;
+            Ok(None)
+        })
+    }
+
+    fn legacy_transfer<'a>(&'a mut self, _cx: Context<'a>, _request: Request) -> BoxFuture<'a, HandlerResult> {
+        Box::pin(async { Err(UserInputError::new(ErrorCode::LegacyTransfer).into()) })
+    }
+
+    fn info<'a>(&'a mut self, cx: Context<'a>, _request: Request) -> BoxFuture<'a, HandlerResult> {
+        Box::pin(async move {
+            // the client shows all in one page
+            let history = service::history(cx.server, self.user.id, 1, u32::MAX).await?;
+            send_response(cx.src, &Response::Info {
+                current_page: history.current_page,
+                total_page: history.total_page,
+                user: self.user.info(),
+                trades: history.trades,
+            })?;
+            Ok(None)
+        })
+    }
+
+    fn begin_totp<'a>(&'a mut self, cx: Context<'a>, _request: Request) -> BoxFuture<'a, HandlerResult> {
+        Box::pin(async move {
+            service::check_operation(cx.server, self.user.id, "begin_totp").await?;
+            let server = cx.server;
+            // the secret is pending until confirmed
+            if server.is_totp_enabled(self.user.id).await? {
+                Err(UserInputError::new(ErrorCode::TotpAlreadyEnabled))?
+            }
+            let secret = totp::generate_secret();
+            server.set_pending_totp(self.user.id, &secret).await?;
+
+            let uri = totp::provisioning_uri(&account::format_account_number(self.user.id), &secret);
+            send_response(cx.src, &Response::TotpBegin { secret, uri })?;
+            Ok(None)
+        })
+    }
+
+    fn confirm_totp<'a>(&'a mut self, cx: Context<'a>, request: Request) -> BoxFuture<'a, HandlerResult> {
+        let Request::ConfirmTotp { code } = request else { unreachable!() };
+        Box::pin(async move {
+            service::check_operation(cx.server, self.user.id, "confirm_totp").await?;
+            let server = cx.server;
+            // confirm totp with the first code
+            let (pending, last_step) = match server.get_totp(self.user.id).await? {
+                Some(TotpRecord { enabled: false, pending_secret: Some(pending), last_step, .. }) => (pending, last_step),
+                _ => Err(UserInputError::new(ErrorCode::TotpNotPending))?
+            };
+            let secret = totp::base32_decode(&pending).ok_or(anyhow!("Bad totp secret"))?;
+            let step = match server.0.totp.verify(&secret, &code, last_step) {
+                Some(step) => step,
+                None => Err(UserInputError::new(ErrorCode::WrongTotpCode))?
+            };
+            let codes = totp::generate_recovery_codes();
+            server.enable_totp(self.user.id, step, &codes).await?;
+
+            send_response(cx.src, &Response::RecoveryCodes(codes))?;
+            Ok(None)
+        })
+    }
+
+    fn disable_totp<'a>(&'a mut self, cx: Context<'a>, request: Request) -> BoxFuture<'a, HandlerResult> {
+        let Request::DisableTotp { code } = request else { unreachable!() };
+        Box::pin(async move {
+            service::check_operation(cx.server, self.user.id, "disable_totp").await?;
+            let server = cx.server;
+            if !server.is_totp_enabled(self.user.id).await? {
+                Err(UserInputError::new(ErrorCode::TotpNotEnabled))?
+            }
+            if !server.check_totp(self.user.id, &code, true).await? {
+                Err(UserInputError::new(ErrorCode::WrongTotpCode))?
+            }
+            server.disable_totp(self.user.id).await?;
+            send_menu(cx.src, &self.user)?;
+            Ok(None)
+        })
+    }
+
+    fn transfer<'a>(&'a mut self, cx: Context<'a>, request: Request) -> BoxFuture<'a, HandlerResult> {
+        let Request::Transfer { target, amount, token, code } = request else { unreachable!() };
+        Box::pin(async move {
+            // transfer with the confirmed token
+            let (target, _) = service::resolve_target(cx.server, &target).await?;
+            // the token is used once whatever the result
+            let pending = match self.pending_transfer.take() {
+                Some(pending) if pending.token == token => pending,
+                _ => Err(UserInputError::new(ErrorCode::RecipientNotConfirmed))?
+            };
+            if pending.expire < Instant::now() {
+                Err(UserInputError::new(ErrorCode::ConfirmExpired))?
+            }
+            if pending.target != target || pending.amount != amount {
+                Err(UserInputError::new(ErrorCode::ConfirmMismatch))?
+            }
+            let signals = self.signals(cx.src);
+            service::transfer(cx.server, &mut self.user, target, amount, code.as_deref().unwrap_or(""), &signals, Some(cx.src.addr)).await?;
+            send_menu(cx.src, &self.user)?;
+            Ok(None)
+        })
+    }
+
+    fn resolve_recipient<'a>(&'a mut self, cx: Context<'a>, request: Request) -> BoxFuture<'a, HandlerResult> {
+        let Request::ResolveRecipient { target, amount } = request else { unreachable!() };
+        Box::pin(async move {
+            service::check_operation(cx.server, self.user.id, "resolve_recipient").await?;
+            // resolve the recipient to confirm
+            let (target, normalized) = service::resolve_target(cx.server, &target).await?;
+            if amount == 0 {
+                Err(UserInputError::new(ErrorCode::BadAmount))?
+            }
+            if !cx.server.account_status(target).await?.accepts_credit() {
+                Err(UserInputError::new(ErrorCode::RecipientClosed))?
+            }
+            let mut sql_connection = cx.server.0.sql_pool.acquire().await?;
+            let target_user = service::get_user(&mut sql_connection, target).await?;
+
+            let token = format!("{:016x}", rand::thread_rng().gen::<u64>());
+            send_response(cx.src, &Response::Confirm {
+                token: token.clone(),
+                target: normalized,
+                masked_name: target_user.masked_name(),
+                amount,
+            })?;
+
+            self.pending_transfer = Some(PendingTransfer {
+                token,
+                target,
+                amount,
+                expire: Instant::now() + TRANSFER_CONFIRM_TIMEOUT,
+            });
+            Ok(None)
+        })
+    }
+
+    fn list_payees<'a>(&'a mut self, cx: Context<'a>, request: Request) -> BoxFuture<'a, HandlerResult> {
+        let Request::ListPayees { for_edit } = request else { unreachable!() };
+        Box::pin(async move {
+            self.send_payees(cx.server, cx.src, for_edit).await?;
+            Ok(None)
+        })
+    }
+
+    fn add_payee<'a>(&'a mut self, cx: Context<'a>, request: Request) -> BoxFuture<'a, HandlerResult> {
+        let Request::AddPayee { nickname, account } = request else { unreachable!() };
+        Box::pin(async move {
+            service::check_operation(cx.server, self.user.id, "add_payee").await?;
+            let server = cx.server;
+            let (nickname, target) = check_payee(server, &nickname, &account).await?;
+            if server.count_payees(self.user.id).await? >= MAX_PAYEES {
+                Err(UserInputError::with_params(ErrorCode::TooManyPayees, vec![MAX_PAYEES.to_string()]))?
+            }
+            if !server.add_payee(self.user.id, &nickname, target).await? {
+                Err(UserInputError::new(ErrorCode::PayeeExists))?
+            }
+            self.send_payees(server, cx.src, true).await?;
+            Ok(None)
+        })
+    }
+
+    fn edit_payee<'a>(&'a mut self, cx: Context<'a>, request: Request) -> BoxFuture<'a, HandlerResult> {
+        let Request::EditPayee { pid, nickname, account } = request else { unreachable!() };
+        Box::pin(async move {
+            service::check_operation(cx.server, self.user.id, "edit_payee").await?;
+            let server = cx.server;
+            let (nickname, target) = check_payee(server, &nickname, &account).await?;
+            if !server.update_payee(self.user.id, pid, &nickname, target).await? {
+                Err(UserInputError::new(ErrorCode::PayeeNotFoundOrExists))?
+            }
+            self.send_payees(server, cx.src, true).await?;
+            Ok(None)
+        })
+    }
+
+    fn delete_payee<'a>(&'a mut self, cx: Context<'a>, request: Request) -> BoxFuture<'a, HandlerResult> {
+        let Request::DeletePayee { pid } = request else { unreachable!() };
+        Box::pin(async move {
+            service::check_operation(cx.server, self.user.id, "delete_payee").await?;
+            if !cx.server.delete_payee(self.user.id, pid).await? {
+                Err(UserInputError::new(ErrorCode::PayeeNotFound))?
+            }
+            self.send_payees(cx.server, cx.src, true).await?;
+            Ok(None)
+        })
+    }
+
+    fn link_phone<'a>(&'a mut self, cx: Context<'a>, request: Request) -> BoxFuture<'a, HandlerResult> {
+        let Request::LinkPhone { link } = request else { unreachable!() };
+        Box::pin(async move {
+            service::check_operation(cx.server, self.user.id, "link_phone").await?;
+            let server = cx.server;
+            if link {
+                let phone = match phone::normalize_phone(&self.user.phone) {
+                    Some(phone) => phone,
+                    None => Err(UserInputError::new(ErrorCode::BadReservedPhone))?
+                };
+                if !server.link_phone(self.user.id, &phone).await? {
+                    Err(UserInputError::new(ErrorCode::PhoneLinkedElsewhere))?
                 }
-                _ => {
-                    Err(anyhow!("Wrong packet type in logged state."))
+                send_tip(cx.src, &format!("已开通手机号收款：{}", phone::mask_phone(&phone)))?;
+            } else {
+                if !server.unlink_phone(self.user.id).await? {
+                    Err(UserInputError::new(ErrorCode::PhoneAliasDisabled))?
                 }
+                send_tip(cx.src, "已关闭手机号收款")?;
             }
-        };
-        Box::new(Box::pin(task))
+            Ok(None)
+        })
+    }
+
+    fn logout<'a>(&'a mut self, cx: Context<'a>, _request: Request) -> BoxFuture<'a, HandlerResult> {
+        Box::pin(async move {
+            cx.server.0.sessions.unregister(self.user.id, cx.src.addr).await;
+            send_response(cx.src, &Response::LoggedOut)?;
+            info!("Logged out user: {}", Redacted::Name(&self.user.name));
+            Ok(Some(Box::new(HandleLogin::new(self.capabilities)) as _))
+        })
+    }
+
+    fn change_password<'a>(&'a mut self, cx: Context<'a>, request: Request) -> BoxFuture<'a, HandlerResult> {
+        let Request::ChangePassword { old, new, code } = request else { unreachable!() };
+        Box::pin(async move {
+            service::change_password(cx.server, self.user.id, old, new, code.as_deref().unwrap_or(""), Some(cx.src.addr)).await?;
+            send_tip(cx.src, "密码已修改，其他设备已退出登录")?;
+            send_menu(cx.src, &self.user)?;
+            // logged in again by the new password, not revoked with the others
+            let handler = LoggedHandler { device: self.device.take(), ..LoggedHandler::new(self.user.clone(), self.capabilities) };
+            Ok(Some(Box::new(handler) as _))
+        })
+    }
+
+    fn update_profile<'a>(&'a mut self, cx: Context<'a>, request: Request) -> BoxFuture<'a, HandlerResult> {
+        let Request::UpdateProfile { name, phone, password } = request else { unreachable!() };
+        Box::pin(async move {
+            let unlinked = service::update_profile(cx.server, &mut self.user, &name, &phone, password, Some(cx.src.addr)).await?;
+            if unlinked {
+                send_tip(cx.src, "手机号已修改，原手机号收款已关闭")?;
+            }
+            send_menu(cx.src, &self.user)?;
+            Ok(None)
+        })
+    }
+
+    fn close_account<'a>(&'a mut self, cx: Context<'a>, request: Request) -> BoxFuture<'a, HandlerResult> {
+        let Request::CloseAccount { payout, password, code } = request else { unreachable!() };
+        Box::pin(async move {
+            let (paid, payout) = service::close_account(cx.server, &mut self.user, &payout, password, code.as_deref().unwrap_or(""), Some(cx.src.addr)).await?;
+            send_tip(cx.src, &format!("账户已销户，余额 {} 已转入 {}，之后只能查询记录", service::format_cents(paid), payout))?;
+            send_menu(cx.src, &self.user)?;
+            info!("Closed account of user: {}", Redacted::Name(&self.user.name));
+            Ok(None)
+        })
+    }
+
+    fn quote_fee<'a>(&'a mut self, cx: Context<'a>, request: Request) -> BoxFuture<'a, HandlerResult> {
+        let Request::QuoteFee { operation, amount } = request else { unreachable!() };
+        Box::pin(async move {
+            service::check_operation(cx.server, self.user.id, "quote_fee").await?;
+            let quote = service::quote_fee(cx.server, &self.user, &operation, amount, Channel::Client)?;
+            send_response(cx.src, &Response::FeeQuote { operation, amount, fee: quote.fee })?;
+            Ok(None)
+        })
     }
-}
\ No newline at end of file
+
+    fn identify_device<'a>(&'a mut self, cx: Context<'a>, request: Request) -> BoxFuture<'a, HandlerResult> {
+        let Request::IdentifyDevice { device } = request else { unreachable!() };
+        Box::pin(async move {
+            // no reply, the client sends it without waiting
+            service::identify_device(cx.server, self.user.id, &device).await?;
+            self.device = Some(device);
+            Ok(None)
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::bank::handlers::logged_router;
+
+    #[test]
+    fn all_routed() {
+        // the type ids of Request
+        let routed: Vec<u8> = logged_router().operations().map(|(id, _)| id).collect();
+        assert_eq!(routed, (0..20).collect::<Vec<u8>>());
+    }
+}
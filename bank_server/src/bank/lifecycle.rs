@@ -0,0 +1,198 @@
+//! The status of the accounts, and the operations allowed in each.
+//!
+//! ```text
+//! active  <-> frozen     by the bank, like for fraud
+//! active   -> dormant    no login for `lifecycle.dormant_after_days`, see [`watch_dormancy`]
+//! dormant  -> active     logged in again
+//! dormant  -> frozen
+//! any      -> closed     the balance paid out to the nominated account, never changed again
+//! ```
+//!
+//! The frozen and the closed accounts could still log in and read the history.
+//! Every change is recorded in `bank_status_changes` with the reason and who changed it.
+//! The changes by the staff commands are pushed to the customers by the running server, see [`watch_notices`].
+
+use std::time::Duration;
+
+use bank_protocol::ErrorCode;
+use chrono::Utc;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use crate::bank::{BankServer, UserInputError};
+
+/// The operations allowed whatever the status, by the names of the router
+const READ_OPERATIONS: [&str; 4] = ["info", "list_payees", "logout", "identify_device"];
+
+/// The notices pushed by one check at most, the others wait for the next
+const NOTICE_BATCH: u32 = 100;
+
+/// Saved as the `status` of `bank_user`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub enum AccountStatus {
+    Active = 0,
+    Frozen = 1,
+    Dormant = 2,
+    Closed = 3,
+}
+
+impl AccountStatus {
+    pub fn from_u8(x: u8) -> Option<Self> {
+        Some(match x {
+            0 => AccountStatus::Active,
+            1 => AccountStatus::Frozen,
+            2 => AccountStatus::Dormant,
+            3 => AccountStatus::Closed,
+            _ => return None,
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AccountStatus::Active => "active",
+            AccountStatus::Frozen => "frozen",
+            AccountStatus::Dormant => "dormant",
+            AccountStatus::Closed => "closed",
+        }
+    }
+
+    /// The transitions documented above
+    pub fn can_change_to(self, to: AccountStatus) -> bool {
+        use AccountStatus::*;
+        matches!((self, to),
+            (Active, Frozen) | (Active, Dormant) | (Frozen, Active) | (Dormant, Active) | (Dormant, Frozen)
+            | (Active | Frozen | Dormant, Closed))
+    }
+
+    /// Whether the logged account could do the operation, the dormant ones are active again once logged
+    pub fn allows(self, op: &str) -> bool {
+        match self {
+            AccountStatus::Active | AccountStatus::Dormant => true,
+            AccountStatus::Frozen => is_read(op) || op == "change_password",
+            AccountStatus::Closed => is_read(op),
+        }
+    }
+
+    /// The frozen and the dormant accounts could still receive
+    pub fn accepts_credit(self) -> bool {
+        self != AccountStatus::Closed
+    }
+
+    /// Why the operation is refused
+    pub fn refusal(self) -> UserInputError {
+        match self {
+            AccountStatus::Closed => UserInputError::new(ErrorCode::AccountClosed),
+            _ => UserInputError::new(ErrorCode::AccountFrozen),
+        }
+    }
+}
+
+/// The operations not changing anything, never refused by the status
+pub fn is_read(op: &str) -> bool {
+    READ_OPERATIONS.contains(&op)
+}
+
+/// The settings of the lifecycle, need restart
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LifecycleConfig {
+    /// The active accounts without login for so long are dormant, 0 for never
+    pub dormant_after_days: u32,
+    pub dormancy_check_secs: u64,
+    /// How often to push the notices of the staff commands, see [`watch_notices`]
+    pub notice_check_millis: u64,
+}
+
+impl Default for LifecycleConfig {
+    fn default() -> Self {
+        Self { dormant_after_days: 365, dormancy_check_secs: 3600, notice_check_millis: 1000 }
+    }
+}
+
+/// Mark the inactive accounts dormant periodically until shutdown
+pub async fn watch_dormancy(server: BankServer, config: LifecycleConfig, shutdown: CancellationToken) {
+    if config.dormant_after_days == 0 {
+        info!("Dormancy detection is disabled");
+        return;
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(config.dormancy_check_secs));
+    let reason = format!("no login for {} days", config.dormant_after_days);
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => return,
+            _ = interval.tick() => {}
+        }
+        let before = Utc::now() - chrono::Duration::days(config.dormant_after_days as i64);
+        match server.mark_dormant(before, &reason).await {
+            Ok(0) => {}
+            Ok(count) => info!("Marked {} accounts dormant", count),
+            Err(e) => error!("Mark the dormant accounts failed for {:?}", e),
+        }
+    }
+}
+
+/// Push the notices queued by the staff commands to the logged sessions until shutdown.
+///
+/// The ones queued before started are skipped, their sessions are gone with the last server
+pub async fn watch_notices(server: BankServer, config: LifecycleConfig, shutdown: CancellationToken) {
+    let mut interval = tokio::time::interval(Duration::from_millis(config.notice_check_millis));
+    let mut last = None;
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => return,
+            _ = interval.tick() => {}
+        }
+        let Some(after) = last else {
+            match server.last_notice().await {
+                Ok(x) => last = Some(x),
+                Err(e) => error!("Read the last notice failed for {:?}", e),
+            }
+            continue;
+        };
+        match server.notices_after(after, NOTICE_BATCH).await {
+            Ok(notices) => for x in notices {
+                last = Some(x.nid);
+                server.notify(x.id, None, x.kind, x.amount, &x.counterpart, &x.msg).await;
+            },
+            Err(e) => error!("Read the queued notices failed for {:?}", e),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::bank::lifecycle::AccountStatus::{self, *};
+
+    #[test]
+    fn transitions() {
+        let all = [Active, Frozen, Dormant, Closed];
+        for x in all {
+            assert_eq!(AccountStatus::from_u8(x as u8), Some(x));
+            assert!(!x.can_change_to(x));
+            // closed is the end
+            assert!(!Closed.can_change_to(x));
+            assert_eq!(x.can_change_to(Closed), x != Closed);
+        }
+        assert!(Active.can_change_to(Dormant));
+        assert!(!Frozen.can_change_to(Dormant));
+        assert!(Dormant.can_change_to(Active));
+        assert_eq!(AccountStatus::from_u8(4), None);
+    }
+
+    #[test]
+    fn operations() {
+        assert!(Active.allows("transfer"));
+        assert!(Dormant.allows("withdraw"));
+        assert!(!Frozen.allows("transfer"));
+        assert!(Frozen.allows("change_password"));
+        assert!(Frozen.allows("info"));
+        assert!(!Closed.allows("change_password"));
+        assert!(Closed.allows("logout"));
+        assert!(Frozen.accepts_credit());
+        assert!(!Closed.accepts_credit());
+    }
+}
@@ -0,0 +1,282 @@
+//! The rate limits of the requests and the temporary bans of the abusing addresses.
+//!
+//! Each connection has a token bucket for all its requests, and one for each operation
+//! configured. The limited requests are replied with [`ErrorCode::RateLimited`], the address
+//! sending too many of them is banned for a while: the connection is closed with
+//! [`ErrorCode::TemporarilyBanned`] and the new ones are closed when accepted.
+//!
+//! The gateway has no connection, its buckets are kept by the address and by the logged account
+//! in [`Limiters`], and the failed logins of an address count toward its ban too.
+//!
+//! [`ErrorCode::RateLimited`]: bank_protocol::ErrorCode::RateLimited
+//! [`ErrorCode::TemporarilyBanned`]: bank_protocol::ErrorCode::TemporarilyBanned
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bank_protocol::ErrorCode;
+use log::{trace, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::bank::UserInputError;
+
+/// Forget the addresses not banned and not limited for long when there are so many
+const MAX_OFFENDERS: usize = 4096;
+/// Forget the buckets refilled when there are so many keys
+const MAX_LIMITERS: usize = 4096;
+
+/// `burst` requests at once, then `per_sec` requests every second
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rate {
+    pub burst: u32,
+    pub per_sec: f64,
+}
+
+impl Rate {
+    pub const fn new(burst: u32, per_sec: f64) -> Self {
+        Self { burst, per_sec }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    /// All the requests of one connection
+    pub session: Rate,
+    /// By the operation like `login` or `transfer`, the same names as the metrics
+    pub operations: BTreeMap<String, Rate>,
+    /// The limited requests from one address in the window to be banned, 0 to never ban
+    pub ban_after: u32,
+    pub ban_window_secs: u64,
+    pub ban_secs: u64,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        let operations = [
+            ("login", Rate::new(5, 0.2)),
+            ("totp_login", Rate::new(5, 0.2)),
+            ("register", Rate::new(3, 0.05)),
+            ("transfer", Rate::new(10, 1.0)),
+            ("resolve_recipient", Rate::new(10, 1.0)),
+            ("change_password", Rate::new(3, 0.05)),
+            ("close_account", Rate::new(3, 0.05)),
+        ];
+        Self {
+            session: Rate::new(30, 10.0),
+            operations: operations.into_iter().map(|(op, rate)| (op.to_string(), rate)).collect(),
+            ban_after: 100,
+            ban_window_secs: 60,
+            ban_secs: 600,
+        }
+    }
+}
+
+struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: Rate, now: Instant) -> Self {
+        Self { rate, tokens: rate.burst as f64, last: now }
+    }
+
+    /// Take one token, or return how long until there is one
+    fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate.per_sec).min(self.rate.burst as f64);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if self.rate.per_sec > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate.per_sec))
+        } else {
+            Err(Duration::MAX)
+        }
+    }
+
+    /// The same as a new one if so
+    fn full(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens + elapsed * self.rate.per_sec >= self.rate.burst as f64
+    }
+}
+
+/// The buckets of one connection, the limits are the ones when it is connected
+pub struct RateLimiter {
+    limits: Arc<RateLimits>,
+    session: TokenBucket,
+    operations: HashMap<&'static str, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: Arc<RateLimits>, now: Instant) -> Self {
+        Self { session: TokenBucket::new(limits.session, now), limits, operations: HashMap::new() }
+    }
+
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    /// Return how long to wait if limited
+    pub fn check(&mut self, op: &'static str, now: Instant) -> Result<(), Duration> {
+        self.session.take(now)?;
+        match self.limits.operations.get(op) {
+            Some(rate) => self.operations.entry(op).or_insert_with(|| TokenBucket::new(*rate, now)).take(now),
+            None => Ok(()),
+        }
+    }
+
+    fn full(&self, now: Instant) -> bool {
+        self.session.full(now) && self.operations.values().all(|x| x.full(now))
+    }
+}
+
+/// The buckets by the key like the address or the account, for the requests without a connection
+pub struct Limiters<K> {
+    limiters: Mutex<HashMap<K, RateLimiter>>,
+}
+
+impl<K> Default for Limiters<K> {
+    fn default() -> Self {
+        Self { limiters: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl<K: Eq + Hash> Limiters<K> {
+    /// Return how long to wait if limited, the buckets of the limits before reloaded are replaced
+    pub fn check(&self, key: K, limits: &Arc<RateLimits>, op: &'static str, now: Instant) -> Result<(), Duration> {
+        let mut limiters = self.limiters.lock().unwrap();
+        if limiters.len() >= MAX_LIMITERS {
+            limiters.retain(|_, x| !x.full(now));
+        }
+        let limiter = limiters.entry(key).or_insert_with(|| RateLimiter::new(limits.clone(), now));
+        if !Arc::ptr_eq(&limiter.limits, limits) {
+            *limiter = RateLimiter::new(limits.clone(), now);
+        }
+        limiter.check(op, now)
+    }
+}
+
+struct Offender {
+    strikes: u32,
+    since: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// The addresses limited recently and the ones banned
+#[derive(Default)]
+pub struct Bans {
+    offenders: Mutex<HashMap<IpAddr, Offender>>,
+}
+
+impl Bans {
+    /// The time left if banned
+    pub fn banned(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let offenders = self.offenders.lock().unwrap();
+        let until = offenders.get(&ip)?.banned_until?;
+        until.checked_duration_since(now).filter(|x| !x.is_zero())
+    }
+
+    /// Count a limited request of `ip`, return true if it is banned for it
+    pub fn strike(&self, ip: IpAddr, limits: &RateLimits, now: Instant) -> bool {
+        if limits.ban_after == 0 {
+            return false;
+        }
+        let window = Duration::from_secs(limits.ban_window_secs);
+        let mut offenders = self.offenders.lock().unwrap();
+        if offenders.len() >= MAX_OFFENDERS {
+            offenders.retain(|_, x| x.banned_until.is_some_and(|until| until > now) || now.saturating_duration_since(x.since) < window);
+        }
+        let offender = offenders.entry(ip).or_insert(Offender { strikes: 0, since: now, banned_until: None });
+        if now.saturating_duration_since(offender.since) >= window {
+            offender.strikes = 0;
+            offender.since = now;
+        }
+        offender.strikes += 1;
+        if offender.strikes >= limits.ban_after {
+            offender.strikes = 0;
+            offender.banned_until = Some(now + Duration::from_secs(limits.ban_secs));
+            return true;
+        }
+        false
+    }
+
+    /// The error of the request limited for `wait`, the address is banned if limited too often
+    pub fn refuse(&self, ip: IpAddr, op: &str, limits: &RateLimits, wait: Duration, now: Instant) -> UserInputError {
+        if self.strike(ip, limits, now) {
+            warn!("Banned {} for {}s for too many requests", ip, limits.ban_secs);
+            UserInputError { code: ErrorCode::TemporarilyBanned, params: vec![limits.ban_secs.to_string()], fatal: true }
+        } else {
+            trace!("Limited {} for {:?}", op, wait);
+            let secs = wait.as_secs() + (wait.subsec_nanos() > 0) as u64;
+            UserInputError::with_params(ErrorCode::RateLimited, vec![secs.to_string()])
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use crate::bank::limit::{Bans, Limiters, Rate, RateLimiter, RateLimits};
+
+    #[test]
+    fn buckets() {
+        let start = Instant::now();
+        let mut limits = RateLimits { session: Rate::new(3, 1.0), ..Default::default() };
+        limits.operations.insert("login".into(), Rate::new(1, 0.5));
+        let mut limiter = RateLimiter::new(Arc::new(limits), start);
+        assert_eq!(limiter.check("login", start), Ok(()));
+        assert_eq!(limiter.check("login", start), Err(Duration::from_secs(2)));
+        assert_eq!(limiter.check("info", start), Ok(()));
+        // the session is empty now
+        assert_eq!(limiter.check("info", start), Err(Duration::from_secs(1)));
+        let later = start + Duration::from_secs(2);
+        assert_eq!(limiter.check("login", later), Ok(()));
+        assert_eq!(limiter.check("info", later), Ok(()));
+        assert_eq!(limiter.check("info", later), Err(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn bans() {
+        let start = Instant::now();
+        let limits = RateLimits { ban_after: 3, ban_window_secs: 10, ban_secs: 60, ..Default::default() };
+        let bans = Bans::default();
+        let ip = "10.0.0.1".parse().unwrap();
+        assert!(!bans.strike(ip, &limits, start));
+        assert!(!bans.strike(ip, &limits, start));
+        // the window passed, counted again
+        let later = start + Duration::from_secs(11);
+        assert!(!bans.strike(ip, &limits, later));
+        assert!(!bans.strike(ip, &limits, later));
+        assert_eq!(bans.banned(ip, later), None);
+        assert!(bans.strike(ip, &limits, later));
+        assert_eq!(bans.banned(ip, later), Some(Duration::from_secs(60)));
+        assert_eq!(bans.banned("10.0.0.2".parse().unwrap(), later), None);
+        assert_eq!(bans.banned(ip, later + Duration::from_secs(60)), None);
+    }
+
+    #[test]
+    fn keyed() {
+        let start = Instant::now();
+        let limits = Arc::new(RateLimits { session: Rate::new(1, 1.0), ..Default::default() });
+        let limiters = Limiters::default();
+        assert_eq!(limiters.check(1, &limits, "info", start), Ok(()));
+        assert_eq!(limiters.check(1, &limits, "info", start), Err(Duration::from_secs(1)));
+        assert_eq!(limiters.check(2, &limits, "info", start), Ok(()));
+        // reloaded, the buckets are new
+        let reloaded = Arc::new((*limits).clone());
+        assert_eq!(limiters.check(1, &reloaded, "info", start), Ok(()));
+        assert_eq!(limiters.check(1, &reloaded, "info", start), Err(Duration::from_secs(1)));
+    }
+}
@@ -1,121 +1,271 @@
-//! Currently packet from master stream -> connection -> bank data handler
+//! Currently packet from master stream -> connection -> [`pipeline`] middlewares -> bank data handler
 //!
-//! for all string: u16 len and utf coded str
+//! The packets are defined in [`bank_protocol`], decoded by the handler of the current state.
 //!
-//! Packet format: `<header> <version: u32> <content>`
-//!
-//! Packet header: rPtm
-//!
-//! version: 0
-//!
-//! Contents:
-//!
-//! Server to client packets:
-//! * Return main menu with info (b"menu") (id: u32) (name: String) (balance: u32) (phone_number: String)
-//! * Normal tip and do nothing (b"msgb") (msg: String)
-//! * Error (and disconnect) (b"errr") (reason: String)
-//! *
+//! The connection must start with the [`Handshake`], other packets before it are refused.
 //!
 
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 use std::future::Future;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::time::Instant;
 
-use bytes::Buf;
-use log::trace;
+use bank_protocol::compress::Compression;
+use bank_protocol::{Capabilities, CURRENT_VERSION, ErrorCode, Failure, Handshake, LEGACY_VERSION, Message, MIN_VERSION, PacketReader, PacketWriter, Response, UNSOLICITED};
+use log::{info, trace};
+use tracing::{info_span, Instrument, Span};
+use tracing::field::Empty;
 
-use crate::bank::ext::PacketWriteExt;
 use crate::bank::handlers::BankDataHandler;
+use crate::bank::pipeline::{Exchange, Middleware, Next};
 use crate::bank::server::BankServer;
 use crate::network::{DataHandler, NetworkMessage};
 use crate::network::peer::Peer;
+use crate::telemetry;
 
 mod handlers;
 pub mod server;
 pub mod user;
-pub mod ext;
+pub mod totp;
+pub mod session;
+pub mod service;
+pub mod limit;
+pub mod pipeline;
+pub mod router;
+pub mod lifecycle;
+pub mod fee;
+pub mod risk;
 
-pub const PACKET_HEADER: &'static [u8] = b"rPtm";
-pub const CURRENT_VERSION: u32 = 0;
+/// The optional features the server supports, some could be turned off by the config
+pub const SERVER_CAPABILITIES: Capabilities = Capabilities::NOTIFICATIONS.union(Capabilities::ZSTD).union(Capabilities::LZ4)
+    .union(Capabilities::HEARTBEAT);
 
 pub struct BankConnection {
     bank_server: BankServer,
     handler: Box<dyn BankDataHandler>,
+    /// The version negotiated, None before the hello
+    version: Option<u32>,
+    /// When logged in, counted in the logged connections
+    logged_at: Option<Instant>,
+    /// Prefix of the correlation ids of the requests
+    session: String,
+    /// With the account after logged in
+    span: Span,
+    middlewares: Vec<Box<dyn Middleware>>,
 }
 
 impl BankConnection {
-    pub fn new(bank_server: BankServer) -> Self {
-        Self { bank_server, handler: Box::new(handlers::HandleLogin::default()) }
+    pub fn new(bank_server: BankServer, addr: SocketAddr) -> Self {
+        let session = telemetry::session_id();
+        let span = info_span!(parent: None, "session", session = %session, addr = %addr, account = Empty);
+        let middlewares = pipeline::middlewares(&bank_server);
+        Self {
+            bank_server,
+            handler: Box::new(handlers::HandleLogin::default()),
+            version: None,
+            logged_at: None,
+            session,
+            span,
+            middlewares,
+        }
+    }
+
+    /// Reply the hello, return false to disconnect
+    fn handshake(&mut self, src: &Peer, data: &[u8]) -> bool {
+        if self.version.is_some() {
+            trace!("Drop the hello again from {}", src.addr);
+            return false;
+        }
+        let hello = match Handshake::from_packet(data) {
+            Ok(hello) => hello,
+            Err(e) => {
+                trace!("Drop bad hello for {}", e);
+                return false;
+            }
+        };
+        let reply = Handshake::negotiate(MIN_VERSION, CURRENT_VERSION, self.bank_server.capabilities(), &hello);
+        match reply.to_packet() {
+            Ok(packet) => {
+                let _ = src.sender.send_control(NetworkMessage::Rely(packet));
+            }
+            Err(e) => {
+                log::error!("Encode handshake failed for {}", e);
+                return false;
+            }
+        }
+        match reply {
+            Handshake::Welcome { version, capabilities } => {
+                info!("{} uses version {} with {:?}", src.addr, version, capabilities);
+                self.version = Some(version);
+                // after the welcome in the queue, so it is never compressed
+                let _ = src.sender.send_control(NetworkMessage::Compress(Compression::negotiate(capabilities)));
+                let _ = src.sender.send_control(NetworkMessage::Heartbeat(capabilities.contains(Capabilities::HEARTBEAT)));
+                self.handler = Box::new(handlers::HandleLogin::new(capabilities));
+                true
+            }
+            _ => {
+                info!("Rejected {} for {:?}", src.addr, hello);
+                false
+            }
+        }
     }
 }
 
 impl DataHandler for BankConnection {
     fn handle<'a>(&'a mut self, src: &'a Peer, data: &'a [u8]) -> Box<dyn Future<Output=bool> + Send + Unpin + 'a> {
+        let _entered = self.span.clone().entered();
         trace!("Handle connection packet for len: {}", data.len());
-        if data.len() < 8 || &data[0..4] != b"rPtm" {
-            return Box::new(Box::pin(async { false }));
+        if bank_protocol::is_handshake(data) {
+            let result = self.handshake(src, data);
+            return Box::new(Box::pin(async move { result }));
         }
-        let version = (&data[4..8]).get_u32();
-
-        if version != CURRENT_VERSION {
-            return Box::new(Box::pin(async { false }));
+        let (version, content) = match bank_protocol::read_frame(data) {
+            Ok(frame) => frame,
+            Err(e) => {
+                trace!("Drop bad packet for {}", e);
+                return Box::new(Box::pin(async { false }));
+            }
+        };
+        match self.version {
+            Some(x) if x == version => {}
+            None if version == LEGACY_VERSION => {
+                // the legacy client only shows b"msgb"
+                if let Ok(packet) = Response::Tip("客户端版本过旧，请升级后再连接".into()).to_packet_version(LEGACY_VERSION) {
+                    let _ = src.sender.send(NetworkMessage::Rely(packet));
+                }
+                return Box::new(Box::pin(async { false }));
+            }
+            None => {
+                let caller = Caller { peer: src, request_id: UNSOLICITED };
+                let _ = send_response(&caller, &Response::Error(Failure::new(ErrorCode::HandshakeRequired)));
+                return Box::new(Box::pin(async { false }));
+            }
+            Some(x) => {
+                trace!("Drop packet of version {} but negotiated {}", version, x);
+                return Box::new(Box::pin(async { false }));
+            }
         }
 
+        let mut reader = PacketReader::new(content);
+        let request_id = match reader.u32() {
+            Ok(id) => id,
+            Err(e) => {
+                trace!("Drop packet without request id for {}", e);
+                return Box::new(Box::pin(async { false }));
+            }
+        };
+        let content = reader.rest();
+        let op = self.handler.operation(content);
+        let span = info_span!("request", corr = %format!("{}-{}", self.session, request_id), op);
 
-        let task = async {
-            let handler_task = self.handler.handle(&self.bank_server, src, &data[8..]);
-            let result = handler_task.await;
-            match result {
-                Ok(x) => {
-                    if let Some(x) = x {
-                        self.handler = x;
+        let task = async move {
+            let caller = Caller { peer: src, request_id };
+            let ex = Exchange {
+                server: &self.bank_server,
+                src: &caller,
+                op,
+                account: self.handler.account(),
+                logged_at: self.logged_at,
+                content,
+            };
+            match Next::new(&mut self.middlewares, self.handler.as_mut()).run(&ex).await {
+                Ok(Some(x)) => {
+                    let metrics = &self.bank_server.0.metrics;
+                    match x.account() {
+                        Some(account) => {
+                            if self.logged_at.replace(Instant::now()).is_none() {
+                                metrics.logged.inc();
+                            }
+                            self.span.record("account", account);
+                        }
+                        None => {
+                            if self.logged_at.take().is_some() {
+                                metrics.logged.dec();
+                            }
+                        }
                     }
+                    self.handler = x;
                     true
                 }
-                Err(e) if e.is::<UserInputError>() => {
-                    let mut data = Vec::<u8>::new();
-                    data.add_header();
-                    data.extend_from_slice(b"msgb");
-                    data.write_string(&format!("{:?}", e.downcast::<UserInputError>().unwrap().msg));
-                    let _ = src.sender.send(NetworkMessage::Rely(data));
-                    true
-                }
-                Err(e) => {
-                    log::error!("Handler handled packet error for {:?}", e);
-                    let mut data = Vec::<u8>::new();
-                    data.add_header();
-                    data.extend_from_slice(b"errr");
-                    data.write_string(&format!("{:?}", e));
-                    let _ = src.sender.send(NetworkMessage::Rely(data));
-                    false
-                }
+                Ok(None) => true,
+                // replied by the middlewares
+                Err(_) => false,
             }
         };
-        Box::new(Box::pin(task))
+        Box::new(Box::pin(task.instrument(span)))
+    }
+
+    fn shutdown(&mut self, src: &Peer) {
+        // the legacy clients and the ones not shaken hands are just closed
+        if self.version.is_some() {
+            let caller = Caller { peer: src, request_id: UNSOLICITED };
+            let _ = send_response(&caller, &Response::Error(Failure::new(ErrorCode::Maintenance)));
+        }
+    }
+
+    fn closed(&mut self, src: &Peer) {
+        if self.logged_at.is_some() {
+            self.bank_server.0.metrics.logged.dec();
+        }
+        // not waiting for the next login to clean the dead session
+        let server = self.bank_server.clone();
+        let addr = src.addr;
+        tokio::spawn(async move { server.0.sessions.evict(addr).await });
     }
 }
 
 
-fn add_fixed_header(vec: &mut Vec<u8>) {
-    vec.extend_from_slice(PACKET_HEADER);
-    vec.extend_from_slice(&CURRENT_VERSION.to_be_bytes());
+/// The peer and the id of the request handling, the responses are tagged by the id
+pub struct Caller<'a> {
+    pub peer: &'a Peer,
+    pub request_id: u32,
+}
+
+impl Deref for Caller<'_> {
+    type Target = Peer;
+
+    fn deref(&self) -> &Self::Target {
+        self.peer
+    }
 }
 
+pub(crate) fn send_response(src: &Caller<'_>, response: &Response) -> anyhow::Result<()> {
+    let mut w = PacketWriter::with_header();
+    w.put_u32(src.request_id);
+    response.encode(&mut w)?;
+    src.sender.send(NetworkMessage::Rely(w.into_inner()))?;
+    Ok(())
+}
 
+
+/// The request failed for the input, replied as [`Response::Failed`] with the code
 #[derive(Debug)]
 pub struct UserInputError {
-    pub msg: &'static str,
+    pub code: ErrorCode,
+    pub params: Vec<String>,
+    /// Disconnect after replied
+    pub fatal: bool,
 }
 
 impl UserInputError {
-    pub fn new(msg: &'static str) -> Self {
-        Self { msg }
+    pub fn new(code: ErrorCode) -> Self {
+        Self { code, params: vec![], fatal: false }
+    }
+
+    pub fn with_params(code: ErrorCode, params: Vec<String>) -> Self {
+        Self { code, params, fatal: false }
+    }
+
+    pub fn fatal(code: ErrorCode) -> Self {
+        Self { code, params: vec![], fatal: true }
     }
 }
 
 impl Display for UserInputError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_str(self.msg)
+        f.write_str(&Failure::with_params(self.code, self.params.clone()).describe())
     }
 }
 
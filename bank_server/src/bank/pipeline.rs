@@ -0,0 +1,195 @@
+//! The middlewares around the [`BankDataHandler`] of the connection.
+//!
+//! Each request goes through the middlewares in order, then the handler of the current state.
+//! A middleware could stop it by returning early, or look at the result of the inner ones.
+//! The order of [`middlewares`]:
+//! 1. [`Logging`] the operation and the time used
+//! 2. [`ErrorMapping`] reply the errors, an error returned by it means disconnecting
+//! 3. [`Metrics`] count the requests by the result before mapped
+//! 4. [`RateLimit`] the buckets of the connection and the bans of the address
+//! 5. [`Auth`] the operations need the account logged in and the session not revoked
+
+use std::time::Instant;
+
+use bank_protocol::{ErrorCode, Failure, ProtocolError, Response};
+use futures_util::future::BoxFuture;
+use log::{debug, info, trace, warn};
+
+use crate::bank::{BankServer, Caller, send_response, UserInputError};
+use crate::bank::handlers::{BankDataHandler, HandlerResult};
+use crate::bank::limit::RateLimiter;
+
+/// The operations allowed before logged in
+const PUBLIC_OPERATIONS: [&str; 3] = ["login", "register", "totp_login"];
+
+/// The request going through the middlewares
+pub struct Exchange<'a> {
+    pub server: &'a BankServer,
+    pub src: &'a Caller<'a>,
+    /// The name by the current handler, the request is not decoded yet
+    pub op: &'static str,
+    /// Logged in this account
+    pub account: Option<u32>,
+    /// When logged in, or re-authenticated
+    pub logged_at: Option<Instant>,
+    pub content: &'a [u8],
+}
+
+pub trait Middleware: Send + 'static {
+    fn handle<'a>(&'a mut self, ex: &'a Exchange<'a>, next: Next<'a>) -> BoxFuture<'a, HandlerResult>;
+}
+
+/// The middlewares left and the handler
+pub struct Next<'a> {
+    middlewares: &'a mut [Box<dyn Middleware>],
+    handler: &'a mut dyn BankDataHandler,
+}
+
+impl<'a> Next<'a> {
+    pub fn new(middlewares: &'a mut [Box<dyn Middleware>], handler: &'a mut dyn BankDataHandler) -> Self {
+        Self { middlewares, handler }
+    }
+
+    pub fn run(self, ex: &'a Exchange<'a>) -> BoxFuture<'a, HandlerResult> {
+        match self.middlewares.split_first_mut() {
+            Some((first, rest)) => first.handle(ex, Next { middlewares: rest, handler: self.handler }),
+            None => Box::pin(self.handler.handle(ex.server, ex.src, ex.content)),
+        }
+    }
+}
+
+/// The middlewares of a new connection, in the order documented above
+pub fn middlewares(server: &BankServer) -> Vec<Box<dyn Middleware>> {
+    vec![
+        Box::new(Logging),
+        Box::new(ErrorMapping),
+        Box::new(Metrics),
+        Box::new(RateLimit { limiter: RateLimiter::new(server.rate_limits(), Instant::now()) }),
+        Box::new(Auth),
+    ]
+}
+
+pub struct Logging;
+
+impl Middleware for Logging {
+    fn handle<'a>(&'a mut self, ex: &'a Exchange<'a>, next: Next<'a>) -> BoxFuture<'a, HandlerResult> {
+        Box::pin(async move {
+            trace!("Handle {} of len {}", ex.op, ex.content.len());
+            let start = Instant::now();
+            let result = next.run(ex).await;
+            match &result {
+                Ok(_) => debug!("Handled {} in {:?}", ex.op, start.elapsed()),
+                Err(_) => debug!("Handled {} in {:?} and disconnecting", ex.op, start.elapsed()),
+            }
+            result
+        })
+    }
+}
+
+/// Reply the errors, keep the connection only for the ones not fatal
+pub struct ErrorMapping;
+
+impl Middleware for ErrorMapping {
+    fn handle<'a>(&'a mut self, ex: &'a Exchange<'a>, next: Next<'a>) -> BoxFuture<'a, HandlerResult> {
+        Box::pin(async move {
+            let e = match next.run(ex).await {
+                Ok(x) => return Ok(x),
+                Err(e) => e,
+            };
+            let addr = ex.src.addr;
+            if let Some(input) = e.downcast_ref::<UserInputError>() {
+                let failure = Failure::with_params(input.code, input.params.clone());
+                if !input.fatal {
+                    let _ = send_response(ex.src, &Response::Failed(failure));
+                    return Ok(None);
+                }
+                info!("Disconnect {} for {:?}", addr, input.code);
+                let _ = send_response(ex.src, &Response::Error(failure));
+            } else if e.is::<ProtocolError>() {
+                info!("Bad packet from {} for {}", addr, e);
+                let failure = Failure::new(ErrorCode::BadPacket).with_message(e.to_string());
+                let _ = send_response(ex.src, &Response::Error(failure));
+            } else {
+                log::error!("Handler handled packet error for {:?}", e);
+                let _ = send_response(ex.src, &Response::Error(Failure::new(ErrorCode::Internal)));
+            }
+            Err(e)
+        })
+    }
+}
+
+pub struct Metrics;
+
+impl Middleware for Metrics {
+    fn handle<'a>(&'a mut self, ex: &'a Exchange<'a>, next: Next<'a>) -> BoxFuture<'a, HandlerResult> {
+        Box::pin(async move {
+            let start = Instant::now();
+            let result = next.run(ex).await;
+            let metrics = &ex.server.0.metrics;
+            let outcome = match &result {
+                Ok(_) => "ok",
+                Err(e) => match e.downcast_ref::<UserInputError>() {
+                    Some(x) if matches!(x.code, ErrorCode::RateLimited | ErrorCode::TemporarilyBanned) => "limited",
+                    Some(x) => {
+                        if matches!(ex.op, "login" | "totp_login") {
+                            metrics.login_failed(&format!("{:?}", x.code));
+                        }
+                        "rejected"
+                    }
+                    None if e.is::<ProtocolError>() => "bad_packet",
+                    None => {
+                        if e.chain().any(|x| x.is::<sqlx::Error>()) {
+                            metrics.sql_error();
+                        }
+                        "error"
+                    }
+                },
+            };
+            metrics.request(ex.op, outcome, start.elapsed());
+            result
+        })
+    }
+}
+
+/// The limits are the ones when connected
+pub struct RateLimit {
+    limiter: RateLimiter,
+}
+
+impl Middleware for RateLimit {
+    fn handle<'a>(&'a mut self, ex: &'a Exchange<'a>, next: Next<'a>) -> BoxFuture<'a, HandlerResult> {
+        let now = Instant::now();
+        let wait = match self.limiter.check(ex.op, now) {
+            Ok(()) => return next.run(ex),
+            Err(wait) => wait,
+        };
+        let e = ex.server.0.bans.refuse(ex.src.addr.ip(), ex.op, self.limiter.limits(), wait, now);
+        Box::pin(async move { Err(e.into()) })
+    }
+}
+
+/// The commands after login never run without the account, whatever the state handler decodes.
+/// The sessions revoked by the password changed elsewhere are closed.
+///
+/// The status of the account is checked by [`check_operation`](crate::bank::service::check_operation)
+/// in each command changing anything, the same for the gateway.
+pub struct Auth;
+
+impl Middleware for Auth {
+    fn handle<'a>(&'a mut self, ex: &'a Exchange<'a>, next: Next<'a>) -> BoxFuture<'a, HandlerResult> {
+        Box::pin(async move {
+            match (ex.account, ex.logged_at) {
+                (Some(id), Some(at)) if ex.server.0.sessions.is_revoked(id, at) => {
+                    info!("The session of {} from {} is revoked", id, ex.src.addr);
+                    Err(UserInputError::fatal(ErrorCode::SessionExpired))?
+                }
+                (None, _) if !PUBLIC_OPERATIONS.contains(&ex.op) => {
+                    warn!("{} sent {} before logged in", ex.src.addr, ex.op);
+                    Err(UserInputError::fatal(ErrorCode::SessionExpired))?
+                }
+                _ => {}
+            }
+            next.run(ex).await
+        })
+    }
+}
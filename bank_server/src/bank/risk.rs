@@ -0,0 +1,292 @@
+//! The risk rules checked before each withdrawal and transfer, by the `[risk]` config changed live by the reload.
+//!
+//! A rule for the operation and the amount fires by its kind:
+//!
+//! * `velocity`: more than `count` of the same operation in the last `window_secs`, this one included
+//! * `new_payee`: the transfer to an account never paid before
+//! * `new_device`: the client not identified, or first seen in the last `window_secs`
+//! * `new_ip`: the address first logged in from in the last `window_secs`, or never
+//!
+//! The strictest action of the rules fired decides: `allow` only logs, `challenge` needs a new
+//! totp code, `block` refuses. No rule by default.
+//!
+//! ```toml
+//! [[risk.rules]]
+//! id = "burst-transfers"
+//! kind = "velocity"
+//! operations = ["transfer"]
+//! count = 5
+//! window_secs = 600
+//! action = "challenge"
+//!
+//! [[risk.rules]]
+//! id = "new-ip-large"
+//! kind = "new_ip"
+//! min_amount = 5000
+//! window_secs = 86400
+//! action = "block"
+//! ```
+//!
+//! The decisions with any rule fired are saved in `bank_risk_decisions` with the ids of the rules.
+
+use std::net::IpAddr;
+
+use anyhow::bail;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::bank::fee::Channel;
+
+/// The operations checked by the rules
+pub const RISK_OPERATIONS: [&str; 2] = ["withdraw", "transfer"];
+
+/// The longest window of the rules, a year
+const MAX_WINDOW_SECS: u64 = 366 * 24 * 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleKind {
+    Velocity,
+    NewPayee,
+    NewDevice,
+    NewIp,
+}
+
+/// In the order of strictness
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Allow,
+    /// Needs a new totp code
+    Challenge,
+    Block,
+}
+
+impl Action {
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Allow => "allow",
+            Action::Challenge => "challenge",
+            Action::Block => "block",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RiskRule {
+    /// Logged in the decisions
+    pub id: String,
+    pub kind: RuleKind,
+    /// Both if empty
+    #[serde(default)]
+    pub operations: Vec<String>,
+    /// In cents, inclusive
+    #[serde(default)]
+    pub min_amount: u32,
+    /// The operations allowed in the window of `velocity`
+    #[serde(default)]
+    pub count: u32,
+    /// Not for `new_payee`
+    #[serde(default)]
+    pub window_secs: u64,
+    pub action: Action,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RiskConfig {
+    pub rules: Vec<RiskRule>,
+}
+
+/// Where the operation is requested from
+#[derive(Debug, Clone)]
+pub struct Signals {
+    /// Also decides the fee
+    pub channel: Channel,
+    pub ip: Option<IpAddr>,
+    /// Sent by the client after logged in, or the `X-Device-Id` of the gateway
+    pub device: Option<String>,
+}
+
+/// What is known when the operation is requested, only the ones the rules need are filled
+#[derive(Debug, Clone, Default)]
+pub struct Facts {
+    /// The times of the same operation before, in the longest window of the velocity rules
+    pub recent: Vec<DateTime<Utc>>,
+    /// The transfer to an account never paid before
+    pub new_payee: bool,
+    /// None if not identified
+    pub device_seen: Option<DateTime<Utc>>,
+    /// When first logged in from the address, None if never
+    pub ip_seen: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    pub action: Action,
+    /// The ids of the rules fired
+    pub rules: Vec<String>,
+}
+
+impl RiskRule {
+    fn applies(&self, operation: &str, amount: u32) -> bool {
+        (self.operations.is_empty() || self.operations.iter().any(|x| x == operation))
+            && amount >= self.min_amount
+    }
+
+    fn window(&self) -> Duration {
+        Duration::seconds(self.window_secs as i64)
+    }
+
+    fn fires(&self, facts: &Facts, now: DateTime<Utc>) -> bool {
+        let since = now - self.window();
+        match self.kind {
+            RuleKind::Velocity => facts.recent.iter().filter(|x| **x >= since).count() >= self.count as usize,
+            RuleKind::NewPayee => facts.new_payee,
+            RuleKind::NewDevice => facts.device_seen.is_none_or(|x| x >= since),
+            RuleKind::NewIp => facts.ip_seen.is_none_or(|x| x >= since),
+        }
+    }
+}
+
+impl RiskConfig {
+    /// The rules to check for the operation
+    pub fn applicable<'a>(&'a self, operation: &'a str, amount: u32) -> impl Iterator<Item=&'a RiskRule> + 'a {
+        self.rules.iter().filter(move |x| x.applies(operation, amount))
+    }
+
+    /// The recent operations since then are needed, None if no velocity rule
+    pub fn velocity_since(&self, operation: &str, amount: u32, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.applicable(operation, amount)
+            .filter(|x| x.kind == RuleKind::Velocity)
+            .map(|x| now - x.window())
+            .min()
+    }
+
+    pub fn evaluate(&self, operation: &str, amount: u32, facts: &Facts, now: DateTime<Utc>) -> Decision {
+        let fired: Vec<&RiskRule> = self.applicable(operation, amount)
+            .filter(|x| x.fires(facts, now))
+            .collect();
+        Decision {
+            action: fired.iter().map(|x| x.action).max().unwrap_or(Action::Allow),
+            rules: fired.into_iter().map(|x| x.id.clone()).collect(),
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (i, rule) in self.rules.iter().enumerate() {
+            // joined by commas in the log
+            if rule.id.is_empty() || rule.id.len() > 40 || rule.id.contains(',') || self.rules[..i].iter().any(|x| x.id == rule.id) {
+                bail!("risk.rules should have the unique ids of at most 40 bytes without commas, the rule {} is not", i + 1);
+            }
+            if let Some(x) = rule.operations.iter().find(|x| !RISK_OPERATIONS.contains(&x.as_str())) {
+                bail!("risk.rules of {} has unknown operation {}", rule.id, x);
+            }
+            if rule.kind == RuleKind::Velocity && rule.count == 0 {
+                bail!("risk.rules of {} should have positive count", rule.id);
+            }
+            if rule.kind != RuleKind::NewPayee && (rule.window_secs == 0 || rule.window_secs > MAX_WINDOW_SECS) {
+                bail!("risk.rules of {} should have window_secs from 1 to {}", rule.id, MAX_WINDOW_SECS);
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::bank::risk::{Action, Facts, RiskConfig};
+
+    fn config() -> RiskConfig {
+        toml::from_str(r#"
+[[rules]]
+id = "burst"
+kind = "velocity"
+operations = ["transfer"]
+count = 2
+window_secs = 600
+action = "challenge"
+
+[[rules]]
+id = "new-payee-large"
+kind = "new_payee"
+min_amount = 5000
+action = "challenge"
+
+[[rules]]
+id = "new-device"
+kind = "new_device"
+window_secs = 3600
+action = "allow"
+
+[[rules]]
+id = "new-ip-large"
+kind = "new_ip"
+min_amount = 8000
+window_secs = 86400
+action = "block"
+"#).unwrap()
+    }
+
+    #[test]
+    fn evaluate() {
+        let config = config();
+        config.validate().unwrap();
+        let now = Utc.with_ymd_and_hms(2024, 1, 1, 12, 0, 0).unwrap();
+        let known = Facts {
+            device_seen: Some(now - Duration::days(30)),
+            ip_seen: Some(now - Duration::days(30)),
+            ..Default::default()
+        };
+        let decision = config.evaluate("transfer", 9000, &known, now);
+        assert_eq!((decision.action, decision.rules.len()), (Action::Allow, 0));
+
+        // the one before the window is not counted
+        let recent = Facts { recent: vec![now - Duration::minutes(11), now - Duration::minutes(5)], ..known.clone() };
+        assert!(config.evaluate("transfer", 100, &recent, now).rules.is_empty());
+        let recent = Facts { recent: vec![now - Duration::minutes(9), now - Duration::minutes(5)], ..known.clone() };
+        assert_eq!(config.evaluate("transfer", 100, &recent, now).rules, vec!["burst"]);
+        assert!(config.evaluate("withdraw", 100, &recent, now).rules.is_empty());
+        assert_eq!(config.velocity_since("transfer", 1, now), Some(now - Duration::minutes(10)));
+        assert_eq!(config.velocity_since("withdraw", 1, now), None);
+
+        let payee = Facts { new_payee: true, ..known.clone() };
+        assert!(config.evaluate("transfer", 4999, &payee, now).rules.is_empty());
+        assert_eq!(config.evaluate("transfer", 5000, &payee, now).action, Action::Challenge);
+
+        // allow only logs
+        let device = Facts { device_seen: None, ..known.clone() };
+        let decision = config.evaluate("withdraw", 1, &device, now);
+        assert_eq!((decision.action, decision.rules), (Action::Allow, vec!["new-device".to_string()]));
+
+        // the strictest wins
+        let all = Facts { new_payee: true, ip_seen: Some(now - Duration::hours(1)), ..device };
+        let decision = config.evaluate("transfer", 8000, &all, now);
+        assert_eq!(decision.action, Action::Block);
+        assert_eq!(decision.rules, vec!["new-payee-large", "new-device", "new-ip-large"]);
+    }
+
+    #[test]
+    fn invalid() {
+        let valid = config();
+        let mut config = valid.clone();
+        config.rules[1].id = "burst".into();
+        assert!(config.validate().is_err());
+        let mut config = valid.clone();
+        config.rules[0].operations = vec!["deposit".into()];
+        assert!(config.validate().is_err());
+        let mut config = valid.clone();
+        config.rules[0].count = 0;
+        assert!(config.validate().is_err());
+        let mut config = valid.clone();
+        config.rules[2].window_secs = 0;
+        assert!(config.validate().is_err());
+        let mut config = valid;
+        config.rules[3].id = "a,b".into();
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,101 @@
+//! Route the [`Request`]s to the commands registered by the type id.
+//!
+//! A command is a plain fn of the state handling, like [`LoggedHandler`](crate::bank::handlers::LoggedHandler),
+//! it gets the decoded request of its own type. The name of the route is the operation of the
+//! metrics, the rate limits and the logs.
+
+use std::collections::BTreeMap;
+
+use bank_protocol::{Message, ProtocolError, Request};
+use futures_util::future::BoxFuture;
+
+use crate::bank::{BankServer, Caller};
+use crate::bank::handlers::HandlerResult;
+
+/// What the commands could use besides the state
+#[derive(Clone, Copy)]
+pub struct Context<'a> {
+    pub server: &'a BankServer,
+    pub src: &'a Caller<'a>,
+}
+
+pub type Command<S> = for<'a> fn(&'a mut S, Context<'a>, Request) -> BoxFuture<'a, HandlerResult>;
+
+struct Route<S> {
+    name: &'static str,
+    command: Command<S>,
+}
+
+pub struct Router<S> {
+    routes: BTreeMap<u8, Route<S>>,
+}
+
+impl<S> Default for Router<S> {
+    fn default() -> Self {
+        Self { routes: BTreeMap::new() }
+    }
+}
+
+impl<S> Router<S> {
+    /// Panics if the type id or the name is routed already
+    pub fn route(mut self, type_id: u8, name: &'static str, command: Command<S>) -> Self {
+        assert!(self.routes.values().all(|x| x.name != name), "Route {} twice", name);
+        assert!(self.routes.insert(type_id, Route { name, command }).is_none(), "Route type {} twice", type_id);
+        self
+    }
+
+    /// The operation of the request not decoded yet
+    pub fn operation(&self, data: &[u8]) -> &'static str {
+        data.first().and_then(|x| self.routes.get(x)).map(|x| x.name).unwrap_or("unknown")
+    }
+
+    pub fn operations(&self) -> impl Iterator<Item=(u8, &'static str)> + '_ {
+        self.routes.iter().map(|(id, x)| (*id, x.name))
+    }
+
+    /// Decode the request and run its command
+    pub fn dispatch<'a>(&self, state: &'a mut S, cx: Context<'a>, data: &[u8]) -> BoxFuture<'a, HandlerResult> {
+        let request = match Request::from_content(data) {
+            Ok(x) => x,
+            Err(e) => return Box::pin(async move { Err(e.into()) }),
+        };
+        match self.routes.get(&request.type_id()) {
+            Some(route) => (route.command)(state, cx, request),
+            None => {
+                let e = ProtocolError::UnknownRequest(request.type_id());
+                Box::pin(async move { Err(e.into()) })
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use bank_protocol::{Message, PacketWriter, Request};
+    use futures_util::future::BoxFuture;
+
+    use crate::bank::handlers::HandlerResult;
+    use crate::bank::router::{Context, Router};
+
+    fn noop<'a>(_: &'a mut (), _: Context<'a>, _: Request) -> BoxFuture<'a, HandlerResult> {
+        Box::pin(async { Ok(None) })
+    }
+
+    #[test]
+    fn operations() {
+        let router = Router::<()>::default().route(3, "info", noop).route(0, "deposit", noop);
+        let mut w = PacketWriter::default();
+        Request::Deposit { amount: 1 }.encode(&mut w).unwrap();
+        assert_eq!(router.operation(&w.into_inner()), "deposit");
+        assert_eq!(router.operation(&[1, 0, 0, 0, 1]), "unknown");
+        assert_eq!(router.operation(&[]), "unknown");
+        assert_eq!(router.operations().collect::<Vec<_>>(), vec![(0, "deposit"), (3, "info")]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn route_twice() {
+        let _ = Router::<()>::default().route(0, "deposit", noop).route(0, "withdraw", noop);
+    }
+}
@@ -1,27 +1,106 @@
 use std::net::SocketAddr;
-use std::sync::Arc;
-use std::time::SystemTime;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
-use chrono::Utc;
-use log::info;
-use sqlx::{Executor, MySqlPool, query};
-use sqlx::mysql::MySqlQueryResult;
+use anyhow::anyhow;
+use bank_protocol::{Capabilities, ErrorCode, Failure, Message, Notification, NotifyKind, Response, Tagged, UNSOLICITED};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use sqlx::{Executor, MySql, MySqlPool, query, Row, Transaction};
 
-use crate::bank::BankConnection;
+use crate::bank::{BankConnection, SERVER_CAPABILITIES, UserInputError};
+use crate::bank::fee::{FEE_SENDER, FeeSchedule};
+use crate::bank::lifecycle::AccountStatus;
+use crate::bank::limit::{Bans, RateLimits};
+use crate::bank::risk::{Decision, RiskConfig, Signals};
+use crate::bank::service::{BALANCE_LIMIT, format_cents};
+use crate::bank::session::SessionRegistry;
+use crate::bank::totp::{self, Totp};
+use crate::bank::user::User;
+use crate::config::DatabaseConfig;
+use crate::metrics::Metrics;
 use crate::network::{DataHandler, DataHandlerGenerator};
 
 pub struct Inner {
     pub sql_pool: MySqlPool,
+    pub totp: Totp,
+    pub sessions: SessionRegistry,
+    pub metrics: Metrics,
+    /// Offered to the new connections, changed by the reload
+    capabilities: AtomicU32,
+    /// For the new connections, changed by the reload
+    rate_limits: RwLock<Arc<RateLimits>>,
+    pub bans: Bans,
+    /// Changed by the reload
+    fees: RwLock<Arc<FeeSchedule>>,
+    /// Changed by the reload
+    risk: RwLock<Arc<RiskConfig>>,
+}
+
+/// The totp setting of one account
+pub struct TotpRecord {
+    /// Empty until enabled
+    pub secret: String,
+    /// The new secret waiting for the first code
+    pub pending_secret: Option<String>,
+    pub enabled: bool,
+    pub last_step: u64,
+}
+
+/// One saved payee in the address book of `owner`
+pub struct Payee {
+    pub pid: i32,
+    pub nickname: String,
+    pub account: u32,
+    pub last_used: Option<DateTime<Utc>>,
+}
+
+/// The columns of `bank_user` deciding the fees
+#[derive(Debug, Clone, Copy)]
+pub enum FeeClass {
+    Product,
+    Segment,
+}
+
+/// What is remembered of the sessions of the accounts, for the risk rules
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum Seen {
+    Ip = 0,
+    Device = 1,
+}
+
+/// One record of `bank_status_changes`
+pub struct StatusChange {
+    pub from: AccountStatus,
+    pub to: AccountStatus,
+    pub reason: String,
+    /// "customer", "system" or the staff
+    pub actor: String,
+    pub time: DateTime<Utc>,
+}
+
+/// One record of `bank_notices`, queued by the staff commands for the running server to notify
+pub struct QueuedNotice {
+    pub nid: u64,
+    pub id: u32,
+    pub kind: NotifyKind,
+    pub amount: u32,
+    pub counterpart: String,
+    pub msg: String,
 }
 
 #[derive(Clone)]
 pub struct BankServer(pub(crate) Arc<Inner>);
 
 impl BankServer {
-    pub async fn new() -> anyhow::Result<Self> {
+    pub async fn new(database: &DatabaseConfig) -> anyhow::Result<Self> {
         let sql_pool = sqlx::mysql::MySqlPoolOptions::new()
-            .connect(std::env::var("sql_url").unwrap().as_ref())
-            .await.unwrap();
+            .max_connections(database.max_connections)
+            .acquire_timeout(Duration::from_secs(database.acquire_timeout_secs))
+            .connect(&database.url)
+            .await?;
 
         // init sql table
         let result = sql_pool.execute(r#"CREATE TABLE IF NOT EXISTS `bank_user` (
@@ -29,9 +108,31 @@ impl BankServer {
   `password` INTEGER NOT NULL,
   `balance` INTEGER UNSIGNED NOT NULL DEFAULT '0',
   `name` VARCHAR(90),
-  `phone_number` varchar(20));
+  `phone_number` varchar(20),
+  `status` TINYINT UNSIGNED NOT NULL DEFAULT '0',
+  `last_active` DATETIME,
+  `product` VARCHAR(20) NOT NULL DEFAULT 'standard',
+  `segment` VARCHAR(20) NOT NULL DEFAULT 'retail');
 
-    CREATE TABLE IF NOT EXISTS `trade_logs` (`tid` int NOT NULL AUTO_INCREMENT PRIMARY KEY,`receiver` INTEGER NOT NULL, `sender` VARCHAR(30) NOT NULL, `time` DATETIME NOT NULL, `amount` INTEGER NOT NULL);
+    CREATE TABLE IF NOT EXISTS `trade_logs` (`tid` int NOT NULL AUTO_INCREMENT PRIMARY KEY,`receiver` INTEGER NOT NULL, `sender` VARCHAR(30) NOT NULL, `time` DATETIME NOT NULL, `amount` INTEGER NOT NULL, `parent` INTEGER);
+
+    CREATE TABLE IF NOT EXISTS `bank_totp` (`id` INTEGER PRIMARY KEY, `secret` VARCHAR(64) NOT NULL DEFAULT '', `pending_secret` VARCHAR(64), `enabled` BOOLEAN NOT NULL DEFAULT FALSE, `last_step` BIGINT UNSIGNED NOT NULL DEFAULT '0');
+
+    CREATE TABLE IF NOT EXISTS `bank_recovery_codes` (`id` INTEGER NOT NULL, `code_hash` CHAR(64) NOT NULL, `used` BOOLEAN NOT NULL DEFAULT FALSE, INDEX (`id`));
+
+    CREATE TABLE IF NOT EXISTS `bank_payees` (`pid` int NOT NULL AUTO_INCREMENT PRIMARY KEY, `owner` INTEGER NOT NULL, `nickname` VARCHAR(90) NOT NULL, `account` INTEGER NOT NULL, `last_used` DATETIME, UNIQUE (`owner`, `account`));
+
+    CREATE TABLE IF NOT EXISTS `bank_phone_alias` (`phone` VARCHAR(20) PRIMARY KEY, `id` INTEGER NOT NULL, UNIQUE (`id`));
+
+    CREATE TABLE IF NOT EXISTS `bank_profile_changes` (`cid` int NOT NULL AUTO_INCREMENT PRIMARY KEY, `id` INTEGER NOT NULL, `field` VARCHAR(20) NOT NULL, `old_value` VARCHAR(90), `new_value` VARCHAR(90), `addr` VARCHAR(64), `time` DATETIME NOT NULL, INDEX (`id`));
+
+    CREATE TABLE IF NOT EXISTS `bank_status_changes` (`cid` int NOT NULL AUTO_INCREMENT PRIMARY KEY, `id` INTEGER NOT NULL, `old_status` TINYINT UNSIGNED NOT NULL, `new_status` TINYINT UNSIGNED NOT NULL, `reason` VARCHAR(200) NOT NULL, `actor` VARCHAR(60) NOT NULL, `time` DATETIME NOT NULL, INDEX (`id`));
+
+    CREATE TABLE IF NOT EXISTS `bank_seen` (`id` INTEGER NOT NULL, `kind` TINYINT UNSIGNED NOT NULL, `value` VARCHAR(64) NOT NULL, `first_seen` DATETIME NOT NULL, `last_seen` DATETIME NOT NULL, PRIMARY KEY (`id`, `kind`, `value`));
+
+    CREATE TABLE IF NOT EXISTS `bank_risk_decisions` (`did` int NOT NULL AUTO_INCREMENT PRIMARY KEY, `id` INTEGER NOT NULL, `operation` VARCHAR(20) NOT NULL, `amount` INTEGER UNSIGNED NOT NULL, `action` VARCHAR(10) NOT NULL, `rules` TEXT NOT NULL, `addr` VARCHAR(64), `device` VARCHAR(64), `time` DATETIME NOT NULL, INDEX (`id`));
+
+    CREATE TABLE IF NOT EXISTS `bank_notices` (`nid` int NOT NULL AUTO_INCREMENT PRIMARY KEY, `id` INTEGER NOT NULL, `kind` TINYINT UNSIGNED NOT NULL, `amount` INTEGER UNSIGNED NOT NULL, `counterpart` VARCHAR(30) NOT NULL, `msg` VARCHAR(250) NOT NULL, `time` DATETIME NOT NULL, INDEX (`time`));
 
     CREATE EVENT IF NOT EXISTS interest_calculator
 ON SCHEDULE EVERY 1 DAY
@@ -42,15 +143,67 @@ END;
 
   "#).await?;
         info!("SQL init execute result: {:?}", result);
+        migrate(&sql_pool).await?;
 
-        let inner = Inner { sql_pool };
+        let inner = Inner {
+            sql_pool,
+            totp: Totp::default(),
+            sessions: Default::default(),
+            metrics: Default::default(),
+            capabilities: AtomicU32::new(SERVER_CAPABILITIES.0),
+            rate_limits: Default::default(),
+            bans: Default::default(),
+            fees: Default::default(),
+            risk: Default::default(),
+        };
         info!("Connected sql and got bank server instance");
         Ok(Self {
             0: inner.into(),
         })
     }
 
-    pub async fn insert_trade_log(&self, receiver: u32, sender: &str, amount: i32) -> anyhow::Result<()> {
+    /// The features offered in the handshake
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities(self.0.capabilities.load(Ordering::Relaxed))
+    }
+
+    /// Only the connections shaking hands later are affected
+    pub fn set_capabilities(&self, capabilities: Capabilities) {
+        self.0.capabilities.store(capabilities.0, Ordering::Relaxed);
+    }
+
+    pub fn rate_limits(&self) -> Arc<RateLimits> {
+        self.0.rate_limits.read().unwrap().clone()
+    }
+
+    /// Only the connections later are affected
+    pub fn set_rate_limits(&self, limits: RateLimits) {
+        *self.0.rate_limits.write().unwrap() = Arc::new(limits);
+    }
+
+    pub fn fees(&self) -> Arc<FeeSchedule> {
+        self.0.fees.read().unwrap().clone()
+    }
+
+    pub fn set_fees(&self, fees: FeeSchedule) {
+        *self.0.fees.write().unwrap() = Arc::new(fees);
+    }
+
+    pub fn risk(&self) -> Arc<RiskConfig> {
+        self.0.risk.read().unwrap().clone()
+    }
+
+    pub fn set_risk(&self, risk: RiskConfig) {
+        *self.0.risk.write().unwrap() = Arc::new(risk);
+    }
+
+    /// Wait for the connections in use returned and close them
+    pub async fn close(&self) {
+        self.0.sql_pool.close().await;
+    }
+
+    /// Return the tid
+    pub async fn insert_trade_log(&self, receiver: u32, sender: &str, amount: i32) -> anyhow::Result<u64> {
         let now = chrono::DateTime::<Utc>::from(SystemTime::now());
         let mut con = self.0.sql_pool.acquire().await?;
         let statement = query("INSERT INTO trade_logs(receiver, sender, time, amount) VALUES(?, ?, ?, ?);")
@@ -60,23 +213,777 @@ END;
             .bind(amount);
         let result = statement.execute(con.as_mut()).await?;
         info!("Inserted trade log {:?}", result);
+        Ok(result.last_insert_id())
+    }
+
+    /// Take the amount and the fee from the account with their trade logs in one transaction.
+    ///
+    /// Fails if the balance is not enough, whatever the caller checked before
+    pub async fn withdraw(&self, id: u32, amount: u32, fee: u32) -> anyhow::Result<()> {
+        let mut tx = self.0.sql_pool.begin().await?;
+        debit(&mut tx, id, amount, fee).await?;
+        let tid = insert_trade(&mut tx, id, "取款", -(amount as i32), None).await?;
+        post_fee(&mut tx, id, tid, fee).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Move the amount between the accounts and charge the fee in one transaction.
+    ///
+    /// Both rows are locked, so the balances checked are the ones changed
+    pub async fn transfer(&self, from: u32, to: u32, amount: u32, fee: u32) -> anyhow::Result<()> {
+        let mut tx = self.0.sql_pool.begin().await?;
+        // locked in the order of the ids against the deadlocks
+        let rows = query("SELECT id, status, balance FROM bank_user WHERE id IN (?, ?) ORDER BY id FOR UPDATE")
+            .bind(from)
+            .bind(to)
+            .fetch_all(&mut *tx).await?;
+        let target = match rows.iter().find(|row| row.get::<i32, _>("id") as u32 == to) {
+            Some(row) => (status_of(row.get("status"))?, row.get::<u32, _>("balance")),
+            None => Err(UserInputError::new(ErrorCode::AccountNotFound))?
+        };
+        if !target.0.accepts_credit() {
+            Err(UserInputError::new(ErrorCode::RecipientClosed))?
+        }
+        if target.1 as u64 + amount as u64 > BALANCE_LIMIT as u64 {
+            Err(UserInputError::with_params(ErrorCode::RecipientBalanceLimit, vec![format_cents(BALANCE_LIMIT)]))?
+        }
+        debit(&mut tx, from, amount, fee).await?;
+        query("UPDATE bank_user SET balance=balance+? WHERE id=?")
+            .bind(amount)
+            .bind(to)
+            .execute(&mut *tx).await?;
+        let tid = insert_trade(&mut tx, to, &from.to_string(), amount as i32, None).await?;
+        post_fee(&mut tx, from, tid, fee).await?;
+        tx.commit().await?;
+        info!("Transferred {} from {} to {}", amount, from, to);
+        Ok(())
+    }
+
+    /// Push the change with the current balance to the logged sessions of `id` except `except`.
+    ///
+    /// Best effort after the change committed, the errors are only logged
+    pub async fn notify(&self, id: u32, except: Option<SocketAddr>, kind: NotifyKind, amount: u32, counterpart: &str, msg: &str) {
+        if let Err(e) = self.send_notice(id, except, kind, amount, counterpart, msg).await {
+            warn!("Notify {} of {:?} failed for {:?}", id, kind, e);
+        }
+    }
+
+    async fn send_notice(&self, id: u32, except: Option<SocketAddr>, kind: NotifyKind, amount: u32, counterpart: &str, msg: &str) -> anyhow::Result<()> {
+        let mut con = self.0.sql_pool.acquire().await?;
+        let balance = match query("SELECT balance FROM bank_user WHERE id=?")
+            .bind(id)
+            .fetch_optional(con.as_mut()).await? {
+            Some(row) => row.get::<u32, _>("balance"),
+            None => return Ok(()),
+        };
+        let notice = Notification {
+            kind,
+            amount,
+            balance,
+            counterpart: counterpart.to_string(),
+            time: Utc::now(),
+            msg: msg.to_string(),
+        };
+        let sent = push_notice(&self.0.sessions, id, except, notice).await?;
+        if sent > 0 {
+            info!("Notified {} sessions of {} for {:?}", sent, id, kind);
+        }
+        Ok(())
+    }
+
+    /// Like [`BankServer::notify`] but by the running server, for the processes without the sessions like
+    /// the staff commands
+    pub async fn queue_notice(&self, id: u32, kind: NotifyKind, amount: u32, counterpart: &str, msg: &str) -> anyhow::Result<()> {
+        let mut con = self.0.sql_pool.acquire().await?;
+        let now = Utc::now();
+        // pushed in seconds or never, no one reads the old ones
+        query("DELETE FROM bank_notices WHERE time<?")
+            .bind(now - chrono::Duration::days(1))
+            .execute(con.as_mut()).await?;
+        query("INSERT INTO bank_notices(id, kind, amount, counterpart, msg, time) VALUES(?, ?, ?, ?, ?, ?)")
+            .bind(id)
+            .bind(kind as u8)
+            .bind(amount)
+            .bind(counterpart)
+            .bind(msg)
+            .bind(now)
+            .execute(con.as_mut()).await?;
+        Ok(())
+    }
+
+    /// The nid of the latest queued notice, 0 if none
+    pub async fn last_notice(&self) -> anyhow::Result<u64> {
+        let mut con = self.0.sql_pool.acquire().await?;
+        let row = query("SELECT CAST(COALESCE(MAX(nid), 0) AS UNSIGNED) AS nid FROM bank_notices")
+            .fetch_one(con.as_mut()).await?;
+        Ok(row.get("nid"))
+    }
+
+    /// The notices queued after `nid`, oldest first
+    pub async fn notices_after(&self, nid: u64, limit: u32) -> anyhow::Result<Vec<QueuedNotice>> {
+        let mut con = self.0.sql_pool.acquire().await?;
+        let result = query("SELECT * FROM bank_notices WHERE nid>? ORDER BY nid LIMIT ?")
+            .bind(nid)
+            .bind(limit)
+            .fetch_all(con.as_mut()).await?;
+        result.into_iter().map(|row| Ok(QueuedNotice {
+            nid: row.get::<i32, _>("nid") as u64,
+            id: row.get::<i32, _>("id") as u32,
+            kind: NotifyKind::from_u8(row.get("kind"))?,
+            amount: row.get("amount"),
+            counterpart: row.get("counterpart"),
+            msg: row.get("msg"),
+        })).collect()
+    }
+
+    /// Log out the other sessions of the account after its password changed, `keep` is the one changed it
+    pub async fn revoke_sessions(&self, id: u32, keep: Option<SocketAddr>) -> anyhow::Result<()> {
+        let notice = Response::Error(Failure::new(ErrorCode::SessionExpired));
+        let data = Tagged { id: UNSOLICITED, body: notice }.to_packet()?;
+        let sent = self.0.sessions.revoke(id, keep, &data, Instant::now()).await;
+        info!("Revoked the sessions of {}, {} connected", id, sent);
+        Ok(())
+    }
+
+    pub async fn check_password(&self, id: u32, password: u32) -> anyhow::Result<bool> {
+        let mut con = self.0.sql_pool.acquire().await?;
+        let result = query("SELECT id FROM bank_user WHERE id=? AND password=?")
+            .bind(id)
+            .bind(password as i32)
+            .fetch_optional(con.as_mut()).await?;
+        Ok(result.is_some())
+    }
+
+    /// Return false if the old password is wrong
+    pub async fn change_password(&self, id: u32, old: u32, new: u32, addr: Option<SocketAddr>) -> anyhow::Result<bool> {
+        let mut tx = self.0.sql_pool.begin().await?;
+        let result = query("UPDATE bank_user SET password=? WHERE id=? AND password=?")
+            .bind(new as i32)
+            .bind(id)
+            .bind(old as i32)
+            .execute(&mut *tx).await?;
+        if result.rows_affected() != 1 {
+            return Ok(false);
+        }
+        // never keep the passwords
+        insert_profile_change(&mut tx, id, "password", None, None, addr).await?;
+        tx.commit().await?;
+        info!("Changed password for {}", id);
+        Ok(true)
+    }
+
+    /// Update the name and the phone of `user`, the linked phone is unlinked if the phone changed.
+    ///
+    /// Return true if unlinked.
+    pub async fn update_profile(&self, user: &User, name: &str, phone: &str, addr: Option<SocketAddr>) -> anyhow::Result<bool> {
+        let mut tx = self.0.sql_pool.begin().await?;
+        query("UPDATE bank_user SET name=?, phone_number=? WHERE id=?")
+            .bind(name)
+            .bind(phone)
+            .bind(user.id)
+            .execute(&mut *tx).await?;
+        let mut unlinked = false;
+        if user.name != name {
+            insert_profile_change(&mut tx, user.id, "name", Some(&user.name), Some(name), addr).await?;
+        }
+        if user.phone != phone {
+            insert_profile_change(&mut tx, user.id, "phone", Some(&user.phone), Some(phone), addr).await?;
+            // the alias is of the old phone
+            let result = query("DELETE FROM bank_phone_alias WHERE id=?")
+                .bind(user.id)
+                .execute(&mut *tx).await?;
+            unlinked = result.rows_affected() == 1;
+        }
+        tx.commit().await?;
+        info!("Updated profile for {}", user.id);
+        Ok(unlinked)
+    }
+
+    pub async fn account_status(&self, id: u32) -> anyhow::Result<AccountStatus> {
+        let mut con = self.0.sql_pool.acquire().await?;
+        let result = query("SELECT status FROM bank_user WHERE id=?")
+            .bind(id)
+            .fetch_optional(con.as_mut()).await?;
+        match result {
+            Some(row) => status_of(row.get("status")),
+            None => Err(UserInputError::new(ErrorCode::AccountNotFound))?
+        }
+    }
+
+    /// Change the status with the reason recorded, return the old one.
+    ///
+    /// Fails if the change is not allowed, see [`AccountStatus::can_change_to`]
+    pub async fn set_status(&self, id: u32, to: AccountStatus, reason: &str, actor: &str) -> anyhow::Result<AccountStatus> {
+        let mut tx = self.0.sql_pool.begin().await?;
+        let result = query("SELECT status FROM bank_user WHERE id=? FOR UPDATE")
+            .bind(id)
+            .fetch_optional(&mut *tx).await?;
+        let from = match result {
+            Some(row) => status_of(row.get("status"))?,
+            None => Err(UserInputError::new(ErrorCode::AccountNotFound))?
+        };
+        if !from.can_change_to(to) {
+            return Err(anyhow!("Could not change {} from {} to {}", id, from.name(), to.name()));
+        }
+        query("UPDATE bank_user SET status=? WHERE id=?")
+            .bind(to as u8)
+            .bind(id)
+            .execute(&mut *tx).await?;
+        insert_status_change(&mut tx, id, from, to, reason, actor).await?;
+        tx.commit().await?;
+        info!("Changed the status of {} from {} to {} by {}", id, from.name(), to.name(), actor);
+        Ok(from)
+    }
+
+    /// The dormancy counts from the last login
+    pub async fn touch_active(&self, id: u32) -> anyhow::Result<()> {
+        let mut con = self.0.sql_pool.acquire().await?;
+        query("UPDATE bank_user SET last_active=? WHERE id=?")
+            .bind(Utc::now())
+            .bind(id)
+            .execute(con.as_mut()).await?;
+        Ok(())
+    }
+
+    /// Mark the active accounts without login since `before` dormant, return how many
+    pub async fn mark_dormant(&self, before: DateTime<Utc>, reason: &str) -> anyhow::Result<usize> {
+        let ids: Vec<u32> = {
+            let mut con = self.0.sql_pool.acquire().await?;
+            query("SELECT id FROM bank_user WHERE status=? AND last_active<?")
+                .bind(AccountStatus::Active as u8)
+                .bind(before)
+                .fetch_all(con.as_mut()).await?
+                .into_iter()
+                .map(|row| row.get::<i32, _>("id") as u32)
+                .collect()
+        };
+        let mut count = 0;
+        for id in ids {
+            let mut tx = self.0.sql_pool.begin().await?;
+            // may log in after selected
+            let result = query("UPDATE bank_user SET status=? WHERE id=? AND status=? AND last_active<?")
+                .bind(AccountStatus::Dormant as u8)
+                .bind(id)
+                .bind(AccountStatus::Active as u8)
+                .bind(before)
+                .execute(&mut *tx).await?;
+            if result.rows_affected() != 1 {
+                continue;
+            }
+            insert_status_change(&mut tx, id, AccountStatus::Active, AccountStatus::Dormant, reason, "system").await?;
+            tx.commit().await?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Pay the balance to `payout` and close the account, return the amount paid.
+    ///
+    /// The trade logs are kept for reading, the linked phone is unlinked.
+    pub async fn close_account(&self, id: u32, payout: u32, reason: &str, actor: &str) -> anyhow::Result<u32> {
+        if id == payout {
+            Err(UserInputError::new(ErrorCode::PayoutToSelf))?
+        }
+        let mut tx = self.0.sql_pool.begin().await?;
+        let result = query("SELECT status, balance FROM bank_user WHERE id=? FOR UPDATE")
+            .bind(id)
+            .fetch_optional(&mut *tx).await?;
+        let (from, balance) = match result {
+            Some(row) => (status_of(row.get("status"))?, row.get::<u32, _>("balance")),
+            None => Err(UserInputError::new(ErrorCode::AccountNotFound))?
+        };
+        if !from.can_change_to(AccountStatus::Closed) {
+            Err(UserInputError::new(ErrorCode::AccountClosed))?
+        }
+        let result = query("SELECT status, balance FROM bank_user WHERE id=? FOR UPDATE")
+            .bind(payout)
+            .fetch_optional(&mut *tx).await?;
+        let (target_status, target_balance) = match result {
+            Some(row) => (status_of(row.get("status"))?, row.get::<u32, _>("balance")),
+            None => Err(UserInputError::new(ErrorCode::AccountNotFound))?
+        };
+        if !target_status.accepts_credit() {
+            Err(UserInputError::new(ErrorCode::RecipientClosed))?
+        }
+        if target_balance + balance > BALANCE_LIMIT {
+            Err(UserInputError::with_params(ErrorCode::RecipientBalanceLimit, vec![format_cents(BALANCE_LIMIT)]))?
+        }
+        if balance > 0 {
+            query("UPDATE bank_user SET balance=balance+? WHERE id=?")
+                .bind(balance)
+                .bind(payout)
+                .execute(&mut *tx).await?;
+            query("INSERT INTO trade_logs(receiver, sender, time, amount) VALUES(?, ?, ?, ?);")
+                .bind(payout)
+                .bind(id.to_string())
+                .bind(Utc::now())
+                .bind(balance as i32)
+                .execute(&mut *tx).await?;
+        }
+        query("UPDATE bank_user SET status=?, balance=0 WHERE id=?")
+            .bind(AccountStatus::Closed as u8)
+            .bind(id)
+            .execute(&mut *tx).await?;
+        query("DELETE FROM bank_phone_alias WHERE id=?")
+            .bind(id)
+            .execute(&mut *tx).await?;
+        insert_status_change(&mut tx, id, from, AccountStatus::Closed, reason, actor).await?;
+        tx.commit().await?;
+        info!("Closed {} by {} and paid {} to {}", id, actor, balance, payout);
+        Ok(balance)
+    }
+
+    /// The status changes of the account, newest first
+    pub async fn status_changes(&self, id: u32, limit: u32) -> anyhow::Result<Vec<StatusChange>> {
+        let mut con = self.0.sql_pool.acquire().await?;
+        let result = query("SELECT * FROM bank_status_changes WHERE id=? ORDER BY cid DESC LIMIT ?")
+            .bind(id)
+            .bind(limit)
+            .fetch_all(con.as_mut()).await?;
+        result.into_iter().map(|row| Ok(StatusChange {
+            from: status_of(row.get("old_status"))?,
+            to: status_of(row.get("new_status"))?,
+            reason: row.get("reason"),
+            actor: row.get("actor"),
+            time: row.get("time"),
+        })).collect()
+    }
+
+    /// Change the product or the segment deciding the fees, recorded like the profile
+    pub async fn set_fee_class(&self, id: u32, field: FeeClass, value: &str) -> anyhow::Result<()> {
+        let column = match field {
+            FeeClass::Product => "product",
+            FeeClass::Segment => "segment",
+        };
+        let mut tx = self.0.sql_pool.begin().await?;
+        let result = query(&format!("SELECT {} FROM bank_user WHERE id=? FOR UPDATE", column))
+            .bind(id)
+            .fetch_optional(&mut *tx).await?;
+        let old: String = match result {
+            Some(row) => row.get(0),
+            None => Err(UserInputError::new(ErrorCode::AccountNotFound))?
+        };
+        query(&format!("UPDATE bank_user SET {}=? WHERE id=?", column))
+            .bind(value)
+            .bind(id)
+            .execute(&mut *tx).await?;
+        insert_profile_change(&mut tx, id, column, Some(&old), Some(value), None).await?;
+        tx.commit().await?;
+        info!("Changed the {} of {} from {} to {}", column, id, old, value);
         Ok(())
     }
 
-    pub async fn add_balance(&self, who: u32, sender: &str, amount: u32) -> anyhow::Result<MySqlQueryResult> {
+    pub async fn get_totp(&self, id: u32) -> anyhow::Result<Option<TotpRecord>> {
         let mut con = self.0.sql_pool.acquire().await?;
-        let result = query("UPDATE bank_user SET balance=balance+? WHERE id=?")
+        let result = query("SELECT * FROM bank_totp WHERE id=?")
+            .bind(id)
+            .fetch_optional(con.as_mut()).await?;
+        Ok(result.map(|row| TotpRecord {
+            secret: row.get("secret"),
+            pending_secret: row.get("pending_secret"),
+            enabled: row.get("enabled"),
+            last_step: row.get("last_step"),
+        }))
+    }
+
+    pub async fn is_totp_enabled(&self, id: u32) -> anyhow::Result<bool> {
+        Ok(self.get_totp(id).await?.map(|x| x.enabled).unwrap_or(false))
+    }
+
+    /// Save the new secret which is not enabled until the first code verified.
+    pub async fn set_pending_totp(&self, id: u32, secret: &str) -> anyhow::Result<()> {
+        let mut con = self.0.sql_pool.acquire().await?;
+        query("INSERT INTO bank_totp(id, pending_secret, enabled, last_step) VALUES(?, ?, FALSE, 0) ON DUPLICATE KEY UPDATE pending_secret=?")
+            .bind(id)
+            .bind(secret)
+            .bind(secret)
+            .execute(con.as_mut()).await?;
+        Ok(())
+    }
+
+    /// Enable the pending secret and replace the recovery codes with the new ones.
+    pub async fn enable_totp(&self, id: u32, step: u64, recovery_codes: &[String]) -> anyhow::Result<()> {
+        let mut tx = self.0.sql_pool.begin().await?;
+        query("UPDATE bank_totp SET secret=pending_secret, pending_secret=NULL, enabled=TRUE, last_step=? WHERE id=? AND pending_secret IS NOT NULL")
+            .bind(step)
+            .bind(id)
+            .execute(&mut *tx).await?;
+        query("DELETE FROM bank_recovery_codes WHERE id=?")
+            .bind(id)
+            .execute(&mut *tx).await?;
+        for code in recovery_codes {
+            query("INSERT INTO bank_recovery_codes(id, code_hash) VALUES(?, ?);")
+                .bind(id)
+                .bind(totp::hash_recovery_code(code))
+                .execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        info!("Enabled totp for {}", id);
+        Ok(())
+    }
+
+    pub async fn disable_totp(&self, id: u32) -> anyhow::Result<()> {
+        let mut tx = self.0.sql_pool.begin().await?;
+        query("DELETE FROM bank_totp WHERE id=?")
+            .bind(id)
+            .execute(&mut *tx).await?;
+        query("DELETE FROM bank_recovery_codes WHERE id=?")
+            .bind(id)
+            .execute(&mut *tx).await?;
+        tx.commit().await?;
+        info!("Disabled totp for {}", id);
+        Ok(())
+    }
+
+    /// Check the totp code (or one unused recovery code if `allow_recovery`) for the account.
+    ///
+    /// Accepted codes are consumed. Returns true if totp is not enabled.
+    pub async fn check_totp(&self, id: u32, code: &str, allow_recovery: bool) -> anyhow::Result<bool> {
+        let record = match self.get_totp(id).await? {
+            Some(record) if record.enabled => record,
+            _ => return Ok(true),
+        };
+        let secret = totp::base32_decode(&record.secret).ok_or(anyhow!("Bad totp secret for {}", id))?;
+        let mut con = self.0.sql_pool.acquire().await?;
+        if let Some(step) = self.0.totp.verify(&secret, code, record.last_step) {
+            // only the first one using this step wins
+            let result = query("UPDATE bank_totp SET last_step=? WHERE id=? AND last_step<?")
+                .bind(step)
+                .bind(id)
+                .bind(step)
+                .execute(con.as_mut()).await?;
+            return Ok(result.rows_affected() == 1);
+        }
+        if allow_recovery {
+            let result = query("UPDATE bank_recovery_codes SET used=TRUE WHERE id=? AND code_hash=? AND used=FALSE")
+                .bind(id)
+                .bind(totp::hash_recovery_code(code))
+                .execute(con.as_mut()).await?;
+            if result.rows_affected() == 1 {
+                info!("Account {} used one recovery code", id);
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    pub async fn get_payees(&self, owner: u32) -> anyhow::Result<Vec<Payee>> {
+        let mut con = self.0.sql_pool.acquire().await?;
+        let result = query("SELECT * FROM bank_payees WHERE owner=? ORDER BY last_used IS NULL, last_used DESC, nickname")
+            .bind(owner)
+            .fetch_all(con.as_mut()).await?;
+        Ok(result.into_iter().map(|row| Payee {
+            pid: row.get("pid"),
+            nickname: row.get("nickname"),
+            account: row.get::<i32, _>("account") as u32,
+            last_used: row.get("last_used"),
+        }).collect())
+    }
+
+    pub async fn count_payees(&self, owner: u32) -> anyhow::Result<i64> {
+        let mut con = self.0.sql_pool.acquire().await?;
+        let result = query("SELECT COUNT(*) FROM bank_payees WHERE owner=?")
+            .bind(owner)
+            .fetch_one(con.as_mut()).await?;
+        Ok(result.get(0))
+    }
+
+    /// Return false if the account is already saved
+    pub async fn add_payee(&self, owner: u32, nickname: &str, account: u32) -> anyhow::Result<bool> {
+        let mut con = self.0.sql_pool.acquire().await?;
+        let result = query("INSERT IGNORE INTO bank_payees(owner, nickname, account) VALUES(?, ?, ?);")
+            .bind(owner)
+            .bind(nickname)
+            .bind(account)
+            .execute(con.as_mut()).await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Return false if no such payee or the account is saved as another payee
+    pub async fn update_payee(&self, owner: u32, pid: i32, nickname: &str, account: u32) -> anyhow::Result<bool> {
+        let mut con = self.0.sql_pool.acquire().await?;
+        let exist = query("SELECT pid FROM bank_payees WHERE owner=? AND account=? AND pid<>?")
+            .bind(owner)
+            .bind(account)
+            .bind(pid)
+            .fetch_optional(con.as_mut()).await?;
+        if exist.is_some() {
+            return Ok(false);
+        }
+        let result = query("SELECT pid FROM bank_payees WHERE owner=? AND pid=?")
+            .bind(owner)
+            .bind(pid)
+            .fetch_optional(con.as_mut()).await?;
+        if result.is_none() {
+            return Ok(false);
+        }
+        query("UPDATE bank_payees SET nickname=?, account=? WHERE owner=? AND pid=?")
+            .bind(nickname)
+            .bind(account)
+            .bind(owner)
+            .bind(pid)
+            .execute(con.as_mut()).await?;
+        Ok(true)
+    }
+
+    pub async fn delete_payee(&self, owner: u32, pid: i32) -> anyhow::Result<bool> {
+        let mut con = self.0.sql_pool.acquire().await?;
+        let result = query("DELETE FROM bank_payees WHERE owner=? AND pid=?")
+            .bind(owner)
+            .bind(pid)
+            .execute(con.as_mut()).await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Update the last used time if the account is a saved payee
+    pub async fn touch_payee(&self, owner: u32, account: u32) -> anyhow::Result<()> {
+        let now = chrono::DateTime::<Utc>::from(SystemTime::now());
+        let mut con = self.0.sql_pool.acquire().await?;
+        query("UPDATE bank_payees SET last_used=? WHERE owner=? AND account=?")
+            .bind(now)
+            .bind(owner)
+            .bind(account)
+            .execute(con.as_mut()).await?;
+        Ok(())
+    }
+
+    /// The accounts `owner` transferred to recently, newest first
+    pub async fn recent_recipients(&self, owner: u32, limit: u32) -> anyhow::Result<Vec<(u32, DateTime<Utc>)>> {
+        let mut con = self.0.sql_pool.acquire().await?;
+        let result = query("SELECT receiver, MAX(time) AS last_time FROM trade_logs WHERE sender=? AND receiver<>? GROUP BY receiver ORDER BY last_time DESC LIMIT ?")
+            .bind(owner.to_string())
+            .bind(owner)
+            .bind(limit)
+            .fetch_all(con.as_mut()).await?;
+        Ok(result.into_iter()
+            .map(|row| (row.get::<i32, _>("receiver") as u32, row.get("last_time")))
+            .collect())
+    }
+
+    /// Whether `owner` transferred to the account before
+    pub async fn paid_before(&self, owner: u32, account: u32) -> anyhow::Result<bool> {
+        let mut con = self.0.sql_pool.acquire().await?;
+        let result = query("SELECT tid FROM trade_logs WHERE sender=? AND receiver=? LIMIT 1")
+            .bind(owner.to_string())
+            .bind(account)
+            .fetch_optional(con.as_mut()).await?;
+        Ok(result.is_some())
+    }
+
+    /// The times of the withdrawals or the transfers of the account since then
+    pub async fn recent_operations(&self, id: u32, operation: &str, since: DateTime<Utc>) -> anyhow::Result<Vec<DateTime<Utc>>> {
+        let statement = match operation {
+            "withdraw" => query("SELECT time FROM trade_logs WHERE receiver=? AND sender='取款' AND time>=?")
+                .bind(id),
+            _ => query("SELECT time FROM trade_logs WHERE sender=? AND receiver<>? AND time>=?")
+                .bind(id.to_string())
+                .bind(id),
+        };
+        let mut con = self.0.sql_pool.acquire().await?;
+        let result = statement.bind(since).fetch_all(con.as_mut()).await?;
+        Ok(result.into_iter().map(|row| row.get("time")).collect())
+    }
+
+    /// Remember the address or the device used by the account
+    pub async fn record_seen(&self, id: u32, kind: Seen, value: &str) -> anyhow::Result<()> {
+        let now = Utc::now();
+        let mut con = self.0.sql_pool.acquire().await?;
+        query("INSERT INTO bank_seen(id, kind, value, first_seen, last_seen) VALUES(?, ?, ?, ?, ?) ON DUPLICATE KEY UPDATE last_seen=?")
+            .bind(id)
+            .bind(kind as u8)
+            .bind(value)
+            .bind(now)
+            .bind(now)
+            .bind(now)
+            .execute(con.as_mut()).await?;
+        Ok(())
+    }
+
+    /// When the account first used the address or the device, None if never
+    pub async fn first_seen(&self, id: u32, kind: Seen, value: &str) -> anyhow::Result<Option<DateTime<Utc>>> {
+        let mut con = self.0.sql_pool.acquire().await?;
+        let result = query("SELECT first_seen FROM bank_seen WHERE id=? AND kind=? AND value=?")
+            .bind(id)
+            .bind(kind as u8)
+            .bind(value)
+            .fetch_optional(con.as_mut()).await?;
+        Ok(result.map(|row| row.get("first_seen")))
+    }
+
+    /// Save the decision with the ids of the rules fired
+    pub async fn log_risk_decision(&self, id: u32, operation: &str, amount: u32, decision: &Decision, signals: &Signals) -> anyhow::Result<()> {
+        let mut con = self.0.sql_pool.acquire().await?;
+        query("INSERT INTO bank_risk_decisions(id, operation, amount, action, rules, addr, device, time) VALUES(?, ?, ?, ?, ?, ?, ?, ?);")
+            .bind(id)
+            .bind(operation)
             .bind(amount)
-            .bind(who)
+            .bind(decision.action.name())
+            .bind(decision.rules.join(","))
+            .bind(signals.ip.map(|x| x.to_string()))
+            .bind(signals.device.as_deref())
+            .bind(Utc::now())
             .execute(con.as_mut()).await?;
-        self.insert_trade_log(who, sender, amount as i32).await?;
+        Ok(())
+    }
 
-        Ok(result)
+    /// Link the phone to the account for receiving transfers.
+    ///
+    /// Return false if the phone is linked to another account.
+    pub async fn link_phone(&self, id: u32, phone: &str) -> anyhow::Result<bool> {
+        let mut tx = self.0.sql_pool.begin().await?;
+        // the account may link another phone before, kept if this one is taken
+        query("DELETE FROM bank_phone_alias WHERE id=? AND phone<>?")
+            .bind(id)
+            .bind(phone)
+            .execute(&mut *tx).await?;
+        // never takes the phone of another account
+        query("INSERT IGNORE INTO bank_phone_alias(phone, id) VALUES(?, ?);")
+            .bind(phone)
+            .bind(id)
+            .execute(&mut *tx).await?;
+        let owner = query("SELECT id FROM bank_phone_alias WHERE phone=?")
+            .bind(phone)
+            .fetch_optional(&mut *tx).await?
+            .map(|row| row.get::<i32, _>("id") as u32);
+        if owner != Some(id) {
+            return Ok(false);
+        }
+        tx.commit().await?;
+        info!("Linked phone for {}", id);
+        Ok(true)
     }
+
+    /// Return false if the account has no linked phone.
+    pub async fn unlink_phone(&self, id: u32) -> anyhow::Result<bool> {
+        let mut con = self.0.sql_pool.acquire().await?;
+        let result = query("DELETE FROM bank_phone_alias WHERE id=?")
+            .bind(id)
+            .execute(con.as_mut()).await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn resolve_phone(&self, phone: &str) -> anyhow::Result<Option<u32>> {
+        let mut con = self.0.sql_pool.acquire().await?;
+        let result = query("SELECT id FROM bank_phone_alias WHERE phone=?")
+            .bind(phone)
+            .fetch_optional(con.as_mut()).await?;
+        Ok(result.map(|row| row.get::<i32, _>("id") as u32))
+    }
+}
+
+/// Take the amount with the fee only if the balance is enough for both
+async fn debit(tx: &mut Transaction<'_, MySql>, id: u32, amount: u32, fee: u32) -> anyhow::Result<()> {
+    let total = amount as u64 + fee as u64;
+    let result = query("UPDATE bank_user SET balance=balance-? WHERE id=? AND balance>=?")
+        .bind(total)
+        .bind(id)
+        .bind(total)
+        .execute(&mut **tx).await?;
+    if result.rows_affected() != 1 {
+        Err(UserInputError::new(ErrorCode::InsufficientBalance))?
+    }
+    Ok(())
+}
+
+/// Return the tid
+async fn insert_trade(tx: &mut Transaction<'_, MySql>, receiver: u32, sender: &str, amount: i32, parent: Option<u64>) -> anyhow::Result<u64> {
+    let result = query("INSERT INTO trade_logs(receiver, sender, time, amount, parent) VALUES(?, ?, ?, ?, ?);")
+        .bind(receiver)
+        .bind(sender)
+        .bind(Utc::now())
+        .bind(amount)
+        .bind(parent)
+        .execute(&mut **tx).await?;
+    Ok(result.last_insert_id())
+}
+
+/// Log the fee of the operation logged as `parent`, already taken by [debit]
+async fn post_fee(tx: &mut Transaction<'_, MySql>, id: u32, parent: u64, fee: u32) -> anyhow::Result<()> {
+    if fee > 0 {
+        insert_trade(tx, id, FEE_SENDER, -(fee as i32), Some(parent)).await?;
+    }
+    Ok(())
+}
+
+async fn insert_profile_change(tx: &mut Transaction<'_, MySql>, id: u32, field: &str, old: Option<&str>, new: Option<&str>, addr: Option<SocketAddr>) -> anyhow::Result<()> {
+    query("INSERT INTO bank_profile_changes(id, field, old_value, new_value, addr, time) VALUES(?, ?, ?, ?, ?, ?);")
+        .bind(id)
+        .bind(field)
+        .bind(old)
+        .bind(new)
+        .bind(addr.map(|x| x.ip().to_string()))
+        .bind(Utc::now())
+        .execute(&mut **tx).await?;
+    Ok(())
+}
+
+/// The columns added after the tables created: (table, column, definition)
+const ADDED_COLUMNS: [(&str, &str, &str); 6] = [
+    ("bank_user", "status", "TINYINT UNSIGNED NOT NULL DEFAULT '0'"),
+    ("bank_user", "last_active", "DATETIME"),
+    ("bank_user", "product", "VARCHAR(20) NOT NULL DEFAULT 'standard'"),
+    ("bank_user", "segment", "VARCHAR(20) NOT NULL DEFAULT 'retail'"),
+    ("trade_logs", "parent", "INTEGER"),
+    ("bank_totp", "pending_secret", "VARCHAR(64)"),
+];
+
+/// Add the columns missing in the tables created by the older versions
+async fn migrate(pool: &MySqlPool) -> anyhow::Result<()> {
+    for (table, column, definition) in ADDED_COLUMNS {
+        let exists: i64 = query("SELECT COUNT(*) FROM information_schema.COLUMNS WHERE TABLE_SCHEMA=DATABASE() AND TABLE_NAME=? AND COLUMN_NAME=?")
+            .bind(table)
+            .bind(column)
+            .fetch_one(pool).await?
+            .get(0);
+        if exists > 0 {
+            continue;
+        }
+        pool.execute(format!("ALTER TABLE `{}` ADD COLUMN `{}` {}", table, column, definition).as_str()).await?;
+        info!("Added {}.{}", table, column);
+    }
+    // the inactivity counts from the upgrade
+    query("UPDATE bank_user SET last_active=? WHERE last_active IS NULL")
+        .bind(Utc::now())
+        .execute(pool).await?;
+    Ok(())
+}
+
+/// Send the notice to the logged sessions of `id` except `except`, return the count sent to
+pub(crate) async fn push_notice(sessions: &SessionRegistry, id: u32, except: Option<SocketAddr>, notice: Notification) -> anyhow::Result<usize> {
+    let data = Tagged { id: UNSOLICITED, body: Response::Notify(notice) }.to_packet()?;
+    Ok(sessions.send(id, except, &data).await)
+}
+
+fn status_of(x: u8) -> anyhow::Result<AccountStatus> {
+    AccountStatus::from_u8(x).ok_or(anyhow!("Bad account status {}", x))
+}
+
+async fn insert_status_change(tx: &mut Transaction<'_, MySql>, id: u32, from: AccountStatus, to: AccountStatus, reason: &str, actor: &str) -> anyhow::Result<()> {
+    query("INSERT INTO bank_status_changes(id, old_status, new_status, reason, actor, time) VALUES(?, ?, ?, ?, ?, ?);")
+        .bind(id)
+        .bind(from as u8)
+        .bind(to as u8)
+        .bind(reason)
+        .bind(actor)
+        .bind(Utc::now())
+        .execute(&mut **tx).await?;
+    Ok(())
 }
 
 impl DataHandlerGenerator for BankServer {
-    fn generate(&self, _: SocketAddr) -> Box<dyn DataHandler> {
-        Box::new(BankConnection::new(self.clone()))
+    fn generate(&self, addr: SocketAddr) -> Box<dyn DataHandler> {
+        Box::new(BankConnection::new(self.clone(), addr))
+    }
+
+    fn admit(&self, addr: SocketAddr) -> bool {
+        match self.0.bans.banned(addr.ip(), Instant::now()) {
+            Some(left) => {
+                info!("Rejected {} banned for {:?} more", addr, left);
+                self.0.metrics.banned();
+                false
+            }
+            None => true,
+        }
     }
 }
\ No newline at end of file
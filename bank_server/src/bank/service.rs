@@ -0,0 +1,428 @@
+//! The operations of the customers, shared by the handlers of the connections and the http gateway.
+//!
+//! The operations check the input and return [`UserInputError`] for the customer to fix,
+//! the caller only decides how to reply.
+
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::anyhow;
+use bank_protocol::{account, ErrorCode, NotifyKind, phone, TradeRecord};
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use rand::Rng;
+use sqlx::{MySql, query, Row};
+use sqlx::pool::PoolConnection;
+
+use crate::bank::{BankServer, UserInputError};
+use crate::bank::fee::{Channel, FEE_OPERATIONS, FEE_SENDER, Quote};
+use crate::bank::lifecycle::AccountStatus;
+use crate::bank::risk::{Action, Facts, RiskRule, RuleKind, Signals};
+use crate::bank::server::Seen;
+use crate::bank::totp::TOTP_TRANSFER_THRESHOLD;
+use crate::bank::user::User;
+
+/// Collisions allowed when allocating a random account id
+const MAX_ALLOCATE_TRIES: usize = 16;
+
+/// The balance could not be more than it, in cents
+pub const BALANCE_LIMIT: u32 = 10000;
+
+/// Like "100.00"
+pub fn format_cents(cents: u32) -> String {
+    format!("{}.{:02}", cents / 100, cents % 100)
+}
+
+pub enum LoginResult {
+    Logged(User),
+    /// The password is right but the totp code is needed
+    TotpRequired(User),
+}
+
+/// One page of the trade logs, the page starts from 1
+pub struct History {
+    pub current_page: u32,
+    pub total_page: u32,
+    pub trades: Vec<TradeRecord>,
+}
+
+pub async fn get_user(sql: &mut PoolConnection<MySql>, id: u32) -> anyhow::Result<User> {
+    let result = query("SELECT * FROM bank_user WHERE id=?").bind(id)
+        .fetch_optional(sql.as_mut()).await?;
+    let result = if let Some(result) = result {
+        result
+    } else {
+        Err(UserInputError::new(ErrorCode::AccountNotFound))?
+    };
+
+    let user = User {
+        id,
+        balance: result.get("balance"),
+        name: result.get::<Option<&str>, _>("name").unwrap_or("").to_string(),
+        phone: result.get::<Option<&str>, _>("phone_number").unwrap_or("").to_string(),
+        product: result.get("product"),
+        segment: result.get("segment"),
+    };
+
+    Ok(user)
+}
+
+/// Check the password, and whether the totp code is needed
+pub async fn login(server: &BankServer, id: u32, password: u32) -> anyhow::Result<LoginResult> {
+    if !server.check_password(id, password).await? {
+        Err(UserInputError::new(ErrorCode::WrongPassword))?
+    }
+    let mut sql = server.0.sql_pool.acquire().await?;
+    let user = get_user(&mut sql, id).await?;
+    if server.is_totp_enabled(id).await? {
+        return Ok(LoginResult::TotpRequired(user));
+    }
+    Ok(LoginResult::Logged(user))
+}
+
+/// After the password and the totp code checked, the dormant account is active again.
+///
+/// The address is remembered for the risk rules.
+/// Return the status for the caller to tell the frozen or closed customer.
+pub async fn logged_in(server: &BankServer, id: u32, ip: Option<IpAddr>) -> anyhow::Result<AccountStatus> {
+    server.touch_active(id).await?;
+    if let Some(ip) = ip {
+        server.record_seen(id, Seen::Ip, &ip.to_string()).await?;
+    }
+    let status = server.account_status(id).await?;
+    if status == AccountStatus::Dormant {
+        server.set_status(id, AccountStatus::Active, "logged in again", "customer").await?;
+        return Ok(AccountStatus::Active);
+    }
+    Ok(status)
+}
+
+/// Refuse the operation not allowed by the status of the account, `op` is the name of the router
+pub async fn check_operation(server: &BankServer, id: u32, op: &str) -> anyhow::Result<()> {
+    let status = server.account_status(id).await?;
+    if !status.allows(op) {
+        debug!("Refused {} of the {} account {}", op, status.name(), id);
+        Err(status.refusal())?
+    }
+    Ok(())
+}
+
+/// Check the password and the totp code if enabled, for the sensitive operations
+async fn reauthenticate(server: &BankServer, id: u32, password: u32, code: &str) -> anyhow::Result<()> {
+    // the code is not consumed by the wrong password
+    if !server.check_password(id, password).await? {
+        Err(UserInputError::new(ErrorCode::WrongPassword))?
+    }
+    if server.is_totp_enabled(id).await? {
+        if code.is_empty() {
+            Err(UserInputError::new(ErrorCode::TotpCodeRequired))?
+        }
+        if !server.check_totp(id, code, true).await? {
+            Err(UserInputError::new(ErrorCode::WrongTotpCode))?
+        }
+    }
+    Ok(())
+}
+
+/// Remember the device of the session for the risk rules
+pub async fn identify_device(server: &BankServer, id: u32, device: &str) -> anyhow::Result<()> {
+    if device.is_empty() || device.len() > 64 {
+        Err(UserInputError::new(ErrorCode::BadInputLength))?
+    }
+    server.record_seen(id, Seen::Device, device).await
+}
+
+/// Check the risk rules before the operation, the decisions with any rule fired are saved.
+///
+/// The blocked operation is refused, the challenged one needs a new totp code.
+/// Return true if the code is checked.
+async fn check_risk(server: &BankServer, user: &User, operation: &str, amount: u32, payee: Option<u32>, code: &str, signals: &Signals) -> anyhow::Result<bool> {
+    let risk = server.risk();
+    let rules: Vec<&RiskRule> = risk.applicable(operation, amount).collect();
+    if rules.is_empty() {
+        return Ok(false);
+    }
+    let needs = |kind| rules.iter().any(|x| x.kind == kind);
+    let now = Utc::now();
+    let mut facts = Facts::default();
+    if let Some(since) = risk.velocity_since(operation, amount, now) {
+        facts.recent = server.recent_operations(user.id, operation, since).await?;
+    }
+    if let Some(payee) = payee.filter(|_| needs(RuleKind::NewPayee)) {
+        facts.new_payee = !server.paid_before(user.id, payee).await?;
+    }
+    if let Some(device) = signals.device.as_deref().filter(|_| needs(RuleKind::NewDevice)) {
+        facts.device_seen = server.first_seen(user.id, Seen::Device, device).await?;
+    }
+    if let Some(ip) = signals.ip.filter(|_| needs(RuleKind::NewIp)) {
+        facts.ip_seen = server.first_seen(user.id, Seen::Ip, &ip.to_string()).await?;
+    }
+
+    let decision = risk.evaluate(operation, amount, &facts, now);
+    if !decision.rules.is_empty() {
+        info!("Risk {} for {} of {} by {}", decision.action.name(), operation, user.id, decision.rules.join(","));
+        server.log_risk_decision(user.id, operation, amount, &decision, signals).await?;
+    }
+    match decision.action {
+        Action::Allow => Ok(false),
+        Action::Block => Err(UserInputError::new(ErrorCode::RiskBlocked))?,
+        Action::Challenge => {
+            if !server.is_totp_enabled(user.id).await? {
+                Err(UserInputError::new(ErrorCode::RiskTotpNotEnabled))?
+            }
+            if code.is_empty() {
+                Err(UserInputError::new(ErrorCode::RiskChallenge))?
+            }
+            if !server.check_totp(user.id, code, false).await? {
+                Err(UserInputError::new(ErrorCode::WrongTotpCode))?
+            }
+            Ok(true)
+        }
+    }
+}
+
+/// Open a new account with a random id
+pub async fn register(server: &BankServer, password: u32, name: String, phone: String) -> anyhow::Result<User> {
+    if name.is_empty() || name.len() > 60 || phone.len() > 20 {
+        Err(UserInputError::new(ErrorCode::BadInputLength))?
+    }
+
+    let mut sql_connection = server.0.sql_pool.acquire().await?;
+    let mut id = None;
+    for _ in 0..MAX_ALLOCATE_TRIES {
+        let new_id = rand::thread_rng().gen_range(account::NEW_ID_RANGE);
+        let result = query("INSERT IGNORE INTO bank_user(id, password, balance, name, phone_number, status, last_active) VALUES(?, ?, 0, ?, ?, ?, ?);")
+            .bind(new_id)
+            .bind(password as i32)
+            .bind(&name)
+            .bind(&phone)
+            .bind(AccountStatus::Active as u8)
+            .bind(Utc::now())
+            .execute(sql_connection.as_mut()).await?;
+        if result.rows_affected() == 1 {
+            id = Some(new_id);
+            break;
+        }
+    }
+    let id = id.ok_or(anyhow!("Allocate account id failed"))?;
+    info!("Allocated account {} for new user", account::format_account_number(id));
+
+    get_user(&mut sql_connection, id).await
+}
+
+/// Change the password after checked the old one and the totp code if enabled.
+///
+/// The other sessions are logged out, `origin` is the session requested.
+pub async fn change_password(server: &BankServer, id: u32, old: u32, new: u32, code: &str, origin: Option<SocketAddr>) -> anyhow::Result<()> {
+    if old == new {
+        Err(UserInputError::new(ErrorCode::SamePassword))?
+    }
+    check_operation(server, id, "change_password").await?;
+    reauthenticate(server, id, old, code).await?;
+    if !server.change_password(id, old, new, origin).await? {
+        Err(UserInputError::new(ErrorCode::WrongPassword))?
+    }
+    server.revoke_sessions(id, origin).await
+}
+
+/// Change the name or the phone, the password is needed for the phone.
+///
+/// Return true if the linked phone is unlinked for the phone changed.
+pub async fn update_profile(server: &BankServer, user: &mut User, name: &str, phone: &str, password: Option<u32>, origin: Option<SocketAddr>) -> anyhow::Result<bool> {
+    check_operation(server, user.id, "update_profile").await?;
+    let name = name.trim();
+    if name.is_empty() || name.len() > 60 {
+        Err(UserInputError::new(ErrorCode::BadInputLength))?
+    }
+    // the phones saved before are not normalized
+    let phone = if phone == user.phone || phone.trim().is_empty() {
+        phone.trim().to_string()
+    } else {
+        match phone::normalize_phone(phone) {
+            Some(phone) => phone,
+            None => Err(UserInputError::new(ErrorCode::BadPhone))?
+        }
+    };
+    if name == user.name && phone == user.phone {
+        Err(UserInputError::new(ErrorCode::ProfileUnchanged))?
+    }
+    if phone != user.phone {
+        match password {
+            None => Err(UserInputError::new(ErrorCode::PasswordRequired))?,
+            Some(password) if !server.check_password(user.id, password).await? => Err(UserInputError::new(ErrorCode::WrongPassword))?,
+            Some(_) => {}
+        }
+    }
+    let unlinked = server.update_profile(user, name, &phone, origin).await?;
+
+    let mut sql_connection = server.0.sql_pool.acquire().await?;
+    *user = get_user(&mut sql_connection, user.id).await?;
+    Ok(unlinked)
+}
+
+/// `origin` is the session requested, which is not notified
+pub async fn deposit(server: &BankServer, user: &mut User, amount: u32, origin: Option<SocketAddr>) -> anyhow::Result<()> {
+    info!("Deposit {}", amount);
+    check_operation(server, user.id, "deposit").await?;
+    if user.balance as u64 + amount as u64 > BALANCE_LIMIT as u64 {
+        Err(UserInputError::with_params(ErrorCode::BalanceLimit, vec![format_cents(BALANCE_LIMIT)]))?
+    }
+    let mut sql_connection = server.0.sql_pool.acquire().await?;
+    let result = query("UPDATE bank_user SET balance=balance+? WHERE id=?")
+        .bind(amount)
+        .bind(user.id)
+        .execute(sql_connection.as_mut()).await?;
+    info!("Deposit result: {:?}", result);
+    server.insert_trade_log(user.id, "存款", amount as i32).await?;
+    server.notify(user.id, origin, NotifyKind::Credit, amount, "存款", "").await;
+
+    *user = get_user(&mut sql_connection, user.id).await?;
+    Ok(())
+}
+
+/// The fee of the operation for the account, without charging it
+pub fn quote_fee(server: &BankServer, user: &User, operation: &str, amount: u32, channel: Channel) -> anyhow::Result<Quote> {
+    if !FEE_OPERATIONS.contains(&operation) {
+        Err(UserInputError::new(ErrorCode::BadFeeOperation))?
+    }
+    if amount == 0 {
+        Err(UserInputError::new(ErrorCode::BadAmount))?
+    }
+    Ok(server.fees().quote(operation, amount, &user.product, &user.segment, channel))
+}
+
+/// The fee is charged in the transaction of the operation, only logged and notified here
+async fn notify_fee(server: &BankServer, user: &User, operation: &str, quote: &Quote, origin: Option<SocketAddr>) {
+    if quote.fee == 0 {
+        return;
+    }
+    info!("Charged {} fee {} of {} by rule {}", user.id, quote.fee, operation, quote.rule.as_deref().unwrap_or(""));
+    server.notify(user.id, origin, NotifyKind::Debit, quote.fee, FEE_SENDER, "").await;
+}
+
+/// The totp code is checked if challenged by the risk rules.
+///
+/// `origin` is the session requested, which is not notified
+pub async fn withdraw(server: &BankServer, user: &mut User, amount: u32, code: &str, signals: &Signals, origin: Option<SocketAddr>) -> anyhow::Result<()> {
+    info!("Withdraw {}", amount);
+    check_operation(server, user.id, "withdraw").await?;
+    let quote = quote_fee(server, user, "withdraw", amount, signals.channel)?;
+    if (user.balance as u64) < amount as u64 + quote.fee as u64 {
+        Err(UserInputError::new(ErrorCode::InsufficientBalance))?
+    }
+    check_risk(server, user, "withdraw", amount, None, code, signals).await?;
+    server.withdraw(user.id, amount, quote.fee).await?;
+    info!("Withdrew {} from {}", amount, user.id);
+    server.notify(user.id, origin, NotifyKind::Debit, amount, "取款", "").await;
+    notify_fee(server, user, "withdraw", &quote, origin).await;
+
+    let mut sql_connection = server.0.sql_pool.acquire().await?;
+    *user = get_user(&mut sql_connection, user.id).await?;
+    Ok(())
+}
+
+/// Resolve the transfer target (account number or linked phone) to the account id.
+///
+/// Also return the target normalized for the client to send back.
+pub async fn resolve_target(server: &BankServer, target: &str) -> anyhow::Result<(u32, String)> {
+    if let Some(phone) = phone::normalize_phone(target) {
+        return match server.resolve_phone(&phone).await? {
+            Some(id) => Ok((id, phone)),
+            None => Err(UserInputError::new(ErrorCode::PhoneNotLinked))?
+        };
+    }
+    match account::parse_account_number(target) {
+        Ok(id) => Ok((id, account::format_account_number(id))),
+        Err(e) => Err(UserInputError::with_params(ErrorCode::BadAccountNumber, vec![e.msg().to_string()]))?
+    }
+}
+
+/// Transfer to the resolved account, the totp code is checked for the large amount or if challenged
+/// by the risk rules.
+///
+/// `origin` is the session requested, which is not notified
+pub async fn transfer(server: &BankServer, user: &mut User, target: u32, amount: u32, code: &str, signals: &Signals, origin: Option<SocketAddr>) -> anyhow::Result<()> {
+    check_operation(server, user.id, "transfer").await?;
+    let quote = quote_fee(server, user, "transfer", amount, signals.channel)?;
+    if !server.account_status(target).await?.accepts_credit() {
+        Err(UserInputError::new(ErrorCode::RecipientClosed))?
+    }
+    let mut sql_connection = server.0.sql_pool.acquire().await?;
+    let target_user = get_user(&mut sql_connection, target).await?;
+    if target_user.balance as u64 + amount as u64 > BALANCE_LIMIT as u64 {
+        Err(UserInputError::with_params(ErrorCode::RecipientBalanceLimit, vec![format_cents(BALANCE_LIMIT)]))?
+    }
+    if (user.balance as u64) < amount as u64 + quote.fee as u64 {
+        Err(UserInputError::new(ErrorCode::InsufficientBalance))?
+    }
+    // the code is used once
+    let verified = check_risk(server, user, "transfer", amount, Some(target), code, signals).await?;
+    if !verified && amount >= TOTP_TRANSFER_THRESHOLD && !server.check_totp(user.id, code, false).await? {
+        Err(UserInputError::new(ErrorCode::TotpRequiredForTransfer))?
+    }
+    // checked again with the rows locked
+    server.transfer(user.id, target, amount, quote.fee).await?;
+    server.notify(target, None, NotifyKind::Credit, amount, &account::format_account_number(user.id), "").await;
+    server.notify(user.id, origin, NotifyKind::Debit, amount, &account::format_account_number(target), "").await;
+    notify_fee(server, user, "transfer", &quote, origin).await;
+
+    // only for the suggestions, the transfer is done anyway
+    if let Err(e) = server.touch_payee(user.id, target).await {
+        warn!("Touch the payee {} of {} failed for {:?}", target, user.id, e);
+    }
+
+    *user = get_user(&mut sql_connection, user.id).await?;
+    Ok(())
+}
+
+/// Close the account after the password and the totp code checked, the balance is paid to `payout`.
+///
+/// Return the amount paid and the payout normalized, `origin` is the session requested.
+pub async fn close_account(server: &BankServer, user: &mut User, payout: &str, password: u32, code: &str, origin: Option<SocketAddr>) -> anyhow::Result<(u32, String)> {
+    let (target, normalized) = resolve_target(server, payout).await?;
+    if target == user.id {
+        Err(UserInputError::new(ErrorCode::PayoutToSelf))?
+    }
+    check_operation(server, user.id, "close_account").await?;
+    reauthenticate(server, user.id, password, code).await?;
+    let paid = server.close_account(user.id, target, "closed by the customer", "customer").await?;
+    if paid > 0 {
+        server.notify(target, None, NotifyKind::Credit, paid, &account::format_account_number(user.id), "销户转入").await;
+        server.notify(user.id, origin, NotifyKind::Debit, paid, &normalized, "销户").await;
+    }
+
+    let mut sql_connection = server.0.sql_pool.acquire().await?;
+    *user = get_user(&mut sql_connection, user.id).await?;
+    Ok((paid, normalized))
+}
+
+/// The trade logs of the account in time order, `page` starts from 1
+pub async fn history(server: &BankServer, id: u32, page: u32, page_size: u32) -> anyhow::Result<History> {
+    if page == 0 || page_size == 0 {
+        Err(UserInputError::new(ErrorCode::BadInputLength))?
+    }
+    let mut sql_connection = server.0.sql_pool.acquire().await?;
+    let total: i64 = query("SELECT COUNT(*) FROM trade_logs WHERE receiver = ? OR sender = ?")
+        .bind(id)
+        .bind(id.to_string())
+        .fetch_one(sql_connection.as_mut()).await?
+        .get(0);
+    let offset = (page as u64 - 1) * page_size as u64;
+    let result = query("SELECT * FROM trade_logs WHERE receiver = ? OR sender = ? ORDER BY tid LIMIT ? OFFSET ?")
+        .bind(id)
+        .bind(id.to_string())
+        .bind(page_size)
+        .bind(offset)
+        .fetch_all(sql_connection.as_mut()).await?;
+
+    let trades = result.into_iter()
+        .map(|row| TradeRecord {
+            tid: row.get("tid"),
+            receiver: row.get::<i32, _>("receiver") as u32,
+            sender: row.get("sender"),
+            time: row.get::<DateTime<Utc>, _>("time"),
+            amount: row.get("amount"),
+        })
+        .collect();
+    let total_page = (total.max(1) as u64).div_ceil(page_size as u64).min(u32::MAX as u64) as u32;
+    Ok(History { current_page: page, total_page, trades })
+}
@@ -0,0 +1,187 @@
+//! The logged sessions by account id, to push notifications to all clients of one account.
+//!
+//! Sessions keep the sender of the peer instead of the [`Peer`] itself,
+//! because dropping a `Peer` stops its connection.
+//!
+//! The sessions are revoked by the password change: the ones logged in before it are refused,
+//! including the tokens of the http gateway.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use bank_protocol::queue::PeerSender;
+use tokio::sync::RwLock;
+
+use crate::network::NetworkMessage;
+use crate::network::peer::Peer;
+
+struct Session {
+    addr: SocketAddr,
+    sender: PeerSender,
+    listening: Arc<AtomicBool>,
+}
+
+impl Session {
+    fn alive(&self) -> bool {
+        self.listening.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+pub struct SessionRegistry {
+    sessions: RwLock<HashMap<u32, Vec<Session>>>,
+    /// The sessions logged in before it are revoked, by account
+    revoked: Mutex<HashMap<u32, Instant>>,
+}
+
+#[allow(unused)]
+impl SessionRegistry {
+    pub async fn register(&self, id: u32, peer: &Peer) {
+        self.insert(id, Session {
+            addr: peer.addr,
+            sender: peer.sender.clone(),
+            listening: peer.listening.clone(),
+        }).await;
+    }
+
+    async fn insert(&self, id: u32, session: Session) {
+        let mut write = self.sessions.write().await;
+        // logins are not frequent, clean the dead sessions here
+        write.retain(|_, sessions| {
+            sessions.retain(Session::alive);
+            !sessions.is_empty()
+        });
+        let sessions = write.entry(id).or_default();
+        sessions.retain(|x| x.addr != session.addr);
+        sessions.push(session);
+    }
+
+    pub async fn unregister(&self, id: u32, addr: SocketAddr) {
+        let mut write = self.sessions.write().await;
+        if let Some(sessions) = write.get_mut(&id) {
+            sessions.retain(|x| x.alive() && x.addr != addr);
+            if sessions.is_empty() {
+                write.remove(&id);
+            }
+        }
+    }
+
+    /// Remove the session of the stopped peer, whatever the account
+    pub async fn evict(&self, addr: SocketAddr) {
+        let mut write = self.sessions.write().await;
+        write.retain(|_, sessions| {
+            sessions.retain(|x| x.alive() && x.addr != addr);
+            !sessions.is_empty()
+        });
+    }
+
+    /// Send the packet to every session of the account except `except`.
+    ///
+    /// Return the count of sessions sent to.
+    pub async fn send(&self, id: u32, except: Option<SocketAddr>, packet: &[u8]) -> usize {
+        let read = self.sessions.read().await;
+        read.get(&id).map(|sessions| {
+            sessions.iter()
+                .filter(|x| x.alive() && Some(x.addr) != except)
+                .filter(|x| x.sender.send(NetworkMessage::Rely(packet.to_vec())).is_ok())
+                .count()
+        }).unwrap_or(0)
+    }
+
+    /// Revoke the sessions of the account logged in before `now`, the one of `keep` is re-authenticated.
+    ///
+    /// The others are sent the packet and not notified anymore, return the count sent to.
+    pub async fn revoke(&self, id: u32, keep: Option<SocketAddr>, packet: &[u8], now: Instant) -> usize {
+        self.revoked.lock().unwrap().insert(id, now);
+        let mut write = self.sessions.write().await;
+        let Some(sessions) = write.get_mut(&id) else { return 0 };
+        let mut sent = 0;
+        sessions.retain(|x| {
+            if Some(x.addr) == keep {
+                return true;
+            }
+            if x.alive() && x.sender.send(NetworkMessage::Rely(packet.to_vec())).is_ok() {
+                sent += 1;
+            }
+            false
+        });
+        if sessions.is_empty() {
+            write.remove(&id);
+        }
+        sent
+    }
+
+    /// Whether the session of the account logged in at `logged_at` is revoked
+    pub fn is_revoked(&self, id: u32, logged_at: Instant) -> bool {
+        self.revoked.lock().unwrap().get(&id).is_some_and(|x| logged_at < *x)
+    }
+
+    /// Count of accounts with at least one live session
+    pub async fn logged_accounts(&self) -> usize {
+        let read = self.sessions.read().await;
+        read.values().filter(|x| x.iter().any(Session::alive)).count()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+    use std::time::{Duration, Instant};
+
+    use bank_protocol::{Message, Notification, NotifyKind, Response, Tagged, UNSOLICITED};
+    use bank_protocol::queue::{queue, NetworkMessage, QueueConfig};
+    use chrono::Utc;
+
+    use crate::bank::server::push_notice;
+    use crate::bank::session::{Session, SessionRegistry};
+
+    #[test]
+    fn revoke() {
+        let registry = SessionRegistry::default();
+        let before = Instant::now();
+        let now = before + Duration::from_secs(1);
+        let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        assert_eq!(rt.block_on(registry.revoke(7, None, b"bye", now)), 0);
+        assert!(registry.is_revoked(7, before));
+        // re-authenticated by the change
+        assert!(!registry.is_revoked(7, now));
+        assert!(!registry.is_revoked(8, before));
+    }
+
+    #[test]
+    fn notify_admin() {
+        let registry = SessionRegistry::default();
+        let listening = Arc::new(AtomicBool::new(true));
+        let (sender, mut receiver) = queue(QueueConfig::default(), listening.clone());
+        let addr = "127.0.0.1:1234".parse().unwrap();
+        let notice = Notification {
+            kind: NotifyKind::Admin,
+            amount: 0,
+            balance: 100,
+            counterpart: String::new(),
+            time: Utc::now(),
+            msg: "账户已冻结：test".to_string(),
+        };
+        let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        rt.block_on(async {
+            registry.insert(7, Session { addr, sender, listening }).await;
+            assert_eq!(push_notice(&registry, 8, None, notice.clone()).await.unwrap(), 0);
+            assert_eq!(push_notice(&registry, 7, Some(addr), notice.clone()).await.unwrap(), 0);
+            assert_eq!(push_notice(&registry, 7, None, notice.clone()).await.unwrap(), 1);
+        });
+        let Some(NetworkMessage::Rely(data)) = receiver.try_recv().map(|x| x.msg) else {
+            panic!("Not notified");
+        };
+        let Tagged { id, body: Response::Notify(received) } = Tagged::<Response>::from_packet(&data).unwrap() else {
+            panic!("Not a notification");
+        };
+        assert_eq!(id, UNSOLICITED);
+        assert_eq!((received.kind, received.msg), (NotifyKind::Admin, notice.msg));
+        assert!(receiver.try_recv().is_none());
+    }
+}
@@ -0,0 +1,241 @@
+//! RFC 6238 time-based one-time passwords for the optional second login factor.
+//!
+//! The secret is stored base32 encoded (as authenticator apps expect) and every accepted code
+//! moves `last_step` forward so one code cannot be used twice.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+pub const TOTP_DIGITS: u32 = 6;
+/// Seconds for one time step
+pub const TOTP_STEP: u64 = 30;
+/// Steps accepted before and after the current one for clock drift
+pub const TOTP_SKEW: u64 = 1;
+pub const TOTP_ISSUER: &'static str = "BankBigEnd";
+/// Transfers with amount not less than this need a fresh code if totp is enabled
+pub const TOTP_TRANSFER_THRESHOLD: u32 = 5000;
+pub const RECOVERY_CODE_COUNT: usize = 8;
+
+const SECRET_LEN: usize = 20;
+const BASE32_ALPHABET: &'static [u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+const RECOVERY_ALPHABET: &'static [u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+/// The time source for totp, replaceable for tests
+pub trait Clock: Send + Sync + 'static {
+    /// Seconds since unix epoch
+    fn now(&self) -> u64;
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0)
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct Totp<C: Clock = SystemClock> {
+    clock: C,
+}
+
+impl<C: Clock> Totp<C> {
+    pub fn new(clock: C) -> Self {
+        Self { clock }
+    }
+
+    pub fn current_step(&self) -> u64 {
+        self.clock.now() / TOTP_STEP
+    }
+
+    /// Get the code for now.
+    pub fn generate(&self, secret: &[u8]) -> String {
+        format_code(hotp(secret, self.current_step()))
+    }
+
+    /// Check the code and return the step it matched.
+    ///
+    /// Steps not after `last_step` are rejected so a code is only accepted once.
+    pub fn verify(&self, secret: &[u8], code: &str, last_step: u64) -> Option<u64> {
+        let code = code.trim();
+        if code.len() != TOTP_DIGITS as usize || !code.bytes().all(|x| x.is_ascii_digit()) {
+            return None;
+        }
+        let current = self.current_step();
+        (current.saturating_sub(TOTP_SKEW)..=current + TOTP_SKEW)
+            .filter(|step| *step > last_step)
+            .find(|step| format_code(hotp(secret, *step)) == code)
+    }
+}
+
+/// RFC 4226 HOTP with HMAC-SHA1
+pub fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC can take key of any size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let bin = ((hash[offset] as u32 & 0x7f) << 24)
+        | ((hash[offset + 1] as u32) << 16)
+        | ((hash[offset + 2] as u32) << 8)
+        | (hash[offset + 3] as u32);
+    bin % 10u32.pow(TOTP_DIGITS)
+}
+
+fn format_code(code: u32) -> String {
+    format!("{:0width$}", code, width = TOTP_DIGITS as usize)
+}
+
+/// Generate new base32 encoded secret
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; SECRET_LEN];
+    rand::thread_rng().fill(&mut secret);
+    base32_encode(&secret)
+}
+
+/// The `otpauth://` uri for authenticator apps
+pub fn provisioning_uri(account: &str, secret: &str) -> String {
+    format!("otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={TOTP_DIGITS}&period={TOTP_STEP}",
+            issuer = TOTP_ISSUER)
+}
+
+/// Generate recovery codes in `XXXX-XXXX` form
+pub fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = rand::thread_rng();
+    (0..RECOVERY_CODE_COUNT).map(|_| {
+        let mut code = String::with_capacity(9);
+        for i in 0..8 {
+            if i == 4 {
+                code.push('-');
+            }
+            code.push(RECOVERY_ALPHABET[rng.gen_range(0..RECOVERY_ALPHABET.len())] as char);
+        }
+        code
+    }).collect()
+}
+
+/// Hash the recovery code for storing. Case and `-` are ignored.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized = code.trim().chars()
+        .filter(|x| *x != '-' && !x.is_whitespace())
+        .map(|x| x.to_ascii_uppercase())
+        .collect::<String>();
+    Sha256::digest(normalized.as_bytes()).iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() * 8 + 4) / 5);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for &b in data {
+        buffer = (buffer << 8) | b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+        buffer &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+pub fn base32_decode(data: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in data.bytes().filter(|x| *x != b'=') {
+        let value = BASE32_ALPHABET.iter().position(|x| *x == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use crate::bank::totp::*;
+
+    #[derive(Clone, Default)]
+    struct TestClock(Arc<AtomicU64>);
+
+    impl TestClock {
+        fn set(&self, now: u64) {
+            self.0.store(now, Ordering::Relaxed);
+        }
+    }
+
+    impl Clock for TestClock {
+        fn now(&self) -> u64 {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    const RFC_SECRET: &'static [u8] = b"12345678901234567890";
+
+    #[test]
+    fn rfc6238_vectors() {
+        let clock = TestClock::default();
+        let totp = Totp::new(clock.clone());
+        for (now, code) in [(59, "287082"), (1111111109, "081804"), (1111111111, "050471"),
+            (1234567890, "005924"), (2000000000, "279037"), (20000000000, "353130")] {
+            clock.set(now);
+            assert_eq!(totp.generate(RFC_SECRET), code);
+        }
+    }
+
+    #[test]
+    fn verify_skew_and_replay() {
+        let clock = TestClock::default();
+        let totp = Totp::new(clock.clone());
+        clock.set(1111111109);
+        let code = totp.generate(RFC_SECRET);
+        let step = totp.current_step();
+
+        assert_eq!(totp.verify(RFC_SECRET, &code, 0), Some(step));
+        // used code will not be accepted again
+        assert_eq!(totp.verify(RFC_SECRET, &code, step), None);
+
+        clock.set(1111111109 + TOTP_STEP);
+        assert_eq!(totp.verify(RFC_SECRET, &code, 0), Some(step));
+        clock.set(1111111109 + TOTP_STEP * 2);
+        assert_eq!(totp.verify(RFC_SECRET, &code, 0), None);
+
+        assert_eq!(totp.verify(RFC_SECRET, "12345", 0), None);
+        assert_eq!(totp.verify(RFC_SECRET, "abcdef", 0), None);
+    }
+
+    #[test]
+    fn base32_round_trip() {
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(base32_decode("MZXW6YTBOI======").unwrap(), b"foobar");
+        let secret = generate_secret();
+        assert_eq!(base32_encode(&base32_decode(&secret).unwrap()), secret);
+        assert_eq!(base32_decode("M1"), None);
+    }
+
+    #[test]
+    fn recovery_code_hash() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert_eq!(hash_recovery_code("abcd-efgh"), hash_recovery_code("ABCDEFGH"));
+        assert_ne!(hash_recovery_code(&codes[0]), hash_recovery_code(&codes[1]));
+    }
+}
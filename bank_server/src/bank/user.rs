@@ -1,6 +1,52 @@
+use bank_protocol::UserInfo;
+
+#[derive(Clone)]
 pub struct User {
     pub id: u32,
     pub balance: u32,
     pub name: String,
     pub phone: String,
-}
\ No newline at end of file
+    /// Decide the fees with the segment
+    pub product: String,
+    pub segment: String,
+}
+
+impl User {
+    pub fn info(&self) -> UserInfo {
+        UserInfo {
+            id: self.id,
+            name: self.name.clone(),
+            balance: self.balance,
+            phone: self.phone.clone(),
+        }
+    }
+
+    /// The name shown to others: only the first and the last char, like "张*明"
+    pub fn masked_name(&self) -> String {
+        mask_name(&self.name)
+    }
+}
+
+pub fn mask_name(name: &str) -> String {
+    let chars = name.trim().chars().collect::<Vec<_>>();
+    match chars.len() {
+        0 => "*".into(),
+        1 | 2 => format!("{}*", chars[0]),
+        n => format!("{}{}{}", chars[0], "*".repeat(n - 2), chars[n - 1]),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::bank::user::mask_name;
+
+    #[test]
+    fn mask() {
+        assert_eq!(mask_name("张小明"), "张*明");
+        assert_eq!(mask_name("欧阳小明"), "欧**明");
+        assert_eq!(mask_name("张明"), "张*");
+        assert_eq!(mask_name("Tom"), "T*m");
+        assert_eq!(mask_name(""), "*");
+    }
+}
@@ -0,0 +1,352 @@
+//! The endpoints of the gateway, the OpenAPI description is derived from them by [`ApiDoc`].
+//!
+//! Amounts are in cents, accounts are the account numbers like the ones shown in the client.
+//! The id of the device could be sent in `X-Device-Id` for the risk rules.
+
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use axum::extract::{ConnectInfo, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::Json;
+use bank_protocol::{account, ErrorCode, TradeRecord};
+use serde::{Deserialize, Serialize};
+use tracing::Span;
+use utoipa::{IntoParams, Modify, OpenApi, ToSchema};
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+
+use crate::bank::{service, UserInputError};
+use crate::bank::fee::Channel;
+use crate::bank::risk::Signals;
+use crate::bank::service::LoginResult;
+use crate::bank::user::User;
+use crate::gateway::{Authed, GatewayState};
+use crate::gateway::token::TOKEN_TTL;
+use crate::telemetry::Redacted;
+
+/// The history page size if not given, and the max
+const DEFAULT_PAGE_SIZE: u32 = 20;
+const MAX_PAGE_SIZE: u32 = 100;
+
+/// The id of the client installation, like the one sent by the client after logged in
+const DEVICE_HEADER: &str = "x-device-id";
+
+/// The failed request, `code` is the same as the error code of the packets
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiError {
+    pub code: u16,
+    pub message: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct LoginBody {
+    /// The account number
+    pub account: String,
+    pub password: u32,
+    /// The totp code or one recovery code, needed if the account enabled it
+    pub totp: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RegisterBody {
+    pub password: u32,
+    pub name: String,
+    /// The reserved phone, could be empty
+    pub phone: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct LoginReply {
+    /// Send it as `Authorization: Bearer <token>`
+    pub token: String,
+    /// Seconds the token keeps valid after the last use
+    pub expires_in: u64,
+    pub account: AccountView,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct AccountView {
+    pub account: String,
+    pub name: String,
+    /// In cents
+    pub balance: u32,
+    pub phone: String,
+}
+
+impl From<&User> for AccountView {
+    fn from(user: &User) -> Self {
+        Self {
+            account: account::format_account_number(user.id),
+            name: user.name.clone(),
+            balance: user.balance,
+            phone: user.phone.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct AmountBody {
+    /// In cents
+    pub amount: u32,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct WithdrawBody {
+    /// In cents
+    pub amount: u32,
+    /// A new totp code, needed if challenged by the risk rules
+    pub totp: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct TransferBody {
+    /// The account number or the linked phone of the recipient
+    pub target: String,
+    /// In cents
+    pub amount: u32,
+    /// A new totp code, needed for the large amount if the account enabled it, or if challenged by the risk rules
+    pub totp: Option<String>,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FeeQuery {
+    /// withdraw or transfer
+    pub operation: String,
+    /// In cents
+    pub amount: u32,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct FeeView {
+    pub operation: String,
+    pub amount: u32,
+    pub fee: u32,
+    /// The amount and the fee taken from the balance
+    pub total: u64,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HistoryQuery {
+    /// Starts from 1, default 1
+    pub page: Option<u32>,
+    /// Default 20, at most 100
+    pub page_size: Option<u32>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TradeView {
+    pub tid: i32,
+    /// The account number received
+    pub receiver: String,
+    /// The account number sent, or the description like deposit
+    pub sender: String,
+    /// RFC 3339
+    pub time: String,
+    /// In cents, negative for withdraw
+    pub amount: i32,
+}
+
+impl From<TradeRecord> for TradeView {
+    fn from(x: TradeRecord) -> Self {
+        Self {
+            tid: x.tid,
+            receiver: account::format_account_number(x.receiver),
+            sender: x.sender.parse::<u32>().map(account::format_account_number).unwrap_or(x.sender),
+            time: x.time.to_rfc3339(),
+            amount: x.amount,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct HistoryReply {
+    pub current_page: u32,
+    pub total_page: u32,
+    pub trades: Vec<TradeView>,
+}
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+fn parse_account(input: &str) -> Result<u32, ApiError> {
+    account::parse_account_number(input).map_err(|e| {
+        anyhow::Error::from(UserInputError::with_params(ErrorCode::BadAccountNumber, vec![e.msg().to_string()])).into()
+    })
+}
+
+async fn load_user(state: &GatewayState, id: u32) -> Result<User, ApiError> {
+    let mut sql = state.server.0.sql_pool.acquire().await.map_err(anyhow::Error::from)?;
+    Ok(service::get_user(&mut sql, id).await?)
+}
+
+fn signals(addr: SocketAddr, headers: &HeaderMap) -> Signals {
+    let device = headers.get(DEVICE_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .filter(|x| !x.is_empty() && x.len() <= 64)
+        .map(str::to_string);
+    Signals { channel: Channel::Gateway, ip: Some(addr.ip()), device }
+}
+
+fn logged(state: &GatewayState, user: &User) -> Json<LoginReply> {
+    Json(LoginReply {
+        token: state.tokens.issue(user.id, Instant::now()),
+        expires_in: TOKEN_TTL.as_secs(),
+        account: user.into(),
+    })
+}
+
+#[utoipa::path(post, path = "/api/login", tag = "auth", request_body = LoginBody,
+    params(("x-device-id" = Option<String>, Header, description = "The id of the device, remembered for the risk rules")),
+    responses((status = 200, body = LoginReply), (status = 401, description = "Wrong password or totp code", body = ApiError)))]
+pub async fn login(State(state): State<GatewayState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap, Json(body): Json<LoginBody>) -> ApiResult<LoginReply> {
+    let id = parse_account(&body.account)?;
+    // the passwords of one account tried from many addresses
+    state.limit_account(id, "login", addr.ip())?;
+    let user = match service::login(&state.server, id, body.password).await? {
+        LoginResult::Logged(user) => user,
+        LoginResult::TotpRequired(user) => {
+            let code = body.totp.ok_or(ApiError::new(ErrorCode::TotpCodeRequired))?;
+            let now = Instant::now();
+            if state.tokens.totp_locked(id, now) {
+                return Err(ApiError::new(ErrorCode::TooManyTotpTries));
+            }
+            if !state.server.check_totp(id, &code, true).await? {
+                state.tokens.totp_failed(id, now);
+                return Err(ApiError::new(ErrorCode::WrongTotpCode));
+            }
+            state.tokens.totp_passed(id);
+            user
+        }
+    };
+    // the status is refused by the operations, not the login
+    service::logged_in(&state.server, user.id, Some(addr.ip())).await?;
+    if let Some(device) = signals(addr, &headers).device {
+        service::identify_device(&state.server, user.id, &device).await?;
+    }
+    Span::current().record("account", user.id);
+    log::info!("Gateway logged user: {}", Redacted::Name(&user.name));
+    Ok(logged(&state, &user))
+}
+
+#[utoipa::path(post, path = "/api/logout", tag = "auth", security(("bearer" = [])),
+    responses((status = 204), (status = 401, body = ApiError)))]
+pub async fn logout(State(state): State<GatewayState>, authed: Authed) -> StatusCode {
+    state.tokens.revoke(&authed.token);
+    StatusCode::NO_CONTENT
+}
+
+#[utoipa::path(post, path = "/api/accounts", tag = "auth", request_body = RegisterBody,
+    responses((status = 200, description = "Opened and logged", body = LoginReply), (status = 400, body = ApiError)))]
+pub async fn register(State(state): State<GatewayState>, Json(body): Json<RegisterBody>) -> ApiResult<LoginReply> {
+    let user = service::register(&state.server, body.password, body.name, body.phone).await?;
+    Ok(logged(&state, &user))
+}
+
+#[utoipa::path(get, path = "/api/account", tag = "account", security(("bearer" = [])),
+    responses((status = 200, body = AccountView), (status = 401, body = ApiError)))]
+pub async fn account(State(state): State<GatewayState>, authed: Authed) -> ApiResult<AccountView> {
+    let user = load_user(&state, authed.id).await?;
+    Ok(Json((&user).into()))
+}
+
+#[utoipa::path(post, path = "/api/deposit", tag = "account", security(("bearer" = [])), request_body = AmountBody,
+    responses((status = 200, body = AccountView), (status = 400, body = ApiError), (status = 401, body = ApiError),
+        (status = 403, description = "The account is frozen or closed", body = ApiError)))]
+pub async fn deposit(State(state): State<GatewayState>, authed: Authed, Json(body): Json<AmountBody>) -> ApiResult<AccountView> {
+    let mut user = load_user(&state, authed.id).await?;
+    service::deposit(&state.server, &mut user, body.amount, None).await?;
+    Ok(Json((&user).into()))
+}
+
+#[utoipa::path(post, path = "/api/withdraw", tag = "account", security(("bearer" = [])), request_body = WithdrawBody,
+    params(("x-device-id" = Option<String>, Header, description = "The id of the device for the risk rules")),
+    responses((status = 200, body = AccountView), (status = 400, body = ApiError),
+        (status = 401, description = "Expired, or the totp code is needed by the risk rules", body = ApiError),
+        (status = 403, description = "The account is frozen or closed, or blocked by the risk rules", body = ApiError)))]
+pub async fn withdraw(State(state): State<GatewayState>, authed: Authed, ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap, Json(body): Json<WithdrawBody>) -> ApiResult<AccountView> {
+    let mut user = load_user(&state, authed.id).await?;
+    let signals = signals(addr, &headers);
+    service::withdraw(&state.server, &mut user, body.amount, body.totp.as_deref().unwrap_or(""), &signals, None).await?;
+    Ok(Json((&user).into()))
+}
+
+#[utoipa::path(post, path = "/api/transfer", tag = "account", security(("bearer" = [])), request_body = TransferBody,
+    params(("x-device-id" = Option<String>, Header, description = "The id of the device for the risk rules")),
+    responses((status = 200, body = AccountView), (status = 400, body = ApiError),
+        (status = 401, description = "Expired, or the totp code is needed by the risk rules", body = ApiError),
+        (status = 403, description = "The account is frozen or closed, or blocked by the risk rules", body = ApiError),
+        (status = 404, description = "Recipient not found", body = ApiError)))]
+pub async fn transfer(State(state): State<GatewayState>, authed: Authed, ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap, Json(body): Json<TransferBody>) -> ApiResult<AccountView> {
+    let mut user = load_user(&state, authed.id).await?;
+    let (target, _) = service::resolve_target(&state.server, &body.target).await?;
+    let signals = signals(addr, &headers);
+    service::transfer(&state.server, &mut user, target, body.amount, body.totp.as_deref().unwrap_or(""), &signals, None).await?;
+    Ok(Json((&user).into()))
+}
+
+#[utoipa::path(get, path = "/api/fees", tag = "account", security(("bearer" = [])), params(FeeQuery),
+    responses((status = 200, description = "The fee charged if the operation is done now", body = FeeView),
+        (status = 400, body = ApiError), (status = 401, body = ApiError)))]
+pub async fn fees(State(state): State<GatewayState>, authed: Authed, Query(query): Query<FeeQuery>) -> ApiResult<FeeView> {
+    let user = load_user(&state, authed.id).await?;
+    let quote = service::quote_fee(&state.server, &user, &query.operation, query.amount, Channel::Gateway)?;
+    Ok(Json(FeeView {
+        total: query.amount as u64 + quote.fee as u64,
+        operation: query.operation,
+        amount: query.amount,
+        fee: quote.fee,
+    }))
+}
+
+#[utoipa::path(get, path = "/api/history", tag = "account", security(("bearer" = [])), params(HistoryQuery),
+    responses((status = 200, body = HistoryReply), (status = 401, body = ApiError)))]
+pub async fn history(State(state): State<GatewayState>, authed: Authed, Query(query): Query<HistoryQuery>) -> ApiResult<HistoryReply> {
+    let page = query.page.unwrap_or(1);
+    let page_size = query.page_size.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let history = service::history(&state.server, authed.id, page, page_size).await?;
+    Ok(Json(HistoryReply {
+        current_page: history.current_page,
+        total_page: history.total_page,
+        trades: history.trades.into_iter().map(TradeView::from).collect(),
+    }))
+}
+
+pub async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Bank gateway"),
+    paths(login, logout, register, account, deposit, withdraw, transfer, fees, history),
+    components(schemas(ApiError, LoginBody, RegisterBody, LoginReply, AccountView, AmountBody, WithdrawBody, TransferBody, FeeView, TradeView, HistoryReply)),
+    modifiers(&BearerAuth),
+)]
+pub struct ApiDoc;
+
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("bearer", SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()));
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use utoipa::OpenApi;
+
+    use crate::gateway::api::ApiDoc;
+
+    #[test]
+    fn openapi() {
+        let doc = ApiDoc::openapi();
+        for path in ["/api/login", "/api/logout", "/api/accounts", "/api/account", "/api/deposit", "/api/withdraw", "/api/transfer", "/api/fees", "/api/history"] {
+            assert!(doc.paths.paths.contains_key(path), "{} not described", path);
+        }
+        assert!(doc.components.unwrap().security_schemes.contains_key("bearer"));
+    }
+}
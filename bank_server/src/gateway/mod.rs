@@ -0,0 +1,215 @@
+//! The http gateway with json for the clients not speaking the packets.
+//!
+//! The operations call [`crate::bank::service`] like the handlers of the connections,
+//! the gateway only maps the json and the errors. After login, the requests carry the token in
+//! `Authorization: Bearer <token>`. The OpenAPI description is served at `/api/openapi.json`.
+//!
+//! The [rate limits](crate::bank::limit) and the bans apply to the requests by the address and by the
+//! logged account, the wrong passwords, totp codes and tokens count toward the ban of the address.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::{async_trait, Json, Router};
+use axum::extract::{ConnectInfo, FromRequestParts, State};
+use axum::http::{header, HeaderMap, HeaderValue, Request, StatusCode};
+use axum::http::request::Parts;
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use bank_protocol::{ErrorCode, Failure};
+use log::{info, warn};
+use tokio_util::sync::CancellationToken;
+use tracing::{info_span, Instrument, Span};
+use tracing::field::Empty;
+
+use crate::bank::limit::{Limiters, RateLimits};
+use crate::bank::server::BankServer;
+use crate::bank::UserInputError;
+use crate::gateway::api::ApiError;
+use crate::gateway::token::TokenStore;
+use crate::telemetry;
+
+pub mod api;
+pub mod token;
+
+#[derive(Clone)]
+pub struct GatewayState {
+    pub server: BankServer,
+    pub tokens: Arc<TokenStore>,
+    pub addresses: Arc<Limiters<IpAddr>>,
+    pub accounts: Arc<Limiters<u32>>,
+}
+
+impl GatewayState {
+    /// Take the tokens of the account for the request from `ip`
+    pub fn limit_account(&self, id: u32, op: &'static str, ip: IpAddr) -> Result<(), ApiError> {
+        let now = Instant::now();
+        let limits = self.server.rate_limits();
+        self.accounts.check(id, &limits, op, now).map_err(|wait| self.refuse(ip, op, &limits, wait, now))
+    }
+
+    fn refuse(&self, ip: IpAddr, op: &str, limits: &RateLimits, wait: Duration, now: Instant) -> ApiError {
+        anyhow::Error::from(self.server.0.bans.refuse(ip, op, limits, wait, now)).into()
+    }
+}
+
+pub fn router(server: BankServer) -> Router {
+    let state = GatewayState { server, tokens: Default::default(), addresses: Default::default(), accounts: Default::default() };
+    Router::new()
+        .route("/api/login", post(api::login))
+        .route("/api/logout", post(api::logout))
+        .route("/api/accounts", post(api::register))
+        .route("/api/account", get(api::account))
+        .route("/api/deposit", post(api::deposit))
+        .route("/api/withdraw", post(api::withdraw))
+        .route("/api/transfer", post(api::transfer))
+        .route("/api/fees", get(api::fees))
+        .route("/api/history", get(api::history))
+        .route("/api/openapi.json", get(api::openapi))
+        .layer(middleware::from_fn_with_state(state.clone(), limit_request))
+        .layer(middleware::from_fn(trace_request))
+        .with_state(state)
+}
+
+/// The span of the request, the correlation id is replied in `x-request-id`
+async fn trace_request<B>(request: Request<B>, next: Next<B>) -> Response {
+    let corr = request.headers().get("x-request-id")
+        .and_then(|x| x.to_str().ok())
+        .filter(|x| !x.is_empty() && x.len() <= 64)
+        .map(str::to_string)
+        .unwrap_or_else(telemetry::session_id);
+    let span = info_span!("http", corr = %corr, method = %request.method(), path = %request.uri().path(), account = Empty);
+    let mut response = next.run(request).instrument(span).await;
+    if let Ok(x) = HeaderValue::from_str(&corr) {
+        response.headers_mut().insert("x-request-id", x);
+    }
+    response
+}
+
+/// The operation of the path, the same names as the ones of the connections
+fn operation(path: &str) -> &'static str {
+    match path {
+        "/api/login" => "login",
+        "/api/logout" => "logout",
+        "/api/accounts" => "register",
+        "/api/account" => "info",
+        "/api/deposit" => "deposit",
+        "/api/withdraw" => "withdraw",
+        "/api/transfer" => "transfer",
+        "/api/fees" => "quote_fee",
+        "/api/history" => "history",
+        _ => "other",
+    }
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers.get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+}
+
+/// The bans and the buckets of the address, and of the account if the token is valid
+async fn limit_request<B>(State(state): State<GatewayState>, ConnectInfo(addr): ConnectInfo<SocketAddr>, request: Request<B>, next: Next<B>) -> Response {
+    let now = Instant::now();
+    let ip = addr.ip();
+    if let Some(left) = state.server.0.bans.banned(ip, now) {
+        let secs = left.as_secs() + (left.subsec_nanos() > 0) as u64;
+        return ApiError::from_failure(&Failure::with_params(ErrorCode::TemporarilyBanned, vec![secs.to_string()])).into_response();
+    }
+    let limits = state.server.rate_limits();
+    let op = operation(request.uri().path());
+    let account = bearer(request.headers()).and_then(|x| state.tokens.account(x, now));
+    let checked = state.addresses.check(ip, &limits, op, now)
+        .and_then(|()| account.map_or(Ok(()), |id| state.accounts.check(id, &limits, op, now)));
+    if let Err(wait) = checked {
+        return state.refuse(ip, op, &limits, wait, now).into_response();
+    }
+
+    let response = next.run(request).await;
+    let failed = response.extensions().get::<ErrorCode>()
+        .is_some_and(|x| matches!(x, ErrorCode::WrongPassword | ErrorCode::WrongTotpCode | ErrorCode::SessionExpired));
+    if failed && state.server.0.bans.strike(ip, &limits, Instant::now()) {
+        warn!("Banned {} for {}s for too many failed authentications", ip, limits.ban_secs);
+    }
+    response
+}
+
+/// Serve until `shutdown` cancelled, the requests handling are finished first
+pub async fn serve(addr: SocketAddr, server: BankServer, shutdown: CancellationToken) -> anyhow::Result<()> {
+    info!("Gateway listening on {}", addr);
+    axum::Server::bind(&addr)
+        .serve(router(server).into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move { shutdown.cancelled().await })
+        .await?;
+    Ok(())
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode) -> Self {
+        Self::from_failure(&Failure::new(code))
+    }
+
+    fn from_failure(failure: &Failure) -> Self {
+        Self { code: failure.code as u16, message: failure.describe() }
+    }
+
+    fn status(&self) -> StatusCode {
+        match ErrorCode::from_u16(self.code) {
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::WrongPassword | ErrorCode::WrongTotpCode | ErrorCode::TotpCodeRequired | ErrorCode::SessionExpired
+            | ErrorCode::RiskChallenge => StatusCode::UNAUTHORIZED,
+            ErrorCode::TooManyTotpTries | ErrorCode::RateLimited | ErrorCode::TemporarilyBanned => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::AccountNotFound | ErrorCode::PhoneNotLinked => StatusCode::NOT_FOUND,
+            ErrorCode::AccountFrozen | ErrorCode::AccountClosed | ErrorCode::RiskBlocked | ErrorCode::RiskTotpNotEnabled => StatusCode::FORBIDDEN,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+/// [`UserInputError`] is replied with its code, others are internal errors
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<UserInputError>() {
+            Ok(e) => Self::from_failure(&Failure::with_params(e.code, e.params)),
+            Err(e) => {
+                log::error!("Gateway request failed for {:?}", e);
+                Self::new(ErrorCode::Internal)
+            }
+        }
+    }
+}
+
+/// The code is kept in the extensions for [`limit_request`]
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let code = ErrorCode::from_u16(self.code);
+        let mut response = (self.status(), Json(self)).into_response();
+        response.extensions_mut().insert(code);
+        response
+    }
+}
+
+/// The account of the bearer token
+pub struct Authed {
+    pub id: u32,
+    pub token: String,
+}
+
+#[async_trait]
+impl FromRequestParts<GatewayState> for Authed {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &GatewayState) -> Result<Self, Self::Rejection> {
+        let token = bearer(&parts.headers).ok_or(ApiError::new(ErrorCode::SessionExpired))?;
+        let (id, issued) = state.tokens.check(token, Instant::now()).ok_or(ApiError::new(ErrorCode::SessionExpired))?;
+        // the password changed after issued
+        if state.server.0.sessions.is_revoked(id, issued) {
+            state.tokens.revoke(token);
+            return Err(ApiError::new(ErrorCode::SessionExpired));
+        }
+        Span::current().record("account", id);
+        Ok(Self { id, token: token.to_string() })
+    }
+}
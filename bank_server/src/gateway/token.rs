@@ -0,0 +1,122 @@
+//! The bearer tokens of the http gateway, kept in memory like the sessions of the connections.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+/// The token expires if not used for it
+pub const TOKEN_TTL: Duration = Duration::from_secs(30 * 60);
+/// Wrong totp codes allowed before the account is locked for the gateway
+const MAX_TOTP_FAILURES: u32 = 5;
+const TOTP_LOCK_TIME: Duration = Duration::from_secs(5 * 60);
+
+struct Token {
+    id: u32,
+    issued: Instant,
+    expire: Instant,
+}
+
+#[derive(Default)]
+pub struct TokenStore {
+    tokens: Mutex<HashMap<String, Token>>,
+    /// The count of wrong totp codes and the time of the last one by account
+    totp_failures: Mutex<HashMap<u32, (u32, Instant)>>,
+}
+
+impl TokenStore {
+    /// A new token for the logged account
+    pub fn issue(&self, id: u32, now: Instant) -> String {
+        let token = format!("{:032x}", rand::thread_rng().gen::<u128>());
+        let mut tokens = self.tokens.lock().unwrap();
+        // logins are not frequent, clean the expired tokens here
+        tokens.retain(|_, x| x.expire > now);
+        tokens.insert(token.clone(), Token { id, issued: now, expire: now + TOKEN_TTL });
+        token
+    }
+
+    /// The account of the token and when issued, and keep it alive for another [`TOKEN_TTL`]
+    pub fn check(&self, token: &str, now: Instant) -> Option<(u32, Instant)> {
+        let mut tokens = self.tokens.lock().unwrap();
+        match tokens.get_mut(token) {
+            Some(x) if x.expire > now => {
+                x.expire = now + TOKEN_TTL;
+                Some((x.id, x.issued))
+            }
+            Some(_) => {
+                tokens.remove(token);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// The account of the token not expired, without keeping it alive
+    pub fn account(&self, token: &str, now: Instant) -> Option<u32> {
+        self.tokens.lock().unwrap().get(token).filter(|x| x.expire > now).map(|x| x.id)
+    }
+
+    /// Return false if the token is not issued
+    pub fn revoke(&self, token: &str) -> bool {
+        self.tokens.lock().unwrap().remove(token).is_some()
+    }
+
+    pub fn totp_locked(&self, id: u32, now: Instant) -> bool {
+        let failures = self.totp_failures.lock().unwrap();
+        matches!(failures.get(&id), Some((count, last)) if *count >= MAX_TOTP_FAILURES && now < *last + TOTP_LOCK_TIME)
+    }
+
+    pub fn totp_failed(&self, id: u32, now: Instant) {
+        let mut failures = self.totp_failures.lock().unwrap();
+        let entry = failures.entry(id).or_insert((0, now));
+        if now >= entry.1 + TOTP_LOCK_TIME {
+            entry.0 = 0;
+        }
+        entry.0 += 1;
+        entry.1 = now;
+    }
+
+    pub fn totp_passed(&self, id: u32) {
+        self.totp_failures.lock().unwrap().remove(&id);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use crate::gateway::token::{TOKEN_TTL, TokenStore};
+
+    #[test]
+    fn tokens() {
+        let store = TokenStore::default();
+        let now = Instant::now();
+        let token = store.issue(7, now);
+        assert_eq!(store.check(&token, now), Some((7, now)));
+        // renewed by the check
+        assert_eq!(store.check(&token, now + TOKEN_TTL - Duration::from_secs(1)), Some((7, now)));
+        assert_eq!(store.check(&token, now + TOKEN_TTL * 2), None);
+        assert_eq!(store.check("bad", now), None);
+
+        let token = store.issue(8, now);
+        assert!(store.revoke(&token));
+        assert_eq!(store.check(&token, now), None);
+    }
+
+    #[test]
+    fn totp_lock() {
+        let store = TokenStore::default();
+        let now = Instant::now();
+        for _ in 0..5 {
+            assert!(!store.totp_locked(1, now));
+            store.totp_failed(1, now);
+        }
+        assert!(store.totp_locked(1, now));
+        assert!(!store.totp_locked(2, now));
+        assert!(!store.totp_locked(1, now + Duration::from_secs(301)));
+        store.totp_passed(1);
+        assert!(!store.totp_locked(1, now));
+    }
+}
@@ -1,21 +1,161 @@
-use log::LevelFilter;
+use std::path::PathBuf;
 
+use log::{error, info, warn};
+use tokio_util::sync::CancellationToken;
+
+use crate::bank::lifecycle;
+use crate::bank::server::BankServer;
+use crate::config::Config;
 use crate::network::server::Server;
+use crate::telemetry::Telemetry;
 
 pub mod network;
 pub mod bank;
+pub mod gateway;
+pub mod config;
+pub mod metrics;
+pub mod telemetry;
+pub mod admin;
+
+
+const USAGE: &str = "Usage: bank_server [--config <path>] [dump-config]
+       bank_server [--config <path>] account <command> ...";
 
+/// Wait for SIGINT or SIGTERM
+async fn shutdown_signal() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            x = tokio::signal::ctrl_c() => x?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+/// Read the config again and apply the settings could be changed live, the old one is kept if invalid
+fn reload(path: Option<&PathBuf>, config: &mut Config, telemetry: &Telemetry, server: &Server, bank_server: &BankServer) {
+    let new = match Config::load(path.map(PathBuf::as_path)) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Reload config failed, keep the old one: {:?}", e);
+            return;
+        }
+    };
+    let restart = config.reload(new);
+    if let Err(e) = telemetry.reload(&config.log) {
+        error!("Reload log filters failed for {:?}", e);
+    }
+    server.set_config(config.server_config());
+    bank_server.set_capabilities(config.capabilities());
+    bank_server.set_rate_limits(config.limits.rate.clone());
+    bank_server.set_fees(config.fees.clone());
+    bank_server.set_risk(config.risk.clone());
+    info!("Config reloaded, offering {:?} to the new connections", config.capabilities());
+    if !restart.is_empty() {
+        warn!("The changes of {} need restart", restart.join(", "));
+    }
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    env_logger::builder()
-        .filter_level(LevelFilter::Info)
-        .parse_default_env()
-        .init();
+    let mut path = None;
+    let mut dump = false;
+    let mut account = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => path = Some(PathBuf::from(args.next().ok_or(anyhow::anyhow!(USAGE))?)),
+            "dump-config" => dump = true,
+            // the rest are for the command
+            "account" => {
+                account = Some(args.by_ref().collect::<Vec<_>>());
+                break;
+            }
+            _ => anyhow::bail!(USAGE),
+        }
+    }
+    let mut config = Config::load(path.as_deref())?;
+    if dump {
+        print!("{}", config.dump()?);
+        return Ok(());
+    }
+    if let Some(args) = account {
+        return admin::run(&config, &args).await;
+    }
+
+    let telemetry = Telemetry::init(&config.log)?;
+
+    let bank_server = BankServer::new(&config.database).await?;
+    bank_server.set_capabilities(config.capabilities());
+    bank_server.set_rate_limits(config.limits.rate.clone());
+    bank_server.set_fees(config.fees.clone());
+    bank_server.set_risk(config.risk.clone());
+    let shutdown = CancellationToken::new();
+    let gateway = config.listen.http.map(|addr| {
+        let server = bank_server.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(e) = gateway::serve(addr, server, shutdown).await {
+                error!("Gateway stopped for {:?}", e);
+            }
+        })
+    });
+    let dormancy = tokio::spawn(lifecycle::watch_dormancy(bank_server.clone(), config.lifecycle.clone(), shutdown.clone()));
+    let notices = tokio::spawn(lifecycle::watch_notices(bank_server.clone(), config.lifecycle.clone(), shutdown.clone()));
+    let server = Server::new(&config.endpoints(), config.server_config(), bank_server.clone()).await?;
+    // kept until the connections drained, so the readiness shows it
+    let metrics_shutdown = CancellationToken::new();
+    let metrics = config.listen.metrics.map(|addr| {
+        let router = metrics::router(&server, bank_server.clone());
+        let shutdown = metrics_shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(addr, router, shutdown).await {
+                error!("Metrics stopped for {:?}", e);
+            }
+        })
+    });
 
-    let bank_server = bank::server::BankServer::new().await?;
-    let _ = Server::run_block("[::]:1234", bank_server).await?;
+    #[cfg(unix)]
+    {
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        let stop = shutdown_signal();
+        tokio::pin!(stop);
+        loop {
+            tokio::select! {
+                x = &mut stop => break x?,
+                _ = hangup.recv() => reload(path.as_ref(), &mut config, &telemetry, &server, &bank_server),
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    shutdown_signal().await?;
 
+    info!("Shutting down, send the signal again to exit at once");
+    tokio::spawn(async {
+        if shutdown_signal().await.is_ok() {
+            warn!("Exit without waiting");
+            std::process::exit(1);
+        }
+    });
+    let drain_timeout = config.drain_timeout();
+    shutdown.cancel();
+    if !server.shutdown(drain_timeout).await {
+        warn!("Some connections not finished in {:?}", drain_timeout);
+    }
+    metrics_shutdown.cancel();
+    for task in gateway.into_iter().chain(metrics).chain([dormancy, notices]) {
+        let _ = tokio::time::timeout(drain_timeout, task).await;
+    }
+    // the connections still used by the handlers not finished are not waited
+    if tokio::time::timeout(drain_timeout, bank_server.close()).await.is_err() {
+        warn!("Close the database timed out");
+    }
+    info!("Server stopped");
+    telemetry.shutdown().await;
 
     Ok(())
 }
@@ -0,0 +1,223 @@
+//! The prometheus metrics and the health checks, served on `listen.metrics` if configured.
+//!
+//! * `/metrics` the text format for prometheus
+//! * `/healthz` liveness, ok while the process answers
+//! * `/readyz` readiness, ok while accepting and the database answers
+//!
+//! The counters are updated where the requests are handled, the gauges of the peers and the
+//! database pool are sampled on each scrape.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::Router;
+use axum::routing::get;
+use bank_protocol::queue::QueueDepth;
+use log::info;
+use prometheus::{Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+
+use crate::bank::server::BankServer;
+use crate::network::peer::Peer;
+use crate::network::server::Server;
+
+/// The database is not ready if `SELECT 1` takes longer
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct Metrics {
+    registry: Registry,
+    /// By the operation and `ok`, `rejected`, `limited`, `bad_packet` or `error`
+    requests: IntCounterVec,
+    latency: HistogramVec,
+    /// By the error code
+    login_failures: IntCounterVec,
+    /// The connections logged in
+    pub logged: IntGauge,
+    sql_errors: IntCounter,
+    banned: IntCounter,
+    peers: IntGaugeVec,
+    link_rtt: GaugeVec,
+    lost_pings: IntGaugeVec,
+    send_queue: IntGaugeVec,
+    sql_connections: IntGauge,
+    sql_idle_connections: IntGauge,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let transport = &["transport"];
+        let this = Self {
+            registry: Registry::new_custom(Some("bank".into()), None).unwrap(),
+            requests: IntCounterVec::new(Opts::new("requests_total", "Requests handled"), &["op", "result"]).unwrap(),
+            latency: HistogramVec::new(
+                HistogramOpts::new("request_duration_seconds", "Time to handle the requests")
+                    .buckets(vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]),
+                &["op"],
+            ).unwrap(),
+            login_failures: IntCounterVec::new(Opts::new("login_failures_total", "Failed logins"), &["reason"]).unwrap(),
+            logged: IntGauge::new("logged_connections", "Connections logged in").unwrap(),
+            sql_errors: IntCounter::new("sql_errors_total", "Requests failed for the database").unwrap(),
+            banned: IntCounter::new("banned_connections_total", "Connections closed for the address banned").unwrap(),
+            peers: IntGaugeVec::new(Opts::new("peers", "Connected peers"), transport).unwrap(),
+            link_rtt: GaugeVec::new(Opts::new("link_rtt_seconds", "Average smoothed rtt of the peers by the heartbeats"), transport).unwrap(),
+            lost_pings: IntGaugeVec::new(Opts::new("link_lost_pings", "Heartbeats not replied of the connected peers"), transport).unwrap(),
+            send_queue: IntGaugeVec::new(Opts::new("send_queue", "Messages and bytes waiting to be sent"), &["kind"]).unwrap(),
+            sql_connections: IntGauge::new("sql_connections", "Connections of the database pool").unwrap(),
+            sql_idle_connections: IntGauge::new("sql_idle_connections", "Idle connections of the database pool").unwrap(),
+        };
+        let r = &this.registry;
+        r.register(Box::new(this.requests.clone())).unwrap();
+        r.register(Box::new(this.latency.clone())).unwrap();
+        r.register(Box::new(this.login_failures.clone())).unwrap();
+        r.register(Box::new(this.logged.clone())).unwrap();
+        r.register(Box::new(this.sql_errors.clone())).unwrap();
+        r.register(Box::new(this.banned.clone())).unwrap();
+        r.register(Box::new(this.peers.clone())).unwrap();
+        r.register(Box::new(this.link_rtt.clone())).unwrap();
+        r.register(Box::new(this.lost_pings.clone())).unwrap();
+        r.register(Box::new(this.send_queue.clone())).unwrap();
+        r.register(Box::new(this.sql_connections.clone())).unwrap();
+        r.register(Box::new(this.sql_idle_connections.clone())).unwrap();
+        this
+    }
+}
+
+impl Metrics {
+    pub fn request(&self, op: &str, result: &str, elapsed: Duration) {
+        self.requests.with_label_values(&[op, result]).inc();
+        self.latency.with_label_values(&[op]).observe(elapsed.as_secs_f64());
+    }
+
+    pub fn login_failed(&self, reason: &str) {
+        self.login_failures.with_label_values(&[reason]).inc();
+    }
+
+    pub fn sql_error(&self) {
+        self.sql_errors.inc();
+    }
+
+    pub fn banned(&self) {
+        self.banned.inc();
+    }
+
+    /// Sample the gauges of the peers and the database pool
+    async fn sample(&self, peers: &RwLock<HashMap<SocketAddr, Peer>>, bank_server: &BankServer) {
+        self.peers.reset();
+        self.link_rtt.reset();
+        self.lost_pings.reset();
+        let mut depth = QueueDepth::default();
+        {
+            let read = peers.read().await;
+            let mut rtt: Vec<(String, f64, u32)> = vec![];
+            for peer in read.values().filter(|x| x.listening.load(Ordering::Relaxed)) {
+                depth = depth + peer.sender.depth();
+                let transport = peer.transport.to_string();
+                self.peers.with_label_values(&[&transport]).inc();
+                let lost = peer.link.pings.load(Ordering::Relaxed).saturating_sub(peer.link.pongs.load(Ordering::Relaxed));
+                self.lost_pings.with_label_values(&[&transport]).add(lost as i64);
+                if let Some(x) = peer.link.rtt() {
+                    match rtt.iter_mut().find(|(t, _, _)| *t == transport) {
+                        Some((_, sum, count)) => {
+                            *sum += x.as_secs_f64();
+                            *count += 1;
+                        }
+                        None => rtt.push((transport, x.as_secs_f64(), 1)),
+                    }
+                }
+            }
+            for (transport, sum, count) in rtt {
+                self.link_rtt.with_label_values(&[&transport]).set(sum / count as f64);
+            }
+        }
+        self.send_queue.with_label_values(&["control"]).set(depth.control as i64);
+        self.send_queue.with_label_values(&["bulk"]).set(depth.bulk as i64);
+        self.send_queue.with_label_values(&["bytes"]).set(depth.bytes as i64);
+        let pool = &bank_server.0.sql_pool;
+        self.sql_connections.set(pool.size() as i64);
+        self.sql_idle_connections.set(pool.num_idle() as i64);
+    }
+
+    fn encode(&self) -> String {
+        let mut buf = vec![];
+        // only fails for the bad metrics, never for the registered ones
+        let _ = TextEncoder::new().encode(&self.registry.gather(), &mut buf);
+        String::from_utf8(buf).unwrap_or_default()
+    }
+}
+
+/// Not keeping the [`Server`], dropping a clone of it stops the server
+#[derive(Clone)]
+struct MetricsState {
+    running: Arc<AtomicBool>,
+    peers: Arc<RwLock<HashMap<SocketAddr, Peer>>>,
+    bank_server: BankServer,
+}
+
+pub fn router(server: &Server, bank_server: BankServer) -> Router {
+    let state = MetricsState { running: server.running.clone(), peers: server.peers.clone(), bank_server };
+    Router::new()
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(|| async { "ok" }))
+        .route("/readyz", get(ready))
+        .with_state(state)
+}
+
+/// Serve until `shutdown` cancelled
+pub async fn serve(addr: SocketAddr, router: Router, shutdown: CancellationToken) -> anyhow::Result<()> {
+    info!("Metrics listening on {}", addr);
+    axum::Server::bind(&addr)
+        .serve(router.into_make_service())
+        .with_graceful_shutdown(async move { shutdown.cancelled().await })
+        .await?;
+    Ok(())
+}
+
+async fn metrics(State(state): State<MetricsState>) -> impl IntoResponse {
+    let metrics = &state.bank_server.0.metrics;
+    metrics.sample(&state.peers, &state.bank_server).await;
+    ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], metrics.encode())
+}
+
+async fn ready(State(state): State<MetricsState>) -> (StatusCode, &'static str) {
+    if !state.running.load(Ordering::Acquire) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "shutting down");
+    }
+    let ping = sqlx::query("SELECT 1").execute(&state.bank_server.0.sql_pool);
+    match tokio::time::timeout(READY_TIMEOUT, ping).await {
+        Ok(Ok(_)) => (StatusCode::OK, "ok"),
+        Ok(Err(e)) => {
+            log::warn!("Readiness check failed for {}", e);
+            (StatusCode::SERVICE_UNAVAILABLE, "database unavailable")
+        }
+        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, "database timed out"),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::metrics::Metrics;
+
+    #[test]
+    fn encode() {
+        let metrics = Metrics::default();
+        metrics.request("deposit", "ok", Duration::from_millis(3));
+        metrics.request("login", "rejected", Duration::from_millis(20));
+        metrics.login_failed("WrongPassword");
+        metrics.logged.inc();
+        let text = metrics.encode();
+        assert!(text.contains("bank_requests_total{op=\"deposit\",result=\"ok\"} 1"), "{}", text);
+        assert!(text.contains("bank_request_duration_seconds_bucket{op=\"login\",le=\"0.025\"} 1"));
+        assert!(text.contains("bank_login_failures_total{reason=\"WrongPassword\"} 1"));
+        assert!(text.contains("bank_logged_connections 1"));
+    }
+}
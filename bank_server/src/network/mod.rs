@@ -2,12 +2,16 @@ use std::future::Future;
 use std::net::SocketAddr;
 use std::time::Duration;
 
+use bank_protocol::chunk::ChunkProgress;
 use tokio_kcp::{KcpConfig, KcpNoDelayConfig};
 
+pub use bank_protocol::queue::NetworkMessage;
+
 use crate::network::peer::Peer;
 
 pub mod server;
 pub mod peer;
+pub mod transport;
 
 #[allow(unused)]
 /// The handler to handle the message from `Peer`
@@ -16,20 +20,27 @@ pub trait DataHandler: Send + 'static {
     /// `data` The data received from `src`
     /// Return true means successful
     fn handle<'a>(&'a mut self, src: &'a Peer, data: &'a [u8]) -> Box<dyn Future<Output=bool> + Unpin + Send + 'a>;
-}
 
-pub trait DataHandlerGenerator: Send + 'static {
-    fn generate(&self, addr: SocketAddr) -> Box<dyn DataHandler>;
+    /// Called for every chunk of a large message before it is complete and handled
+    fn progress(&mut self, _src: &Peer, _progress: ChunkProgress) {}
+
+    /// Called when the server is shutting down, the messages sent here are still sent before the peer stopped
+    fn shutdown(&mut self, _src: &Peer) {}
+
+    /// Called once after the peer stopped
+    fn closed(&mut self, _src: &Peer) {}
 }
 
+pub trait DataHandlerGenerator: Send + Sync + 'static {
+    fn generate(&self, addr: SocketAddr) -> Box<dyn DataHandler>;
 
-#[allow(unused)]
-#[derive(Debug)]
-pub enum NetworkMessage {
-    Rely(Vec<u8>),
-    Once(Vec<u8>),
+    /// Return false to close the stream accepted at once, like the banned addresses
+    fn admit(&self, _addr: SocketAddr) -> bool {
+        true
+    }
 }
 
+
 #[allow(unused)]
 pub const DEFAULT_KCP_CONFIG: KcpConfig = KcpConfig {
     mtu: 1400,
@@ -1,16 +1,24 @@
+use std::borrow::Cow;
+use std::io::ErrorKind;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::task::{Context, Poll, Wake, Waker};
+use std::time::Instant;
 
-use log::{error, info, trace};
-use tokio::io::AsyncWriteExt;
+use log::{error, info, warn, trace};
 use tokio::select;
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-use tokio_kcp::KcpStream;
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+use tracing::{info_span, Instrument};
+use bank_protocol::chunk::{Chunker, CHUNK_SIZE, is_chunk, Reassembled, Reassembler};
+use bank_protocol::compress::{compress, CompressionStats, decompress, is_compressed};
+use bank_protocol::heartbeat::{Heartbeat, HeartbeatConfig, is_heartbeat, LinkStats, RttEstimator};
+use bank_protocol::queue::{PeerReceiver, PeerSender, queue, QueueConfig, Queued};
 
 
 use crate::network::{DataHandler, NetworkMessage};
+use crate::network::transport::{FrameStream, Transport};
 
 /// The peer wrapped socket addr
 #[derive(Debug, Clone)]
@@ -18,8 +26,21 @@ pub struct Peer {
     pub listening: Arc<AtomicBool>,
     /// The remote socket address.
     pub addr: SocketAddr,
+    pub transport: Transport,
     /// sender to send the message to the target
-    pub sender: UnboundedSender<NetworkMessage>,
+    pub sender: PeerSender,
+    /// The bytes saved by the compression
+    pub compression: Arc<CompressionStats>,
+    /// The round trip time and loss measured by the heartbeats
+    pub link: Arc<LinkStats>,
+    draining: CancellationToken,
+}
+
+/// The settings of a peer
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PeerConfig {
+    pub queue: QueueConfig,
+    pub heartbeat: HeartbeatConfig,
 }
 
 pub struct NeverWaker;
@@ -30,18 +51,36 @@ impl Wake for NeverWaker {
 
 impl Peer {
     /// Need call in tokio runtime
-    pub fn new(stream: KcpStream, addr: SocketAddr, handler: Box<dyn DataHandler>) -> Self {
-        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    pub fn new(stream: FrameStream, addr: SocketAddr, handler: Box<dyn DataHandler>) -> Self {
+        Self::with_config(stream, addr, handler, PeerConfig::default())
+    }
+
+    /// Need call in tokio runtime
+    pub fn with_config(stream: FrameStream, addr: SocketAddr, handler: Box<dyn DataHandler>, config: PeerConfig) -> Self {
+        let listening = Arc::new(AtomicBool::new(true));
+        let (sender, receiver) = queue(config.queue, listening.clone());
         let this = Self {
-            listening: Arc::new(AtomicBool::new(true)),
+            listening,
             addr,
+            transport: stream.transport(),
             sender,
+            compression: Default::default(),
+            link: Default::default(),
+            draining: CancellationToken::new(),
         };
-        tokio::spawn(this.clone().run_loop(stream, receiver, handler));
+        let span = info_span!("peer", addr = %addr, transport = %this.transport);
+        tokio::spawn(this.clone().run_loop(stream, receiver, handler, config.heartbeat).instrument(span));
         this
     }
 
-    async fn run_loop(self, mut stream: KcpStream, mut receiver: UnboundedReceiver<NetworkMessage>, mut handler: Box<dyn DataHandler>) {
+    /// Stop reading the requests, send the queued and stop.
+    ///
+    /// The request handling is finished first.
+    pub fn drain(&self) {
+        self.draining.cancel();
+    }
+
+    async fn run_loop(self, mut stream: FrameStream, mut receiver: PeerReceiver, mut handler: Box<dyn DataHandler>, config: HeartbeatConfig) {
         let mut errs = 0;
         macro_rules! got_err {
             () => {
@@ -53,21 +92,57 @@ impl Peer {
         }
         let mut buf = Vec::new();
         buf.resize(65536, 0);
+        // the large messages are sent as chunks
+        let mut chunker = Chunker::default();
+        let mut reassembler = Reassembler::default();
+        // set by NetworkMessage::Compress after negotiated
+        let mut compression = None;
+        // set by NetworkMessage::Heartbeat after negotiated
+        let mut heartbeat = false;
+        let mut rtt = RttEstimator::default();
+        let mut ticker = tokio::time::interval(config.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last_received = Instant::now();
+        let mut last_handled = Instant::now();
+        let mut draining = false;
         while self.listening.load(Ordering::Acquire) {
+            if draining && self.sender.is_empty() {
+                break;
+            }
             select! {
                 mut msg = receiver.recv() => {
                     loop {
                         match msg {
-                            Some(msg) => {
+                            Some(Queued { msg, permit }) => {
                                 match msg {
+                                    NetworkMessage::Compress(x) => {
+                                        compression = x;
+                                    }
+                                    NetworkMessage::Heartbeat(x) => {
+                                        heartbeat = x;
+                                        last_received = Instant::now();
+                                    }
                                     NetworkMessage::Rely(packet) => {
-                                        if let Err(e) = stream.send(&packet[..]).await {
-                                            error!("Send packet failed for {:?}", e);
-                                            got_err!();
-                                        } else {
-                                            errs = 0;
+                                        let packet = match compression {
+                                            Some(x) => compress(x, packet, &self.compression),
+                                            None => packet,
+                                        };
+                                        let chunks = chunker.split(packet).unwrap_or_else(|e| {
+                                            error!("Drop the packet for {}", e);
+                                            vec![]
+                                        });
+                                        for chunk in chunks {
+                                            if let Err(e) = stream.send(&chunk[..]).await {
+                                                error!("Send packet failed for {:?}", e);
+                                                got_err!();
+                                            } else {
+                                                errs = 0;
+                                            }
                                         }
                                     }
+                                    NetworkMessage::Once(packet) if packet.len() > CHUNK_SIZE => {
+                                        error!("Drop the unreliable packet of {} bytes, too large to send at once", packet.len());
+                                    }
                                     NetworkMessage::Once(packet) => {
                                         match stream.poll_send(&mut Context::from_waker(&Waker::from(Arc::new(NeverWaker))), &packet[..]) {
                                             Poll::Ready(x) => {
@@ -89,14 +164,15 @@ impl Peer {
                                         }
                                     }
                                 }
+                                // the bytes are given back after sent
+                                drop(permit);
                             }
                             None => {
                                 break;
                             }
                         }
-                        if let Ok(nmsg) = receiver.try_recv() {
-                            msg = Some(nmsg);
-                        } else {
+                        msg = receiver.try_recv();
+                        if msg.is_none() {
                             break;
                         }
                     }
@@ -105,16 +181,71 @@ impl Peer {
                         got_err!();
                     }
                 }
-                data = stream.recv(&mut buf) => {
+                // stop reading the requests until the replies could be queued
+                data = stream.recv(&mut buf), if !draining && self.sender.accepting() => {
                     match data {
                         Ok(n) => {
                             errs = 0;
-                            trace!("Got packet for len {} from {}", n, self.addr);
-                            let task = handler.handle(&self, &buf[..n]);
-                            if !task.await {
-                                break;
+                            let now = Instant::now();
+                            last_received = now;
+                            if reassembler.expire(now) > 0 {
+                                warn!("Dropped the timed out messages from {}", self.addr);
+                            }
+                            let message = if is_heartbeat(&buf[..n]) {
+                                match Heartbeat::from_packet(&buf[..n]) {
+                                    Ok(Heartbeat::Ping(seq)) => {
+                                        let _ = self.sender.send_control(NetworkMessage::Once(Heartbeat::Pong(seq).to_packet()));
+                                    }
+                                    Ok(Heartbeat::Pong(seq)) => {
+                                        rtt.pong(seq, now);
+                                        rtt.publish(&self.link, now);
+                                    }
+                                    Err(e) => {
+                                        warn!("Drop bad heartbeat from {} for {}", self.addr, e);
+                                        got_err!();
+                                    }
+                                }
+                                None
+                            } else if is_chunk(&buf[..n]) {
+                                match reassembler.push(&buf[..n], now) {
+                                    Ok(Reassembled::Progress(progress)) => {
+                                        handler.progress(&self, progress);
+                                        None
+                                    }
+                                    Ok(Reassembled::Complete(message)) => Some(Cow::Owned(message)),
+                                    Err(e) => {
+                                        warn!("Drop bad chunk from {} for {}", self.addr, e);
+                                        got_err!();
+                                        None
+                                    }
+                                }
+                            } else {
+                                Some(Cow::Borrowed(&buf[..n]))
+                            };
+                            let message = match message {
+                                Some(x) if is_compressed(&x) => match decompress(&x, &self.compression) {
+                                    Ok(x) => Some(Cow::Owned(x)),
+                                    Err(e) => {
+                                        warn!("Drop bad compressed packet from {} for {}", self.addr, e);
+                                        got_err!();
+                                        None
+                                    }
+                                },
+                                x => x,
+                            };
+                            if let Some(message) = message {
+                                last_handled = now;
+                                trace!("Got packet for len {} from {}", message.len(), self.addr);
+                                let task = handler.handle(&self, &message);
+                                if !task.await {
+                                    break;
+                                }
+                                trace!("Handled packet.");
                             }
-                            trace!("Handled packet.");
+                        }
+                        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                            info!("{} closed the {} stream", self.addr, self.transport);
+                            break;
                         }
                         Err(e) => {
                             error!("Receive packet failed for {:?}", e);
@@ -122,11 +253,33 @@ impl Peer {
                         }
                     }
                 }
+                _ = self.draining.cancelled(), if !draining => {
+                    draining = true;
+                    handler.shutdown(&self);
+                }
+                _ = ticker.tick() => {
+                    let now = Instant::now();
+                    if config.idle_timeout.is_some_and(|x| now.saturating_duration_since(last_handled) >= x) {
+                        info!("Disconnect {} for idle", self.addr);
+                        break;
+                    }
+                    if heartbeat {
+                        // not reading while the queue is full, so received nothing is expected
+                        if self.sender.accepting() && now.saturating_duration_since(last_received) >= config.dead_timeout {
+                            warn!("{} is dead, nothing received for {:?}", self.addr, config.dead_timeout);
+                            break;
+                        }
+                        let _ = self.sender.send_control(NetworkMessage::Once(rtt.ping(now).to_packet()));
+                        rtt.publish(&self.link, now);
+                    }
+                }
 
             }
         }
-        info!("Stop listen to {}", self.addr);
+        info!("Stop listen to {}, {} bytes saved by compression, {} messages dropped", self.addr, self.compression.saved(),
+            self.sender.stats().dropped.load(Ordering::Relaxed));
         self.listening.store(false, Ordering::Release);
+        handler.closed(&self);
     }
 }
 
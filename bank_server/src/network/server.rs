@@ -1,18 +1,53 @@
 use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
-use log::info;
+use bank_protocol::queue::QueueDepth;
+use log::{info, warn};
 use tokio::{pin, select};
-use tokio::net::ToSocketAddrs;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tokio::sync::RwLock;
-use tokio_kcp::KcpListener;
+use tokio_kcp::KcpConfig;
+use tokio_util::sync::CancellationToken;
 
+use crate::network::DataHandlerGenerator;
+use crate::network::peer::{Peer, PeerConfig};
+use crate::network::transport::{self, Accepted, Endpoint, FrameStream, Listener};
 
-use crate::network::{DataHandlerGenerator, DEFAULT_KCP_CONFIG};
-use crate::network::peer::Peer;
+/// The accepted streams waiting for the server loop, the accept loops wait if full
+const ACCEPT_BACKLOG: usize = 1024;
+/// The WebSocket handshakes in flight, the streams beyond are closed at once
+const MAX_UPGRADES: usize = 256;
+
+/// The concurrent connections allowed, 0 for no limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    pub total: usize,
+    pub per_ip: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self { total: 10000, per_ip: 16 }
+    }
+}
+
+impl ConnectionLimits {
+    /// Whether one more connection from `ip` is allowed, with the counts of the running ones
+    pub fn allow(&self, total: usize, from_ip: usize) -> bool {
+        (self.total == 0 || total < self.total) && (self.per_ip == 0 || from_ip < self.per_ip)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ServerConfig {
+    /// Only used when binding
+    pub kcp: KcpConfig,
+    pub peer: PeerConfig,
+    pub connections: ConnectionLimits,
+}
 
 /// The server object which could be clone
 #[allow(unused)]
@@ -21,65 +56,236 @@ pub struct Server {
     pub running: Arc<AtomicBool>,
     /// The peers still running
     pub peers: Arc<RwLock<HashMap<SocketAddr, Peer>>>,
+    /// The settings of the peers accepted later, see [`Server::set_config`]
+    pub config: Arc<Mutex<ServerConfig>>,
+    /// Cancelled to stop accepting
+    pub shutdown: CancellationToken,
 }
 
 #[allow(unused)]
 impl Server {
-    /// Construct the server and start to listen messages.
-    pub async fn new(listen_ip: impl ToSocketAddrs, handler: impl DataHandlerGenerator) -> anyhow::Result<Self> {
-        let listener = KcpListener::bind(DEFAULT_KCP_CONFIG, listen_ip).await?;
+    /// Construct the server and start to listen messages on all the endpoints.
+    pub async fn new(endpoints: &[Endpoint], config: ServerConfig, handler: impl DataHandlerGenerator) -> anyhow::Result<Self> {
         let this = Self {
             running: Arc::new(AtomicBool::new(true)),
             peers: Default::default(),
+            config: Arc::new(Mutex::new(config)),
+            shutdown: CancellationToken::new(),
         };
-        tokio::spawn(this.clone().run_loop(listener, handler));
+        let handler = Arc::new(handler);
+        let accepted = this.listen(endpoints, config.kcp, handler.clone()).await?;
+        tokio::spawn(this.clone().run_loop(accepted, handler));
         Ok(this)
     }
 
-    /// Construct the server and start to listen messages.
-    pub async fn run_block(listen_ip: impl ToSocketAddrs, handler: impl DataHandlerGenerator) -> anyhow::Result<()> {
-        let listener = KcpListener::bind(DEFAULT_KCP_CONFIG, listen_ip).await?;
+    /// Construct the server and start to listen messages on all the endpoints.
+    pub async fn run_block(endpoints: &[Endpoint], config: ServerConfig, handler: impl DataHandlerGenerator) -> anyhow::Result<()> {
         let this = Self {
             running: Arc::new(AtomicBool::new(true)),
             peers: Default::default(),
+            config: Arc::new(Mutex::new(config)),
+            shutdown: CancellationToken::new(),
         };
-        this.run_loop(listener, handler).await;
+        let handler = Arc::new(handler);
+        let accepted = this.listen(endpoints, config.kcp, handler.clone()).await?;
+        this.run_loop(accepted, handler).await;
         Ok(())
     }
 
-    async fn run_loop(self, mut listener: KcpListener, handler: impl DataHandlerGenerator) {
+    /// Stop accepting, then drain all the peers, see [`Peer::drain`].
+    ///
+    /// Return false if some peers are still running after the deadline.
+    pub async fn shutdown(&self, deadline: Duration) -> bool {
+        self.running.store(false, Ordering::Release);
+        self.shutdown.cancel();
+        // never clone the peers, dropping one stops it
+        let listening: Vec<_> = {
+            let read = self.peers.read().await;
+            read.values().map(|x| {
+                x.drain();
+                x.listening.clone()
+            }).collect()
+        };
+        info!("Draining {} peers", listening.len());
+        let wait = async {
+            while listening.iter().any(|x| x.load(Ordering::Acquire)) {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+        tokio::time::timeout(deadline, wait).await.is_ok()
+    }
+
+    /// Used by the peers accepted later, the running ones keep theirs. The kcp config is ignored
+    pub fn set_config(&self, config: ServerConfig) {
+        *self.config.lock().unwrap() = config;
+    }
+
+    /// The messages waiting to be sent to all the peers
+    pub async fn queue_depth(&self) -> QueueDepth {
+        let read = self.peers.read().await;
+        read.values().map(|x| x.sender.depth()).fold(QueueDepth::default(), |a, b| a + b)
+    }
+
+    /// Bind all the endpoints first, the streams accepted are sent to the receiver
+    async fn listen<H: DataHandlerGenerator>(&self, endpoints: &[Endpoint], kcp: KcpConfig, handler: Arc<H>) -> anyhow::Result<Receiver<(FrameStream, SocketAddr)>> {
+        let (sender, receiver) = channel(ACCEPT_BACKLOG);
+        let acceptor = Arc::new(Acceptor {
+            shutdown: self.shutdown.clone(),
+            peers: self.peers.clone(),
+            config: self.config.clone(),
+            handler,
+            upgrading: Default::default(),
+            sender,
+        });
+        for endpoint in endpoints {
+            let listener = Listener::bind(*endpoint, kcp).await?;
+            info!("Listening {} on {}", endpoint.transport, endpoint.addr);
+            tokio::spawn(acceptor.clone().accept_loop(listener));
+        }
+        Ok(receiver)
+    }
+
+    async fn run_loop(self, mut accepted: Receiver<(FrameStream, SocketAddr)>, handler: Arc<impl DataHandlerGenerator>) {
         info!("Server looping");
         while self.running.load(Ordering::Acquire) {
             let sleep = tokio::time::sleep(Duration::from_secs(60));
             pin!(sleep);
             select! {
-                packet = listener.accept() => {
-                    match packet {
-                        Ok((stream, addr)) => {
-                            info!("Accepted KcpStream from {:?}", addr);
-                            let peer = Peer::new(stream, addr, handler.generate(addr.clone()));
-                            let mut write = self.peers.write().await;
-                            if let Some(old_peer) = write.insert(peer.addr, peer) {
-                                old_peer.listening.store(false, Ordering::Relaxed);
-                            }
-
-                            write.retain(|_, p| p.listening.load(Ordering::Relaxed));
-                        }
-                        Err(e) => {
-                            log::warn!("accept packet from listener failed for {:?}", e);
-                        }
+                stream = accepted.recv() => {
+                    let Some((stream, addr)) = stream else {
+                        break;
+                    };
+                    let config = *self.config.lock().unwrap();
+                    let mut write = self.peers.write().await;
+                    let (total, from_ip) = count_running(&write, addr.ip());
+                    if !config.connections.allow(total, from_ip) {
+                        warn!("Rejected {} stream from {:?}, {} running and {} from the ip", stream.transport(), addr, total, from_ip);
+                        continue;
+                    }
+                    if !handler.admit(addr) {
+                        continue;
                     }
+                    info!("Accepted {} stream from {:?}", stream.transport(), addr);
+                    let peer = Peer::with_config(stream, addr, handler.generate(addr), config.peer);
+                    if let Some(old_peer) = write.insert(peer.addr, peer) {
+                        old_peer.listening.store(false, Ordering::Relaxed);
+                    }
+
+                    write.retain(|_, p| p.listening.load(Ordering::Relaxed));
                 }
                 _ = &mut sleep => {
                     let mut write = self.peers.write().await;
                     write.retain(|_, p| p.listening.load(Ordering::Relaxed));
                 }
+                _ = self.shutdown.cancelled() => {
+                    break;
+                }
             }
         }
         info!("Server loop exited");
     }
 }
 
+/// Shared by the accept loops of all the endpoints
+struct Acceptor<H> {
+    shutdown: CancellationToken,
+    peers: Arc<RwLock<HashMap<SocketAddr, Peer>>>,
+    config: Arc<Mutex<ServerConfig>>,
+    handler: Arc<H>,
+    upgrading: Arc<Mutex<Upgrading>>,
+    sender: Sender<(FrameStream, SocketAddr)>,
+}
+
+impl<H: DataHandlerGenerator> Acceptor<H> {
+    async fn accept_loop(self: Arc<Self>, mut listener: Listener) {
+        loop {
+            let accepted = select! {
+                _ = self.shutdown.cancelled() => break,
+                x = listener.accept() => x,
+            };
+            match accepted {
+                Ok((Accepted::Ready(stream), addr)) => {
+                    if self.sender.send((*stream, addr)).await.is_err() {
+                        break;
+                    }
+                }
+                Ok((Accepted::Upgrade(stream), addr)) => {
+                    let Some(slot) = self.start_upgrade(addr).await else {
+                        continue;
+                    };
+                    let sender = self.sender.clone();
+                    tokio::spawn(async move {
+                        match transport::websocket(stream).await {
+                            Ok(stream) => {
+                                drop(slot);
+                                let _ = sender.send((stream, addr)).await;
+                            }
+                            Err(e) => {
+                                log::warn!("Upgrade websocket from {} failed for {:?}", addr, e);
+                            }
+                        }
+                    });
+                }
+                Err(e) => {
+                    log::warn!("accept packet from listener failed for {:?}", e);
+                }
+            }
+        }
+    }
+
+    /// Check the stream before its handshake like the server loop does, the handshakes in flight
+    /// are counted as the running connections
+    async fn start_upgrade(&self, addr: SocketAddr) -> Option<UpgradeSlot> {
+        if !self.handler.admit(addr) {
+            return None;
+        }
+        let limits = self.config.lock().unwrap().connections;
+        let (total, from_ip) = count_running(&*self.peers.read().await, addr.ip());
+        let mut upgrading = self.upgrading.lock().unwrap();
+        let pending = upgrading.by_ip.get(&addr.ip()).copied().unwrap_or(0);
+        if upgrading.total >= MAX_UPGRADES || !limits.allow(total + upgrading.total, from_ip + pending) {
+            warn!("Rejected websocket stream from {:?}, {} running and {} upgrading, {} and {} of them from the ip", addr, total, upgrading.total, from_ip, pending);
+            return None;
+        }
+        upgrading.total += 1;
+        *upgrading.by_ip.entry(addr.ip()).or_default() += 1;
+        Some(UpgradeSlot { upgrading: self.upgrading.clone(), ip: addr.ip() })
+    }
+}
+
+/// The WebSocket handshakes in flight
+#[derive(Debug, Default)]
+struct Upgrading {
+    total: usize,
+    by_ip: HashMap<IpAddr, usize>,
+}
+
+/// Counted in [`Upgrading`] until dropped
+struct UpgradeSlot {
+    upgrading: Arc<Mutex<Upgrading>>,
+    ip: IpAddr,
+}
+
+impl Drop for UpgradeSlot {
+    fn drop(&mut self) {
+        let mut upgrading = self.upgrading.lock().unwrap();
+        upgrading.total -= 1;
+        if let Some(x) = upgrading.by_ip.get_mut(&self.ip) {
+            *x -= 1;
+            if *x == 0 {
+                upgrading.by_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// The peers running, and the ones of them from `ip`
+fn count_running(peers: &HashMap<SocketAddr, Peer>, ip: IpAddr) -> (usize, usize) {
+    peers.values()
+        .filter(|x| x.listening.load(Ordering::Relaxed))
+        .fold((0, 0), |(total, from_ip), x| (total + 1, from_ip + (x.addr.ip() == ip) as usize))
+}
+
 impl Drop for Server {
     fn drop(&mut self) {
         self.running.store(false, Ordering::Relaxed);
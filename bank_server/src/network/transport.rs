@@ -0,0 +1,74 @@
+//! The listeners of the [`Peer`](crate::network::peer::Peer) streams, the framing is shared with the
+//! client in [`bank_protocol::transport`].
+//!
+//! KCP and TCP could share the same port, WebSocket listens on another one.
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bank_protocol::transport::{websocket_config, websocket_error};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_kcp::{KcpConfig, KcpListener};
+use tokio_tungstenite::MaybeTlsStream;
+
+pub use bank_protocol::transport::{FrameStream, Transport};
+
+/// Drop the WebSocket connections not upgraded in time
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The address to listen with the transport
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Endpoint {
+    pub transport: Transport,
+    pub addr: SocketAddr,
+}
+
+impl Endpoint {
+    pub fn new(transport: Transport, addr: SocketAddr) -> Self {
+        Self { transport, addr }
+    }
+}
+
+/// Upgrade the accepted tcp stream
+pub async fn websocket(stream: TcpStream) -> io::Result<FrameStream> {
+    let _ = stream.set_nodelay(true);
+    let upgrade = tokio_tungstenite::accept_async_with_config(MaybeTlsStream::Plain(stream), Some(websocket_config()));
+    match tokio::time::timeout(UPGRADE_TIMEOUT, upgrade).await {
+        Ok(x) => Ok(FrameStream::WebSocket(x.map_err(websocket_error)?)),
+        Err(_) => Err(io::ErrorKind::TimedOut.into()),
+    }
+}
+
+/// Accept the streams of a transport
+pub enum Listener {
+    Kcp(KcpListener),
+    Tcp(TcpListener),
+    WebSocket(TcpListener),
+}
+
+impl Listener {
+    /// The kcp config is only used by the kcp endpoint
+    pub async fn bind(endpoint: Endpoint, kcp: KcpConfig) -> anyhow::Result<Self> {
+        Ok(match endpoint.transport {
+            Transport::Kcp => Listener::Kcp(KcpListener::bind(kcp, endpoint.addr).await?),
+            Transport::Tcp => Listener::Tcp(TcpListener::bind(endpoint.addr).await?),
+            Transport::WebSocket => Listener::WebSocket(TcpListener::bind(endpoint.addr).await?),
+        })
+    }
+
+    /// Accept the next stream, the websocket one is not upgraded yet
+    pub async fn accept(&mut self) -> io::Result<(Accepted, SocketAddr)> {
+        match self {
+            Listener::Kcp(x) => x.accept().await.map(|(s, addr)| (Accepted::Ready(Box::new(FrameStream::Kcp(s))), addr)).map_err(io::Error::from),
+            Listener::Tcp(x) => x.accept().await.map(|(s, addr)| (Accepted::Ready(Box::new(FrameStream::tcp(s))), addr)),
+            Listener::WebSocket(x) => x.accept().await.map(|(s, addr)| (Accepted::Upgrade(s), addr)),
+        }
+    }
+}
+
+pub enum Accepted {
+    Ready(Box<FrameStream>),
+    /// Need [`websocket`], not in the accept loop to not block the others
+    Upgrade(TcpStream),
+}
@@ -0,0 +1,140 @@
+//! The logs as tracing events, in text or json lines, and the spans exported to an OTLP collector.
+//!
+//! Spans carried by the events:
+//! * `peer` the address and the transport, around [`Peer`](crate::network::peer::Peer)'s loop
+//! * `session` the id of the connection and the account after logged in
+//! * `request` the correlation id `<session>-<request id>` and the operation
+//! * `http` the requests of the gateway, the correlation id is from `x-request-id` if given
+//!
+//! The `log` macros used by the most code are bridged to tracing, so they get the spans too.
+//! Never log the names, the phones or the codes as they are, wrap them in [`Redacted`].
+
+use std::fmt::{Display, Formatter};
+
+use anyhow::Context;
+use bank_protocol::phone::mask_phone;
+use opentelemetry_otlp::WithExportConfig;
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::{EnvFilter, fmt, Registry, reload};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::config::LogConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    /// One json object per line with the spans
+    Json,
+}
+
+/// Keep it to change the filters, and to flush the spans at exit
+pub struct Telemetry {
+    filter: reload::Handle<EnvFilter, Registry>,
+    otlp: bool,
+}
+
+/// The level with the directives, `RUST_LOG` is applied last if set
+pub fn env_filter(config: &LogConfig) -> anyhow::Result<EnvFilter> {
+    config.level.parse::<LevelFilter>().map_err(|_| anyhow::anyhow!("Unknown log.level {}", config.level))?;
+    let mut directives = config.level.clone();
+    for x in [config.filters.clone(), std::env::var("RUST_LOG").unwrap_or_default()] {
+        if !x.is_empty() {
+            directives.push(',');
+            directives.push_str(&x);
+        }
+    }
+    EnvFilter::try_new(&directives).with_context(|| format!("Bad log filters {}", directives))
+}
+
+impl Telemetry {
+    /// Install as the global subscriber, should be in the tokio runtime if exporting
+    pub fn init(config: &LogConfig) -> anyhow::Result<Self> {
+        let (filter, handle) = reload::Layer::new(env_filter(config)?);
+        let text = (config.format == LogFormat::Text).then(fmt::layer);
+        let json = (config.format == LogFormat::Json).then(|| fmt::layer().json().with_current_span(false).with_span_list(true));
+        let otlp = match &config.otlp_endpoint {
+            Some(endpoint) => {
+                let tracer = opentelemetry_otlp::new_pipeline()
+                    .tracing()
+                    .with_exporter(opentelemetry_otlp::new_exporter().http().with_endpoint(endpoint))
+                    .with_trace_config(opentelemetry_sdk::trace::config().with_resource(opentelemetry_sdk::Resource::new([
+                        opentelemetry::KeyValue::new("service.name", "bank_server"),
+                    ])))
+                    .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+                Some(tracing_opentelemetry::layer().with_tracer(tracer))
+            }
+            None => None,
+        };
+        let exporting = otlp.is_some();
+        tracing_subscriber::registry().with(filter).with(text).with(json).with(otlp).try_init()?;
+        // the filter decides, the level of `log` is fixed when bridged so it could be reloaded
+        log::set_max_level(log::LevelFilter::Trace);
+        Ok(Self { filter: handle, otlp: exporting })
+    }
+
+    /// Apply the level and the filters, the format and the exporter need restart
+    pub fn reload(&self, config: &LogConfig) -> anyhow::Result<()> {
+        self.filter.reload(env_filter(config)?)?;
+        Ok(())
+    }
+
+    /// Export the spans not sent yet
+    pub async fn shutdown(self) {
+        if self.otlp {
+            let _ = tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await;
+        }
+    }
+}
+
+/// A new id for the connection
+pub fn session_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// Hide the personal data in the logs
+pub enum Redacted<'a> {
+    /// Only the first char is kept
+    Name(&'a str),
+    /// Like 138****5678
+    Phone(&'a str),
+}
+
+impl Display for Redacted<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Redacted::Name(x) => {
+                let mut chars = x.chars();
+                match chars.next() {
+                    Some(first) => write!(f, "{}{}", first, "*".repeat(chars.count().min(8))),
+                    None => Ok(()),
+                }
+            }
+            Redacted::Phone(x) => f.write_str(&mask_phone(x)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::config::LogConfig;
+    use crate::telemetry::{env_filter, Redacted};
+
+    #[test]
+    fn redacted() {
+        assert_eq!(Redacted::Name("张三丰").to_string(), "张**");
+        assert_eq!(Redacted::Name("").to_string(), "");
+        assert_eq!(Redacted::Phone("13812345678").to_string(), "138****5678");
+    }
+
+    #[test]
+    fn filter() {
+        let mut config = LogConfig { filters: "sqlx=warn,bank_server::network=debug".into(), ..Default::default() };
+        assert!(env_filter(&config).is_ok());
+        config.level = "loud".into();
+        assert!(env_filter(&config).is_err());
+    }
+}
@@ -1,5 +1,10 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::SystemTime;
+
 use crate::config::consts::*;
 use crate::engine::config::Config;
+use crate::engine::global::GLOBAL_DATA;
 
 pub struct ConfigData {
     user_name: String,
@@ -13,6 +18,32 @@ impl From<&Config> for ConfigData {
     }
 }
 
+/// The id of this installation, told to the server after logged in for its risk rules
+pub fn device_id() -> Option<String> {
+    GLOBAL_DATA.cfg_data.read().unwrap().get_str(DEVICE_ID_KEY).map(str::to_string)
+}
+
+/// Where the server listens WebSocket, it is not the same port as KCP and TCP
+pub fn websocket_addr() -> String {
+    GLOBAL_DATA.cfg_data.read().unwrap().get_str(WEBSOCKET_ADDR_KEY).unwrap_or(DEFAULT_WEBSOCKET_ADDR).to_string()
+}
+
+/// 32 random hex digits, made once at the first start
+pub fn new_device_id() -> String {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos();
+    let mut ret = String::new();
+    for _ in 0..2 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(now);
+        ret.push_str(&format!("{:016x}", hasher.finish()));
+    }
+    ret
+}
+
 pub mod consts {
     pub const USER_NAME_KEY: &'static str = "name";
+    pub const DEVICE_ID_KEY: &'static str = "device_id";
+    pub const WEBSOCKET_ADDR_KEY: &'static str = "websocket_addr";
+    /// The default of the server
+    pub const DEFAULT_WEBSOCKET_ADDR: &'static str = "[::1]:1235";
 }
\ No newline at end of file
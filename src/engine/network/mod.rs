@@ -1,11 +1,15 @@
 use std::time::Duration;
 
+use bank_protocol::chunk::ChunkProgress;
 use tokio_kcp::{KcpConfig, KcpNoDelayConfig};
 
+pub use bank_protocol::queue::NetworkMessage;
+
 use crate::engine::network::peer::Peer;
 
 pub mod server;
 pub mod peer;
+pub mod transport;
 
 #[allow(unused)]
 /// The handler to handle the message from `Peer`
@@ -14,13 +18,9 @@ pub trait DataHandler: Send + Clone + 'static {
     /// `data` The data received from `src`
     /// Return true means successful
     fn handle(&self, src: &Peer, data: &[u8]) -> bool;
-}
 
-#[allow(unused)]
-#[derive(Debug)]
-pub enum NetworkMessage {
-    Rely(Vec<u8>),
-    Once(Vec<u8>),
+    /// Called for every chunk of a large message before it is complete and handled
+    fn progress(&self, _src: &Peer, _progress: ChunkProgress) {}
 }
 
 #[allow(unused)]
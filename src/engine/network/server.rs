@@ -12,6 +12,7 @@ use tokio_kcp::KcpListener;
 
 use crate::engine::network::{DataHandler, DEFAULT_KCP_CONFIG};
 use crate::engine::network::peer::Peer;
+use crate::engine::network::transport::FrameStream;
 
 /// The server object which could be clone
 #[allow(unused)]
@@ -45,7 +46,7 @@ impl Server {
                     match packet {
                         Ok((stream, addr)) => {
                             info!("Accepted KcpStream from {:?}", addr);
-                            let peer = Peer::new(stream, addr, handler.clone());
+                            let peer = Peer::new(FrameStream::Kcp(stream), addr, handler.clone());
                             let mut write = self.peers.write().await;
                             if let Some(old_peer) = write.insert(peer.addr, peer) {
                                 old_peer.listening.store(false, Ordering::Relaxed);
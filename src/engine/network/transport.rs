@@ -0,0 +1,28 @@
+//! Connect the streams a [`Peer`](crate::engine::network::peer::Peer) could use, the framing is
+//! shared with the server in [`bank_protocol::transport`].
+//!
+//! The bank server listens KCP and TCP on the same port by default, and WebSocket on its own
+//! address, which the client is told by the `websocket_addr` setting.
+
+use std::net::SocketAddr;
+
+use bank_protocol::transport::websocket_config;
+use tokio::net::TcpStream;
+use tokio_kcp::KcpStream;
+
+pub use bank_protocol::transport::{FrameStream, Transport};
+
+use crate::engine::network::DEFAULT_KCP_CONFIG;
+
+/// Connect to the address the server listens the transport on
+pub async fn connect(transport: Transport, addr: SocketAddr) -> anyhow::Result<FrameStream> {
+    Ok(match transport {
+        Transport::Kcp => FrameStream::Kcp(KcpStream::connect(&DEFAULT_KCP_CONFIG, addr).await?),
+        Transport::Tcp => FrameStream::tcp(TcpStream::connect(addr).await?),
+        Transport::WebSocket => {
+            let url = format!("ws://{}/", addr);
+            let (stream, _) = tokio_tungstenite::connect_async_with_config(url, Some(websocket_config()), true).await?;
+            FrameStream::WebSocket(stream)
+        }
+    })
+}
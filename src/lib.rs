@@ -8,7 +8,6 @@ use crate::state::MainMenu;
 mod engine;
 mod state;
 mod config;
-mod ext;
 
 
 pub fn real_main() {
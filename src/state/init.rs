@@ -6,7 +6,8 @@ use log::error;
 use toml_edit::{Item, Value};
 use wgpu::{Device, Queue};
 
-use crate::config::consts::USER_NAME_KEY;
+use crate::config::consts::{DEFAULT_WEBSOCKET_ADDR, DEVICE_ID_KEY, USER_NAME_KEY, WEBSOCKET_ADDR_KEY};
+use crate::config::new_device_id;
 use crate::engine::{GameState, LoopState, ResourceManager, StateData, StateEvent, Trans, WaitFutureState, WaitResult};
 use crate::engine::global::{GLOBAL_DATA, INITED, IO_POOL};
 
@@ -43,6 +44,8 @@ impl GameState for InitState {
                         let handle = IO_POOL.spawn_with_handle(async {
                             let mut cfg = GLOBAL_DATA.cfg_data.write().unwrap();
                             cfg.toml_mut().entry(USER_NAME_KEY).or_insert(Item::Value(Value::from("guest")));
+                            cfg.toml_mut().entry(DEVICE_ID_KEY).or_insert_with(|| Item::Value(Value::from(new_device_id())));
+                            cfg.toml_mut().entry(WEBSOCKET_ADDR_KEY).or_insert(Item::Value(Value::from(DEFAULT_WEBSOCKET_ADDR)));
                             if cfg.is_dirty() {
                                 std::fs::write("cfg.toml", cfg.toml().to_string())?;
                             }
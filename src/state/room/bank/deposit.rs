@@ -1,12 +1,10 @@
 use std::str::FromStr;
 
-use bytes::BufMut;
+use bank_protocol::Request;
 use egui::{Button, Color32, Context, Frame, Vec2};
 use msgbox::IconType;
 
-use crate::engine::network::NetworkMessage;
 use crate::engine::StateData;
-use crate::ext::PacketWriteExt;
 use crate::state::room::bank::{BankUi, BankUiRenderArg};
 use crate::state::room::bank::index::{Index, User};
 
@@ -24,6 +22,10 @@ impl Deposit {
 
 
 impl BankUi for Deposit {
+    fn user_mut(&mut self) -> Option<&mut User> {
+        Some(&mut self.user)
+    }
+
     fn render(&mut self, s: &mut StateData, ctx: &Context, args: BankUiRenderArg<'_>) -> Option<Box<dyn BankUi>> {
         let mut ret = None;
         egui::CentralPanel::default().frame(Frame::default().fill(Color32::BLACK)).show(ctx, |ui| {
@@ -50,12 +52,7 @@ impl BankUi for Deposit {
                             }
                         };
                         if amount > 0 {
-                            let mut data = Vec::<u8>::new();
-                            data.add_header();
-                            data.put_u8(0);
-                            data.put_u32(amount);
-                            let peer = args.target;
-                            peer.sender.send(NetworkMessage::Rely(data)).expect("how send error");
+                            args.send(&Request::Deposit { amount });
                         }
                     }
                     if ui.add_sized(size, back).clicked() {
@@ -1,11 +1,13 @@
-use bytes::BufMut;
+use bank_protocol::{account, Request, UserInfo};
 use egui::{Button, Color32, Context, Frame, Vec2};
 
-use crate::engine::network::NetworkMessage;
 use crate::engine::StateData;
-use crate::ext::PacketWriteExt;
 use crate::state::room::bank::{BankUi, BankUiRenderArg};
 use crate::state::room::bank::deposit::Deposit;
+use crate::state::room::bank::payee::request_payees;
+use crate::state::room::bank::phone::PhoneAlias;
+use crate::state::room::bank::profile::AccountSettings;
+use crate::state::room::bank::totp::TotpMenu;
 use crate::state::room::bank::transfer::Transfer;
 use crate::state::room::bank::withdraw::Withdraw;
 
@@ -17,12 +19,22 @@ pub struct User {
     pub phone: String,
 }
 
+impl From<UserInfo> for User {
+    fn from(info: UserInfo) -> Self {
+        Self { id: info.id, balance: info.balance, name: info.name, phone: info.phone }
+    }
+}
+
 pub struct Index {
     pub(crate) user: User,
 }
 
 
 impl BankUi for Index {
+    fn user_mut(&mut self) -> Option<&mut User> {
+        Some(&mut self.user)
+    }
+
     fn render(&mut self, s: &mut StateData, ctx: &Context, args: BankUiRenderArg<'_>) -> Option<Box<dyn BankUi>> {
         let mut ret = None;
         egui::CentralPanel::default().frame(Frame::default().fill(Color32::BLACK)).show(ctx, |ui| {
@@ -30,16 +42,19 @@ impl BankUi for Index {
                 // 1600 900
 
                 let scale = s.app.gpu.as_ref().unwrap().size_scale;
-                let size = Vec2::new(320.0, 180.0) * Vec2::from(scale);
+                let size = Vec2::new(320.0, 100.0) * Vec2::from(scale);
                 let deposit = Button::new("存款").min_size(size);
                 let withdraw = Button::new("取款").min_size(size);
                 let transfer = Button::new("转账").min_size(size);
                 let log = Button::new("记录").min_size(size);
+                let totp = Button::new("两步验证").min_size(size);
+                let phone = Button::new("手机号收款").min_size(size);
+                let settings = Button::new("账户设置").min_size(size);
                 ui.vertical_centered(|ui| {
                     let max = ui.max_rect().height();
-                    ui.add_space(max * 0.5 - size.y * 2.0);
+                    ui.add_space(max * 0.5 - size.y * 3.5);
                     ui.label(format!("账号: {}，余额：{}.{}，姓名：{}，联系电话：{}",
-                                     self.user.id, self.user.balance / 100, self.user.balance % 100, self.user.name, self.user.phone));
+                                     account::group_account_number(&account::format_account_number(self.user.id)), self.user.balance / 100, self.user.balance % 100, self.user.name, self.user.phone));
 
                     if ui.add_sized(size, deposit).clicked() {
                         ret = Some(Box::new(Deposit::new(self.user.clone())) as Box<dyn BankUi>);
@@ -49,14 +64,20 @@ impl BankUi for Index {
 
                     }
                     if ui.add_sized(size, transfer).clicked() {
+                        request_payees(&args, false);
                         ret = Some(Box::new(Transfer::new(self.user.clone())) as Box<dyn BankUi>);
                     }
                     if ui.add_sized(size, log).clicked() {
-                        let mut data = Vec::<u8>::new();
-                        data.add_header();
-                        data.put_u8(3);
-                        let peer = args.target;
-                        peer.sender.send(NetworkMessage::Rely(data)).expect("how send error");
+                        args.send(&Request::Info);
+                    }
+                    if ui.add_sized(size, totp).clicked() {
+                        ret = Some(Box::new(TotpMenu::new(self.user.clone())) as Box<dyn BankUi>);
+                    }
+                    if ui.add_sized(size, phone).clicked() {
+                        ret = Some(Box::new(PhoneAlias::new(self.user.clone())) as Box<dyn BankUi>);
+                    }
+                    if ui.add_sized(size, settings).clicked() {
+                        ret = Some(Box::new(AccountSettings::new(self.user.clone())) as Box<dyn BankUi>);
                     }
                 });
             });
@@ -1,4 +1,4 @@
-use chrono::Utc;
+use bank_protocol::{account, TradeRecord};
 use egui::{Button, Color32, Context, Frame, Label, ScrollArea, Sense, Ui, Vec2};
 use nalgebra::DimAdd;
 
@@ -6,29 +6,14 @@ use crate::engine::StateData;
 use crate::state::room::bank::{BankUi, BankUiRenderArg};
 use crate::state::room::bank::index::{Index, User};
 
-pub struct TradeInfo {
-    pub tid: i32,
-    pub receiver: u32,
-    pub sender: String,
-    pub time: chrono::DateTime<Utc>,
-    pub amount: i32,
-}
-
-impl TradeInfo {
-    pub fn new(tid: i32, receiver: u32, sender: String, time: chrono::DateTime<Utc>, amount: i32) -> Self {
-        Self { tid, receiver, sender, time, amount }
-    }
-
-}
-
 pub struct InfoUi {
     pub(crate) user: User,
-    info: Vec<TradeInfo>,
+    info: Vec<TradeRecord>,
 
 }
 
 impl InfoUi {
-    pub fn new(user: User, mut info: Vec<TradeInfo>) -> Self {
+    pub fn new(user: User, mut info: Vec<TradeRecord>) -> Self {
         info.sort_unstable_by(|a, b| {
             a.time.cmp(&b.time)
         });
@@ -38,6 +23,10 @@ impl InfoUi {
 
 
 impl BankUi for InfoUi {
+    fn user_mut(&mut self) -> Option<&mut User> {
+        Some(&mut self.user)
+    }
+
     fn render(&mut self, s: &mut StateData, ctx: &Context, args: BankUiRenderArg<'_>) -> Option<Box<dyn BankUi>> {
         let mut ret = None;
         egui::CentralPanel::default().frame(Frame::default().fill(Color32::BLACK)).show(ctx, |ui| {
@@ -75,7 +64,7 @@ impl BankUi for InfoUi {
                                 for info in &self.info {
                                     ui.horizontal(|ui| {
                                         add_ui(ui, Label::new(info.tid.to_string()));
-                                        add_ui(ui, Label::new(info.receiver.to_string()));
+                                        add_ui(ui, Label::new(account::format_account_number(info.receiver)));
                                         add_ui(ui, Label::new(&info.sender));
                                         add_ui(ui, Label::new(&info.time.to_string()));
                                         add_ui(ui, Label::new(info.amount.to_string()));
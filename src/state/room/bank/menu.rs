@@ -1,18 +1,22 @@
 use std::hash::{Hash, Hasher, SipHasher};
-use std::str::FromStr;
 
-use bytes::BufMut;
+use bank_protocol::{account, AuthRequest};
 use egui::{Button, Color32, Context, Frame, TextEdit, Vec2};
 use msgbox::IconType;
 
-use crate::engine::network::NetworkMessage;
 use crate::engine::StateData;
-use crate::ext::PacketWriteExt;
 use crate::state::room::bank::{BankUi, BankUiRenderArg};
 
 #[derive(Default)]
 pub struct BankMenu {}
 
+/// The server only knows the hash
+pub(super) fn hash_password(password: &str) -> u32 {
+    let mut hasher = SipHasher::new_with_keys(233, 9961);
+    password.hash(&mut hasher);
+    hasher.finish() as u32
+}
+
 #[derive(Default)]
 pub struct Login {
     id: String,
@@ -21,7 +25,6 @@ pub struct Login {
 
 #[derive(Default)]
 pub struct Register {
-    id: String,
     password: String,
     name: String,
     phone: String,
@@ -71,6 +74,11 @@ impl BankUi for Login {
                     ui.add_space(max * 0.5 - size.y * 0.5);
                     ui.label("账号：");
                     ui.text_edit_singleline(&mut self.id);
+                    if !self.id.is_empty() {
+                        if let Err(e) = account::parse_account_number(&self.id) {
+                            ui.colored_label(Color32::RED, e.msg());
+                        }
+                    }
                     ui.label("密码：");
                     ui.add(TextEdit::singleline(&mut self.password).password(true));
                     ui.label("");
@@ -80,29 +88,19 @@ impl BankUi for Login {
                             msgbox::create("错误", "账号密码不能为空", IconType::Error).expect("panic!");
                             return;
                         }
-                        let mut data = Vec::<u8>::new();
-                        data.add_header();
-                        // format:
-                        // Register packet: (id: u32) (password: u32) (name: String) (phone_number: String)
-
-                        let id = match u64::from_str(&self.id) {
+                        let id = match account::parse_account_number(&self.id) {
                             Ok(id) => {
                                 id
                             }
-                            Err(_) => {
-                                msgbox::create("错误", "账号需要为数字", IconType::Error).expect("panic!");
+                            Err(e) => {
+                                msgbox::create("错误", e.msg(), IconType::Error).expect("panic!");
                                 return;
                             }
                         };
 
-                        let mut hasher = SipHasher::new_with_keys(233, 9961);
-                        self.password.hash(&mut hasher);
-                        let pswd = hasher.finish();
+                        let pswd = hash_password(&self.password);
 
-                        data.put_u32(id as u32);
-                        data.put_u32(pswd as u32);
-                        let peer = arg.target;
-                        peer.sender.send(NetworkMessage::Rely(data)).expect("how send error");
+                        arg.send(&AuthRequest::Login { id, password: pswd });
                     }
                 });
             });
@@ -124,9 +122,7 @@ impl BankUi for Register {
                 ui.vertical_centered(|ui| {
                     let max = ui.max_rect().height();
                     ui.add_space(max * 0.5 - size.y * 0.5 - size.y);
-                    ui.label("账号：");
-
-                    ui.text_edit_singleline(&mut self.id);
+                    ui.label("账号由银行分配，注册成功后显示在主页");
                     ui.label("密码：");
                     ui.add(TextEdit::singleline(&mut self.password).password(true));
                     ui.label("姓名：");
@@ -135,36 +131,17 @@ impl BankUi for Register {
                     ui.text_edit_singleline(&mut self.phone);
                     ui.label("");
                     if ui.add_sized(size, login).clicked() {
-                        if self.id.is_empty() || self.password.is_empty() {
-                            msgbox::create("错误", "账号密码不能为空", IconType::Error).expect("panic!");
+                        if self.password.is_empty() || self.name.is_empty() {
+                            msgbox::create("错误", "密码和姓名不能为空", IconType::Error).expect("panic!");
                             return;
                         }
-                        let mut data = Vec::<u8>::new();
-                        data.add_header();
-                        // format:
-                        // Register packet: (id: u32) (password: u32) (name: String) (phone_number: String)
-
-                        let id = match u64::from_str(&self.id) {
-                            Ok(id) => {
-                                id
-                            }
-                            Err(e) => {
-                                msgbox::create("错误", "账号需要为数字", IconType::Error).expect("panic!");
-                                return;
-                            }
-                        };
-
-                        let mut hasher = SipHasher::new_with_keys(233, 9961);
-                        self.password.hash(&mut hasher);
-                        let pswd = hasher.finish();
-
-                        data.put_u32(id as u32);
-                        data.put_u32(pswd as u32);
-                        data.write_string(&self.name);
-                        data.write_string(&self.phone);
+                        let pswd = hash_password(&self.password);
 
-                        let peer = arg.target;
-                        peer.sender.send(NetworkMessage::Rely(data)).expect("how send error");
+                        arg.send(&AuthRequest::Register {
+                            password: pswd,
+                            name: self.name.clone(),
+                            phone: self.phone.clone(),
+                        });
                     }
                 });
             });
@@ -1,22 +1,60 @@
+use bank_protocol::{Message, PacketWriter};
 use egui::Context;
+use msgbox::IconType;
 use tokio::runtime::Runtime;
 
+use crate::engine::network::NetworkMessage;
 use crate::engine::network::peer::Peer;
 use crate::engine::StateData;
+use crate::state::room::bank::index::User;
+use crate::state::room::bank::request::{Operation, RequestTracker};
 
 pub(crate) mod menu;
 pub(crate) mod index;
-mod transfer;
-mod withdraw;
+pub(super) mod transfer;
+pub(super) mod withdraw;
 mod deposit;
 pub(super) mod info;
+pub(super) mod totp;
+pub(super) mod payee;
+mod phone;
+mod profile;
+pub(super) mod notify;
+pub(super) mod request;
 
 pub struct BankUiRenderArg<'a> {
     pub(crate) rt: &'a Runtime,
     pub(crate) target: &'a Peer,
+    pub(crate) requests: &'a RequestTracker,
+}
+
+impl BankUiRenderArg<'_> {
+    /// Encode and send the request tagged with a new id to server
+    pub fn send<M: Message + Operation>(&self, msg: &M) {
+        let id = self.requests.start(msg.operation());
+        let mut w = PacketWriter::with_header();
+        w.put_u32(id);
+        match msg.encode(&mut w) {
+            Ok(()) => if let Err(e) = self.target.sender.send(NetworkMessage::Rely(w.into_inner())) {
+                // the server is not reading the requests
+                self.requests.finish(id);
+                log::warn!("Send request failed for {}", e);
+                msgbox::create("错误", "网络繁忙，请稍后再试", IconType::Error).expect("panic!");
+            },
+            Err(e) => {
+                self.requests.finish(id);
+                msgbox::create("错误", &format!("{}", e), IconType::Error).expect("panic!");
+            }
+        }
+    }
 }
 
 pub trait BankUi: Send {
+    /// The logged user shown in this screen, to update the balance pushed by server
+    fn user_mut(&mut self) -> Option<&mut User> {
+        None
+    }
+
     fn render(&mut self, _: &mut StateData, ctx: &Context, args: BankUiRenderArg<'_>) -> Option<Box<dyn BankUi>>;
 }
 
@@ -0,0 +1,48 @@
+use bank_protocol::{account, Notification, NotifyKind};
+use chrono::Local;
+use egui::{Color32, Context, Frame, RichText};
+
+/// Keep only the latest notifications on the screen
+pub const MAX_NOTICES: usize = 5;
+
+/// One line text for the notification
+pub fn describe(notice: &Notification) -> String {
+    let counterpart = if notice.counterpart.len() == account::ACCOUNT_NUMBER_LEN {
+        account::group_account_number(&notice.counterpart)
+    } else {
+        notice.counterpart.clone()
+    };
+    let action = match notice.kind {
+        NotifyKind::Credit => format!("收到 {} 的 {}.{:02}", counterpart, notice.amount / 100, notice.amount % 100),
+        NotifyKind::Debit => format!("向 {} 支出 {}.{:02}", counterpart, notice.amount / 100, notice.amount % 100),
+        NotifyKind::Admin => format!("银行调整 {}.{:02}", notice.amount / 100, notice.amount % 100),
+    };
+    let mut text = format!("[{}] {}，余额 {}.{:02}", notice.time.with_timezone(&Local).format("%H:%M:%S"),
+                           action, notice.balance / 100, notice.balance % 100);
+    if !notice.msg.is_empty() {
+        text.push('（');
+        text.push_str(&notice.msg);
+        text.push('）');
+    }
+    text
+}
+
+/// Show the notifications on the top, return the index dismissed
+pub fn render_notices(ctx: &Context, notices: &[Notification]) -> Option<usize> {
+    if notices.is_empty() {
+        return None;
+    }
+    let mut dismissed = None;
+    egui::TopBottomPanel::top("notices").frame(Frame::default().fill(Color32::from_gray(32))).show(ctx, |ui| {
+        for (i, notice) in notices.iter().enumerate() {
+            ui.horizontal(|ui| {
+                let color = if notice.kind == NotifyKind::Credit { Color32::LIGHT_GREEN } else { Color32::LIGHT_RED };
+                ui.label(RichText::new(describe(notice)).color(color));
+                if ui.small_button("×").clicked() {
+                    dismissed = Some(i);
+                }
+            });
+        }
+    });
+    dismissed
+}
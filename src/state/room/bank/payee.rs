@@ -0,0 +1,112 @@
+use bank_protocol::{account, PayeeEntry, RecentEntry, Request};
+use egui::{Button, Color32, Context, Frame, ScrollArea, Vec2};
+use msgbox::IconType;
+
+use crate::engine::StateData;
+use crate::state::room::bank::{BankUi, BankUiRenderArg};
+use crate::state::room::bank::index::User;
+use crate::state::room::bank::transfer::Transfer;
+
+/// Add, edit and delete the saved payees
+pub struct PayeeBook {
+    pub(crate) user: User,
+    /// (payee, editing nickname, editing account)
+    payees: Vec<(PayeeEntry, String, String)>,
+    recent: Vec<RecentEntry>,
+    nickname: String,
+    account: String,
+}
+
+impl PayeeBook {
+    pub fn new(user: User, payees: Vec<PayeeEntry>, recent: Vec<RecentEntry>) -> Self {
+        let payees = payees.into_iter()
+            .map(|x| {
+                let nickname = x.nickname.clone();
+                let account = x.account.clone();
+                (x, nickname, account)
+            })
+            .collect();
+        Self { user, payees, recent, nickname: Default::default(), account: Default::default() }
+    }
+}
+
+/// Ask server for the payees, server will reply b"payl"
+pub fn request_payees(args: &BankUiRenderArg<'_>, for_edit: bool) {
+    args.send(&Request::ListPayees { for_edit });
+}
+
+fn check_payee(nickname: &str, target: &str) -> bool {
+    if nickname.trim().is_empty() {
+        msgbox::create("错误", "备注名不能为空", IconType::Error).expect("panic!");
+        return false;
+    }
+    if let Err(e) = account::parse_account_number(target) {
+        msgbox::create("错误", e.msg(), IconType::Error).expect("panic!");
+        return false;
+    }
+    true
+}
+
+impl BankUi for PayeeBook {
+    fn user_mut(&mut self) -> Option<&mut User> {
+        Some(&mut self.user)
+    }
+
+    fn render(&mut self, s: &mut StateData, ctx: &Context, args: BankUiRenderArg<'_>) -> Option<Box<dyn BankUi>> {
+        let mut ret = None;
+        egui::CentralPanel::default().frame(Frame::default().fill(Color32::BLACK)).show(ctx, |ui| {
+            ui.vertical_centered(|ui| {
+                // 1600 900
+                let scale = s.app.gpu.as_ref().unwrap().size_scale;
+                let size = Vec2::new(320.0, 180.0) * Vec2::from(scale);
+                let back = Button::new("返回").min_size(size * 0.5);
+                ui.heading("收款人");
+                ScrollArea::vertical().max_height(ui.max_rect().height() - size.y * 1.5).show(ui, |ui| {
+                    for (payee, nickname, target) in &mut self.payees {
+                        ui.horizontal(|ui| {
+                            ui.label("备注：");
+                            ui.text_edit_singleline(nickname);
+                            ui.label("账号：");
+                            ui.text_edit_singleline(target);
+                            if ui.button("保存").clicked() && check_payee(nickname, target) {
+                                args.send(&Request::EditPayee {
+                                    pid: payee.pid,
+                                    nickname: nickname.trim().to_string(),
+                                    account: target.clone(),
+                                });
+                            }
+                            if ui.button("删除").clicked() {
+                                args.send(&Request::DeletePayee { pid: payee.pid });
+                            }
+                        });
+                    }
+                    if !self.recent.is_empty() {
+                        ui.label("最近转账：");
+                        for recent in &self.recent {
+                            if ui.button(format!("{} ({})", account::group_account_number(&recent.account), recent.time.date_naive())).clicked() {
+                                self.account = recent.account.clone();
+                            }
+                        }
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label("备注：");
+                    ui.text_edit_singleline(&mut self.nickname);
+                    ui.label("账号：");
+                    ui.text_edit_singleline(&mut self.account);
+                    if ui.button("添加").clicked() && check_payee(&self.nickname, &self.account) {
+                        args.send(&Request::AddPayee {
+                            nickname: self.nickname.trim().to_string(),
+                            account: self.account.clone(),
+                        });
+                    }
+                });
+                if ui.add_sized(size * 0.5, back).clicked() {
+                    request_payees(&args, false);
+                    ret = Some(Box::new(Transfer::new(self.user.clone())) as _);
+                }
+            });
+        });
+        ret
+    }
+}
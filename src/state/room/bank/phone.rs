@@ -0,0 +1,60 @@
+use bank_protocol::{phone, Request};
+use egui::{Button, Color32, Context, Frame, Vec2};
+
+use crate::engine::StateData;
+use crate::state::room::bank::{BankUi, BankUiRenderArg};
+use crate::state::room::bank::index::{Index, User};
+
+/// Link or unlink the phone number for receiving transfers
+pub struct PhoneAlias {
+    pub(crate) user: User,
+}
+
+impl PhoneAlias {
+    pub fn new(user: User) -> Self {
+        Self { user }
+    }
+}
+
+impl BankUi for PhoneAlias {
+    fn user_mut(&mut self) -> Option<&mut User> {
+        Some(&mut self.user)
+    }
+
+    fn render(&mut self, s: &mut StateData, ctx: &Context, args: BankUiRenderArg<'_>) -> Option<Box<dyn BankUi>> {
+        let mut ret = None;
+        egui::CentralPanel::default().frame(Frame::default().fill(Color32::BLACK)).show(ctx, |ui| {
+            ui.vertical_centered(|ui| {
+                // 1600 900
+                let scale = s.app.gpu.as_ref().unwrap().size_scale;
+                let size = Vec2::new(320.0, 180.0) * Vec2::from(scale);
+                let link = Button::new("开通").min_size(size);
+                let unlink = Button::new("关闭").min_size(size);
+                let back = Button::new("返回").min_size(size);
+                ui.vertical_centered(|ui| {
+                    let max = ui.max_rect().height();
+                    ui.add_space(max * 0.5 - size.y * 1.5);
+                    match phone::normalize_phone(&self.user.phone) {
+                        Some(phone) => {
+                            ui.label(format!("开通后他人可以通过手机号 {} 向本账户转账，对方只能看到打码后的姓名", phone::mask_phone(&phone)));
+                        }
+                        None => {
+                            ui.colored_label(Color32::RED, "预留手机号格式错误，无法开通手机号收款");
+                        }
+                    }
+                    for (button, link) in [(link, true), (unlink, false)] {
+                        if ui.add_sized(size, button).clicked() {
+                            args.send(&Request::LinkPhone { link });
+                        }
+                    }
+                    if ui.add_sized(size, back).clicked() {
+                        ret = Some(Box::new(Index {
+                            user: self.user.clone(),
+                        }) as _);
+                    }
+                });
+            });
+        });
+        ret
+    }
+}
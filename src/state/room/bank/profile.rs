@@ -0,0 +1,277 @@
+use bank_protocol::{phone, Request};
+use egui::{Button, Color32, Context, Frame, TextEdit, Vec2};
+use msgbox::IconType;
+
+use crate::engine::StateData;
+use crate::state::room::bank::{BankUi, BankUiRenderArg};
+use crate::state::room::bank::index::{Index, User};
+use crate::state::room::bank::menu::hash_password;
+
+/// Change the profile or the password, log out or close the account
+pub struct AccountSettings {
+    pub(crate) user: User,
+}
+
+/// Change the name and the phone, the password is needed for the phone
+pub struct EditProfile {
+    pub(crate) user: User,
+    name: String,
+    phone: String,
+    password: String,
+}
+
+/// Change the password with the old one, and the totp code if enabled
+pub struct ChangePassword {
+    pub(crate) user: User,
+    old: String,
+    new: String,
+    confirm: String,
+    code: String,
+}
+
+/// Close the account with the password, the balance is paid to another account
+pub struct CloseAccount {
+    pub(crate) user: User,
+    payout: String,
+    password: String,
+    code: String,
+    /// Asked again before sending
+    confirmed: bool,
+}
+
+impl AccountSettings {
+    pub fn new(user: User) -> Self {
+        Self { user }
+    }
+}
+
+impl EditProfile {
+    pub fn new(user: User) -> Self {
+        Self { name: user.name.clone(), phone: user.phone.clone(), user, password: Default::default() }
+    }
+}
+
+impl ChangePassword {
+    pub fn new(user: User) -> Self {
+        Self { user, old: Default::default(), new: Default::default(), confirm: Default::default(), code: Default::default() }
+    }
+}
+
+impl CloseAccount {
+    pub fn new(user: User) -> Self {
+        Self { user, payout: Default::default(), password: Default::default(), code: Default::default(), confirmed: false }
+    }
+}
+
+impl BankUi for AccountSettings {
+    fn user_mut(&mut self) -> Option<&mut User> {
+        Some(&mut self.user)
+    }
+
+    fn render(&mut self, s: &mut StateData, ctx: &Context, args: BankUiRenderArg<'_>) -> Option<Box<dyn BankUi>> {
+        let mut ret = None;
+        egui::CentralPanel::default().frame(Frame::default().fill(Color32::BLACK)).show(ctx, |ui| {
+            ui.vertical_centered(|ui| {
+                // 1600 900
+                let scale = s.app.gpu.as_ref().unwrap().size_scale;
+                let size = Vec2::new(320.0, 150.0) * Vec2::from(scale);
+                let profile = Button::new("修改资料").min_size(size);
+                let password = Button::new("修改密码").min_size(size);
+                let logout = Button::new("退出登录").min_size(size);
+                let close = Button::new("销户").min_size(size);
+                let back = Button::new("返回").min_size(size);
+                ui.vertical_centered(|ui| {
+                    let max = ui.max_rect().height();
+                    ui.add_space(max * 0.5 - size.y * 2.5);
+                    if ui.add_sized(size, profile).clicked() {
+                        ret = Some(Box::new(EditProfile::new(self.user.clone())) as Box<dyn BankUi>);
+                    }
+                    if ui.add_sized(size, password).clicked() {
+                        ret = Some(Box::new(ChangePassword::new(self.user.clone())) as Box<dyn BankUi>);
+                    }
+                    if ui.add_sized(size, logout).clicked() {
+                        args.send(&Request::Logout);
+                    }
+                    if ui.add_sized(size, close).clicked() {
+                        ret = Some(Box::new(CloseAccount::new(self.user.clone())) as Box<dyn BankUi>);
+                    }
+                    if ui.add_sized(size, back).clicked() {
+                        ret = Some(Box::new(Index {
+                            user: self.user.clone(),
+                        }) as _);
+                    }
+                });
+            });
+        });
+        ret
+    }
+}
+
+impl BankUi for EditProfile {
+    fn user_mut(&mut self) -> Option<&mut User> {
+        Some(&mut self.user)
+    }
+
+    fn render(&mut self, s: &mut StateData, ctx: &Context, args: BankUiRenderArg<'_>) -> Option<Box<dyn BankUi>> {
+        let mut ret = None;
+        egui::CentralPanel::default().frame(Frame::default().fill(Color32::BLACK)).show(ctx, |ui| {
+            ui.vertical_centered(|ui| {
+                // 1600 900
+                let scale = s.app.gpu.as_ref().unwrap().size_scale;
+                let size = Vec2::new(320.0, 180.0) * Vec2::from(scale);
+                let save = Button::new("保存").min_size(size);
+                let back = Button::new("返回").min_size(size);
+                ui.vertical_centered(|ui| {
+                    let max = ui.max_rect().height();
+                    ui.add_space(max * 0.5 - size.y * 1.5);
+                    ui.label("姓名：");
+                    ui.text_edit_singleline(&mut self.name);
+                    ui.label("手机号：");
+                    ui.text_edit_singleline(&mut self.phone);
+                    let phone_changed = self.phone.trim() != self.user.phone;
+                    if phone_changed {
+                        if !self.phone.trim().is_empty() && phone::normalize_phone(&self.phone).is_none() {
+                            ui.colored_label(Color32::RED, "手机号格式错误");
+                        }
+                        ui.label("修改手机号需要验证密码，已开通的手机号收款将被关闭：");
+                        ui.add(TextEdit::singleline(&mut self.password).password(true));
+                    }
+                    ui.label("");
+                    if ui.add_sized(size, save).clicked() {
+                        if self.name.trim().is_empty() {
+                            msgbox::create("错误", "姓名不能为空", IconType::Error).expect("panic!");
+                            return;
+                        }
+                        let password = if phone_changed {
+                            if self.password.is_empty() {
+                                msgbox::create("错误", "修改手机号需要输入密码", IconType::Error).expect("panic!");
+                                return;
+                            }
+                            Some(hash_password(&self.password))
+                        } else {
+                            None
+                        };
+                        args.send(&Request::UpdateProfile {
+                            name: self.name.trim().to_string(),
+                            phone: self.phone.trim().to_string(),
+                            password,
+                        });
+                        self.password.clear();
+                    }
+                    if ui.add_sized(size, back).clicked() {
+                        ret = Some(Box::new(AccountSettings::new(self.user.clone())) as _);
+                    }
+                });
+            });
+        });
+        ret
+    }
+}
+
+impl BankUi for ChangePassword {
+    fn user_mut(&mut self) -> Option<&mut User> {
+        Some(&mut self.user)
+    }
+
+    fn render(&mut self, s: &mut StateData, ctx: &Context, args: BankUiRenderArg<'_>) -> Option<Box<dyn BankUi>> {
+        let mut ret = None;
+        egui::CentralPanel::default().frame(Frame::default().fill(Color32::BLACK)).show(ctx, |ui| {
+            ui.vertical_centered(|ui| {
+                // 1600 900
+                let scale = s.app.gpu.as_ref().unwrap().size_scale;
+                let size = Vec2::new(320.0, 180.0) * Vec2::from(scale);
+                let change = Button::new("修改").min_size(size);
+                let back = Button::new("返回").min_size(size);
+                ui.vertical_centered(|ui| {
+                    let max = ui.max_rect().height();
+                    ui.add_space(max * 0.5 - size.y * 1.5);
+                    ui.label("原密码：");
+                    ui.add(TextEdit::singleline(&mut self.old).password(true));
+                    ui.label("新密码：");
+                    ui.add(TextEdit::singleline(&mut self.new).password(true));
+                    ui.label("确认新密码：");
+                    ui.add(TextEdit::singleline(&mut self.confirm).password(true));
+                    if !self.confirm.is_empty() && self.confirm != self.new {
+                        ui.colored_label(Color32::RED, "两次输入的新密码不一致");
+                    }
+                    ui.label("动态验证码（开启两步验证时需要）：");
+                    ui.text_edit_singleline(&mut self.code);
+                    ui.label("修改后其他设备将退出登录");
+                    if ui.add_sized(size, change).clicked() {
+                        if self.old.is_empty() || self.new.is_empty() {
+                            msgbox::create("错误", "密码不能为空", IconType::Error).expect("panic!");
+                            return;
+                        }
+                        if self.new != self.confirm {
+                            msgbox::create("错误", "两次输入的新密码不一致", IconType::Error).expect("panic!");
+                            return;
+                        }
+                        let code = Some(self.code.trim().to_string()).filter(|x| !x.is_empty());
+                        args.send(&Request::ChangePassword { old: hash_password(&self.old), new: hash_password(&self.new), code });
+                        self.old.clear();
+                        self.new.clear();
+                        self.confirm.clear();
+                        self.code.clear();
+                    }
+                    if ui.add_sized(size, back).clicked() {
+                        ret = Some(Box::new(AccountSettings::new(self.user.clone())) as _);
+                    }
+                });
+            });
+        });
+        ret
+    }
+}
+
+impl BankUi for CloseAccount {
+    fn user_mut(&mut self) -> Option<&mut User> {
+        Some(&mut self.user)
+    }
+
+    fn render(&mut self, s: &mut StateData, ctx: &Context, args: BankUiRenderArg<'_>) -> Option<Box<dyn BankUi>> {
+        let mut ret = None;
+        egui::CentralPanel::default().frame(Frame::default().fill(Color32::BLACK)).show(ctx, |ui| {
+            ui.vertical_centered(|ui| {
+                // 1600 900
+                let scale = s.app.gpu.as_ref().unwrap().size_scale;
+                let size = Vec2::new(320.0, 180.0) * Vec2::from(scale);
+                let close = Button::new(if self.confirmed { "确认销户" } else { "销户" }).min_size(size);
+                let back = Button::new("返回").min_size(size);
+                ui.vertical_centered(|ui| {
+                    let max = ui.max_rect().height();
+                    ui.add_space(max * 0.5 - size.y * 1.5);
+                    ui.label(format!("余额 {}.{:02} 将转入以下账户（账号或手机号）：", self.user.balance / 100, self.user.balance % 100));
+                    if ui.text_edit_singleline(&mut self.payout).changed() {
+                        self.confirmed = false;
+                    }
+                    ui.label("密码：");
+                    ui.add(TextEdit::singleline(&mut self.password).password(true));
+                    ui.label("动态验证码（开启两步验证时需要）：");
+                    ui.text_edit_singleline(&mut self.code);
+                    if self.confirmed {
+                        ui.colored_label(Color32::RED, "销户后无法恢复，只能查询记录，请再次确认");
+                    }
+                    if ui.add_sized(size, close).clicked() {
+                        if self.payout.trim().is_empty() || self.password.is_empty() {
+                            msgbox::create("错误", "请输入收款账户和密码", IconType::Error).expect("panic!");
+                            return;
+                        }
+                        if !self.confirmed {
+                            self.confirmed = true;
+                            return;
+                        }
+                        let code = Some(self.code.trim().to_string()).filter(|x| !x.is_empty());
+                        args.send(&Request::CloseAccount { payout: self.payout.trim().to_string(), password: hash_password(&self.password), code });
+                        self.password.clear();
+                        self.code.clear();
+                        self.confirmed = false;
+                    }
+                    if ui.add_sized(size, back).clicked() {
+                        ret = Some(Box::new(AccountSettings::new(self.user.clone())) as _);
+                    }
+                });
+            });
+        });
+        ret
+    }
+}
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use bank_protocol::{AuthRequest, Request, TotpCode, UNSOLICITED};
+
+/// Requests waiting for reply at most, the older are forgotten
+const MAX_PENDING: usize = 64;
+
+/// What the request is for, to tell the user which one failed
+pub trait Operation {
+    fn operation(&self) -> &'static str;
+}
+
+impl Operation for AuthRequest {
+    fn operation(&self) -> &'static str {
+        match self {
+            AuthRequest::Login { .. } => "登录",
+            AuthRequest::Register { .. } => "注册",
+        }
+    }
+}
+
+impl Operation for TotpCode {
+    fn operation(&self) -> &'static str {
+        "两步验证"
+    }
+}
+
+impl Operation for Request {
+    fn operation(&self) -> &'static str {
+        match self {
+            Request::Deposit { .. } => "存款",
+            Request::Withdraw { .. } => "取款",
+            Request::LegacyTransfer { .. } | Request::Transfer { .. } => "转账",
+            Request::Info => "查询流水",
+            Request::BeginTotp | Request::ConfirmTotp { .. } => "开启两步验证",
+            Request::DisableTotp { .. } => "关闭两步验证",
+            Request::ResolveRecipient { .. } => "确认收款人",
+            Request::ListPayees { .. } => "查询收款人",
+            Request::AddPayee { .. } => "添加收款人",
+            Request::EditPayee { .. } => "修改收款人",
+            Request::DeletePayee { .. } => "删除收款人",
+            Request::LinkPhone { link: true } => "开通手机号收款",
+            Request::LinkPhone { link: false } => "关闭手机号收款",
+            Request::Logout => "退出登录",
+            Request::ChangePassword { .. } => "修改密码",
+            Request::UpdateProfile { .. } => "修改资料",
+            Request::CloseAccount { .. } => "销户",
+            Request::QuoteFee { .. } => "查询手续费",
+            Request::IdentifyDevice { .. } => "识别设备",
+        }
+    }
+}
+
+/// Number the requests sent and remember their operations until replied
+#[derive(Default)]
+pub struct RequestTracker {
+    last_id: AtomicU32,
+    pending: Mutex<HashMap<u32, &'static str>>,
+}
+
+impl RequestTracker {
+    /// The id for the new request
+    pub fn start(&self, operation: &'static str) -> u32 {
+        let mut id = self.last_id.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        if id == UNSOLICITED {
+            id = self.last_id.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        }
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() >= MAX_PENDING {
+            pending.retain(|x, _| id.wrapping_sub(*x) < MAX_PENDING as u32);
+        }
+        pending.insert(id, operation);
+        id
+    }
+
+    /// The operation of the request replied
+    pub fn finish(&self, id: u32) -> Option<&'static str> {
+        if id == UNSOLICITED {
+            return None;
+        }
+        self.pending.lock().unwrap().remove(&id)
+    }
+}
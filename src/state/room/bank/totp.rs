@@ -0,0 +1,230 @@
+use bank_protocol::{Request, TotpCode};
+use egui::{Button, Color32, Context, Frame, Rect, Sense, Ui, Vec2};
+use msgbox::IconType;
+
+use crate::engine::StateData;
+use crate::state::room::bank::{BankUi, BankUiRenderArg};
+use crate::state::room::bank::index::{Index, User};
+
+/// Enable or disable the totp
+pub struct TotpMenu {
+    pub(crate) user: User,
+    code: String,
+}
+
+/// Input the totp code after password accepted
+#[derive(Default)]
+pub struct TotpLogin {
+    code: String,
+}
+
+/// Show the secret and qr code to bind, and confirm with the first code
+pub struct TotpSetup {
+    pub(crate) user: User,
+    secret: String,
+    uri: String,
+    /// (width, is dark for each module)
+    qr: Option<(usize, Vec<bool>)>,
+    code: String,
+}
+
+/// Show the recovery codes once after totp enabled
+pub struct RecoveryCodes {
+    pub(crate) user: User,
+    codes: Vec<String>,
+}
+
+impl TotpMenu {
+    pub fn new(user: User) -> Self {
+        Self { user, code: Default::default() }
+    }
+}
+
+impl TotpSetup {
+    pub fn new(user: User, secret: String, uri: String) -> Self {
+        let qr = match qrcode::QrCode::new(uri.as_bytes()) {
+            Ok(code) => {
+                let colors = code.to_colors().into_iter()
+                    .map(|x| x == qrcode::Color::Dark)
+                    .collect();
+                Some((code.width(), colors))
+            }
+            Err(e) => {
+                log::warn!("Create qr code failed for {:?}", e);
+                None
+            }
+        };
+        Self { user, secret, uri, qr, code: Default::default() }
+    }
+}
+
+impl RecoveryCodes {
+    pub fn new(user: User, codes: Vec<String>) -> Self {
+        Self { user, codes }
+    }
+}
+
+fn draw_qr(ui: &mut Ui, width: usize, dark: &[bool], size: f32) {
+    // keep 4 modules quiet zone
+    let modules = width + 8;
+    let cell = size / modules as f32;
+    let (rect, _) = ui.allocate_exact_size(Vec2::splat(size), Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0.0, Color32::WHITE);
+    for y in 0..width {
+        for x in 0..width {
+            if dark[y * width + x] {
+                let min = rect.min + Vec2::new((x + 4) as f32 * cell, (y + 4) as f32 * cell);
+                painter.rect_filled(Rect::from_min_size(min, Vec2::splat(cell)), 0.0, Color32::BLACK);
+            }
+        }
+    }
+}
+
+impl BankUi for TotpMenu {
+    fn user_mut(&mut self) -> Option<&mut User> {
+        Some(&mut self.user)
+    }
+
+    fn render(&mut self, s: &mut StateData, ctx: &Context, args: BankUiRenderArg<'_>) -> Option<Box<dyn BankUi>> {
+        let mut ret = None;
+        egui::CentralPanel::default().frame(Frame::default().fill(Color32::BLACK)).show(ctx, |ui| {
+            ui.vertical_centered(|ui| {
+                // 1600 900
+                let scale = s.app.gpu.as_ref().unwrap().size_scale;
+                let size = Vec2::new(320.0, 180.0) * Vec2::from(scale);
+                let enable = Button::new("开启两步验证").min_size(size);
+                let disable = Button::new("关闭两步验证").min_size(size);
+                let back = Button::new("返回").min_size(size);
+                ui.vertical_centered(|ui| {
+                    let max = ui.max_rect().height();
+                    ui.add_space(max * 0.5 - size.y * 1.5);
+                    if ui.add_sized(size, enable).clicked() {
+                        args.send(&Request::BeginTotp);
+                    }
+                    ui.label("动态验证码或恢复码：");
+                    ui.text_edit_singleline(&mut self.code);
+                    if ui.add_sized(size, disable).clicked() {
+                        if self.code.trim().is_empty() {
+                            msgbox::create("错误", "关闭需要输入验证码", IconType::Error).expect("panic!");
+                            return;
+                        }
+                        args.send(&Request::DisableTotp { code: self.code.trim().to_string() });
+                        self.code.clear();
+                    }
+                    if ui.add_sized(size, back).clicked() {
+                        ret = Some(Box::new(Index {
+                            user: self.user.clone(),
+                        }) as _);
+                    }
+                });
+            });
+        });
+        ret
+    }
+}
+
+impl BankUi for TotpLogin {
+    fn render(&mut self, s: &mut StateData, ctx: &Context, args: BankUiRenderArg<'_>) -> Option<Box<dyn BankUi>> {
+        egui::CentralPanel::default().frame(Frame::default().fill(Color32::BLACK)).show(ctx, |ui| {
+            ui.vertical_centered(|ui| {
+                // 1600 900
+                let scale = s.app.gpu.as_ref().unwrap().size_scale;
+                let size = Vec2::new(320.0, 180.0) * Vec2::from(scale);
+                let confirm = Button::new("验证").min_size(size);
+                ui.vertical_centered(|ui| {
+                    let max = ui.max_rect().height();
+                    ui.add_space(max * 0.5 - size.y * 0.5);
+                    ui.label("动态验证码（或恢复码）：");
+                    ui.text_edit_singleline(&mut self.code);
+                    ui.label("");
+                    if ui.add_sized(size, confirm).clicked() {
+                        if self.code.trim().is_empty() {
+                            msgbox::create("错误", "验证码不能为空", IconType::Error).expect("panic!");
+                            return;
+                        }
+                        args.send(&TotpCode { code: self.code.trim().to_string() });
+                        self.code.clear();
+                    }
+                });
+            });
+        });
+        None
+    }
+}
+
+impl BankUi for TotpSetup {
+    fn user_mut(&mut self) -> Option<&mut User> {
+        Some(&mut self.user)
+    }
+
+    fn render(&mut self, s: &mut StateData, ctx: &Context, args: BankUiRenderArg<'_>) -> Option<Box<dyn BankUi>> {
+        let mut ret = None;
+        egui::CentralPanel::default().frame(Frame::default().fill(Color32::BLACK)).show(ctx, |ui| {
+            ui.vertical_centered(|ui| {
+                // 1600 900
+                let scale = s.app.gpu.as_ref().unwrap().size_scale;
+                let size = Vec2::new(320.0, 180.0) * Vec2::from(scale);
+                let confirm = Button::new("确认绑定").min_size(size * 0.5);
+                let back = Button::new("返回").min_size(size * 0.5);
+                ui.vertical_centered(|ui| {
+                    ui.heading("使用验证器应用扫描二维码");
+                    if let Some((width, dark)) = &self.qr {
+                        draw_qr(ui, *width, dark, size.y * 2.0);
+                    }
+                    ui.label(format!("密钥：{}", self.secret));
+                    ui.label(&self.uri);
+                    ui.label("动态验证码：");
+                    ui.text_edit_singleline(&mut self.code);
+                    if ui.add_sized(size * 0.5, confirm).clicked() {
+                        if self.code.trim().is_empty() {
+                            msgbox::create("错误", "验证码不能为空", IconType::Error).expect("panic!");
+                            return;
+                        }
+                        args.send(&Request::ConfirmTotp { code: self.code.trim().to_string() });
+                        self.code.clear();
+                    }
+                    if ui.add_sized(size * 0.5, back).clicked() {
+                        ret = Some(Box::new(Index {
+                            user: self.user.clone(),
+                        }) as _);
+                    }
+                });
+            });
+        });
+        ret
+    }
+}
+
+impl BankUi for RecoveryCodes {
+    fn user_mut(&mut self) -> Option<&mut User> {
+        Some(&mut self.user)
+    }
+
+    fn render(&mut self, s: &mut StateData, ctx: &Context, _: BankUiRenderArg<'_>) -> Option<Box<dyn BankUi>> {
+        let mut ret = None;
+        egui::CentralPanel::default().frame(Frame::default().fill(Color32::BLACK)).show(ctx, |ui| {
+            ui.vertical_centered(|ui| {
+                // 1600 900
+                let scale = s.app.gpu.as_ref().unwrap().size_scale;
+                let size = Vec2::new(320.0, 180.0) * Vec2::from(scale);
+                let back = Button::new("我已保存").min_size(size);
+                ui.vertical_centered(|ui| {
+                    let max = ui.max_rect().height();
+                    ui.add_space(max * 0.5 - size.y * 1.5);
+                    ui.heading("两步验证已开启，请保存恢复码（每个只能使用一次）");
+                    for code in &self.codes {
+                        ui.monospace(code);
+                    }
+                    ui.label("");
+                    if ui.add_sized(size, back).clicked() {
+                        ret = Some(Box::new(Index {
+                            user: self.user.clone(),
+                        }) as _);
+                    }
+                });
+            });
+        });
+        ret
+    }
+}
@@ -1,30 +1,58 @@
 use std::str::FromStr;
 
-use bytes::BufMut;
+use bank_protocol::{account, PayeeEntry, phone, RecentEntry, Request};
 use egui::{Button, Color32, Context, Frame, Vec2};
 use msgbox::IconType;
 
-use crate::engine::network::NetworkMessage;
 use crate::engine::StateData;
-use crate::ext::PacketWriteExt;
 use crate::state::room::bank::{BankUi, BankUiRenderArg};
 use crate::state::room::bank::index::{Index, User};
+use crate::state::room::bank::payee::request_payees;
+use crate::state::room::bank::withdraw::format_cents;
 
 pub struct Transfer {
     pub(crate) user: User,
     target: String,
     amount: String,
+    payees: Vec<PayeeEntry>,
+    recent: Vec<RecentEntry>,
+}
 
+/// Show the recipient resolved by server and send the transfer after confirmed
+pub struct TransferConfirm {
+    pub(crate) user: User,
+    token: String,
+    target: String,
+    masked_name: String,
+    amount: u32,
+    /// Quoted by server
+    fee: u32,
+    /// Totp code for large amount
+    code: String,
 }
 
 impl Transfer {
     pub fn new(user: User) -> Self {
-        Self { user, target: "".into(), amount: Default::default() }
+        Self::with_payees(user, vec![], vec![])
+    }
+
+    pub fn with_payees(user: User, payees: Vec<PayeeEntry>, recent: Vec<RecentEntry>) -> Self {
+        Self { user, target: "".into(), amount: Default::default(), payees, recent }
+    }
+}
+
+impl TransferConfirm {
+    pub fn new(user: User, token: String, target: String, masked_name: String, amount: u32, fee: u32) -> Self {
+        Self { user, token, target, masked_name, amount, fee, code: Default::default() }
     }
 }
 
 
 impl BankUi for Transfer {
+    fn user_mut(&mut self) -> Option<&mut User> {
+        Some(&mut self.user)
+    }
+
     fn render(&mut self, s: &mut StateData, ctx: &Context, args: BankUiRenderArg<'_>) -> Option<Box<dyn BankUi>> {
         let mut ret = None;
         egui::CentralPanel::default().frame(Frame::default().fill(Color32::BLACK)).show(ctx, |ui| {
@@ -32,26 +60,51 @@ impl BankUi for Transfer {
                 // 1600 900
                 let scale = s.app.gpu.as_ref().unwrap().size_scale;
                 let size = Vec2::new(320.0, 180.0) * Vec2::from(scale);
-                let transfer = Button::new("转账").min_size(size);
+                let transfer = Button::new("下一步").min_size(size);
                 let back = Button::new("返回").min_size(size);
+                let payee_book = Button::new("管理收款人");
                 ui.vertical_centered(|ui| {
                     let max = ui.max_rect().height();
-                    ui.add_space(max * 0.5 - size.y * 1.0);
-                    ui.label("目标账号：");
+                    ui.add_space(max * 0.5 - size.y * 1.5);
+                    ui.horizontal_wrapped(|ui| {
+                        for payee in &self.payees {
+                            if ui.button(format!("{} ({})", payee.nickname, account::group_account_number(&payee.account))).clicked() {
+                                self.target = payee.account.clone();
+                            }
+                        }
+                        for recent in &self.recent {
+                            if ui.button(format!("最近：{}", account::group_account_number(&recent.account))).clicked() {
+                                self.target = recent.account.clone();
+                            }
+                        }
+                        if ui.add(payee_book).clicked() {
+                            request_payees(&args, true);
+                        }
+                    });
+                    ui.label("目标账号或手机号：");
                     ui.text_edit_singleline(&mut self.target);
+                    if let Some(phone) = phone::normalize_phone(&self.target) {
+                        ui.colored_label(Color32::GREEN, format!("手机号转账：{}", phone));
+                    } else if !self.target.is_empty() {
+                        match account::parse_account_number(&self.target) {
+                            Ok(id) => {
+                                ui.colored_label(Color32::GREEN, account::group_account_number(&account::format_account_number(id)));
+                            }
+                            Err(e) => {
+                                ui.colored_label(Color32::RED, e.msg());
+                            }
+                        }
+                    }
                     ui.label("数量：");
                     ui.text_edit_singleline(&mut self.amount);
                     ui.label("");
                     if ui.add_sized(size, transfer).clicked() {
-                        let target = match u32::from_str(&self.target) {
-                            Ok(id) => {
-                                id
-                            }
-                            Err(_) => {
-                                msgbox::create("错误", "需要为数字", IconType::Error).expect("panic!");
+                        if phone::normalize_phone(&self.target).is_none() {
+                            if let Err(e) = account::parse_account_number(&self.target) {
+                                msgbox::create("错误", e.msg(), IconType::Error).expect("panic!");
                                 return;
                             }
-                        };
+                        }
                         let amount = match u32::from_str(&self.amount) {
                             Ok(id) => {
                                 id
@@ -62,13 +115,9 @@ impl BankUi for Transfer {
                             }
                         };
                         if amount > 0 {
-                            let mut data = Vec::<u8>::new();
-                            data.add_header();
-                            data.put_u8(2);
-                            data.put_u32(target);
-                            data.put_u32(amount);
-                            let peer = args.target;
-                            peer.sender.send(NetworkMessage::Rely(data)).expect("how send error");
+                            // resolve the recipient first and the server will send b"cfrm", then b"feeq" for the fee
+                            args.send(&Request::ResolveRecipient { target: self.target.clone(), amount });
+                            args.send(&Request::QuoteFee { operation: "transfer".into(), amount });
                         }
                     }
                     if ui.add_sized(size, back).clicked() {
@@ -81,4 +130,58 @@ impl BankUi for Transfer {
         });
         ret
     }
-}
\ No newline at end of file
+}
+
+impl BankUi for TransferConfirm {
+    fn user_mut(&mut self) -> Option<&mut User> {
+        Some(&mut self.user)
+    }
+
+    fn render(&mut self, s: &mut StateData, ctx: &Context, args: BankUiRenderArg<'_>) -> Option<Box<dyn BankUi>> {
+        let mut ret = None;
+        egui::CentralPanel::default().frame(Frame::default().fill(Color32::BLACK)).show(ctx, |ui| {
+            ui.vertical_centered(|ui| {
+                // 1600 900
+                let scale = s.app.gpu.as_ref().unwrap().size_scale;
+                let size = Vec2::new(320.0, 180.0) * Vec2::from(scale);
+                let confirm = Button::new("确认转账").min_size(size);
+                let back = Button::new("取消").min_size(size);
+                ui.vertical_centered(|ui| {
+                    let max = ui.max_rect().height();
+                    ui.add_space(max * 0.5 - size.y * 1.0);
+                    ui.heading("请确认收款人");
+                    if phone::normalize_phone(&self.target).is_some() {
+                        ui.label(format!("收款手机号：{}", self.target));
+                    } else {
+                        ui.label(format!("收款账号：{}", account::group_account_number(&self.target)));
+                    }
+                    ui.label(format!("收款人：{}", self.masked_name));
+                    ui.label(format!("金额：{}", format_cents(self.amount)));
+                    ui.label(format!("手续费：{}", format_cents(self.fee)));
+                    if self.fee > 0 {
+                        let total = self.amount as u64 + self.fee as u64;
+                        ui.label(format!("合计扣款：{}.{:02}", total / 100, total % 100));
+                    }
+                    ui.label("动态验证码（大额转账或需要验证时填写）：");
+                    ui.text_edit_singleline(&mut self.code);
+                    ui.label("");
+                    if ui.add_sized(size, confirm).clicked() {
+                        let code = self.code.trim();
+                        args.send(&Request::Transfer {
+                            target: self.target.clone(),
+                            amount: self.amount,
+                            token: self.token.clone(),
+                            code: (!code.is_empty()).then(|| code.to_string()),
+                        });
+                        // the token is used once, go back and wait for the menu
+                        ret = Some(Box::new(Transfer::new(self.user.clone())) as _);
+                    }
+                    if ui.add_sized(size, back).clicked() {
+                        ret = Some(Box::new(Transfer::new(self.user.clone())) as _);
+                    }
+                });
+            });
+        });
+        ret
+    }
+}
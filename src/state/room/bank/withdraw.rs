@@ -1,12 +1,10 @@
 use std::str::FromStr;
 
-use bytes::BufMut;
+use bank_protocol::Request;
 use egui::{Button, Color32, Context, Frame, Vec2};
 use msgbox::IconType;
 
-use crate::engine::network::NetworkMessage;
 use crate::engine::StateData;
-use crate::ext::PacketWriteExt;
 use crate::state::room::bank::{BankUi, BankUiRenderArg};
 use crate::state::room::bank::index::{Index, User};
 
@@ -16,14 +14,32 @@ pub struct Withdraw {
 
 }
 
+/// Show the fee quoted by server and withdraw after confirmed
+pub struct WithdrawConfirm {
+    pub(crate) user: User,
+    amount: u32,
+    fee: u32,
+    code: String,
+}
+
 impl Withdraw {
     pub fn new(user: User) -> Self {
         Self { user, amount: Default::default() }
     }
 }
 
+impl WithdrawConfirm {
+    pub fn new(user: User, amount: u32, fee: u32) -> Self {
+        Self { user, amount, fee, code: String::new() }
+    }
+}
+
 
 impl BankUi for Withdraw {
+    fn user_mut(&mut self) -> Option<&mut User> {
+        Some(&mut self.user)
+    }
+
     fn render(&mut self, s: &mut StateData, ctx: &Context, args: BankUiRenderArg<'_>) -> Option<Box<dyn BankUi>> {
         let mut ret = None;
         egui::CentralPanel::default().frame(Frame::default().fill(Color32::BLACK)).show(ctx, |ui| {
@@ -31,7 +47,7 @@ impl BankUi for Withdraw {
                 // 1600 900
                 let scale = s.app.gpu.as_ref().unwrap().size_scale;
                 let size = Vec2::new(320.0, 180.0) * Vec2::from(scale);
-                let withdraw = Button::new("取款").min_size(size);
+                let withdraw = Button::new("下一步").min_size(size);
                 let back = Button::new("返回").min_size(size);
                 ui.vertical_centered(|ui| {
                     let max = ui.max_rect().height();
@@ -54,12 +70,8 @@ impl BankUi for Withdraw {
                             return;
                         }
                         if amount > 0 {
-                            let mut data = Vec::<u8>::new();
-                            data.add_header();
-                            data.put_u8(1);
-                            data.put_u32(amount);
-                            let peer = args.target;
-                            peer.sender.send(NetworkMessage::Rely(data)).expect("how send error");
+                            // the server will send b"feeq" to confirm
+                            args.send(&Request::QuoteFee { operation: "withdraw".into(), amount });
                         }
                     }
                     if ui.add_sized(size, back).clicked() {
@@ -72,4 +84,54 @@ impl BankUi for Withdraw {
         });
         ret
     }
-}
\ No newline at end of file
+}
+impl BankUi for WithdrawConfirm {
+    fn user_mut(&mut self) -> Option<&mut User> {
+        Some(&mut self.user)
+    }
+
+    fn render(&mut self, s: &mut StateData, ctx: &Context, args: BankUiRenderArg<'_>) -> Option<Box<dyn BankUi>> {
+        let mut ret = None;
+        egui::CentralPanel::default().frame(Frame::default().fill(Color32::BLACK)).show(ctx, |ui| {
+            ui.vertical_centered(|ui| {
+                // 1600 900
+                let scale = s.app.gpu.as_ref().unwrap().size_scale;
+                let size = Vec2::new(320.0, 180.0) * Vec2::from(scale);
+                let confirm = Button::new("确认取款").min_size(size);
+                let back = Button::new("取消").min_size(size);
+                ui.vertical_centered(|ui| {
+                    let max = ui.max_rect().height();
+                    ui.add_space(max * 0.5 - size.y * 1.0);
+                    ui.heading("请确认取款");
+                    ui.label(format!("金额：{}", format_cents(self.amount)));
+                    ui.label(format!("手续费：{}", format_cents(self.fee)));
+                    let total = self.amount as u64 + self.fee as u64;
+                    ui.label(format!("合计扣款：{}.{:02}", total / 100, total % 100));
+                    if total > self.user.balance as u64 {
+                        ui.colored_label(Color32::RED, "余额不足以支付手续费");
+                    }
+                    ui.label("动态验证码（需要验证时填写）：");
+                    ui.text_edit_singleline(&mut self.code);
+                    ui.label("");
+                    if ui.add_sized(size, confirm).clicked() {
+                        let code = self.code.trim();
+                        args.send(&Request::Withdraw {
+                            amount: self.amount,
+                            code: (!code.is_empty()).then(|| code.to_string()),
+                        });
+                        ret = Some(Box::new(Withdraw::new(self.user.clone())) as _);
+                    }
+                    if ui.add_sized(size, back).clicked() {
+                        ret = Some(Box::new(Withdraw::new(self.user.clone())) as _);
+                    }
+                });
+            });
+        });
+        ret
+    }
+}
+
+/// Like "1.00"
+pub fn format_cents(cents: u32) -> String {
+    format!("{}.{:02}", cents / 100, cents % 100)
+}
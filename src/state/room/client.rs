@@ -1,19 +1,43 @@
 use std::net::SocketAddr;
+use std::time::Duration;
 
-use tokio_kcp::KcpStream;
+use anyhow::anyhow;
+use log::warn;
 
-use crate::engine::network::DEFAULT_KCP_CONFIG;
+use crate::engine::network::NetworkMessage;
 use crate::engine::network::peer::Peer;
+use crate::engine::network::transport::{connect, Transport};
 use crate::state::room::{MessageHandler, ReceiverType};
 
+/// Try the next transport if the server not replied the hello in time
+const HELLO_TIMEOUT: Duration = Duration::from_secs(3);
+
 pub struct Client {
     pub target: Peer,
     pub receiver: ReceiverType,
 }
 
-async fn get_target_receiver(addr: SocketAddr) -> anyhow::Result<(Peer, ReceiverType)> {
-    let stream = KcpStream::connect(&DEFAULT_KCP_CONFIG, addr).await?;
-    log::info!("Connected to {}", addr);
+/// The addresses of the server, WebSocket is listened on its own one
+#[derive(Debug, Clone, Copy)]
+pub struct ServerAddrs {
+    /// KCP and TCP
+    pub stream: SocketAddr,
+    /// None to never try WebSocket
+    pub websocket: Option<SocketAddr>,
+}
+
+impl ServerAddrs {
+    fn of(&self, transport: Transport) -> Option<SocketAddr> {
+        match transport {
+            Transport::WebSocket => self.websocket,
+            _ => Some(self.stream),
+        }
+    }
+}
+
+async fn get_target_receiver(addr: SocketAddr, transport: Transport) -> anyhow::Result<(Peer, ReceiverType)> {
+    let stream = connect(transport, addr).await?;
+    log::info!("Connected to {} by {}", addr, transport);
 
     let (receiver, handler) = MessageHandler::create();
     let target = Peer::new(stream, addr, handler);
@@ -25,11 +49,43 @@ async fn get_target_receiver(addr: SocketAddr) -> anyhow::Result<(Peer, Receiver
 
 impl Client {
     /// Should be called in `tokio` context
-    pub async fn new(addr: SocketAddr) -> anyhow::Result<Self> {
-        let (target, receiver) = get_target_receiver(addr).await?;
+    pub async fn new(addr: SocketAddr, transport: Transport) -> anyhow::Result<Self> {
+        let (target, receiver) = get_target_receiver(addr, transport).await?;
         Ok(Self {
             target,
             receiver,
         })
     }
-}
\ No newline at end of file
+
+    /// Send the hello by the transport, or by each of [`Transport::FALLBACK`] until replied.
+    ///
+    /// Return the client and the reply. Should be called in `tokio` context
+    pub async fn connect(addrs: ServerAddrs, transport: Option<Transport>, hello: Vec<u8>) -> anyhow::Result<(Self, Vec<u8>)> {
+        let transports = transport.map(|x| vec![x]).unwrap_or_else(|| Transport::FALLBACK.to_vec());
+        let mut last_err = anyhow!("No transport to try");
+        for transport in transports {
+            let Some(addr) = addrs.of(transport) else {
+                last_err = anyhow!("No address for {}", transport);
+                continue;
+            };
+            let attempt = async {
+                let mut client = Client::new(addr, transport).await?;
+                client.target.sender.send(NetworkMessage::Rely(hello.clone()))?;
+                let (_, reply) = client.receiver.recv().await.ok_or(anyhow!("Connection closed"))?;
+                anyhow::Ok((client, reply))
+            };
+            match tokio::time::timeout(HELLO_TIMEOUT, attempt).await {
+                Ok(Ok(x)) => return Ok(x),
+                Ok(Err(e)) => {
+                    warn!("Connect {} by {} failed for {:?}", addr, transport, e);
+                    last_err = e;
+                }
+                Err(_) => {
+                    warn!("No reply from {} by {}", addr, transport);
+                    last_err = anyhow!("No reply from {} by {}", addr, transport);
+                }
+            }
+        }
+        Err(last_err)
+    }
+}
@@ -1,25 +1,40 @@
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::Arc;
 use std::sync::atomic::Ordering;
 
 use anyhow::anyhow;
-use bytes::Buf;
-use chrono::DateTime;
-use egui::Context;
-use log::info;
+use bank_protocol::compress::Compression;
+use bank_protocol::heartbeat::{LinkStats, Quality};
+use bank_protocol::queue::PeerSender;
+use bank_protocol::{Capabilities, Handshake, Message, Notification, PacketWriter, ProtocolError, Request, Response, Tagged};
+use egui::{Align2, Color32, Context, RichText, vec2};
+use log::{info, warn};
 use msgbox::IconType;
 use tokio::runtime::{Builder, Runtime};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 use winit::event::VirtualKeyCode;
 
+use crate::config;
 use crate::engine::{GameState, LoopState, StateData, Trans};
+use crate::engine::network::NetworkMessage;
 use crate::engine::network::peer::Peer;
+use crate::engine::network::transport::Transport;
 use crate::engine::window::EventLoopMessage;
-use crate::ext::{CURRENT_VERSION, PacketReadExt};
 use crate::state::room::{bank, ReceiverType};
 use crate::state::room::bank::{BankUi, BankUiRenderArg};
 use crate::state::room::bank::index::{Index, User};
-use crate::state::room::bank::info::{InfoUi, TradeInfo};
-use crate::state::room::client::Client;
+use crate::state::room::bank::info::InfoUi;
+use crate::state::room::bank::notify::{MAX_NOTICES, render_notices};
+use crate::state::room::bank::totp::{RecoveryCodes, TotpLogin, TotpSetup};
+use crate::state::room::bank::payee::PayeeBook;
+use crate::state::room::bank::request::{Operation, RequestTracker};
+use crate::state::room::bank::transfer::{Transfer, TransferConfirm};
+use crate::state::room::bank::withdraw::WithdrawConfirm;
+use crate::state::room::client::{Client, ServerAddrs};
+
+/// The optional features the client supports
+const CLIENT_CAPABILITIES: Capabilities = Capabilities::NOTIFICATIONS.union(Capabilities::ZSTD).union(Capabilities::LZ4)
+    .union(Capabilities::HEARTBEAT);
 
 pub struct ConnectingState {
     /// The tokio runtime to run tasks
@@ -28,24 +43,35 @@ pub struct ConnectingState {
     pub(crate) target: Peer,
     bank: Box<dyn BankUi>,
     change_ui: UnboundedReceiver<Box<dyn BankUi>>,
+    notify: UnboundedReceiver<Notification>,
+    /// Shared with the receiving task to match the replies
+    requests: Arc<RequestTracker>,
+    /// The latest notifications not dismissed
+    notices: Vec<Notification>,
 }
 
 // build runtime and new host state and then new peer
 
 impl ConnectingState {
-    pub async fn create(addr: String, is_join: bool) -> anyhow::Result<Self> {
+    /// `websocket` is the address of WebSocket, empty to not try it
+    pub async fn create(addr: String, websocket: String, is_join: bool, transport: Option<Transport>) -> anyhow::Result<Self> {
         let rt = Builder::new_multi_thread()
             .worker_threads(4)
             .enable_all()
             .build()?;
-        let addr = rt.spawn(async move {
-            tokio::net::lookup_host(&addr).await?.next().ok_or(anyhow!("Get ip failed"))
+        let (addr, websocket) = rt.spawn(async move {
+            let addr = tokio::net::lookup_host(&addr).await?.next().ok_or(anyhow!("Get ip failed"))?;
+            let websocket = match websocket.trim() {
+                "" => None,
+                x => Some(tokio::net::lookup_host(x).await?.next().ok_or(anyhow!("Get websocket ip failed"))?),
+            };
+            anyhow::Ok((addr, websocket))
         }).await??;
 
 
         info!("Listening on {}", addr);
 
-        let connect_ip = if is_join {
+        let connect_ip = |addr: SocketAddr| if is_join {
             addr
         } else {
             match addr {
@@ -58,17 +84,25 @@ impl ConnectingState {
                 }
             }
         };
-        let client = rt.spawn(Client::new(connect_ip)).await??;
+        let addrs = ServerAddrs { stream: connect_ip(addr), websocket: websocket.map(connect_ip) };
+        // the hello must be the first packet, and also tells which transport works
+        let hello = Handshake::hello(CLIENT_CAPABILITIES).to_packet()?;
+        let (client, reply) = rt.spawn(Client::connect(addrs, transport, hello)).await??;
+        handle_handshake(&reply, &client.target.sender);
 
         let (tx, rx) = unbounded_channel();
+        let (notify_tx, notify_rx) = unbounded_channel();
         let this = Self {
             rt,
             target: client.target,
             bank: Box::new(bank::menu::BankMenu::default()),
             change_ui: rx,
+            notify: notify_rx,
+            requests: Default::default(),
+            notices: vec![],
         };
 
-        this.get_msg(client.receiver, tx);
+        this.get_msg(client.receiver, tx, notify_tx);
         Ok(this)
     }
 }
@@ -82,6 +116,15 @@ impl GameState for ConnectingState {
         while let Ok(ui) = self.change_ui.try_recv() {
             self.bank = ui;
         }
+        while let Ok(notice) = self.notify.try_recv() {
+            if let Some(user) = self.bank.user_mut() {
+                user.balance = notice.balance;
+            }
+            self.notices.push(notice);
+            if self.notices.len() > MAX_NOTICES {
+                self.notices.remove(0);
+            }
+        }
         if s.app.inputs.is_pressed(&[VirtualKeyCode::Escape]) || !self.target.listening.load(Ordering::Relaxed) {
             (Trans::Pop, LoopState::WAIT)
         } else {
@@ -92,9 +135,14 @@ impl GameState for ConnectingState {
 
     fn render(&mut self, s: &mut StateData, ctx: &Context) -> Trans {
         let tran = Trans::None;
+        if let Some(i) = render_notices(ctx, &self.notices) {
+            self.notices.remove(i);
+        }
+        render_link(ctx, &self.target.link);
         let ret = self.bank.render(s, ctx, BankUiRenderArg {
             rt: &self.rt,
             target: &self.target,
+            requests: &self.requests,
         });
         if let Some(ret) = ret {
             self.bank = ret;
@@ -104,75 +152,168 @@ impl GameState for ConnectingState {
     }
 }
 
+/// Show the quality of the connection measured by the heartbeats at the corner
+fn render_link(ctx: &Context, link: &LinkStats) {
+    let (quality, color) = match link.quality() {
+        Quality::Unknown => ("未知", Color32::GRAY),
+        Quality::Good => ("良好", Color32::LIGHT_GREEN),
+        Quality::Fair => ("一般", Color32::YELLOW),
+        Quality::Poor => ("较差", Color32::LIGHT_RED),
+    };
+    let mut text = format!("网络：{}", quality);
+    if let Some(rtt) = link.rtt() {
+        text.push_str(&format!(" {} ms", rtt.as_millis()));
+    }
+    if link.loss() > 0.0 {
+        text.push_str(&format!(" 丢包 {:.0}%", link.loss() * 100.0));
+    }
+    egui::Area::new("link").anchor(Align2::RIGHT_BOTTOM, vec2(-8.0, -8.0)).show(ctx, |ui| {
+        ui.label(RichText::new(text).color(color));
+    });
+}
+
+/// Handle the reply of the hello
+fn handle_handshake(data: &[u8], target: &PeerSender) {
+    match Handshake::from_packet(data) {
+        Ok(Handshake::Welcome { version, capabilities }) => {
+            info!("Server accepted version {} with {:?}", version, capabilities);
+            let _ = target.send_control(NetworkMessage::Compress(Compression::negotiate(capabilities)));
+            let _ = target.send_control(NetworkMessage::Heartbeat(capabilities.contains(Capabilities::HEARTBEAT)));
+        }
+        Ok(Handshake::Reject { min_version, max_version, reason }) => {
+            let msg = format!("{}\n服务器支持的协议版本：{} - {}", reason, min_version, max_version);
+            msgbox::create("无法连接", &msg, IconType::Error).unwrap();
+        }
+        Ok(x) => warn!("Unexpected handshake {:?}", x),
+        Err(e) => warn!("Receive bad handshake for {}", e),
+    }
+}
+
+/// Tell the server this installation after logged in, not replied unless failed
+fn identify_device(target: &PeerSender, requests: &RequestTracker) {
+    let Some(device) = config::device_id() else { return };
+    let request = Request::IdentifyDevice { device };
+    let id = requests.start(request.operation());
+    let mut w = PacketWriter::with_header();
+    w.put_u32(id);
+    if request.encode(&mut w).is_err() || target.send(NetworkMessage::Rely(w.into_inner())).is_err() {
+        requests.finish(id);
+        warn!("Send the device id failed");
+    }
+}
+
 impl ConnectingState {
-    fn get_msg(&self, mut receiver: ReceiverType, sender: UnboundedSender<Box<dyn BankUi>>) {
+    fn get_msg(&self, mut receiver: ReceiverType, sender: UnboundedSender<Box<dyn BankUi>>, notify: UnboundedSender<Notification>) {
+        let requests = self.requests.clone();
+        let target = self.target.sender.clone();
         self.rt.spawn(async move {
+            // the user from the last menu, for the screens opened by server
+            let mut last_user: Option<User> = None;
+            // the recipient confirmed, shown after the fee quoted: (token, target, masked_name, amount)
+            let mut pending_confirm: Option<(String, String, String, u32)> = None;
+            let mut version_warned = false;
             while let Some((_, data)) = receiver.recv().await {
-                if data.len() < 12 || &data[0..4] != b"rPtm" {
-                    continue;
-                }
-                let version = (&data[4..8]).get_u32();
-
-                if version != CURRENT_VERSION {
+                if bank_protocol::is_handshake(&data) {
+                    handle_handshake(&data, &target);
                     continue;
                 }
-
-                let r#type = &data[8..12];
-                let mut data = &data[12..];
-                match r#type {
-                    b"msgb" => {
-                        let msg = data.read_packet_string().unwrap();
+                let Tagged { id, body: response } = match Tagged::<Response>::from_packet(&data) {
+                    Ok(tagged) => tagged,
+                    Err(ProtocolError::UnsupportedVersion(version)) => {
+                        warn!("Receive packet of version {}", version);
+                        if !version_warned {
+                            version_warned = true;
+                            msgbox::create("错误", "服务器协议版本不兼容，请升级客户端", IconType::Error).unwrap();
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("Receive bad packet for {}", e);
+                        continue;
+                    }
+                };
+                let operation = requests.finish(id);
+                match response {
+                    Response::Tip(msg) => {
                         msgbox::create("Tip!", &msg, IconType::Info).unwrap();
                     }
-                    b"menu" => {
+                    Response::Failed(failure) => {
+                        let title = operation.map(|x| format!("{}失败", x)).unwrap_or_else(|| "错误".into());
+                        msgbox::create(&title, &failure.describe(), IconType::Error).unwrap();
+                    }
+                    Response::Error(failure) => {
+                        warn!("Server error: {:?}", failure);
+                        msgbox::create("连接已断开", &failure.describe(), IconType::Error).unwrap();
+                    }
+                    Response::Menu(user) => {
                         info!("Menu packet!");
-                        let id = data.get_u32();
-                        let name = data.read_packet_string().unwrap();
-                        let balance = data.get_u32();
-                        let phone = data.read_packet_string().unwrap();
+                        let user = User::from(user);
+                        // the first menu after logged in
+                        if last_user.is_none() {
+                            identify_device(&target, &requests);
+                        }
+                        last_user = Some(user.clone());
                         let _ = sender.send(Box::new(Index {
-                            user: User {
-                                id,
-                                balance,
-                                name,
-                                phone,
-                            },
+                            user,
                         }));
                     }
-                    b"info" => {
-                        info!("Info packet!");
-                        let _cur_page = data.get_u32();
-                        let _total_page = data.get_u32();
-                        let info_count = data.get_u32();
-
-                        let id = data.get_u32();
-                        let name = data.read_packet_string().unwrap();
-                        let balance = data.get_u32();
-                        let phone = data.read_packet_string().unwrap();
-
-                        let mut info = vec![];
-                        info!("Got info count: {}", info_count);
-                        for _ in 0..info_count {
-                            let tid = data.get_i32();
-                            let receiver = data.get_u32();
-                            let sender = data.read_packet_string().unwrap();
-                            let secs = data.get_i64();
-                            let nanos = data.get_u32();
-                            let date_time = DateTime::from_timestamp(secs, nanos)
-                                .unwrap();
-                            let amount = data.get_i32();
-
-                            info.push(TradeInfo::new(tid, receiver, sender, date_time, amount));
+                    Response::Info { user, trades, .. } => {
+                        info!("Got info count: {}", trades.len());
+                        let user = User::from(user);
+                        last_user = Some(user.clone());
+                        let _ = sender.send(Box::new(InfoUi::new(user, trades)) as _);
+                    }
+                    Response::TotpRequired => {
+                        info!("Login need totp code");
+                        let _ = sender.send(Box::new(TotpLogin::default()));
+                    }
+                    Response::TotpBegin { secret, uri } => {
+                        if let Some(user) = &last_user {
+                            let _ = sender.send(Box::new(TotpSetup::new(user.clone(), secret, uri)));
+                        }
+                    }
+                    Response::RecoveryCodes(codes) => {
+                        if let Some(user) = &last_user {
+                            let _ = sender.send(Box::new(RecoveryCodes::new(user.clone(), codes)));
+                        }
+                    }
+                    Response::Confirm { token, target, masked_name, amount } => {
+                        pending_confirm = Some((token, target, masked_name, amount));
+                    }
+                    Response::FeeQuote { operation, amount, fee } => {
+                        let Some(user) = &last_user else { continue };
+                        match operation.as_str() {
+                            "withdraw" => {
+                                let _ = sender.send(Box::new(WithdrawConfirm::new(user.clone(), amount, fee)));
+                            }
+                            "transfer" => {
+                                if let Some((token, target, masked_name, amount)) = pending_confirm.take().filter(|x| x.3 == amount) {
+                                    let _ = sender.send(Box::new(TransferConfirm::new(user.clone(), token, target, masked_name, amount, fee)));
+                                }
+                            }
+                            _ => warn!("Fee quoted for unknown {}", operation),
+                        }
+                    }
+                    Response::Payees { for_edit, payees, recent } => {
+                        if let Some(user) = &last_user {
+                            if for_edit {
+                                let _ = sender.send(Box::new(PayeeBook::new(user.clone(), payees, recent)));
+                            } else {
+                                let _ = sender.send(Box::new(Transfer::with_payees(user.clone(), payees, recent)));
+                            }
+                        }
+                    }
+                    Response::Notify(notice) => {
+                        if let Some(user) = &mut last_user {
+                            user.balance = notice.balance;
                         }
-                        let _ = sender.send(Box::new(InfoUi::new(User {
-                            id,
-                            balance,
-                            name,
-                            phone,
-                        }, info)) as _);
+                        let _ = notify.send(notice);
                     }
-                    _ => {
-                        info!("Receive unknown packet: {:?}", r#type);
+                    Response::LoggedOut => {
+                        info!("Logged out");
+                        last_user = None;
+                        pending_confirm = None;
+                        let _ = sender.send(Box::new(bank::menu::BankMenu::default()));
                     }
                 }
             }
@@ -1,21 +1,29 @@
-use egui::{Button, Color32, Context, Frame, RichText};
+use egui::{Button, Color32, ComboBox, Context, Frame, RichText};
 use futures::task::SpawnExt;
 
+use crate::config;
 use crate::engine::{GameState, LoopState, StateData, Trans, WaitFutureState, WaitResult};
 use crate::engine::global::IO_POOL;
+use crate::engine::network::transport::Transport;
 use crate::engine::window::EventLoopMessage;
 use crate::state::room::connecting::ConnectingState;
 
 pub struct JoiningRoom {
     addr: String,
+    /// The address of WebSocket, empty to not try it
+    websocket: String,
     is_join: bool,
+    /// None to try each of them
+    transport: Option<Transport>,
 }
 
 impl Default for JoiningRoom {
     fn default() -> Self {
         Self {
             addr: "0.0.0.0:5555".to_string(),
+            websocket: config::websocket_addr(),
             is_join: false,
+            transport: None,
         }
     }
 }
@@ -25,7 +33,9 @@ impl JoiningRoom {
     pub fn join() -> Self {
         Self {
             addr: "[::1]:1234".to_string(),
+            websocket: config::websocket_addr(),
             is_join: true,
+            transport: None,
         }
     }
 }
@@ -52,6 +62,25 @@ impl GameState for JoiningRoom {
                 ui.heading(["监听IP地址:", "加入IP地址:"][self.is_join as usize]);
                 ui.text_edit_singleline(&mut self.addr);
 
+                let transport_name = |x: Option<Transport>| match x {
+                    None => "自动",
+                    Some(Transport::Kcp) => "KCP",
+                    Some(Transport::Tcp) => "TCP",
+                    Some(Transport::WebSocket) => "WebSocket",
+                };
+                ComboBox::from_label("传输方式")
+                    .selected_text(transport_name(self.transport))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.transport, None, transport_name(None));
+                        for x in Transport::FALLBACK {
+                            ui.selectable_value(&mut self.transport, Some(x), transport_name(Some(x)));
+                        }
+                    });
+                if self.transport.is_none() || self.transport == Some(Transport::WebSocket) {
+                    ui.label("WebSocket地址:");
+                    ui.text_edit_singleline(&mut self.websocket);
+                }
+
                 ui.add_space((ui.available_height() / 4.0).min(100.0));
 
                 ui.style_mut().spacing.button_padding *= 8.0;
@@ -60,9 +89,11 @@ impl GameState for JoiningRoom {
                 let ret = Button::new(RichText::new("返回").heading());
                 if ui.add(create).clicked() {
                     let addr = self.addr.clone();
+                    let websocket = self.websocket.clone();
                     let is_join = self.is_join;
+                    let transport = self.transport;
                     match IO_POOL.spawn_with_handle(async move {
-                        let result = ConnectingState::create(addr, is_join).await;
+                        let result = ConnectingState::create(addr, websocket, is_join, transport).await;
                         match result {
                             Ok(ok) => {
                                 WaitResult::Switch(Box::new(ok))
@@ -11,6 +11,7 @@
 
 use std::net::SocketAddr;
 
+use bank_protocol::chunk::ChunkProgress;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
 pub use join::*;
@@ -33,6 +34,10 @@ impl DataHandler for MessageHandler {
         let x = self.sender.send((src.addr, Vec::from(data))).is_ok();
         x
     }
+
+    fn progress(&self, src: &Peer, progress: ChunkProgress) {
+        log::debug!("Receiving {} / {} bytes from {}", progress.received, progress.total, src.addr);
+    }
 }
 
 pub type ReceiverType = UnboundedReceiver<(SocketAddr, Vec<u8>)>;